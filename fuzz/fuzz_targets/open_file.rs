@@ -0,0 +1,22 @@
+//! Feeds arbitrary bytes to everything that parses a data file. Any panic
+//! or oversized allocation is a bug, bad files have to come back as errors.
+//! Run with `cargo fuzz run open_file` from the repo root.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tempfile::NamedTempFile;
+use tiny_db_exp::EmbeddedDatabase;
+
+fuzz_target!(|data: &[u8]| {
+    let temp_file = NamedTempFile::new().expect("failed to create temp file");
+    std::fs::write(temp_file.path(), data).expect("failed to write temp file");
+
+    let _ = EmbeddedDatabase::verify_backup(temp_file.path(), None);
+    if let Ok(mut db) = EmbeddedDatabase::new(temp_file.path()) {
+        let _ = db.iter().map(|iter| iter.count());
+        for key in db.scan_keys("") {
+            let _ = db.get_bytes(&key);
+        }
+        let _ = db.compact();
+    }
+});
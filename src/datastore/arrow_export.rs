@@ -0,0 +1,164 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{collection_of, user_key},
+    database::read_record_at,
+    transform::TransformerRegistry,
+};
+use arrow_array::{
+    ArrayRef, RecordBatch, RecordBatchReader, StringArray, TimestampMillisecondArray, UInt64Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use std::{fs::File, sync::Arc, vec};
+
+/// Columns of the exported batches:
+/// * `collection`: "" for the default collection
+/// * `key` & `value`, decoded
+/// * `seq`: sequence number of the write that produced the value, the
+///   records don't carry a wall clock time
+/// * `expires_at`: when the TTL runs out, null without one
+pub fn arrow_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("collection", DataType::Utf8, false),
+        Field::new("key", DataType::Utf8, false),
+        Field::new("value", DataType::Utf8, false),
+        Field::new("seq", DataType::UInt64, false),
+        Field::new(
+            "expires_at",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            true,
+        ),
+    ]))
+}
+
+/// Live records as Arrow record batches, from a snapshot taken when it was
+/// created (see `SnapshotIter`). It is a `RecordBatchReader`, so it can be
+/// handed to DataFusion's `MemTable`/stream providers or a Parquet writer.
+pub struct ArrowBatches {
+    file: File,
+    entries: vec::IntoIter<(String, u64)>, // Stored key & offset of its record
+    transformers: Arc<TransformerRegistry>,
+    batch_size: usize,
+    schema: SchemaRef,
+    version: u32, // Format of the file the handle points at
+}
+
+impl ArrowBatches {
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>> {
+        let mut collections = Vec::with_capacity(self.batch_size);
+        let mut keys = Vec::with_capacity(self.batch_size);
+        let mut values = Vec::with_capacity(self.batch_size);
+        let mut seqs = Vec::with_capacity(self.batch_size);
+        let mut expiries = Vec::with_capacity(self.batch_size);
+        for (stored_key, offset) in self.entries.by_ref().take(self.batch_size) {
+            let record = read_record_at(&mut self.file, offset, self.version)?;
+            seqs.push(record.seq);
+            expiries.push(record.expires_at.map(|millis| millis as i64));
+            values.push(String::from_utf8(self.transformers.decode(record)?)?);
+            collections.push(collection_of(&stored_key).to_string());
+            keys.push(user_key(&stored_key).to_string());
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(collections)),
+            Arc::new(StringArray::from(keys)),
+            Arc::new(StringArray::from(values)),
+            Arc::new(UInt64Array::from(seqs)),
+            Arc::new(TimestampMillisecondArray::from(expiries)),
+        ];
+        Ok(Some(RecordBatch::try_new(self.schema.clone(), columns)?))
+    }
+}
+
+impl Iterator for ArrowBatches {
+    type Item = std::result::Result<RecordBatch, ArrowError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch()
+            .map_err(|err| ArrowError::ExternalError(err.to_string().into()))
+            .transpose()
+    }
+}
+
+impl RecordBatchReader for ArrowBatches {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Every live record of the user collections (the ones the db keeps for
+    /// itself, starting with "__", are left out) in batches of `batch_size`
+    /// rows, ordered by collection & key
+    pub fn arrow_batches(&self, batch_size: usize) -> Result<ArrowBatches> {
+        let entries: Vec<(String, u64)> = self
+            .all_live_keys()
+            .into_iter()
+            .filter(|key| !collection_of(key).starts_with("__"))
+            .filter_map(|key| {
+                let (offset, _) = self.live_span(&key)?;
+                Some((key, offset))
+            })
+            .collect();
+        Ok(ArrowBatches {
+            file: File::open(&self.path)?,
+            entries: entries.into_iter(),
+            transformers: self.transformers.clone(),
+            batch_size: batch_size.max(1),
+            schema: arrow_schema(),
+            version: self.format_version,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::datastore::collection::namespaced_key;
+    use arrow_array::Array;
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_arrow_batches() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        for i in 0..5 {
+            db.set(&format!("key{i}"), &format!("val{i}")).unwrap();
+        }
+        db.collection("users").unwrap().set("42", "Alice").unwrap();
+        db.put(
+            namespaced_key("users", "ttl"),
+            b"Bob",
+            Some(Duration::from_secs(60)),
+        )
+        .unwrap();
+        db.queue("jobs").unwrap().enqueue("internal").unwrap();
+
+        let batches: Vec<RecordBatch> = db
+            .arrow_batches(4)
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            batches
+                .iter()
+                .map(RecordBatch::num_rows)
+                .collect::<Vec<_>>(),
+            vec![4, 3]
+        );
+
+        let last = &batches[1];
+        let column = |name: &str| last.column_by_name(name).unwrap().clone();
+        let collections = column("collection");
+        let collections = collections.as_any().downcast_ref::<StringArray>().unwrap();
+        let values = column("value");
+        let values = values.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(collections.value(0), "");
+        assert_eq!((collections.value(1), values.value(1)), ("users", "Alice"));
+        let expiries = column("expires_at");
+        assert!(expiries.is_null(1) && !expiries.is_null(2));
+    }
+}
@@ -0,0 +1,185 @@
+use super::{
+    EmbeddedDatabase, MacKey, Record, RecordKind, Result, header::check_header,
+    integrity::verify_whole_file,
+};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// What `verify_backup` found in a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackupReport {
+    pub bytes: u64,
+    /// Records of every kind, padding & commit markers included
+    pub records: u64,
+    /// Keys holding a value at the end of the file, expired or not
+    pub live_keys: u64,
+    pub batches: u64,
+    pub last_seq: u64,
+    /// Whether the file matched its MAC sidecar, `None` without a key
+    pub mac_verified: Option<bool>,
+    /// Everything wrong with the file, empty for a good backup
+    pub problems: Vec<String>,
+}
+
+impl BackupReport {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Check a backup or copied data file without opening it for writing:
+    /// every frame has to decode, the file can't end in a torn record or an
+    /// uncommitted batch, & with `mac_key` it has to be signed all the way
+    /// through by its `.mac` sidecar. The file has no trailer to compare
+    /// counts against, the report has what was found so a pipeline can
+    /// compare them with what it expects.
+    /// Only fails if the file can't be read at all, problems with its
+    /// contents end up in `BackupReport::problems`.
+    pub fn verify_backup<P: AsRef<Path>>(
+        path: P,
+        mac_key: Option<&MacKey>,
+    ) -> Result<BackupReport> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mut report = BackupReport {
+            bytes: file.metadata()?.len(),
+            ..Default::default()
+        };
+
+        let version = match check_header(&mut file.try_clone()?) {
+            Ok(header) => header.version,
+            Err(err) => {
+                report.problems.push(err.to_string());
+                return Ok(report);
+            }
+        };
+        // The clones share one cursor
+        (&file).seek(SeekFrom::Start(0))?;
+
+        let mut reader = BufReader::new(file.try_clone()?);
+        let mut live = HashSet::new();
+        let mut pending_batch: Vec<Record> = Vec::new();
+        let mut position = 0;
+        while position < report.bytes {
+            let mut len_buffer = [0u8; 8];
+            let len = match reader.read_exact(&mut len_buffer) {
+                Ok(()) => u64::from_le_bytes(len_buffer),
+                Err(_) => {
+                    report
+                        .problems
+                        .push(format!("torn length prefix at byte {position}"));
+                    break;
+                }
+            };
+            if len > report.bytes.saturating_sub(position + 8) {
+                report.problems.push(format!(
+                    "record at byte {position} claims {len} bytes, past the end of the file"
+                ));
+                break;
+            }
+            let mut record_buffer = vec![0u8; len as usize];
+            reader.read_exact(&mut record_buffer)?;
+            let record = match Record::decode(&record_buffer, version) {
+                Ok(record) => record,
+                Err(err) => {
+                    report
+                        .problems
+                        .push(format!("record at byte {position} doesn't decode: {err}"));
+                    break;
+                }
+            };
+            if record.kind != RecordKind::Header {
+                report.records += 1;
+            }
+            report.last_seq = report.last_seq.max(record.seq);
+            match record.kind {
+                RecordKind::Single => apply(&mut live, record),
+                RecordKind::Batched => pending_batch.push(record),
+                RecordKind::BatchCommit => {
+                    report.batches += 1;
+                    for record in pending_batch.drain(..) {
+                        apply(&mut live, record);
+                    }
+                }
+                RecordKind::Padding | RecordKind::Header => {}
+            }
+            position += 8 + len;
+        }
+        if !pending_batch.is_empty() {
+            report.problems.push(format!(
+                "the file ends in a batch of {} records without its commit marker",
+                pending_batch.len()
+            ));
+        }
+        report.live_keys = live.len() as u64;
+
+        if let Some(key) = mac_key {
+            let verified = verify_whole_file(path, key, &file);
+            if let Err(err) = &verified {
+                report.problems.push(err.to_string());
+            }
+            report.mac_verified = Some(verified.is_ok());
+        }
+        Ok(report)
+    }
+}
+
+fn apply(live: &mut HashSet<String>, record: Record) {
+    if record.is_tombstone() {
+        live.remove(&record.key);
+    } else {
+        live.insert(record.key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, WriteBatch};
+    use std::{fs, io::Write};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_verify_backup() {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let path = dir.path().join("live.db");
+        let key = MacKey::new([7; 32]);
+        let options = DbOptions {
+            mac_key: Some(key.clone()),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(&path, options).unwrap();
+        db.set("a", "1").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("b", "2").delete("a");
+        db.apply_batch(batch).unwrap();
+        db.sync().unwrap();
+
+        let report = EmbeddedDatabase::verify_backup(&path, Some(&key)).unwrap();
+        assert!(report.is_valid(), "{:?}", report.problems);
+        assert_eq!(report.records, 4);
+        assert_eq!(report.live_keys, 1);
+        assert_eq!(report.batches, 1);
+        assert_eq!(report.last_seq, 2);
+        assert_eq!(report.mac_verified, Some(true));
+
+        // A copy that was cut short & one that was tampered with
+        let torn = dir.path().join("torn.db");
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&torn, &bytes[..bytes.len() - 3]).unwrap();
+        let report = EmbeddedDatabase::verify_backup(&torn, None).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.mac_verified, None);
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0; 8]).unwrap();
+        let report = EmbeddedDatabase::verify_backup(&path, Some(&key)).unwrap();
+        assert_eq!(report.mac_verified, Some(false));
+        assert!(EmbeddedDatabase::verify_backup(dir.path().join("missing.db"), None).is_err());
+    }
+}
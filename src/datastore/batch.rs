@@ -0,0 +1,66 @@
+/// A single staged operation. Keys are stored together with their collection
+/// so one batch can touch any number of collections.
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Set {
+        collection: String,
+        key: String,
+        val: String,
+    },
+    Delete {
+        collection: String,
+        key: String,
+    },
+}
+
+/// A group of `set`/`delete` operations that are applied all together or not at all.
+/// Stage operations on it, then hand it to `EmbeddedDatabase::apply_batch`.
+/// On disk the records are followed by one commit marker, a crash before the
+/// marker is written makes the whole batch disappear on the next open.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stage a write to the default collection
+    pub fn set(&mut self, key: &str, val: &str) -> &mut Self {
+        self.set_in("", key, val)
+    }
+
+    /// Stage a delete from the default collection
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.delete_in("", key)
+    }
+
+    /// Stage a write to a named collection
+    pub fn set_in(&mut self, collection: &str, key: &str, val: &str) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            val: val.to_string(),
+        });
+        self
+    }
+
+    /// Stage a delete from a named collection
+    pub fn delete_in(&mut self, collection: &str, key: &str) -> &mut Self {
+        self.ops.push(BatchOp::Delete {
+            collection: collection.to_string(),
+            key: key.to_string(),
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,75 @@
+use super::{EmbeddedDatabase, Result};
+
+/// Byte & mask of a bit, bit 0 is the highest bit of the first byte
+fn locate(index: u64) -> Result<(usize, u8)> {
+    let byte = usize::try_from(index / 8).map_err(|_| format!("bit {index} is out of range"))?;
+    Ok((byte, 0x80 >> (index % 8)))
+}
+
+impl EmbeddedDatabase {
+    /// Set or clear one bit of the bitmap stored under `key` & return what it
+    /// was. The value grows with zero bytes as needed. Nothing is written when
+    /// the bit already had that value.
+    pub fn setbit(&mut self, key: &str, index: u64, bit: bool) -> Result<bool> {
+        let (byte, mask) = locate(index)?;
+        let mut bitmap = self.get_bytes(key)?.unwrap_or_default();
+        let was = bitmap.get(byte).is_some_and(|val| val & mask != 0);
+        if was == bit {
+            return Ok(was);
+        }
+        if bitmap.len() <= byte {
+            bitmap.resize(byte + 1, 0);
+        }
+        bitmap[byte] ^= mask;
+        self.set_bytes(key, &bitmap)?;
+        Ok(was)
+    }
+
+    /// One bit of the bitmap under `key`, bits past its end & missing keys are 0
+    pub fn getbit(&mut self, key: &str, index: u64) -> Result<bool> {
+        let (byte, mask) = locate(index)?;
+        let bitmap = self.get_bytes(key)?.unwrap_or_default();
+        Ok(bitmap.get(byte).is_some_and(|val| val & mask != 0))
+    }
+
+    /// Number of bits set in the bitmap under `key`
+    pub fn bitcount(&mut self, key: &str) -> Result<u64> {
+        let bitmap = self.get_bytes(key)?.unwrap_or_default();
+        Ok(bitmap.iter().map(|byte| u64::from(byte.count_ones())).sum())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_bitmap() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert!(!db.setbit("rollout", 0, true).unwrap());
+        assert!(!db.setbit("rollout", 1_000, true).unwrap());
+        assert!(db.setbit("rollout", 1_000, true).unwrap());
+        assert!(!db.setbit("rollout", 7, false).unwrap());
+        assert_eq!(
+            db.get_bytes("rollout").unwrap().map(|val| val.len()),
+            Some(126)
+        );
+        assert_eq!(db.get_bytes("rollout").unwrap().unwrap()[0], 0x80);
+
+        let seq = db.last_seq();
+        db.setbit("rollout", 5_000, false).unwrap();
+        assert_eq!(db.last_seq(), seq);
+
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert!(db.getbit("rollout", 1_000).unwrap());
+        assert!(!db.getbit("rollout", 999).unwrap());
+        assert!(!db.getbit("rollout", 1 << 40).unwrap());
+        assert_eq!(db.bitcount("rollout").unwrap(), 2);
+        assert!(db.setbit("rollout", 0, false).unwrap());
+        assert_eq!(db.bitcount("rollout").unwrap(), 1);
+        assert_eq!(db.bitcount("nobody").unwrap(), 0);
+    }
+}
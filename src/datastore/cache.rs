@@ -0,0 +1,315 @@
+use super::{EmbeddedDatabase, Result, database::validate_plain_key, hot_keys::CountMinSketch};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Width of the frequency sketch behind the admission policy
+const FREQUENCY_WIDTH: usize = 1024;
+
+/// Reads counted before the frequency sketch is halved, so keys that were
+/// popular a long time ago don't keep their edge forever
+const FREQUENCY_SAMPLE: u64 = 10 * FREQUENCY_WIDTH as u64;
+
+/// Decoded values of recently read keys, so hot reads skip the disk & the
+/// value transformers. Every entry weighs the bytes of its key & value.
+/// When the cache is full a new value only gets in (TinyLFU style) if it is
+/// read more often than the least recently used entries it would push out,
+/// so one big value read once can't flush thousands of small hot ones.
+/// Pinned entries don't count against the capacity & stay until unpinned.
+/// Keys are stored keys, so every collection shares the one cache.
+#[derive(Debug)]
+pub(crate) struct ReadCache {
+    capacity: u64, // Bytes of unpinned entries kept at most
+    used: u64,
+    entries: HashMap<String, CachedValue>,
+    recency: BTreeMap<u64, String>, // Unpinned keys by the tick they were last used at
+    pinned: HashSet<String>,
+    clock: u64,
+    frequency: CountMinSketch,
+    samples: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct CachedValue {
+    val: String,
+    last_used: u64,
+}
+
+impl CachedValue {
+    fn weight(&self, key: &str) -> u64 {
+        (key.len() + self.val.len()) as u64
+    }
+}
+
+/// Counters of the read cache since the db was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries pushed out to make room for others
+    pub evictions: u64,
+    /// Values that were read but not let into the cache
+    pub rejections: u64,
+    /// Bytes taken by unpinned entries
+    pub used_bytes: u64,
+}
+
+impl ReadCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        ReadCache {
+            capacity,
+            used: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            pinned: HashSet::new(),
+            clock: 0,
+            frequency: CountMinSketch::new(FREQUENCY_WIDTH),
+            samples: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<String> {
+        // A disabled cache shouldn't cost every read a few hashes
+        if self.capacity == 0 && self.pinned.is_empty() {
+            return None;
+        }
+        self.clock += 1;
+        self.count_read(key);
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        if !self.pinned.contains(key) {
+            self.recency.remove(&entry.last_used);
+            self.recency.insert(self.clock, key.to_string());
+        }
+        entry.last_used = self.clock;
+        Some(entry.val.clone())
+    }
+
+    /// Cache a value that was just read from disk, if the admission policy lets it in
+    pub(crate) fn insert(&mut self, key: &str, val: &str) {
+        let pinned = self.pinned.contains(key);
+        let weight = (key.len() + val.len()) as u64;
+        if !pinned && weight > self.capacity {
+            self.stats.rejections += 1;
+            return;
+        }
+        self.invalidate(key);
+        if !pinned && !self.make_room(key, weight) {
+            self.stats.rejections += 1;
+            return;
+        }
+
+        self.clock += 1;
+        self.entries.insert(
+            key.to_string(),
+            CachedValue {
+                val: val.to_string(),
+                last_used: self.clock,
+            },
+        );
+        if !pinned {
+            self.recency.insert(self.clock, key.to_string());
+            self.used += weight;
+        }
+    }
+
+    /// Evict least recently used entries until `weight` more bytes fit, as
+    /// long as each of them is read less often than `key`. Nothing is evicted
+    /// when the newcomer loses against one of them.
+    fn make_room(&mut self, key: &str, weight: u64) -> bool {
+        let frequency = self.frequency.estimate(key);
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        for victim in self.recency.values() {
+            if self.used - freed + weight <= self.capacity {
+                break;
+            }
+            if self.frequency.estimate(victim) >= frequency {
+                return false;
+            }
+            freed += self.entries[victim].weight(victim);
+            victims.push(victim.clone());
+        }
+
+        for victim in victims {
+            self.invalidate(&victim);
+            self.stats.evictions += 1;
+        }
+        true
+    }
+
+    fn count_read(&mut self, key: &str) {
+        self.frequency.increment(key);
+        self.samples += 1;
+        if self.samples >= FREQUENCY_SAMPLE {
+            self.frequency.halve();
+            self.samples = 0;
+        }
+    }
+
+    /// Drop the cached value of a key that was written, deleted or expired.
+    /// A pinned key stays pinned & gets cached again on its next read.
+    pub(crate) fn invalidate(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key)
+            && !self.pinned.contains(key)
+        {
+            self.recency.remove(&entry.last_used);
+            self.used -= entry.weight(key);
+        }
+    }
+
+    /// Keep the value of a pinned key up to date with a write
+    pub(crate) fn refresh_pinned(&mut self, key: &str, val: &[u8]) {
+        if self.pinned.contains(key)
+            && let Ok(val) = std::str::from_utf8(val)
+        {
+            self.insert(key, val);
+        }
+    }
+
+    fn pin(&mut self, key: &str) {
+        // Whatever is cached already moves out of the LRU order
+        if let Some(entry) = self.entries.get(key)
+            && !self.pinned.contains(key)
+        {
+            self.recency.remove(&entry.last_used);
+            self.used -= entry.weight(key);
+        }
+        self.pinned.insert(key.to_string());
+    }
+
+    fn unpin(&mut self, key: &str) {
+        if self.pinned.remove(key) {
+            self.entries.remove(key);
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            used_bytes: self.used,
+            ..self.stats
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Load the values of `keys` into the read cache & keep them there, no
+    /// matter how many other keys are read. Pins live in memory only, pin the
+    /// latency critical keys again after a restart.
+    pub fn cache_pin(&mut self, keys: &[&str]) -> Result<()> {
+        for key in keys {
+            validate_plain_key(key)?;
+        }
+        for key in keys {
+            self.cache.pin(key);
+            self.get_stored(key)?;
+        }
+        Ok(())
+    }
+
+    /// Let pinned keys be evicted again
+    pub fn cache_unpin(&mut self, keys: &[&str]) {
+        for key in keys {
+            self.cache.unpin(key);
+        }
+    }
+
+    /// Read the keys of the default collection starting with `prefix` into the
+    /// read cache, until it is full. Returns how many were loaded.
+    pub fn warmup(&mut self, prefix: &str) -> Result<usize> {
+        let mut loaded = 0;
+        for key in self.scan_keys(prefix) {
+            if self.cache.used >= self.cache.capacity {
+                break;
+            }
+            let evictions = self.cache.stats.evictions;
+            self.get_stored(&key)?;
+            if self.cache.contains(&key) {
+                loaded += 1;
+            }
+            // Warming up shouldn't push out what was loaded a moment ago
+            if self.cache.stats.evictions > evictions {
+                break;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Hit, miss & eviction counters of the read cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_pinned_keys_survive_eviction() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            cache_capacity_bytes: 4,
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        for key in ["config", "a", "b", "c"] {
+            db.set(key, "1").unwrap();
+        }
+
+        db.cache_pin(&["config"]).unwrap();
+        assert_eq!(db.warmup("").unwrap(), 2, "a & b take 2 bytes each");
+        for _ in 0..3 {
+            db.get("c").unwrap();
+        }
+        assert!(db.cache.contains("config"));
+        assert!(!db.cache.contains("a"), "least recently used goes first");
+        assert!(db.cache.contains("b") && db.cache.contains("c"));
+
+        // Writes keep the cache honest
+        db.set("config", "2").unwrap();
+        assert!(db.cache.contains("config"));
+        assert_eq!(db.get("config").unwrap(), Some("2".to_string()));
+        db.delete("b").unwrap();
+        assert_eq!(db.get("b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_big_values_dont_flush_hot_ones() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            cache_capacity_bytes: 1000,
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        for i in 0..10 {
+            db.set(&format!("small{i}"), "x").unwrap();
+        }
+        db.set("big", &"x".repeat(990)).unwrap();
+
+        for _ in 0..3 {
+            for i in 0..10 {
+                db.get(&format!("small{i}")).unwrap();
+            }
+        }
+        db.get("big").unwrap();
+        assert!((0..10).all(|i| db.cache.contains(&format!("small{i}"))));
+        assert!(!db.cache.contains("big"));
+
+        let stats = db.cache_stats();
+        assert_eq!(stats.hits, 20);
+        assert_eq!(stats.misses, 11);
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(stats.rejections, 1);
+        assert_eq!(stats.used_bytes, 70);
+    }
+}
@@ -0,0 +1,461 @@
+use super::{
+    EmbeddedDatabase, RecordKind, Result,
+    collection::{SYSTEM_COLLECTION, collection_of, namespaced_key, user_key},
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::mpsc::{self, Receiver, Sender},
+    time::Duration,
+};
+
+/// Where consumer offsets live inside the system collection
+const CONSUMER_PREFIX: &str = "cdc/";
+/// Most write times `UserWrites` holds on to, the oldest go first
+const MAX_TIMED_WRITES: usize = 65_536;
+
+/// What happened to a key
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Set(String),
+    Delete,
+    /// Closes the write with sequence number `seq`. Everything since the
+    /// previous commit belongs to that one write & was applied atomically.
+    /// Commit events carry an empty collection & key.
+    Commit,
+}
+
+/// A single change to the database, delivered to every change subscriber
+/// after it has been written to the data file.
+/// The changes of a write (one for `set`, several for a batch) are followed
+/// by a `Change::Commit` event with the same `seq`, so consumers can apply
+/// them transactionally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub seq: u64,
+    /// "" for the default collection
+    pub collection: String,
+    pub key: String,
+    pub change: Change,
+}
+
+impl ChangeEvent {
+    pub(crate) fn commit(seq: u64) -> Self {
+        ChangeEvent {
+            seq,
+            collection: String::new(),
+            key: String::new(),
+            change: Change::Commit,
+        }
+    }
+
+    pub(crate) fn new(stored_key: &str, val: Option<&[u8]>, seq: u64) -> Self {
+        let change = match val {
+            Some(val) => Change::Set(String::from_utf8_lossy(val).into_owned()),
+            None => Change::Delete,
+        };
+        ChangeEvent {
+            seq,
+            collection: collection_of(stored_key).to_string(),
+            key: user_key(stored_key).to_string(),
+            change,
+        }
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Receive every change made through this handle from now on.
+    /// The subscription ends when the receiver is dropped.
+    pub fn subscribe_changes(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    /// Hand the events of write `seq` followed by its commit to every
+    /// subscriber, forgetting the ones that went away
+    pub(crate) fn notify(&mut self, seq: u64, mut events: Vec<ChangeEvent>) {
+        if self.user_writes.last_seq == seq {
+            let now = self.now_millis();
+            self.user_writes.made(seq, now);
+        }
+        // The db's own bookkeeping (sequences, consumer offsets) isn't a change anyone asked for
+        events.retain(|event| event.collection != SYSTEM_COLLECTION);
+        if events.is_empty() {
+            return;
+        }
+        events.push(ChangeEvent::commit(seq));
+        self.subscribers.retain(|subscriber| {
+            events
+                .iter()
+                .all(|event| subscriber.send(event.clone()).is_ok())
+        });
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Open the named consumer, registering it on first use.
+    /// It replays every change after the offset it last acked that is still in
+    /// the data file, then picks up live changes. A new consumer starts from the
+    /// beginning of the file. Compaction keeps the records a registered consumer
+    /// hasn't acked yet, so nothing it hasn't seen gets compacted away.
+    pub fn consumer(&mut self, name: &str) -> Result<Consumer> {
+        let key = namespaced_key(SYSTEM_COLLECTION, &format!("{CONSUMER_PREFIX}{name}"));
+        let offset = match self.get_stored(&key)? {
+            Some(val) => val.parse::<u64>()?,
+            None => {
+                self.put(key, b"0", None)?;
+                self.sync()?;
+                0
+            }
+        };
+
+        // Both happen under the same borrow, so no write can slip in between
+        let backlog = self.changes_since(offset)?;
+        let live = self.subscribe_changes();
+        Ok(Consumer {
+            name: name.to_string(),
+            backlog,
+            live,
+        })
+    }
+
+    /// Forget a consumer, compaction stops keeping changes around for it
+    pub fn remove_consumer(&mut self, name: &str) -> Result<()> {
+        let key = namespaced_key(SYSTEM_COLLECTION, &format!("{CONSUMER_PREFIX}{name}"));
+        self.remove(key)
+    }
+
+    /// How far every registered consumer has come, sorted by name
+    pub fn consumer_offsets(&mut self) -> Result<Vec<ConsumerOffset>> {
+        let mut offsets = Vec::new();
+        for key in self.scan_keys_in(SYSTEM_COLLECTION, CONSUMER_PREFIX) {
+            let stored_key = namespaced_key(SYSTEM_COLLECTION, &key);
+            if let Some(val) = self.get_stored(&stored_key)? {
+                let acked = val.parse::<u64>()?;
+                let lag = self.user_writes.last_seq.saturating_sub(acked);
+                let lag_time = match lag {
+                    0 => Duration::ZERO,
+                    _ => Duration::from_millis(
+                        self.now_millis()
+                            .saturating_sub(self.user_writes.first_after(acked)),
+                    ),
+                };
+                offsets.push(ConsumerOffset {
+                    name: key[CONSUMER_PREFIX.len()..].to_string(),
+                    acked,
+                    lag,
+                    lag_time,
+                });
+            }
+        }
+        Ok(offsets)
+    }
+
+    /// Lowest offset acked by any registered consumer, `None` without consumers
+    pub(crate) fn min_consumer_offset(&mut self) -> Result<Option<u64>> {
+        let offsets = self.consumer_offsets()?;
+        Ok(offsets.iter().map(|offset| offset.acked).min())
+    }
+
+    /// The consumer that acked the least, if it still has changes to read:
+    /// compaction has to keep those around
+    pub(crate) fn slowest_consumer(&mut self) -> Result<Option<String>> {
+        let offsets = self.consumer_offsets()?;
+        Ok(offsets
+            .into_iter()
+            .filter(|offset| offset.lag > 0)
+            .min_by_key(|offset| offset.acked)
+            .map(|offset| offset.name))
+    }
+
+    /// Change events for every write after `offset` that is still in the data file.
+    /// Compaction sorts records by key, so writes are put back in seq order here.
+    fn changes_since(&self, offset: u64) -> Result<VecDeque<ChangeEvent>> {
+        let mut writes: BTreeMap<u64, Vec<ChangeEvent>> = BTreeMap::new();
+        self.scan_records(|_, record| {
+            if record.seq <= offset
+                || record.kind == RecordKind::BatchCommit
+                || record.kind == RecordKind::Padding
+                || collection_of(&record.key) == SYSTEM_COLLECTION
+            {
+                return Ok(());
+            }
+            let key = record.key.clone();
+            let seq = record.seq;
+            let val = match record.is_tombstone() {
+                true => None,
+                false => Some(self.transformers.decode(record)?),
+            };
+            writes
+                .entry(seq)
+                .or_default()
+                .push(ChangeEvent::new(&key, val.as_deref(), seq));
+            Ok(())
+        })?;
+
+        let mut events = VecDeque::new();
+        for (seq, changes) in writes {
+            events.extend(changes);
+            events.push_back(ChangeEvent::commit(seq));
+        }
+        Ok(events)
+    }
+}
+
+/// Position of a named consumer compared to the database's latest write
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerOffset {
+    pub name: String,
+    /// Seq of the last write the consumer acked
+    pub acked: u64,
+    /// How far the latest change a consumer can see is ahead of `acked`, in
+    /// sequence numbers. The db's own bookkeeping writes after it (like the
+    /// ack itself) don't count.
+    pub lag: u64,
+    /// How long the oldest change the consumer hasn't acked has been waiting.
+    /// Changes from before the db was opened count from the open.
+    pub lag_time: Duration,
+}
+
+/// The latest write consumers can see & when recent ones were made, what
+/// consumer lag is measured against
+pub(crate) struct UserWrites {
+    /// Seq of the latest write outside the system collection
+    pub(crate) last_seq: u64,
+    /// Unix millis of the user writes made through this handle, by seq
+    times: BTreeMap<u64, u64>,
+    /// Writes up to this seq are older than anything in `times`, they were
+    /// in the file when it was opened or their time was dropped
+    untimed_until: Option<u64>,
+    /// Stands in for the time of those writes
+    untimed_at: u64,
+}
+
+impl UserWrites {
+    pub(crate) fn new(opened_at: u64) -> Self {
+        UserWrites {
+            last_seq: 0,
+            times: BTreeMap::new(),
+            untimed_until: None,
+            untimed_at: opened_at,
+        }
+    }
+
+    /// Note a write that was indexed, from the file or just now
+    pub(crate) fn indexed(&mut self, stored_key: &str, seq: u64) {
+        if collection_of(stored_key) != SYSTEM_COLLECTION {
+            self.last_seq = self.last_seq.max(seq);
+        }
+    }
+
+    fn made(&mut self, seq: u64, now: u64) {
+        self.untimed_until.get_or_insert(seq - 1);
+        self.times.insert(seq, now);
+        if self.times.len() > MAX_TIMED_WRITES
+            && let Some((seq, at)) = self.times.pop_first()
+        {
+            self.untimed_until = Some(seq);
+            self.untimed_at = at;
+        }
+    }
+
+    /// When the first user write after `seq` was made
+    fn first_after(&self, seq: u64) -> u64 {
+        match self.untimed_until {
+            Some(untimed_until) if seq >= untimed_until => self
+                .times
+                .range(seq + 1..)
+                .next()
+                .map_or(self.untimed_at, |(_, &at)| at),
+            _ => self.untimed_at,
+        }
+    }
+
+    /// Drop the times of writes every consumer has acked
+    fn forget_until(&mut self, seq: u64) {
+        self.times = self.times.split_off(&(seq + 1));
+    }
+}
+
+/// A named change consumer that remembers how far it got across restarts.
+/// Process the events of a write, then `ack` its seq; after a restart the
+/// consumer carries on right after the last acked write.
+pub struct Consumer {
+    name: String,
+    /// Replayed changes that haven't been handed out yet
+    backlog: VecDeque<ChangeEvent>,
+    live: Receiver<ChangeEvent>,
+}
+
+impl Consumer {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Next change if there is one right now
+    pub fn try_next(&mut self) -> Option<ChangeEvent> {
+        self.backlog
+            .pop_front()
+            .or_else(|| self.live.try_recv().ok())
+    }
+
+    /// Next change, waiting up to `timeout` for one to show up
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<ChangeEvent> {
+        if let Some(event) = self.backlog.pop_front() {
+            return Some(event);
+        }
+        self.live.recv_timeout(timeout).ok()
+    }
+
+    /// Durably record that every write up to & including `offset` has been
+    /// processed. Acking an older offset than before is a no-op.
+    pub fn ack(&self, db: &mut EmbeddedDatabase, offset: u64) -> Result<()> {
+        let key = namespaced_key(
+            SYSTEM_COLLECTION,
+            &format!("{CONSUMER_PREFIX}{}", self.name),
+        );
+        let acked = match db.get_stored(&key)? {
+            Some(val) => val.parse::<u64>()?,
+            None => 0,
+        };
+        if offset <= acked {
+            return Ok(());
+        }
+        db.put(key, offset.to_string().as_bytes(), None)?;
+        db.sync()?;
+        if let Some(lowest) = db.min_consumer_offset()? {
+            db.user_writes.forget_until(lowest);
+        }
+        Ok(())
+    }
+}
+
+/// Senders for everyone listening to changes on a database
+pub(crate) type Subscribers = Vec<Sender<ChangeEvent>>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, MockClock, WriteBatch};
+    use std::{sync::Arc, time::UNIX_EPOCH};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_changes_are_grouped_by_commit() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let changes = db.subscribe_changes();
+
+        db.set("a", "1").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set_in("users", "42", "Alice").delete("a");
+        db.apply_batch(batch).unwrap();
+
+        let events: Vec<ChangeEvent> = changes.try_iter().collect();
+        let kinds: Vec<(u64, &Change)> = events.iter().map(|e| (e.seq, &e.change)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, &Change::Set("1".to_string())),
+                (1, &Change::Commit),
+                (2, &Change::Set("Alice".to_string())),
+                (2, &Change::Delete),
+                (2, &Change::Commit),
+            ]
+        );
+        assert_eq!(events[2].collection, "users");
+
+        // Sequence numbers keep going after a compaction & a restart
+        db.compact().unwrap();
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.last_seq(), 2);
+        db.set("b", "2").unwrap();
+        assert_eq!(db.last_seq(), 3);
+    }
+
+    #[test]
+    fn test_consumer_resumes_after_ack() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let mut consumer = db.consumer("indexer").unwrap();
+
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        let first = consumer.try_next().unwrap();
+        assert_eq!(first.change, Change::Set("1".to_string()));
+        assert_eq!(consumer.try_next().unwrap().change, Change::Commit);
+        consumer.ack(&mut db, first.seq).unwrap();
+
+        // Overwriting & compacting doesn't lose the unacked write of "b"
+        db.delete("b").unwrap();
+        db.compact().unwrap();
+        drop(consumer);
+        drop(db);
+
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let mut consumer = db.consumer("indexer").unwrap();
+        let mut replayed = Vec::new();
+        while let Some(event) = consumer.try_next() {
+            replayed.push((event.key, event.change));
+        }
+        assert_eq!(
+            replayed,
+            vec![
+                ("b".to_string(), Change::Set("2".to_string())),
+                (String::new(), Change::Commit),
+                ("b".to_string(), Change::Delete),
+                (String::new(), Change::Commit),
+            ]
+        );
+        assert_eq!(db.get("b").unwrap(), None);
+
+        let offsets = db.consumer_offsets().unwrap();
+        assert_eq!(offsets.len(), 1);
+        assert_eq!(offsets[0].name, "indexer");
+        assert_eq!(offsets[0].acked, first.seq);
+        assert_eq!(offsets[0].lag, db.last_seq() - first.seq);
+
+        // Acking the latest write leaves no lag behind
+        let latest = db.last_seq();
+        consumer.ack(&mut db, latest).unwrap();
+        assert_eq!(db.consumer_offsets().unwrap()[0].lag, 0);
+    }
+
+    #[test]
+    fn test_consumer_lag() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_800_000_000));
+        let options = DbOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        let consumer = db.consumer("indexer").unwrap();
+        db.set("a", "1").unwrap();
+        clock.advance(Duration::from_secs(10));
+        db.set("b", "2").unwrap();
+        let latest = db.last_seq();
+        clock.advance(Duration::from_secs(5));
+
+        let lag = &db.consumer_offsets().unwrap()[0];
+        assert_eq!((lag.lag, lag.lag_time), (latest, Duration::from_secs(15)));
+        consumer.ack(&mut db, latest - 1).unwrap();
+        let lag = &db.consumer_offsets().unwrap()[0];
+        assert_eq!((lag.lag, lag.lag_time), (1, Duration::from_secs(5)));
+
+        // Once caught up, the db's own bookkeeping doesn't count as lag
+        consumer.ack(&mut db, latest).unwrap();
+        db.consumer("other").unwrap();
+        clock.advance(Duration::from_secs(5));
+        let lag = &db.consumer_offsets().unwrap()[0];
+        assert_eq!(
+            (lag.name.as_str(), lag.lag, lag.lag_time),
+            ("indexer", 0, Duration::ZERO)
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Where the db gets the time from for TTLs & the scheduler, see `DbOptions::clock`
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, so tests of expiry & scheduled
+/// tasks don't have to sleep. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl MockClock {
+    pub fn new(start: SystemTime) -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *now += by;
+    }
+
+    pub fn set(&self, to: SystemTime) {
+        *self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = to;
+    }
+}
+
+impl Default for MockClock {
+    /// Starts at the unix epoch
+    fn default() -> Self {
+        MockClock::new(UNIX_EPOCH)
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Unix time in millis on `clock`, what TTLs are stored as
+pub(crate) fn millis(clock: &dyn Clock) -> u64 {
+    clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, Schedule, Task, ThreadSafeDB};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_mock_clock() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_800_000_000));
+        let options = DbOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+
+        // Leases expire on the mock clock
+        assert!(db.try_lease("job", "a", Duration::from_secs(30)).unwrap());
+        clock.advance(Duration::from_secs(29));
+        assert!(!db.try_lease("job", "b", Duration::from_secs(30)).unwrap());
+        clock.advance(Duration::from_secs(1));
+        assert!(db.try_lease("job", "b", Duration::from_secs(30)).unwrap());
+
+        // & so do scheduled tasks
+        let hourly = Schedule::every(Duration::from_secs(3600));
+        db.schedule("sweep", hourly, Task::SweepExpired).unwrap();
+        assert_eq!(db.run_pending().unwrap(), 0);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(db.run_pending().unwrap(), 1);
+        assert_eq!(db.run_pending().unwrap(), 0);
+        let status = &db.scheduled_tasks().unwrap()[0];
+        assert_eq!(status.last_run, Some(clock.now()));
+        assert_eq!(status.next_run, clock.now() + Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,214 @@
+use super::{DbError, Result};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// What a finished read handed to the callers that waited on it
+type Outcome = std::result::Result<Option<String>, Arc<ReadFailed>>;
+
+/// Why a shared read failed. A `DbError` is kept as it is, so waiters get the
+/// same error the leader did & can match on it; anything else only has its
+/// message, boxed errors can't be shared across threads.
+#[derive(Debug)]
+enum ReadFailed {
+    Db(DbError),
+    Other(String),
+}
+
+impl ReadFailed {
+    fn new(err: &(dyn Error + 'static)) -> Self {
+        match err.downcast_ref::<DbError>() {
+            Some(db_error) => ReadFailed::Db(db_error.clone()),
+            None => ReadFailed::Other(err.to_string()),
+        }
+    }
+}
+
+/// A `get` that is running right now, other callers for the same key wait for it
+#[derive(Default)]
+pub(crate) struct SharedRead {
+    outcome: Mutex<Option<Outcome>>,
+    ready: Condvar,
+}
+
+impl SharedRead {
+    /// Block until the read that is running finishes & take a copy of its result
+    pub(crate) fn wait(&self) -> Result<Option<String>> {
+        let mut outcome = self
+            .outcome
+            .lock()
+            .map_err(|_| "a coalesced read panicked")?;
+        loop {
+            match outcome.as_ref() {
+                Some(Ok(val)) => return Ok(val.clone()),
+                Some(Err(failed)) => {
+                    return Err(match failed.as_ref() {
+                        ReadFailed::Db(db_error) => db_error.clone().into(),
+                        ReadFailed::Other(message) => message.clone().into(),
+                    });
+                }
+                None => {}
+            }
+            outcome = self
+                .ready
+                .wait(outcome)
+                .map_err(|_| "a coalesced read panicked")?;
+        }
+    }
+
+    fn publish(&self, outcome: Outcome) {
+        if let Ok(mut slot) = self.outcome.lock() {
+            *slot = Some(outcome);
+        }
+        self.ready.notify_all();
+    }
+}
+
+/// Reads of the same key that overlap in time share one lookup & disk read,
+/// so a hot key that every thread asks for at once is only read once.
+#[derive(Default)]
+pub(crate) struct InFlightGets {
+    reads: Mutex<HashMap<String, Arc<SharedRead>>>,
+}
+
+/// How a caller takes part in a coalesced read
+pub(crate) enum Joined<'a> {
+    /// Someone else is reading the key already, wait for them
+    Wait(Arc<SharedRead>),
+    /// The caller has to do the read
+    Lead(LeadRead<'a>),
+}
+
+impl InFlightGets {
+    /// Join the read of `key` that is running, or start a new one
+    pub(crate) fn join<'a>(&'a self, key: &'a str) -> Result<Joined<'a>> {
+        let mut reads = self
+            .reads
+            .lock()
+            .map_err(|_| "the in-flight reads lock was poisoned by a panic")?;
+        if let Some(read) = reads.get(key) {
+            return Ok(Joined::Wait(read.clone()));
+        }
+        let read = Arc::new(SharedRead::default());
+        reads.insert(key.to_string(), read.clone());
+        Ok(Joined::Lead(LeadRead {
+            gets: self,
+            key,
+            read,
+            outcome: None,
+        }))
+    }
+
+    fn close(&self, key: &str, read: &Arc<SharedRead>) {
+        if let Ok(mut reads) = self.reads.lock()
+            && reads.get(key).is_some_and(|open| Arc::ptr_eq(open, read))
+        {
+            reads.remove(key);
+        }
+    }
+}
+
+/// Held by the caller doing a coalesced read. Dropping it closes the read
+/// & wakes everyone waiting on it, with an error if the read never finished
+/// (it panicked), so nobody waits forever & the key can be read again.
+pub(crate) struct LeadRead<'a> {
+    gets: &'a InFlightGets,
+    key: &'a str,
+    read: Arc<SharedRead>,
+    outcome: Option<Outcome>,
+}
+
+impl LeadRead<'_> {
+    /// Stop letting new callers join the read. This has to happen while the
+    /// database lock is still held, otherwise a caller could join after a
+    /// newer write & get the old value.
+    pub(crate) fn close(&self) {
+        self.gets.close(self.key, &self.read);
+    }
+
+    /// Hand the result to everyone waiting
+    pub(crate) fn finish(mut self, result: &Result<Option<String>>) {
+        self.outcome = Some(match result {
+            Ok(val) => Ok(val.clone()),
+            Err(err) => Err(Arc::new(ReadFailed::new(err.as_ref()))),
+        });
+    }
+}
+
+impl Drop for LeadRead<'_> {
+    fn drop(&mut self) {
+        self.close();
+        let outcome = self.outcome.take().unwrap_or_else(|| {
+            let message = "the coalesced read panicked".to_string();
+            Err(Arc::new(ReadFailed::Other(message)))
+        });
+        self.read.publish(outcome);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{sync::mpsc, thread};
+
+    /// Start a waiter on `key` once `gets` has a leader for it
+    fn waiter(
+        gets: &Arc<InFlightGets>,
+        key: &'static str,
+    ) -> thread::JoinHandle<std::result::Result<String, String>> {
+        let (joined, has_joined) = mpsc::channel();
+        let gets = gets.clone();
+        let waiter = thread::spawn(move || {
+            let Ok(Joined::Wait(read)) = gets.join(key) else {
+                panic!("the waiter should find the read running");
+            };
+            joined.send(()).unwrap();
+            // Errors aren't `Send`, hand back what the test looks at
+            read.wait().map(|val| format!("{val:?}")).map_err(|err| {
+                match err.downcast_ref::<DbError>() {
+                    Some(db_error) => format!("DbError::{db_error:?}"),
+                    None => err.to_string(),
+                }
+            })
+        });
+        has_joined.recv().unwrap();
+        waiter
+    }
+
+    #[test]
+    fn test_waiters_get_the_typed_error() {
+        let gets = Arc::new(InFlightGets::default());
+        let Joined::Lead(lead) = gets.join("key").unwrap() else {
+            panic!("the first caller leads");
+        };
+        let waiter = waiter(&gets, "key");
+        lead.close();
+        lead.finish(&Err(DbError::ReadOnly.into()));
+        let err = waiter.join().unwrap().err().unwrap();
+        assert_eq!(err, "DbError::ReadOnly");
+    }
+
+    #[test]
+    fn test_panicking_leader_wakes_waiters() {
+        let gets = Arc::new(InFlightGets::default());
+        let waiter = thread::scope(|scope| {
+            let Joined::Lead(lead) = gets.join("key").unwrap() else {
+                panic!("the first caller leads");
+            };
+            let waiter = waiter(&gets, "key");
+            let leader = scope.spawn(move || {
+                let _lead = lead;
+                panic!("the read blew up");
+            });
+            assert!(leader.join().is_err());
+            waiter
+        });
+        let err = waiter.join().unwrap().err().unwrap();
+        assert!(err.contains("panicked"), "{err}");
+
+        // The key isn't stuck, the next caller reads it again
+        assert!(matches!(gets.join("key").unwrap(), Joined::Lead(_)));
+    }
+}
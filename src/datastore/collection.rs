@@ -0,0 +1,208 @@
+use super::{EmbeddedDatabase, Result, Snapshot, SnapshotIter, error::Context};
+use std::{cmp::Ordering, ops::RangeBounds, time::Duration};
+
+/// Separates the collection name from the key inside the index & data file.
+/// Collection names may not contain it, so the first one always ends the name.
+pub(crate) const COLLECTION_SEPARATOR: char = '\0';
+
+/// Collection the database keeps its own bookkeeping in (sequences etc.)
+pub(crate) const SYSTEM_COLLECTION: &str = "__system";
+
+/// Build the key that is actually stored for `key` inside `collection`
+pub(crate) fn namespaced_key(collection: &str, key: &str) -> String {
+    if collection.is_empty() {
+        return key.to_string();
+    }
+    format!("{collection}{COLLECTION_SEPARATOR}{key}")
+}
+
+/// Name of the collection a stored key belongs to ("" for the default collection)
+pub(crate) fn collection_of(stored_key: &str) -> &str {
+    match stored_key.split_once(COLLECTION_SEPARATOR) {
+        Some((collection, _)) => collection,
+        None => "",
+    }
+}
+
+/// The key without its collection prefix
+pub(crate) fn user_key(stored_key: &str) -> &str {
+    match stored_key.split_once(COLLECTION_SEPARATOR) {
+        Some((_, key)) => key,
+        None => stored_key,
+    }
+}
+
+/// Names starting with "__" are reserved for the database itself
+pub(crate) fn validate_collection_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(COLLECTION_SEPARATOR) || name.starts_with("__") {
+        return Err(format!("invalid collection name {name:?}").into());
+    }
+    Ok(())
+}
+
+/// A named group of keys living in the same data file as the rest of the db.
+/// Each collection picks up its own TTL, value transformer & compaction settings
+/// from `DbOptions`.
+pub struct Collection<'a> {
+    db: &'a mut EmbeddedDatabase,
+    name: String,
+}
+
+impl<'a> Collection<'a> {
+    pub(crate) fn new(db: &'a mut EmbeddedDatabase, name: &str) -> Self {
+        Collection {
+            db,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set(&mut self, key: &str, val: &str) -> Result<()> {
+        let stored_key = namespaced_key(&self.name, key);
+        self.db
+            .put(stored_key, val.as_bytes(), None)
+            .context(|| format!("set(collection={}, key={key})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::set_with_ttl`
+    pub fn set_with_ttl(&mut self, key: &str, val: &str, ttl: Duration) -> Result<()> {
+        let stored_key = namespaced_key(&self.name, key);
+        self.db
+            .put(stored_key, val.as_bytes(), Some(ttl))
+            .context(|| format!("set_with_ttl(collection={}, key={key})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::set_if_absent`
+    pub fn set_if_absent(&mut self, key: &str, val: &str) -> Result<bool> {
+        let stored_key = namespaced_key(&self.name, key);
+        self.db
+            .put_if_absent(stored_key, val.as_bytes(), None)
+            .context(|| format!("set_if_absent(collection={}, key={key})", self.name))
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let stored_key = namespaced_key(&self.name, key);
+        self.db
+            .get_stored(&stored_key)
+            .context(|| format!("get(collection={}, key={key})", self.name))
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        let stored_key = namespaced_key(&self.name, key);
+        self.db
+            .remove(stored_key)
+            .context(|| format!("delete(collection={}, key={key})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::take`
+    pub fn take(&mut self, key: &str) -> Result<Option<String>> {
+        let stored_key = namespaced_key(&self.name, key);
+        self.db
+            .take_stored(stored_key)
+            .context(|| format!("take(collection={}, key={key})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::swap`
+    pub fn swap(&mut self, key_a: &str, key_b: &str) -> Result<()> {
+        let stored_a = namespaced_key(&self.name, key_a);
+        let stored_b = namespaced_key(&self.name, key_b);
+        self.db.swap_stored(stored_a, stored_b).context(|| {
+            format!(
+                "swap(collection={}, key_a={key_a}, key_b={key_b})",
+                self.name
+            )
+        })
+    }
+
+    /// See `EmbeddedDatabase::rename`
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<bool> {
+        let stored_from = namespaced_key(&self.name, from);
+        let stored_to = namespaced_key(&self.name, to);
+        self.db
+            .rename_stored(stored_from, stored_to)
+            .context(|| format!("rename(collection={}, from={from}, to={to})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::delete_prefix`
+    pub fn delete_prefix(&mut self, prefix: &str) -> Result<usize> {
+        self.db
+            .delete_prefix_in(&self.name, prefix)
+            .context(|| format!("delete_prefix(collection={}, prefix={prefix})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::approx_count_prefix`
+    pub fn approx_count_prefix(&self, prefix: &str) -> usize {
+        self.db.approx_count_in(&self.name, prefix)
+    }
+
+    /// See `EmbeddedDatabase::range`
+    pub fn range<'k>(&mut self, range: impl RangeBounds<&'k str>) -> Result<Vec<(String, String)>> {
+        self.db
+            .range_in(&self.name, range)
+            .context(|| format!("range(collection={})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::floor`
+    pub fn floor(&self, key: &str) -> Option<String> {
+        self.db.nearest_key(&self.name, key, Ordering::Less)
+    }
+
+    /// See `EmbeddedDatabase::ceiling`
+    pub fn ceiling(&self, key: &str) -> Option<String> {
+        self.db.nearest_key(&self.name, key, Ordering::Greater)
+    }
+
+    /// Keys of the collection starting with `prefix`, see `EmbeddedDatabase::scan_keys`
+    pub fn scan_keys(&self, prefix: &str) -> Vec<String> {
+        self.db.scan_keys_in(&self.name, prefix)
+    }
+
+    /// Iterate over the collection as it is right now, see `EmbeddedDatabase::iter_snapshot`
+    pub fn iter_snapshot(&self) -> Result<SnapshotIter> {
+        self.db
+            .snapshot_of(&self.name)
+            .context(|| format!("iter_snapshot(collection={})", self.name))
+    }
+
+    /// Read the whole collection into a `Snapshot`
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::read(self.iter_snapshot()?)
+    }
+
+    /// Write every key of `snapshot` to the collection, see `EmbeddedDatabase::load_snapshot`
+    pub fn load_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let writes = snapshot
+            .iter()
+            .map(|(key, val)| {
+                (
+                    namespaced_key(&self.name, key),
+                    Some(val.clone().into_bytes()),
+                )
+            })
+            .collect();
+        self.db.commit_writes(writes, None).context(|| {
+            format!(
+                "load_snapshot(collection={}, {} keys)",
+                self.name,
+                snapshot.len()
+            )
+        })
+    }
+
+    /// See `EmbeddedDatabase::iter_since`
+    pub fn iter_since(&self, since: u64) -> Result<SnapshotIter> {
+        self.db
+            .snapshot_since(&self.name, since)
+            .context(|| format!("iter_since(collection={}, since={since})", self.name))
+    }
+
+    /// See `EmbeddedDatabase::search`, needs `enable_text_index` for this collection
+    pub fn search(&self, query: &str) -> Result<Vec<String>> {
+        self.db
+            .search_in(&self.name, query)
+            .context(|| format!("search(collection={}, query={query})", self.name))
+    }
+}
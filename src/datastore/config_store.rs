@@ -0,0 +1,102 @@
+use super::{Change, Result, ThreadSafeDB};
+use serde::de::DeserializeOwned;
+use std::{
+    sync::{Arc, RwLock, Weak},
+    thread,
+};
+
+/// A typed, cached view of a single JSON value in the default collection.
+/// The value is parsed once & kept up to date from the database's change
+/// events, so `current()` never touches the disk.
+pub struct ConfigStore<T> {
+    key: String,
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T: DeserializeOwned + Send + Sync + 'static> ConfigStore<T> {
+    /// Load & parse `key`, then keep following its changes in the background.
+    /// Updates that fail to parse are ignored, the last good value is kept.
+    pub fn open(db: &ThreadSafeDB, key: &str) -> Result<Self> {
+        // Subscribe under the same lock as the first read so no update slips through
+        let (changes, json) = {
+            let mut db = db.lock()?;
+            let changes = db.subscribe_changes();
+            (changes, db.get(key)?)
+        };
+        let json = json.ok_or_else(|| format!("config key {key:?} not found"))?;
+        let value: T = serde_json::from_str(&json)?;
+        let current = Arc::new(RwLock::new(Arc::new(value)));
+
+        let watched_key = key.to_string();
+        let shared: Weak<RwLock<Arc<T>>> = Arc::downgrade(&current);
+        thread::spawn(move || {
+            // Runs until the database or the store goes away
+            for event in changes {
+                if !event.collection.is_empty() || event.key != watched_key {
+                    continue;
+                }
+                let Some(current) = shared.upgrade() else {
+                    break;
+                };
+                if let Change::Set(json) = event.change
+                    && let Ok(value) = serde_json::from_str::<T>(&json)
+                    && let Ok(mut current) = current.write()
+                {
+                    *current = Arc::new(value);
+                }
+            }
+        });
+
+        Ok(ConfigStore {
+            key: key.to_string(),
+            current,
+        })
+    }
+
+    /// The latest value that parsed successfully
+    pub fn current(&self) -> Arc<T> {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            // The writer only swaps an Arc, a poisoned lock still holds a whole value
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Limits {
+        max_users: u32,
+    }
+
+    #[test]
+    fn test_config_store_follows_updates() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = ThreadSafeDB::new(temp_file.path()).unwrap();
+        assert!(ConfigStore::<Limits>::open(&db, "limits").is_err());
+
+        db.set("limits", r#"{"max_users": 10}"#).unwrap();
+        let limits = ConfigStore::<Limits>::open(&db, "limits").unwrap();
+        assert_eq!(limits.current().max_users, 10);
+
+        db.set("limits", "not json").unwrap();
+        db.set("limits", r#"{"max_users": 20}"#).unwrap();
+        for _ in 0..100 {
+            if limits.current().max_users == 20 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(*limits.current(), Limits { max_users: 20 });
+    }
+}
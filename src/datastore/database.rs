@@ -1,72 +1,750 @@
-use super::{Record, Result};
+use super::{
+    Collection, DbError, DbOptions, IndexHasher, Iter, OpenProgress, Record, RecordKind, Result,
+    SnapshotIter, SyncPolicy, WriteBatch,
+    batch::BatchOp,
+    cache::ReadCache,
+    cdc::{ChangeEvent, Subscribers, UserWrites},
+    clock::{self, Clock},
+    collection::{collection_of, namespaced_key, user_key, validate_collection_name},
+    direct_io::{self, DirectFile},
+    error::{Context, catch_callback},
+    header::{FLAG_SIGNED, FORMAT_VERSION, check_header, header_record},
+    hint::Hint,
+    hot_keys::AccessTracker,
+    integrity::FileMac,
+    metering::UsageMeter,
+    prealloc,
+    repartition::MovedRecord,
+    sequence::ReservedIds,
+    text_index::TextIndexes,
+    transform::{TransformerRegistry, encode_value},
+};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
     fs::{File, OpenOptions},
-    io::{Read, Seek, Write},
-    path::Path,
+    io::{BufReader, Read, Seek, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
+
+/// Index entries `approx_count_prefix` looks at when the index isn't sorted
+const COUNT_SAMPLE: usize = 4096;
+/// Bytes read at a time when looking for a record behind a bad length prefix.
+/// Records longer than this aren't looked for.
+const RESYNC_CHUNK: usize = 1 << 20;
+/// How far behind a bad length prefix to look before giving up
+const RESYNC_LIMIT: u64 = 8 << 20;
+
+/// Where a live record sits in the data file
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct IndexEntry {
+    offset: u64, // Byte offset of the record's length prefix
+    len: u64,    // Bytes taken on disk, length prefix included
+    expires_at: Option<u64>,
+    transformed: bool, // Whether the stored value went through value transformers
+    seq: u64,          // Sequence number of the write that put the value there
+}
+
+impl IndexEntry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// Bytes on disk that belong to a single collection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CollectionStats {
+    pub live_bytes: u64,
+    pub live_keys: u64,
+    /// Overwritten, deleted or expired records & tombstones
+    pub garbage_bytes: u64,
+}
+
+impl CollectionStats {
+    pub fn garbage_ratio(&self) -> f64 {
+        let total = self.live_bytes + self.garbage_bytes;
+        if total == 0 {
+            return 0.0;
+        }
+        self.garbage_bytes as f64 / total as f64
+    }
+}
+
+/// Bytes written to the data file since the db was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WriteStats {
+    /// Records appended by writes, batch commit markers included
+    pub user_bytes: u64,
+    /// Records compaction copied into the new file
+    pub compaction_bytes: u64,
+    pub compactions: u64,
+}
+
+impl WriteStats {
+    /// Bytes that hit the data file per byte written by the user, 1.0 means
+    /// compaction hasn't rewritten anything yet. 0.0 before the first write.
+    pub fn write_amplification(&self) -> f64 {
+        if self.user_bytes == 0 {
+            return 0.0;
+        }
+        (self.user_bytes + self.compaction_bytes) as f64 / self.user_bytes as f64
+    }
+}
+
+/// Values read together by `multi_get`, all as of the write numbered `seq`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiGet {
+    pub seq: u64,
+    /// In the order the keys were asked for, `None` for missing keys
+    pub values: Vec<Option<String>>,
+}
+
 /// The main datastore struct.
 /// It holds a file handle to the data file & an in-memory index
 pub struct EmbeddedDatabase {
     file: File,
-    index: HashMap<String, u64>, // Maps key to byte offset in the file
+    pub(crate) path: PathBuf,
+    pub(crate) options: DbOptions,
+    pub(crate) transformers: Arc<TransformerRegistry>,
+    index: HashMap<String, IndexEntry, IndexHasher>, // Maps key to its location in the file
+    stats: HashMap<String, CollectionStats>,         // Keyed by collection name
+    sorted_keys: Option<BTreeSet<String>>, // Keys of `index`, with `DbOptions::sorted_index`
+    pub(crate) reserved_ids: ReservedIds,
+    pub(crate) subscribers: Subscribers,
+    last_seq: u64, // Sequence number of the latest write
+    pub(crate) user_writes: UserWrites,
+    pub(crate) access_tracker: Option<AccessTracker>,
+    pub(crate) cache: ReadCache,
+    unsynced_since: Option<Instant>, // When the oldest write that isn't synced yet happened
+    mac: Option<FileMac>,
+    pub(crate) text_index: TextIndexes,
+    clock: Arc<dyn Clock>,
+    write_stats: WriteStats,
+    direct_file: Option<DirectFile>, // Appends go through here with `DbOptions::direct_io`
+    pub(crate) end_of_data: u64,     // Where the next append goes
+    reserved_until: u64, // End of the space reserved with `DbOptions::preallocate_chunk`
+    pub(crate) usage_meter: Option<UsageMeter>,
+    pub(crate) epoch: Option<u64>, // Set by `fence`, checked before every write
+    pub(crate) format_version: u32, // Of the data file, 0 for one without a header
 }
 
 impl EmbeddedDatabase {
     /// Creates a new EmbeddedDatabase or opens an existing one from a db file
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let mut file = OpenOptions::new()
-            .read(true) // Allow reading
-            .write(true) // Allow writing
-            .create(true) // Create if it does not exist
-            .open(path)?;
-        let mut index = HashMap::new();
-        let mut position = 0;
-        let file_len = file.metadata()?.len();
+        Self::with_options(path, DbOptions::default())
+    }
 
-        // Read the file & populate the index
+    /// Same as `new` but with per-collection TTL, value transformer & compaction settings.
+    /// Unless `DbOptions::read_only` is set, an unfinished write at the end
+    /// of the file is cut off, see `rebuild_index`.
+    pub fn with_options<P: AsRef<Path>>(path: P, options: DbOptions) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if options.read_only && (options.mac_key.is_some() || options.direct_io) {
+            return Err("a read-only handle can't check a MAC or use direct IO".into());
+        }
+        let file = open_data_file(&path, options.read_only)?;
+
+        if let Some(alignment) = options.record_alignment
+            && !alignment.is_power_of_two()
+        {
+            return Err(format!("record alignment {alignment} isn't a power of two").into());
+        }
+        let direct_file = match (options.direct_io, options.record_alignment) {
+            (false, _) => None,
+            (true, Some(alignment)) => Some(DirectFile::open(&path, alignment)?),
+            (true, None) => return Err("direct IO needs a record alignment".into()),
+        };
+        let transformers = Arc::new(TransformerRegistry::new(&options, &path)?);
+        let mac = options
+            .mac_key
+            .as_ref()
+            .map(|key| FileMac::open(&path, key, &file))
+            .transpose()?;
+        let mut db = EmbeddedDatabase {
+            file,
+            path,
+            transformers,
+            access_tracker: options.track_hot_keys.then(AccessTracker::new),
+            cache: ReadCache::new(options.cache_capacity_bytes),
+            clock: options.clock(),
+            user_writes: UserWrites::new(clock::millis(options.clock().as_ref())),
+            usage_meter: options.metering.clone().map(UsageMeter::new),
+            index: HashMap::with_hasher(options.index_hasher()),
+            sorted_keys: options.sorted_index.then(BTreeSet::new),
+            options,
+            stats: HashMap::new(),
+            reserved_ids: ReservedIds::new(),
+            subscribers: Subscribers::new(),
+            last_seq: 0,
+            unsynced_since: None,
+            mac,
+            text_index: TextIndexes::new(),
+            write_stats: WriteStats::default(),
+            direct_file,
+            end_of_data: 0,
+            reserved_until: 0,
+            epoch: None,
+            format_version: FORMAT_VERSION,
+        };
+        // A new file gets a header, anything else has to start with one
+        match db.file.metadata()?.len() {
+            0 if db.options.read_only => {}
+            0 => db.write_header()?,
+            _ => {
+                let header = check_header(&mut db.file)?;
+                db.format_version = header.version;
+                // Appends without the key would leave the file half signed
+                if header.flags & FLAG_SIGNED != 0 && db.mac.is_none() && !db.options.read_only {
+                    return Err(DbError::IntegrityCheckFailed {
+                        reason: "the file is signed, open it with DbOptions::mac_key".to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+        let from = match db.options.hint_files {
+            true => db.load_hint()?,
+            false => 0,
+        };
+        db.load_index(from)?;
+        db.backfill_numeric_indexes()?;
+        db.check_schemas()?;
+        Ok(db)
+    }
+
+    /// Throw the in-memory index away & scan the data file again, without
+    /// closing the handle. For when the index can't be trusted anymore or
+    /// another process appended to the file. The open options (progress,
+    /// timeout, MAC) apply like they do on open.
+    /// Like the open, a handle that can write cuts off an uncommitted batch
+    /// or torn write at the end of the file, so it isn't safe while another
+    /// process is writing: that process's batch in flight would be cut off
+    /// under it. Follow another writer with a `DbOptions::read_only` handle.
+    pub fn rebuild_index(&mut self) -> Result<()> {
+        // Someone may have compacted the file, i.e. renamed a new one over it
+        self.file = open_data_file(&self.path, self.options.read_only)?;
+        if let Some(direct_file) = &mut self.direct_file {
+            *direct_file = DirectFile::open(&self.path, direct_file.alignment())?;
+        }
+        if let Some(key) = &self.options.mac_key {
+            self.mac = Some(FileMac::open(&self.path, key, &self.file)?);
+        }
+        if self.file.metadata()?.len() > 0 {
+            self.format_version = check_header(&mut self.file)?.version;
+        }
+
+        // Goes through `forget` so cached values & text index entries go too
+        let keys: Vec<String> = self.index.keys().cloned().collect();
+        for key in keys {
+            self.forget(&key);
+        }
+        self.stats.clear();
+        self.load_index(0)?;
+        self.backfill_numeric_indexes()?;
+        self.rebuild_text_indexes()
+    }
+
+    /// Index whatever another process appended to the file since the open
+    /// or the last refresh, without scanning the rest again. Lets a handle
+    /// opened with `DbOptions::read_only` follow a writer in another process.
+    /// A batch that isn't committed yet is left alone in the file & picked up
+    /// by a later refresh. Falls back to `rebuild_index` when the file was
+    /// compacted, or is signed with a MAC.
+    pub fn refresh(&mut self) -> Result<()> {
+        if self.options.mac_key.is_some() || self.file_replaced()? {
+            return self.rebuild_index();
+        }
+
+        let file_len = self.file.metadata()?.len();
+        let now = self.now_millis();
+        let mut position = self.end_of_data;
+        let mut pending_batch: Vec<(Record, u64, u64)> = Vec::new();
         while position < file_len {
+            // The writer may be halfway through this one
+            let Ok((record, disk_len)) =
+                read_frame_at(&mut self.file, position, self.format_version)
+            else {
+                break;
+            };
+            self.last_seq = self.last_seq.max(record.seq);
+            match record.kind {
+                RecordKind::Single => {
+                    for (record, _, len) in pending_batch.drain(..) {
+                        self.stats_mut(&record.key).garbage_bytes += len;
+                    }
+                    self.index_tailed(record, position, disk_len, now)?;
+                    self.end_of_data = position + disk_len;
+                }
+                RecordKind::Batched => pending_batch.push((record, position, disk_len)),
+                RecordKind::BatchCommit => {
+                    for (record, offset, len) in pending_batch.drain(..) {
+                        self.index_tailed(record, offset, len, now)?;
+                    }
+                    self.stats_mut(&record.key).garbage_bytes += disk_len;
+                    self.end_of_data = position + disk_len;
+                }
+                RecordKind::Padding | RecordKind::Header if pending_batch.is_empty() => {
+                    self.end_of_data = position + disk_len;
+                }
+                RecordKind::Padding | RecordKind::Header => {}
+            }
+            position += disk_len;
+        }
+        self.reserved_until = self.reserved_until.max(self.end_of_data);
+        Ok(())
+    }
+
+    /// `index_record` for a record written by someone else, which the text
+    /// index hasn't seen either
+    fn index_tailed(&mut self, record: Record, offset: u64, len: u64, now: u64) -> Result<()> {
+        let key = record.key.clone();
+        let text = (self.text_index.covers(collection_of(&key)) && !record.is_tombstone())
+            .then(|| self.transformers.decode(record.clone()))
+            .transpose()?;
+        self.index_record(record, offset, len, now);
+        if let Some(val) = text
+            && self.index.contains_key(&key)
+        {
+            self.text_index.insert(&key, &val);
+        }
+        Ok(())
+    }
+
+    /// Whether another handle appended to or replaced the file since this
+    /// one last wrote or refreshed
+    pub(crate) fn written_elsewhere(&self) -> Result<bool> {
+        Ok(self.file_replaced()? || self.file.metadata()?.len() != self.end_of_data)
+    }
+
+    /// Whether the file at `path` isn't the one `self.file` has open anymore,
+    /// or got shorter than what was indexed
+    fn file_replaced(&self) -> Result<bool> {
+        let on_disk = std::fs::metadata(&self.path)?;
+        let ours = self.file.metadata()?;
+        if on_disk.len() < self.end_of_data {
+            return Ok(true);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Ok((on_disk.dev(), on_disk.ino()) != (ours.dev(), ours.ino()))
+        }
+        #[cfg(not(unix))]
+        Ok(ours.len() < self.end_of_data)
+    }
+
+    /// Current unix time in millis on the db's clock, used for TTLs
+    pub(crate) fn now_millis(&self) -> u64 {
+        clock::millis(self.clock.as_ref())
+    }
+
+    /// Take the index from the hint file if it matches the data file.
+    /// Returns where the hint leaves off, 0 when there's no usable hint.
+    fn load_hint(&mut self) -> Result<u64> {
+        let Some(hint) = Hint::load(&self.path, &mut self.file)? else {
+            return Ok(0);
+        };
+        let now = self.now_millis();
+        self.last_seq = hint.last_seq;
+        self.stats = hint.stats.into_iter().collect();
+        for (key, entry) in hint.entries {
+            // Expired while the db was closed
+            if entry.is_expired(now) {
+                let stats = self.stats_mut(&key);
+                stats.live_bytes -= entry.len;
+                stats.live_keys -= 1;
+                stats.garbage_bytes += entry.len;
+                continue;
+            }
+            if let Some(sorted_keys) = &mut self.sorted_keys {
+                sorted_keys.insert(key.clone());
+            }
+            self.user_writes.indexed(&key, entry.seq);
+            self.index.insert(key, entry);
+        }
+        Ok(hint.data_len)
+    }
+
+    /// Read the file from `from` to finish & populate the index
+    fn load_index(&mut self, from: u64) -> Result<()> {
+        let started = Instant::now();
+        let now = self.now_millis();
+        let mut position = from;
+        let file_len = self.file.metadata()?.len();
+        // Records of batches that never got their commit marker
+        let mut records_skipped = 0;
+
+        // Records of a batch whose commit marker we haven't reached yet
+        let mut pending_batch: Vec<(Record, u64, u64)> = Vec::new();
+        // Everything before this point has been fully applied to the index
+        let mut committed_len = from;
+
+        let progress_callback = self.options.open_progress.clone();
+        let progress_step = (file_len / 100).max(1);
+        let mut next_report = from + progress_step;
+        let mut records_indexed = 0;
+        let deadline = self
+            .options
+            .open_timeout
+            .map(|timeout| Instant::now() + timeout);
+
+        while position < file_len {
+            // Checking the clock is cheap but not free, so only look every so often
+            if records_indexed % 256 == 0 {
+                self.check_open_aborted(deadline, position, file_len)?;
+            }
+
             // Move cursor to the begining of the next record
-            file.seek(std::io::SeekFrom::Start(position))?;
+            self.file.seek(std::io::SeekFrom::Start(position))?;
 
             // Read the 8-byte length of the serialized record
             // if we can't read the length, we have reached the end of the file
             let mut len_buffer = [0u8; 8];
-            if file.read_exact(&mut len_buffer).is_err() {
+            if self.file.read_exact(&mut len_buffer).is_err() {
+                break;
+            }
+            // A record running past the end of the file means the last write
+            // was torn by a crash, unless good records follow it: then the
+            // damage is in the middle & cutting there would throw them away
+            let len = match frame_len(len_buffer, position, file_len) {
+                Ok(len) => len,
+                Err(err) if self.decodable_frame_after(position + 1, file_len)? => {
+                    return Err(err);
+                }
+                Err(_) => break,
+            };
+            let mut record_buffer = vec![0u8; len];
+            if self.file.read_exact(&mut record_buffer).is_err() {
                 break;
             }
-            let len = u64::from_le_bytes(len_buffer);
 
-            // Read the record data
-            let mut record_buffer = vec![0u8; len as usize];
-            file.read_exact(&mut record_buffer)?;
+            let record = Record::decode(&record_buffer, self.format_version)?;
+            let disk_len = 8 + len as u64;
+            self.last_seq = self.last_seq.max(record.seq);
+            if record.kind != RecordKind::Header {
+                records_indexed += 1;
+            }
 
-            let record: Record = bincode::deserialize(&record_buffer)?;
+            match record.kind {
+                RecordKind::Single => {
+                    // A batch that never got its commit marker didn't happen
+                    for (record, _, len) in pending_batch.drain(..) {
+                        self.stats_mut(&record.key).garbage_bytes += len;
+                        records_skipped += 1;
+                    }
+                    self.index_record(record, position, disk_len, now);
+                    committed_len = position + disk_len;
+                }
+                RecordKind::Batched => pending_batch.push((record, position, disk_len)),
+                RecordKind::BatchCommit => {
+                    for (record, offset, len) in pending_batch.drain(..) {
+                        self.index_record(record, offset, len, now);
+                    }
+                    self.stats_mut(&record.key).garbage_bytes += disk_len;
+                    committed_len = position + disk_len;
+                }
+                // Not counted as garbage, compacting it away would only
+                // bring it back with the next write
+                RecordKind::Padding | RecordKind::Header if pending_batch.is_empty() => {
+                    committed_len = position + disk_len;
+                }
+                RecordKind::Padding | RecordKind::Header => {}
+            }
 
-            // Check if the record is a tombstone
-            if record.val.is_empty() {
-                // Remove the key from the index
-                index.remove(&record.key);
-            } else {
-                // The start of the record is the curent "position"
-                index.insert(record.key, position);
+            position += disk_len;
+
+            if let Some(callback) = &progress_callback
+                && position >= next_report
+                && position < file_len
+            {
+                let progress = OpenProgress {
+                    bytes_scanned: position,
+                    total_bytes: file_len,
+                    records_indexed,
+                };
+                catch_callback(
+                    || "open progress callback".to_string(),
+                    || (callback.0)(progress),
+                )?;
+                next_report = position + progress_step;
+            }
+        }
+
+        if let Some(callback) = &progress_callback {
+            let progress = OpenProgress {
+                bytes_scanned: file_len,
+                total_bytes: file_len,
+                records_indexed,
             };
+            catch_callback(
+                || "open progress callback".to_string(),
+                || (callback.0)(progress),
+            )?;
+        }
 
-            position += 8 + len;
+        records_skipped += pending_batch.len() as u64;
+        let bytes_truncated = file_len - committed_len;
+        if bytes_truncated > 0 || records_skipped > 0 {
+            log::warn!(
+                path:% = self.path.display(),
+                records_indexed,
+                records_skipped,
+                bytes_truncated,
+                elapsed_ms = started.elapsed().as_millis() as u64;
+                "recovered from an unclean shutdown"
+            );
+        } else {
+            log::debug!(
+                path:% = self.path.display(),
+                records_indexed,
+                elapsed_ms = started.elapsed().as_millis() as u64;
+                "index loaded"
+            );
+        }
+
+        // Chop off a torn record or an uncommitted batch at the tail so new
+        // appends don't end up behind bytes that will never be applied. A
+        // read-only handle leaves it to the writer, whose batch it may be.
+        if committed_len < file_len && !self.options.read_only {
+            self.file.set_len(committed_len)?;
+            if let Some(mac) = &mut self.mac {
+                mac.rebuild(&self.file)?;
+            }
         }
+        self.end_of_data = committed_len;
+        self.reserved_until = committed_len;
 
-        Ok(EmbeddedDatabase { file, index })
+        Ok(())
+    }
+
+    /// Whether a whole record that decodes starts anywhere in `from..file_len`.
+    /// Gives up after `RESYNC_LIMIT` bytes: that much behind a bad length
+    /// prefix is no torn write, so it counts as found & nothing is cut off.
+    fn decodable_frame_after(&mut self, from: u64, file_len: u64) -> Result<bool> {
+        let mut window = Vec::with_capacity(2 * RESYNC_CHUNK + 8);
+        let mut start = from;
+        while start + 8 <= file_len {
+            if start - from >= RESYNC_LIMIT {
+                return Ok(true);
+            }
+            // Every start in this chunk gets room for a record of up to a chunk
+            window.clear();
+            self.file.seek(std::io::SeekFrom::Start(start))?;
+            (&mut self.file)
+                .take(2 * RESYNC_CHUNK as u64 + 8)
+                .read_to_end(&mut window)?;
+            let window_end = start + window.len() as u64;
+            let found = (0..RESYNC_CHUNK.min(window.len() - 8)).any(|at| {
+                let len_buffer = window[at..at + 8].try_into().unwrap_or_default();
+                let Ok(len) = frame_len(len_buffer, start + at as u64, window_end) else {
+                    return false;
+                };
+                // Trailing bytes would mean a decode by chance, not a record
+                Record::decode(&window[at + 8..at + 8 + len], 0).is_ok()
+            });
+            if found {
+                return Ok(true);
+            }
+            start += RESYNC_CHUNK as u64;
+        }
+        Ok(false)
     }
+
+    /// Stop rebuilding the index when the open ran out of time or was cancelled
+    fn check_open_aborted(
+        &self,
+        deadline: Option<Instant>,
+        bytes_scanned: u64,
+        total_bytes: u64,
+    ) -> Result<()> {
+        if self
+            .options
+            .open_cancel
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+        {
+            return Err(DbError::OpenCancelled {
+                bytes_scanned,
+                total_bytes,
+            }
+            .into());
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(DbError::OpenTimedOut {
+                bytes_scanned,
+                total_bytes,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Point the index at a record that is now on disk
+    fn index_record(&mut self, record: Record, offset: u64, len: u64, now: u64) {
+        // Whatever this key pointed at before is now garbage
+        self.forget(&record.key);
+        self.user_writes.indexed(&record.key, record.seq);
+
+        // Tombstones & records that expired while the db was closed are dead on arrival
+        if record.is_tombstone() || record.is_expired(now) {
+            self.stats_mut(&record.key).garbage_bytes += len;
+        } else {
+            let stats = self.stats_mut(&record.key);
+            stats.live_bytes += len;
+            stats.live_keys += 1;
+            let entry = IndexEntry {
+                offset,
+                len,
+                expires_at: record.expires_at,
+                transformed: !record.transforms.is_empty(),
+                seq: record.seq,
+            };
+            if let Some(sorted_keys) = &mut self.sorted_keys {
+                sorted_keys.insert(record.key.clone());
+            }
+            self.index.insert(record.key, entry);
+        }
+    }
+
     /// Serialize a K, V pair and append it to the data file as well as update
     /// in memory idx in order to find the data later without scanning the file.
     /// Our on-disk format for a single entry will look like this :
     /// [8-byte len of record] [actual Record data bytes]
     pub fn set(&mut self, key: &str, val: &str) -> Result<()> {
-        // Create a Record with the given key & Val
-        let record = Record {
-            key: key.to_string(),
-            val: val.to_string(),
-        };
+        validate_plain_key(key)
+            .and_then(|()| self.put(key.to_string(), val.as_bytes(), None))
+            .context(|| format!("set(key={key})"))
+    }
+
+    /// Use in-memory idx to perform a fast lookup
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        validate_plain_key(key)
+            .and_then(|()| self.get_stored(key))
+            .context(|| format!("get(key={key})"))
+    }
+
+    /// `set` with a TTL of its own, overriding the collection's default.
+    /// Once it passes `get` no longer sees the key & compaction drops it.
+    pub fn set_with_ttl(&mut self, key: &str, val: &str, ttl: Duration) -> Result<()> {
+        validate_plain_key(key)
+            .and_then(|()| self.put(key.to_string(), val.as_bytes(), Some(ttl)))
+            .context(|| format!("set_with_ttl(key={key})"))
+    }
+
+    /// `set` for values that aren't text, any bytes (none at all too) will do
+    pub fn set_bytes(&mut self, key: &str, val: &[u8]) -> Result<()> {
+        validate_plain_key(key)
+            .and_then(|()| self.put(key.to_string(), val, None))
+            .context(|| format!("set_bytes(key={key})"))
+    }
+
+    /// `get` for values written with `set_bytes`, works for text values too
+    pub fn get_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        validate_plain_key(key)
+            .and_then(|()| self.get_stored_bytes(key))
+            .context(|| format!("get_bytes(key={key})"))
+    }
+
+    /// Read several keys at the same sequence number, so invariants across
+    /// keys (a balance & its ledger entry) can be checked without a
+    /// transaction. Every key is checked before anything is read.
+    pub fn multi_get(&mut self, keys: &[&str]) -> Result<MultiGet> {
+        // Reads don't write, so nothing moves between the first & the last one
+        let values = keys
+            .iter()
+            .try_for_each(|key| validate_plain_key(key))
+            .and_then(|()| keys.iter().map(|key| self.get_stored(key)).collect())
+            .context(|| format!("multi_get({} keys)", keys.len()))?;
+        Ok(MultiGet {
+            seq: self.last_seq,
+            values,
+        })
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        validate_plain_key(key)
+            .and_then(|()| self.remove(key.to_string()))
+            .context(|| format!("delete(key={key})"))
+    }
+
+    /// User vs compaction writes since the db was opened, see
+    /// `WriteStats::write_amplification`
+    pub fn write_stats(&self) -> WriteStats {
+        self.write_stats
+    }
+
+    /// Write the value only if the key doesn't hold a live value yet.
+    /// Returns whether the write happened.
+    pub fn set_if_absent(&mut self, key: &str, val: &str) -> Result<bool> {
+        validate_plain_key(key)
+            .and_then(|()| self.put_if_absent(key.to_string(), val.as_bytes(), None))
+            .context(|| format!("set_if_absent(key={key})"))
+    }
+
+    /// Read a value & delete it in the same call, so two takers can't both
+    /// get it. The delete is a single tombstone append.
+    pub fn take(&mut self, key: &str) -> Result<Option<String>> {
+        validate_plain_key(key)
+            .and_then(|()| self.take_stored(key.to_string()))
+            .context(|| format!("take(key={key})"))
+    }
+
+    /// Exchange the values of two keys in one batch, so no reader ever sees
+    /// both keys holding the same value. A missing key swaps as a delete.
+    /// Both values lose their TTL.
+    pub fn swap(&mut self, key_a: &str, key_b: &str) -> Result<()> {
+        validate_plain_key(key_a)
+            .and_then(|()| validate_plain_key(key_b))
+            .and_then(|()| self.swap_stored(key_a.to_string(), key_b.to_string()))
+            .context(|| format!("swap(key_a={key_a}, key_b={key_b})"))
+    }
+
+    /// Move a value to another key in one batch, overwriting whatever `to`
+    /// held. The value keeps no TTL. Returns false if `from` had no value.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<bool> {
+        validate_plain_key(from)
+            .and_then(|()| validate_plain_key(to))
+            .and_then(|()| self.rename_stored(from.to_string(), to.to_string()))
+            .context(|| format!("rename(from={from}, to={to})"))
+    }
+
+    /// Delete every key of the default collection starting with `prefix`, in
+    /// one batch. Returns the number of keys deleted.
+    pub fn delete_prefix(&mut self, prefix: &str) -> Result<usize> {
+        validate_plain_key(prefix)
+            .and_then(|()| self.delete_prefix_in("", prefix))
+            .context(|| format!("delete_prefix(prefix={prefix})"))
+    }
+
+    /// A handle for the keys of a named collection
+    pub fn collection(&mut self, name: &str) -> Result<Collection<'_>> {
+        validate_collection_name(name).context(|| format!("collection(name={name})"))?;
+        Ok(Collection::new(self, name))
+    }
+
+    /// Live & garbage bytes for a collection ("" is the default collection)
+    pub fn collection_stats(&self, name: &str) -> CollectionStats {
+        self.stats.get(name).copied().unwrap_or_default()
+    }
+
+    /// Append a value for an already namespaced key.
+    /// `ttl` overrides the default TTL of the key's collection.
+    pub(crate) fn put(&mut self, key: String, val: &[u8], ttl: Option<Duration>) -> Result<()> {
+        self.record_access(&key);
+        // The index entries have to land together with the value
+        if self.has_numeric_index(collection_of(&key)) {
+            return self.commit_writes(vec![(key, Some(val.to_vec()))], ttl);
+        }
+        let seq = self.last_seq + 1;
+        let event = self
+            .has_subscribers()
+            .then(|| ChangeEvent::new(&key, Some(val), seq));
+        let record = self.build_record(key, val, ttl, RecordKind::Single, seq)?;
         /*
         Note to self:
         bincode doesn't just blindly join the bytes of
@@ -76,78 +754,1303 @@ impl EmbeddedDatabase {
         Record {key: "cat",
                 value: "meow"
         }
-        might look like this:
-        [length of key: 3] [actual bytes for "cat"] [length of value: 4] [actual bytes for "meow"]
-        */
-        let encoded_record = bincode::serialize(&record)?;
-        let encoded_record_len = encoded_record.len();
+        might look like this:
+        [length of key: 3] [actual bytes for "cat"] [length of value: 4] [actual bytes for "meow"]
+        */
+        let (offset, len) = self.append(&record)?;
+        self.last_seq = seq;
+
+        // Update the in-memory idx & move the old version over to the garbage pile
+        let collection = collection_of(&record.key).to_string();
+        let key = record.key.clone();
+        self.index_record(record, offset, len, self.now_millis());
+        self.cache.refresh_pinned(&key, val);
+        self.text_index.insert(&key, val);
+        self.meter_write(&key, val.len());
+        self.notify(seq, event.into_iter().collect());
+
+        self.maybe_compact(&collection)
+    }
+
+    /// Stage all the writes of a batch behind a single commit marker.
+    /// The index is only touched once the marker is written, so either every
+    /// operation in the batch is visible or none of them is.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        // Resolve every key first so a bad one fails the batch before anything is written
+        let len = batch.len();
+        let writes = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set {
+                    collection,
+                    key,
+                    val,
+                } => Ok((batch_key(&collection, &key)?, Some(val.into_bytes()))),
+                BatchOp::Delete { collection, key } => Ok((batch_key(&collection, &key)?, None)),
+            })
+            .collect::<Result<_>>();
+        writes
+            .and_then(|writes| self.commit_writes(writes, None))
+            .context(|| format!("apply_batch({len} ops)"))
+    }
+
+    /// Write already namespaced keys (`None` deletes) behind one commit
+    /// marker, along with the numeric index entries they change
+    pub(crate) fn commit_writes(
+        &mut self,
+        writes: Vec<(String, Option<Vec<u8>>)>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let metered: Vec<(String, usize)> = match self.usage_meter {
+            Some(_) => writes
+                .iter()
+                .map(|(key, val)| (key.clone(), val.as_ref().map_or(0, Vec::len)))
+                .collect(),
+            None => Vec::new(),
+        };
+        let writes = self.with_index_updates(writes)?;
+
+        // Every record of the batch shares one sequence number
+        let seq = self.last_seq + 1;
+
+        let mut records = Vec::with_capacity(writes.len() + 1);
+        let mut events = Vec::new();
+        let mut written = Vec::new();
+        for (key, val) in writes {
+            if self.has_subscribers() {
+                events.push(ChangeEvent::new(&key, val.as_deref(), seq));
+            }
+            let record = match val {
+                Some(val) => {
+                    let record =
+                        self.build_record(key.clone(), &val, ttl, RecordKind::Batched, seq)?;
+                    written.push((key, val));
+                    record
+                }
+                None => tombstone(key, RecordKind::Batched, seq),
+            };
+            records.push(record);
+        }
+        records.push(tombstone(String::new(), RecordKind::BatchCommit, seq));
+
+        // Encode the whole batch up front so it goes out in one write
+        let mut buffer = Vec::new();
+        let mut lens = Vec::with_capacity(records.len());
+        for record in &records {
+            lens.push(encode_frame(record, &mut buffer)?);
+        }
+        let mut offset = self.write_frames(&buffer)?;
+        self.last_seq = seq;
+
+        let now = self.now_millis();
+        let mut collections = Vec::new();
+        for (record, len) in records.into_iter().zip(lens) {
+            if record.kind == RecordKind::BatchCommit {
+                self.stats_mut(&record.key).garbage_bytes += len;
+            } else {
+                collections.push(collection_of(&record.key).to_string());
+                self.index_record(record, offset, len, now);
+            }
+            offset += len;
+        }
+        for (key, val) in written {
+            self.text_index.insert(&key, &val);
+        }
+        for (key, val_len) in metered {
+            self.meter_write(&key, val_len);
+        }
+        self.notify(seq, events);
+
+        collections.sort();
+        collections.dedup();
+        for collection in collections {
+            self.maybe_compact(&collection)?;
+        }
+        Ok(())
+    }
+
+    /// Append values copied over from another file, see `split` & `merge_from`.
+    /// They keep their expiry & sequence numbers, or with `batch_seq` all go
+    /// behind one commit marker under that number instead.
+    pub(crate) fn import_records(
+        &mut self,
+        moved: Vec<MovedRecord>,
+        batch_seq: Option<u64>,
+    ) -> Result<()> {
+        if moved.is_empty() {
+            return Ok(());
+        }
+        let kind = match batch_seq {
+            Some(_) => RecordKind::Batched,
+            None => RecordKind::Single,
+        };
+        let mut records = Vec::with_capacity(moved.len() + 1);
+        let mut values = Vec::with_capacity(moved.len());
+        for moved in moved {
+            let seq = batch_seq.unwrap_or(moved.seq);
+            let mut record = self.build_record(moved.key.clone(), &moved.val, None, kind, seq)?;
+            record.expires_at = moved.expires_at;
+            records.push(record);
+            values.push((moved.key, moved.val));
+        }
+        if let Some(seq) = batch_seq {
+            records.push(tombstone(String::new(), RecordKind::BatchCommit, seq));
+        }
+
+        let mut buffer = Vec::new();
+        let mut lens = Vec::with_capacity(records.len());
+        for record in &records {
+            lens.push(encode_frame(record, &mut buffer)?);
+        }
+        let mut offset = self.write_frames(&buffer)?;
+
+        let now = self.now_millis();
+        for (record, len) in records.into_iter().zip(lens) {
+            self.last_seq = self.last_seq.max(record.seq);
+            if record.kind == RecordKind::BatchCommit {
+                self.stats_mut(&record.key).garbage_bytes += len;
+            } else {
+                self.index_record(record, offset, len, now);
+            }
+            offset += len;
+        }
+        for (key, val) in &values {
+            self.text_index.insert(key, val);
+        }
+
+        // The copied values never went through the numeric index extractors
+        let indexes: Vec<String> = self
+            .options
+            .numeric_indexes
+            .iter()
+            .filter(|(_, index)| {
+                values
+                    .iter()
+                    .any(|(key, _)| collection_of(key) == index.collection)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in indexes {
+            self.rebuild_numeric_index(&name)?;
+        }
+        Ok(())
+    }
+
+    /// `put` that only goes ahead when the key is missing or expired
+    pub(crate) fn put_if_absent(
+        &mut self,
+        key: String,
+        val: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        if self.is_live(&key) {
+            return Ok(false);
+        }
+        self.put(key, val, ttl)?;
+        Ok(true)
+    }
+
+    /// `take` for an already namespaced key
+    pub(crate) fn take_stored(&mut self, key: String) -> Result<Option<String>> {
+        let Some(val) = self.get_stored(&key)? else {
+            return Ok(None);
+        };
+        self.remove(key)?;
+        Ok(Some(val))
+    }
+
+    /// `swap` for already namespaced keys
+    pub(crate) fn swap_stored(&mut self, key_a: String, key_b: String) -> Result<()> {
+        if key_a == key_b {
+            return Ok(());
+        }
+        let val_a = self.get_stored(&key_a)?.map(String::into_bytes);
+        let val_b = self.get_stored(&key_b)?.map(String::into_bytes);
+        self.commit_writes(vec![(key_a, val_b), (key_b, val_a)], None)
+    }
+
+    /// `rename` for already namespaced keys
+    pub(crate) fn rename_stored(&mut self, from: String, to: String) -> Result<bool> {
+        let Some(val) = self.get_stored(&from)? else {
+            return Ok(false);
+        };
+        if from != to {
+            self.commit_writes(vec![(from, None), (to, Some(val.into_bytes()))], None)?;
+        }
+        Ok(true)
+    }
+
+    /// `delete_prefix` for any collection
+    pub(crate) fn delete_prefix_in(&mut self, collection: &str, prefix: &str) -> Result<usize> {
+        let stored_prefix = namespaced_key(collection, prefix);
+        let doomed: Vec<(String, Option<Vec<u8>>)> = self
+            .live_keys(collection)
+            .into_iter()
+            .filter(|key| key.starts_with(&stored_prefix))
+            .map(|key| (key, None))
+            .collect();
+        let count = doomed.len();
+        self.commit_writes(doomed, None)?;
+        Ok(count)
+    }
+
+    /// Whether an already namespaced key holds a value that hasn't expired
+    pub(crate) fn is_live(&mut self, key: &str) -> bool {
+        match self.index.get(key) {
+            Some(entry) if entry.is_expired(self.now_millis()) => {
+                self.forget(key);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Stored keys starting with `stored_prefix` whose TTL has passed, sorted
+    pub(crate) fn expired_keys(&self, stored_prefix: &str) -> Vec<String> {
+        let now = self.now_millis();
+        let mut keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(key, entry)| key.starts_with(stored_prefix) && entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Drop every expired key from the index instead of waiting for a read
+    /// to trip over it, so its bytes count as garbage for compaction.
+    /// Returns the number of keys dropped.
+    pub fn sweep_expired(&mut self) -> usize {
+        let now = self.now_millis();
+        let expired: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.forget(key);
+        }
+        expired.len()
+    }
+
+    /// Look up an already namespaced key
+    pub(crate) fn get_stored(&mut self, key: &str) -> Result<Option<String>> {
+        let Some(entry) = self.lookup(key) else {
+            return Ok(None);
+        };
+        let val = match self.cache.get(key) {
+            Some(val) => val,
+            None => {
+                let record = self.read_record(entry.offset)?;
+                let val = String::from_utf8(self.transformers.decode(record)?)?;
+                self.cache.insert(key, &val);
+                val
+            }
+        };
+        self.meter_read(key, val.len());
+        Ok(Some(val))
+    }
+
+    /// `get_stored` for values that don't have to be UTF-8. Only values
+    /// that already are go into the read cache.
+    pub(crate) fn get_stored_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(entry) = self.lookup(key) else {
+            return Ok(None);
+        };
+        let val = match self.cache.get(key) {
+            Some(val) => val.into_bytes(),
+            None => {
+                let record = self.read_record(entry.offset)?;
+                let val = self.transformers.decode(record)?;
+                if let Ok(text) = std::str::from_utf8(&val) {
+                    self.cache.insert(key, text);
+                }
+                val
+            }
+        };
+        self.meter_read(key, val.len());
+        Ok(Some(val))
+    }
+
+    /// The index entry of a live key, a missing or expired one counts as a
+    /// read of nothing
+    fn lookup(&mut self, key: &str) -> Option<IndexEntry> {
+        self.record_access(key);
+        // Look up requested key in the index HashMap.
+        let entry = match self.index.get(key) {
+            // get the location of where the record starts in the file
+            Some(entry) => *entry,
+            // Key does not exist return immediately
+            None => {
+                self.meter_read(key, 0);
+                return None;
+            }
+        };
+
+        // Expired keys are dropped lazily, the bytes get reclaimed by compaction
+        if entry.is_expired(self.now_millis()) {
+            self.forget(key);
+            self.meter_read(key, 0);
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// The first `n` bytes of a value, without reading the rest of it from disk.
+    /// Values that went through value transformers have to be read whole to
+    /// be decoded, for those the prefix is cut from the decoded value.
+    pub fn get_prefix_bytes(&mut self, key: &str, n: usize) -> Result<Option<Vec<u8>>> {
+        validate_plain_key(key)
+            .and_then(|()| self.read_prefix_bytes(key, n))
+            .context(|| format!("get_prefix_bytes(key={key})"))
+    }
+
+    fn read_prefix_bytes(&mut self, key: &str, n: usize) -> Result<Option<Vec<u8>>> {
+        self.record_access(key);
+        let entry = match self.index.get(key) {
+            Some(entry) if entry.is_expired(self.now_millis()) => {
+                self.forget(key);
+                return Ok(None);
+            }
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+
+        if entry.transformed {
+            let record = self.read_record(entry.offset)?;
+            let mut val = self.transformers.decode(record)?;
+            val.truncate(n);
+            self.meter_read(key, val.len());
+            return Ok(Some(val));
+        }
+
+        // bincode lays a record out as [key len][key][val len][val]..., so the
+        // value can be found by skipping over the key
+        // [8-byte record len][8-byte key len][key bytes][8-byte val len][val bytes]
+        let mut len_buffer = [0u8; 8];
+        self.file.seek(std::io::SeekFrom::Start(entry.offset + 8))?;
+        self.file.read_exact(&mut len_buffer)?;
+        let key_len = u64::from_le_bytes(len_buffer);
+        self.file.seek(std::io::SeekFrom::Current(key_len as i64))?;
+        self.file.read_exact(&mut len_buffer)?;
+        let val_len = u64::from_le_bytes(len_buffer);
+
+        let mut val = vec![0u8; (val_len as usize).min(n)];
+        self.file.read_exact(&mut val)?;
+        self.meter_read(key, val.len());
+        Ok(Some(val))
+    }
+
+    /// Iterate over every live key & value of the default collection, read
+    /// from the file as the iterator goes, see `Iter`
+    pub fn iter(&self) -> Result<Iter<'_>> {
+        Iter::new(self)
+    }
+
+    /// Iterate over the default collection as it is at the time of the call.
+    /// The index is cloned & the data file reopened, so writes & compactions
+    /// that happen afterwards are never seen by the iterator.
+    pub fn iter_snapshot(&self) -> Result<SnapshotIter> {
+        self.snapshot_of("")
+    }
+
+    /// Keys of the default collection starting with `prefix`, sorted.
+    /// Only the in-memory index is consulted, the data file is never read.
+    pub fn scan_keys(&self, prefix: &str) -> Vec<String> {
+        self.scan_keys_in("", prefix)
+    }
+
+    /// Live keys & values of the default collection within `range`, in key
+    /// order, e.g. `db.range("a".."m")`. With `DbOptions::sorted_index` only
+    /// the keys in range are looked at, otherwise every key is.
+    pub fn range<'k>(&mut self, range: impl RangeBounds<&'k str>) -> Result<Vec<(String, String)>> {
+        self.range_in("", range).context(|| "range".to_string())
+    }
+
+    pub(crate) fn range_in<'k>(
+        &mut self,
+        collection: &str,
+        range: impl RangeBounds<&'k str>,
+    ) -> Result<Vec<(String, String)>> {
+        // Anything before the collection's own keys can be skipped right away
+        let start = match range.start_bound() {
+            Bound::Unbounded => Bound::Included(namespaced_key(collection, "")),
+            bound => bound.map(|key| namespaced_key(collection, key)),
+        };
+        let end = range.end_bound().map(|key| namespaced_key(collection, key));
+        let bounds = (start.as_ref(), end.as_ref());
+
+        let keys: Vec<String> = match &self.sorted_keys {
+            // `BTreeSet::range` panics on a backwards range, it's just empty here
+            Some(_) if is_empty_range(&bounds) => Vec::new(),
+            Some(sorted_keys) => sorted_keys
+                .range::<String, _>(bounds)
+                .filter(|key| collection_of(key) == collection)
+                .cloned()
+                .collect(),
+            None => self
+                .live_keys(collection)
+                .into_iter()
+                .filter(|key| RangeBounds::<String>::contains(&bounds, key))
+                .collect(),
+        };
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for stored_key in keys {
+            if let Some(val) = self.get_stored(&stored_key)? {
+                pairs.push((user_key(&stored_key).to_string(), val));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// The greatest live key of the default collection at or below `key`,
+    /// e.g. the start of the interval `key` falls into. With
+    /// `DbOptions::sorted_index` it's found right away, otherwise every key
+    /// is looked at like `scan_keys` does.
+    pub fn floor(&self, key: &str) -> Option<String> {
+        self.nearest_key("", key, Ordering::Less)
+    }
+
+    /// The smallest live key of the default collection at or above `key`
+    pub fn ceiling(&self, key: &str) -> Option<String> {
+        self.nearest_key("", key, Ordering::Greater)
+    }
+
+    /// The live key of `collection` closest to `key` on its `side`
+    /// (`Less` for the floor, `Greater` for the ceiling), `key` included
+    pub(crate) fn nearest_key(
+        &self,
+        collection: &str,
+        key: &str,
+        side: Ordering,
+    ) -> Option<String> {
+        let now = self.now_millis();
+        let live = |stored_key: &&String| {
+            collection_of(stored_key) == collection
+                && self
+                    .index
+                    .get(*stored_key)
+                    .is_some_and(|entry| !entry.is_expired(now))
+        };
+        if let Some(sorted_keys) = &self.sorted_keys {
+            // A named collection's keys all sit behind its prefix, the default
+            // collection's are mixed in with those of the other collections
+            let prefix = namespaced_key(collection, "");
+            let target = namespaced_key(collection, key);
+            let in_collection = |stored_key: &&String| stored_key.starts_with(&prefix);
+            let found = match side {
+                Ordering::Greater => sorted_keys
+                    .range(target..)
+                    .take_while(in_collection)
+                    .find(live),
+                _ => sorted_keys
+                    .range(..=target)
+                    .rev()
+                    .take_while(in_collection)
+                    .find(live),
+            };
+            return found.map(|stored_key| user_key(stored_key).to_string());
+        }
+
+        let mut best: Option<&str> = None;
+        for stored_key in self.index.keys().filter(live) {
+            let candidate = user_key(stored_key);
+            if candidate.cmp(key) != side.reverse()
+                && best.is_none_or(|best| candidate.cmp(best) == side.reverse())
+            {
+                best = Some(candidate);
+            }
+        }
+        best.map(str::to_string)
+    }
+
+    /// `scan_keys` for any collection, returning keys without the collection prefix
+    pub(crate) fn scan_keys_in(&self, collection: &str, prefix: &str) -> Vec<String> {
+        let now = self.now_millis();
+        let stored_prefix = namespaced_key(collection, prefix);
+        let mut keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(key, entry)| {
+                key.starts_with(&stored_prefix)
+                    && collection_of(key) == collection
+                    && !entry.is_expired(now)
+            })
+            .map(|(key, _)| user_key(key).to_string())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Live stored keys of `collection` starting with `prefix`, sorted.
+    /// Only the keys in range are looked at with `DbOptions::sorted_index`.
+    pub(crate) fn stored_keys_with_prefix(&self, collection: &str, prefix: &str) -> Vec<String> {
+        let stored_prefix = namespaced_key(collection, prefix);
+        let Some(sorted_keys) = &self.sorted_keys else {
+            return self
+                .live_keys(collection)
+                .into_iter()
+                .filter(|key| key.starts_with(&stored_prefix))
+                .collect();
+        };
+        let now = self.now_millis();
+        sorted_keys
+            .range::<String, _>(&stored_prefix..)
+            .take_while(|key| key.starts_with(&stored_prefix))
+            .filter(|key| {
+                collection_of(key) == collection
+                    && self
+                        .index
+                        .get(*key)
+                        .is_some_and(|entry| !entry.is_expired(now))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Roughly how many keys of the default collection start with `prefix`,
+    /// cheap enough to call on every dashboard refresh. With
+    /// `DbOptions::sorted_index` the keys in range are counted exactly,
+    /// otherwise a fixed sample of the index is scaled up to its full size.
+    /// Expired keys nobody has touched yet may be counted too.
+    pub fn approx_count_prefix(&self, prefix: &str) -> usize {
+        self.approx_count_in("", prefix)
+    }
+
+    pub(crate) fn approx_count_in(&self, collection: &str, prefix: &str) -> usize {
+        let stored_prefix = namespaced_key(collection, prefix);
+        let matches =
+            |key: &String| key.starts_with(&stored_prefix) && collection_of(key) == collection;
+        if let Some(sorted_keys) = &self.sorted_keys {
+            return sorted_keys
+                .range::<String, _>(&stored_prefix..)
+                .take_while(|key| key.starts_with(&stored_prefix))
+                .filter(|key| matches(key))
+                .count();
+        }
+
+        // Keys are spread over the hash table by their hash, so the first
+        // entries are as good a sample as any
+        let sampled = self.index.len().min(COUNT_SAMPLE);
+        if sampled == 0 {
+            return 0;
+        }
+        let hits = self
+            .index
+            .keys()
+            .take(sampled)
+            .filter(|key| matches(key))
+            .count();
+        (hits as f64 * self.index.len() as f64 / sampled as f64).round() as usize
+    }
+
+    /// Snapshot of the live keys in a collection, in key order
+    pub(crate) fn snapshot_of(&self, collection: &str) -> Result<SnapshotIter> {
+        let entries = self
+            .live_entries(collection)
+            .into_iter()
+            .map(|(key, entry)| (key, entry.offset))
+            .collect();
+        self.snapshot_over(entries)
+    }
+
+    /// Iterate over the keys of the default collection whose value was
+    /// written after the write numbered `since`, oldest write first (keys of
+    /// one batch in key order). It's a snapshot like `iter_snapshot`: hand
+    /// its `seq()` to the next call & nothing is missed or seen twice.
+    /// Deletes aren't reported, follow them with a `consumer`.
+    pub fn iter_since(&self, since: u64) -> Result<SnapshotIter> {
+        self.snapshot_since("", since)
+    }
+
+    /// `iter_since` for any collection
+    pub(crate) fn snapshot_since(&self, collection: &str, since: u64) -> Result<SnapshotIter> {
+        let mut entries: Vec<(String, IndexEntry)> = self
+            .live_entries(collection)
+            .into_iter()
+            .filter(|(_, entry)| entry.seq > since)
+            .collect();
+        // Stable, so keys written under the same number stay in key order
+        entries.sort_by_key(|(_, entry)| entry.seq);
+        let entries = entries
+            .into_iter()
+            .map(|(key, entry)| (key, entry.offset))
+            .collect();
+        self.snapshot_over(entries)
+    }
+
+    fn snapshot_over(&self, entries: Vec<(String, u64)>) -> Result<SnapshotIter> {
+        // Compaction renames a new file over the path, this handle keeps
+        // pointing at the file the offsets above belong to
+        let file = File::open(&self.path)?;
+        Ok(SnapshotIter::new(
+            file,
+            entries,
+            self.transformers.clone(),
+            self.last_seq,
+            self.format_version,
+        ))
+    }
+
+    /// Stored keys that are live right now, sorted
+    pub(crate) fn live_keys(&self, collection: &str) -> Vec<String> {
+        self.live_entries(collection)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Every stored key that is live right now, across all collections, sorted
+    pub(crate) fn all_live_keys(&self) -> Vec<String> {
+        let now = self.now_millis();
+        let mut keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Offset & length on disk of a live record
+    pub(crate) fn live_span(&self, key: &str) -> Option<(u64, u64)> {
+        self.index
+            .get(key)
+            .filter(|entry| !entry.is_expired(self.now_millis()))
+            .map(|entry| (entry.offset, entry.len))
+    }
+
+    /// Unexpired index entries of a collection, sorted by stored key
+    fn live_entries(&self, collection: &str) -> Vec<(String, IndexEntry)> {
+        let now = self.now_millis();
+        let mut entries: Vec<(String, IndexEntry)> = self
+            .index
+            .iter()
+            .filter(|(key, entry)| collection_of(key) == collection && !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Write a tombstone for an already namespaced key
+    pub(crate) fn remove(&mut self, key: String) -> Result<()> {
+        self.record_access(&key);
+        if self.has_numeric_index(collection_of(&key)) {
+            return self.commit_writes(vec![(key, None)], None);
+        }
+        // Create a tombstone record
+        let seq = self.last_seq + 1;
+        let record = tombstone(key, RecordKind::Single, seq);
+        let (offset, len) = self.append(&record)?;
+        self.last_seq = seq;
+
+        // Also remove the key from the live in memory index
+        let collection = collection_of(&record.key).to_string();
+        let event = self
+            .has_subscribers()
+            .then(|| ChangeEvent::new(&record.key, None, seq));
+        self.meter_write(&record.key, 0);
+        self.index_record(record, offset, len, self.now_millis());
+        self.notify(seq, event.into_iter().collect());
+
+        self.maybe_compact(&collection)
+    }
+
+    /// Rewrite the data file so it only holds live records.
+    /// The new file is built next to the old one & then renamed over it,
+    /// so a crash half way through leaves the original file untouched.
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_reencrypting(false)
+            .context(|| "compact".to_string())
+    }
+
+    /// `compact`, optionally moving every encrypted record over to the
+    /// current data key of its collection on the way
+    pub(crate) fn compact_reencrypting(&mut self, reencrypt: bool) -> Result<()> {
+        if self.options.read_only {
+            return Err(DbError::ReadOnly.into());
+        }
+        self.check_epoch()?;
+        let now = self.now_millis();
+        let compact_path = self.compaction_path();
+        let mut compact_file = File::create(&compact_path)?;
+
+        // Changes a registered consumer hasn't acked yet are kept around, dead
+        // or not, so the consumer can still replay them
+        let retain_after = self.min_consumer_offset()?;
+
+        let mut new_index =
+            HashMap::with_capacity_and_hasher(self.index.len(), self.index.hasher().clone());
+        let mut new_stats: HashMap<String, CollectionStats> = HashMap::new();
+        let mut position = 0;
+        let mut max_seq = 0;
+        let mut new_mac = self.mac.as_ref().map(FileMac::start_over);
+
+        let mut buffer = Vec::new();
+        position += encode_frame(&header_record(self.header_flags()), &mut buffer)?;
+        compact_file.write_all(&buffer)?;
+        if let Some(new_mac) = &mut new_mac {
+            new_mac.update(&buffer);
+        }
+
+        // Pick what survives, along with the index entry of the live ones
+        let mut kept: Vec<(String, u64, Option<IndexEntry>)> = Vec::new();
+        self.scan_records(|offset, record| {
+            if record.kind == RecordKind::BatchCommit || record.kind == RecordKind::Padding {
+                return Ok(());
+            }
+            let live = self
+                .index
+                .get(&record.key)
+                .filter(|entry| entry.offset == offset && !entry.is_expired(now))
+                .copied();
+            let unacked = retain_after.is_some_and(|after| record.seq > after);
+            if live.is_some() || unacked {
+                kept.push((record.key, offset, live));
+            }
+            Ok(())
+        })?;
+
+        // Write them in key order so range scans over a compacted file read
+        // it front to back. Versions of the same key keep their order, the
+        // live one always comes last.
+        kept.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+        for (key, offset, live) in kept {
+            // Records that came from a batch are rewritten as standalone ones,
+            // the batch's commit marker doesn't make it into the new file
+            let mut record = self.read_record(offset)?;
+            record.kind = RecordKind::Single;
+            if reencrypt {
+                self.transformers.reencrypt(&mut record)?;
+            }
+            max_seq = max_seq.max(record.seq);
+            let mut buffer = Vec::new();
+            let len = encode_frame(&record, &mut buffer)?;
+            compact_file.write_all(&buffer)?;
+            if let Some(new_mac) = &mut new_mac {
+                new_mac.update(&buffer);
+            }
+
+            let stats = new_stats
+                .entry(collection_of(&key).to_string())
+                .or_default();
+            match live {
+                Some(entry) => {
+                    stats.live_bytes += len;
+                    stats.live_keys += 1;
+                    new_index.insert(
+                        key,
+                        IndexEntry {
+                            offset: position,
+                            len,
+                            ..entry
+                        },
+                    );
+                }
+                None => stats.garbage_bytes += len,
+            }
+            position += len;
+        }
+
+        // When the newest records were dropped as garbage, an empty commit
+        // marker carries the latest sequence number over to the new file
+        if max_seq < self.last_seq {
+            let mut buffer = Vec::new();
+            let marker = tombstone(String::new(), RecordKind::BatchCommit, self.last_seq);
+            let len = encode_frame(&marker, &mut buffer)?;
+            compact_file.write_all(&buffer)?;
+            if let Some(new_mac) = &mut new_mac {
+                new_mac.update(&buffer);
+            }
+            position += len;
+            new_stats.entry(String::new()).or_default().garbage_bytes += len;
+        }
+
+        // The next append starts aligned again
+        if let Some(alignment) = self.options.record_alignment {
+            let padding = direct_io::tail_padding(position, alignment)?;
+            compact_file.write_all(&padding)?;
+            if let Some(new_mac) = &mut new_mac {
+                new_mac.update(&padding);
+            }
+            position += padding.len() as u64;
+        }
+
+        // Make sure the new file is durable before it replaces the old one
+        compact_file.sync_all()?;
+        drop(compact_file);
+        if let (Some(mac), Some(new_mac)) = (&self.mac, &new_mac) {
+            mac.stage(&self.path, new_mac, position)?;
+        }
+        // A crash between the rename & the new hint mustn't leave the old one
+        Hint::remove(&self.path)?;
+        std::fs::rename(&compact_path, &self.path)?;
+        if let (Some(mac), Some(new_mac)) = (&mut self.mac, new_mac) {
+            mac.commit_staged(&self.path, new_mac, position)?;
+        }
+
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        if let Some(direct_file) = &mut self.direct_file {
+            *direct_file = DirectFile::open(&self.path, direct_file.alignment())?;
+        }
+        self.end_of_data = position;
+        self.reserved_until = position;
+        self.format_version = FORMAT_VERSION;
+        if let Some(sorted_keys) = &mut self.sorted_keys {
+            *sorted_keys = new_index.keys().cloned().collect();
+        }
+        self.index = new_index;
+        self.stats = new_stats;
+        self.write_stats.compaction_bytes += position;
+        self.write_stats.compactions += 1;
+        // Everything that was live is in the synced new file now
+        self.unsynced_since = None;
+
+        if self.options.hint_files {
+            self.save_hint()?;
+        }
+        Ok(())
+    }
+
+    /// Save the index of the freshly compacted file as its hint
+    fn save_hint(&mut self) -> Result<()> {
+        let entries = self
+            .index
+            .iter()
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+        let stats = self
+            .stats
+            .iter()
+            .map(|(collection, stats)| (collection.clone(), *stats))
+            .collect();
+        let hint = Hint::new(
+            &mut self.file,
+            self.end_of_data,
+            self.last_seq,
+            entries,
+            stats,
+        )?;
+        hint.save(&self.path)
+    }
+
+    /// Walk every record in the data file in the order it was written, along
+    /// with its offset, the header left out. Uses its own handle so
+    /// `self.file` isn't moved around.
+    pub(crate) fn scan_records(
+        &self,
+        mut visit: impl FnMut(u64, Record) -> Result<()>,
+    ) -> Result<()> {
+        let file = File::open(&self.path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut position = 0;
+        loop {
+            let mut len_buffer = [0u8; 8];
+            if reader.read_exact(&mut len_buffer).is_err() {
+                break;
+            }
+            let len = frame_len(len_buffer, position, file_len)?;
+
+            let mut record_buffer = vec![0u8; len];
+            reader.read_exact(&mut record_buffer)?;
+            let record = Record::decode(&record_buffer, self.format_version)?;
+            if record.kind != RecordKind::Header {
+                visit(position, record)?;
+            }
+            position += 8 + len as u64;
+        }
+        Ok(())
+    }
+
+    /// Sequence number of the latest write. Every write, or every batch as a
+    /// whole, gets the next number & change events carry it too.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Flush everything written so far to the disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data().context(|| "sync".to_string())?;
+        if let Some(mac) = &self.mac {
+            mac.sync()
+                .context(|| "sync of the MAC sidecar".to_string())?;
+        }
+        self.unsynced_since = None;
+        Ok(())
+    }
+
+    /// Whether anything was written since the last sync
+    pub(crate) fn has_unsynced_writes(&self) -> bool {
+        self.unsynced_since.is_some()
+    }
+
+    /// Sync if `DbOptions::sync_policy` says the writes so far are due
+    fn sync_if_overdue(&mut self) -> Result<()> {
+        let Some(since) = self.unsynced_since else {
+            return Ok(());
+        };
+        let due = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::Interval(interval) => since.elapsed() >= interval,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Compact if any collection's policy says it has piled up enough garbage.
+    /// Returns whether a compaction ran.
+    pub fn compact_if_needed(&mut self) -> Result<bool> {
+        let collections: Vec<String> = self.stats.keys().cloned().collect();
+        if collections.iter().any(|c| self.over_compaction_policy(c)) {
+            self.compact()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Garbage bytes across every collection in the file
+    pub fn garbage_bytes(&self) -> u64 {
+        self.stats.values().map(|stats| stats.garbage_bytes).sum()
+    }
+
+    /// Collections with garbage in them, the most reclaimable bytes first.
+    /// Compaction always rewrites the whole file, this only shows where the
+    /// space it would win back comes from.
+    pub fn garbage_by_collection(&self) -> Vec<(String, CollectionStats)> {
+        let mut ranked: Vec<(String, CollectionStats)> = self
+            .stats
+            .iter()
+            .filter(|(_, stats)| stats.garbage_bytes > 0)
+            .map(|(name, stats)| (name.clone(), *stats))
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.1.garbage_bytes
+                .cmp(&a.1.garbage_bytes)
+                .then(a.0.cmp(&b.0))
+        });
+        ranked
+    }
+
+    pub fn options(&self) -> &DbOptions {
+        &self.options
+    }
+
+    /// Compact after a write if the collection's policy says so.
+    /// With background compaction turned on this is left to the compaction thread.
+    fn maybe_compact(&mut self, collection: &str) -> Result<()> {
+        if self.options.background_compaction.is_none() && self.over_compaction_policy(collection) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Logs the numbers that made it fire when it does
+    fn over_compaction_policy(&self, collection: &str) -> bool {
+        let Some(policy) = self.options.collection(collection).compaction else {
+            return false;
+        };
+        let stats = self.collection_stats(collection);
+        let over = stats.garbage_bytes >= policy.min_garbage_bytes
+            && stats.garbage_ratio() >= policy.garbage_ratio;
+        if over {
+            log::info!(
+                collection,
+                garbage_bytes = stats.garbage_bytes,
+                garbage_ratio = stats.garbage_ratio(),
+                policy_min_garbage_bytes = policy.min_garbage_bytes,
+                policy_garbage_ratio = policy.garbage_ratio;
+                "compaction policy fired"
+            );
+        }
+        over
+    }
+
+    /// Start a new file with the header
+    fn write_header(&mut self) -> Result<()> {
+        let mut buffer = Vec::new();
+        encode_frame(&header_record(self.header_flags()), &mut buffer)?;
+        self.write_frames(&buffer)?;
+        self.sync()
+    }
+
+    /// Flags for the header of a file this handle starts
+    fn header_flags(&self) -> u32 {
+        match self.mac {
+            Some(_) => FLAG_SIGNED,
+            None => 0,
+        }
+    }
+
+    /// Serialize the record & append it to the end of the file.
+    /// Returns the offset it was written at and the bytes it took up.
+    fn append(&mut self, record: &Record) -> Result<(u64, u64)> {
+        let mut buffer = Vec::new();
+        let len = encode_frame(record, &mut buffer)?;
+        let offset = self.write_frames(&buffer)?;
+        Ok((offset, len))
+    }
+
+    /// Make sure the space up to `until` is reserved, a chunk at a time
+    fn reserve(&mut self, until: u64) -> Result<()> {
+        let Some(chunk) = self.options.preallocate_chunk.filter(|chunk| *chunk > 0) else {
+            return Ok(());
+        };
+        if until <= self.reserved_until {
+            return Ok(());
+        }
+        let reserve_until = until.div_ceil(chunk) * chunk;
+        prealloc::reserve(
+            &self.file,
+            self.end_of_data,
+            reserve_until - self.end_of_data,
+        )?;
+        self.reserved_until = reserve_until;
+        Ok(())
+    }
+
+    /// Append already framed records to the end of the file & return where they start.
+    /// A failed write is cut off again so the next append doesn't land behind a torn record.
+    fn write_frames(&mut self, buffer: &[u8]) -> Result<u64> {
+        if self.options.read_only {
+            return Err(DbError::ReadOnly.into());
+        }
+        self.check_epoch()?;
+        // Appends go after the data, not after the space reserved behind it
+        let end_of_file = self.end_of_data;
+        self.file.seek(std::io::SeekFrom::Start(end_of_file))?;
+        let padded;
+        let (start, buffer) = match self.options.record_alignment {
+            Some(alignment) => {
+                padded = direct_io::align_frames(buffer, end_of_file, alignment)?;
+                (padded.0, &padded.1[..])
+            }
+            None => (end_of_file, buffer),
+        };
+
+        self.reserve(end_of_file + buffer.len() as u64)?;
+        let written = match &self.direct_file {
+            // The tail of a file that wasn't aligned yet goes the normal way
+            Some(direct_file) if end_of_file.is_multiple_of(direct_file.alignment()) => {
+                direct_file.write_at(end_of_file, buffer)
+            }
+            _ => self.file.write_all(buffer).map_err(Into::into),
+        }
+        .context(|| format!("write at offset {end_of_file}"));
+        if let Err(err) = written {
+            let _ = self.file.set_len(end_of_file);
+            // Cutting the file back gives up the space reserved behind it too
+            self.reserved_until = end_of_file;
+            return Err(err);
+        }
+        if let Some(mac) = &mut self.mac
+            && let Err(err) = mac.append(buffer)
+        {
+            let _ = self.file.set_len(end_of_file);
+            self.reserved_until = end_of_file;
+            return Err(err);
+        }
 
-        // Find EOF to to get where to write
-        let end_of_file = self.file.seek(std::io::SeekFrom::End(0))?;
+        self.end_of_data = end_of_file + buffer.len() as u64;
+        self.write_stats.user_bytes += buffer.len() as u64;
+        self.unsynced_since.get_or_insert_with(Instant::now);
+        self.sync_if_overdue()?;
+        Ok(start)
+    }
 
-        // Add the length of the current record being inserted into the file
-        self.file.write_all(&encoded_record_len.to_le_bytes())?;
+    /// Create a record for an already namespaced key, applying the TTL &
+    /// value transformers of the collection it belongs to
+    fn build_record(
+        &self,
+        key: String,
+        val: &[u8],
+        ttl: Option<Duration>,
+        kind: RecordKind,
+        seq: u64,
+    ) -> Result<Record> {
+        let options = self.options.collection(collection_of(&key));
+        let expires_at = ttl
+            .or(options.default_ttl)
+            .map(|ttl| self.now_millis() + ttl.as_millis() as u64);
+        let (val, mut transforms) = encode_value(&options.transformers, val)?;
+        let val = self.transformers.encrypt(&key, val, &mut transforms)?;
 
-        // Write the actual contents of the record
-        self.file.write_all(&encoded_record)?;
+        Ok(Record {
+            key,
+            val,
+            tombstone: false,
+            expires_at,
+            transforms,
+            kind,
+            seq,
+        })
+    }
 
-        // Update the in-memory idx
-        self.index.insert(key.to_string(), end_of_file); // end_of_file may not be a good variale name here.
+    /// Read back the record whose length prefix starts at `offset`
+    pub(crate) fn read_record(&mut self, offset: u64) -> Result<Record> {
+        read_record_at(&mut self.file, offset, self.format_version)
+    }
 
-        Ok(())
+    /// Drop a key from the index, counting its bytes as garbage
+    fn forget(&mut self, key: &str) {
+        self.cache.invalidate(key);
+        self.text_index.remove(key);
+        if let Some(sorted_keys) = &mut self.sorted_keys {
+            sorted_keys.remove(key);
+        }
+        if let Some(old) = self.index.remove(key) {
+            let stats = self.stats_mut(key);
+            stats.live_bytes -= old.len;
+            stats.live_keys -= 1;
+            stats.garbage_bytes += old.len;
+        }
     }
-    /// Use in-memory idx to perform a fast lookup
-    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        // Look up requested key in the index HashMap.
-        let byte_offset = match self.index.get(key) {
-            // get the byte offset of where the record starts in the file
-            Some(val) => val,
-            // Key does not exist return immediately
-            None => return Ok(None),
-        };
-        // Seek to that exact offset in the file
-        let _position_in_file = self.file.seek(std::io::SeekFrom::Start(*byte_offset))?;
 
-        // Read the 8-byte lenght of the serialized record
-        let mut buffer_for_length_of_record = [0u8; 8];
-        self.file.read_exact(&mut buffer_for_length_of_record)?;
-        let len_of_record = u64::from_le_bytes(buffer_for_length_of_record);
+    fn stats_mut(&mut self, key: &str) -> &mut CollectionStats {
+        self.stats
+            .entry(collection_of(key).to_string())
+            .or_default()
+    }
 
-        // self.file.read_exact_at(buffer, position_in_file);
+    /// `<db file>.compact`, the scratch file compaction writes into
+    fn compaction_path(&self) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".compact");
+        self.path.with_file_name(file_name)
+    }
+}
 
-        // Convert that buffer of bytes back into the Record struct
-        let mut buffer_for_actual_record = vec![0u8; len_of_record as usize];
-        self.file.read_exact(&mut buffer_for_actual_record)?;
-        let record: Record = bincode::deserialize(&buffer_for_actual_record)?;
+impl Drop for EmbeddedDatabase {
+    /// With a sync interval writes were promised to hit the disk soon,
+    /// closing the db is as soon as it gets
+    fn drop(&mut self) {
+        if matches!(self.options.sync_policy, SyncPolicy::Interval(_)) && self.has_unsynced_writes()
+        {
+            let _ = self.sync();
+        }
+    }
+}
 
-        Ok(Some(record.val))
+/// Keys of the default collection can't contain the collection separator,
+/// otherwise they would be read back as belonging to another collection
+pub(crate) fn validate_plain_key(key: &str) -> Result<()> {
+    if collection_of(key).is_empty() {
+        Ok(())
+    } else {
+        Err(format!("key {key:?} contains a reserved NUL character").into())
     }
+}
 
-    pub fn delete(&mut self, key: &str) -> Result<()> {
-        // Create a tombstone record with an empty value
-        let record = Record {
-            key: key.to_string(),
-            val: "".to_string(),
-        };
+/// Whether no key can fall between the bounds
+fn is_empty_range(bounds: &(Bound<&String>, Bound<&String>)) -> bool {
+    match bounds {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
 
-        let encoded_record = bincode::serialize(&record)?;
-        let encoded_record_len = encoded_record.len();
+/// Read the record whose length prefix starts at `offset` in any handle to a
+/// data file of format `version`
+pub(crate) fn read_record_at(file: &mut File, offset: u64, version: u32) -> Result<Record> {
+    read_frame_at(file, offset, version)
+        .map(|(record, _)| record)
+        .context(|| format!("read at offset {offset}"))
+}
 
-        // Go to file end & add the length of tombstone and the empty record
-        self.file.seek(std::io::SeekFrom::End(0))?;
-        self.file.write_all(&encoded_record_len.to_le_bytes())?;
-        self.file.write_all(&encoded_record)?;
+/// The record at `offset` & the bytes its frame takes up
+fn read_frame_at(file: &mut File, offset: u64, version: u32) -> Result<(Record, u64)> {
+    // Seek to that exact offset in the file
+    file.seek(std::io::SeekFrom::Start(offset))?;
 
-        // Also remove the key from the live in memory index
-        self.index.remove(key);
+    // Read the 8-byte lenght of the serialized record
+    let mut buffer_for_length_of_record = [0u8; 8];
+    file.read_exact(&mut buffer_for_length_of_record)?;
+    let len_of_record = frame_len(buffer_for_length_of_record, offset, file.metadata()?.len())?;
 
-        Ok(())
+    // Convert that buffer of bytes back into the Record struct
+    let mut buffer_for_actual_record = vec![0u8; len_of_record];
+    file.read_exact(&mut buffer_for_actual_record)?;
+    let record = Record::decode(&buffer_for_actual_record, version)?;
+    Ok((record, 8 + len_of_record as u64))
+}
+
+/// Stored key for a batched op, validated the same way as a direct write
+fn batch_key(collection: &str, key: &str) -> Result<String> {
+    if collection.is_empty() {
+        validate_plain_key(key)?;
+    } else {
+        validate_collection_name(collection)?;
+    }
+    Ok(namespaced_key(collection, key))
+}
+
+/// A record marking `key` as deleted
+pub(crate) fn tombstone(key: String, kind: RecordKind, seq: u64) -> Record {
+    Record {
+        key,
+        val: Vec::new(),
+        tombstone: true,
+        expires_at: None,
+        transforms: Vec::new(),
+        kind,
+        seq,
+    }
+}
+
+/// Open the data file for reading & appending, creating it if needed, or
+/// just for reading
+fn open_data_file(path: &Path, read_only: bool) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .create(!read_only)
+        .truncate(false) // Keep whatever is already in there
+        .open(path)?)
+}
+
+/// Length of the record behind the length prefix at `offset`, checked
+/// against the `data_len` bytes there are so a damaged prefix can't make us
+/// allocate more than that
+pub(crate) fn frame_len(len_buffer: [u8; 8], offset: u64, data_len: u64) -> Result<usize> {
+    let len = u64::from_le_bytes(len_buffer);
+    if len > data_len.saturating_sub(offset).saturating_sub(8) {
+        return Err(DbError::CorruptFrame { offset, len }.into());
     }
+    Ok(len as usize)
+}
+
+/// Append `[8-byte len][record bytes]` to the buffer & return the bytes added
+pub(crate) fn encode_frame(record: &Record, buffer: &mut Vec<u8>) -> Result<u64> {
+    let encoded_record = bincode::serialize(record)?;
+    let encoded_record_len = encoded_record.len() as u64;
+
+    // Add the length of the record followed by the actual contents of the record
+    buffer.extend_from_slice(&encoded_record_len.to_le_bytes());
+    buffer.extend_from_slice(&encoded_record);
+
+    Ok(8 + encoded_record_len)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{CollectionOptions, CompactionPolicy, Lz4Compression, MockClock};
+    use std::sync::Arc;
     use tempfile::NamedTempFile;
     #[test]
     fn test_new_set_and_get() {
@@ -204,4 +2107,759 @@ mod test {
             "The key should still be deleted after reopening "
         );
     }
+    #[test]
+    fn test_collection_options() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db_path = temp_file.path();
+        let options = DbOptions::default()
+            .with_collection(
+                "cache",
+                CollectionOptions {
+                    default_ttl: Some(Duration::from_millis(20)),
+                    ..Default::default()
+                },
+            )
+            .with_collection(
+                "config",
+                CollectionOptions {
+                    transformers: vec![Arc::new(Lz4Compression)],
+                    ..Default::default()
+                },
+            );
+        let mut db = EmbeddedDatabase::with_options(db_path, options.clone())
+            .expect("failed to open the db with options");
+
+        db.collection("cache")
+            .unwrap()
+            .set("session", "abc")
+            .unwrap();
+        db.collection("config")
+            .unwrap()
+            .set("mode", "dark")
+            .unwrap();
+        // Same key in another collection must not clash
+        db.set("mode", "light").unwrap();
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(
+            db.collection("cache").unwrap().get("session").unwrap(),
+            None
+        );
+
+        drop(db);
+        let mut db = EmbeddedDatabase::with_options(db_path, options).unwrap();
+        assert_eq!(
+            db.collection("config").unwrap().get("mode").unwrap(),
+            Some("dark".to_string())
+        );
+        assert_eq!(db.get("mode").unwrap(), Some("light".to_string()));
+        assert_eq!(
+            db.collection("cache").unwrap().get("session").unwrap(),
+            None
+        );
+    }
+    #[test]
+    fn test_compaction_threshold() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db_path = temp_file.path();
+        let options = DbOptions::default().with_collection(
+            "counters",
+            CollectionOptions {
+                compaction: Some(CompactionPolicy {
+                    garbage_ratio: 0.5,
+                    min_garbage_bytes: 0,
+                }),
+                ..Default::default()
+            },
+        );
+        let mut db = EmbeddedDatabase::with_options(db_path, options).unwrap();
+        db.set("untouched", "1").unwrap();
+        db.set("untouched", "2").unwrap();
+
+        let mut counters = db.collection("counters").unwrap();
+        counters.set("hits", "1").unwrap();
+        // Overwriting makes half of the collection garbage which triggers a compaction
+        counters.set("hits", "2").unwrap();
+
+        assert_eq!(db.collection_stats("counters").garbage_bytes, 0);
+        // Compaction rewrites the whole file so the default collection is cleaned up too
+        assert_eq!(db.collection_stats("").garbage_bytes, 0);
+        let writes = db.write_stats();
+        assert_eq!(writes.compactions, 1);
+        assert_eq!(
+            writes.compaction_bytes,
+            std::fs::metadata(db_path).unwrap().len()
+        );
+        assert!(writes.write_amplification() > 1.0);
+        assert_eq!(
+            db.collection("counters").unwrap().get("hits").unwrap(),
+            Some("2".to_string())
+        );
+
+        drop(db);
+        let mut db = EmbeddedDatabase::new(db_path).unwrap();
+        assert_eq!(db.get("untouched").unwrap(), Some("2".to_string()));
+
+        // The compacted file holds its records in key order
+        for key in ["zebra", "apple", "mango"] {
+            db.set(key, "fruit").unwrap();
+        }
+        db.compact().unwrap();
+        let mut keys = Vec::new();
+        db.scan_records(|_, record| {
+            keys.push(record.key);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            keys,
+            vec!["apple", "counters\0hits", "mango", "untouched", "zebra"]
+        );
+    }
+    #[test]
+    fn test_batch_across_collections() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db_path = temp_file.path();
+        let mut db = EmbeddedDatabase::new(db_path).unwrap();
+        db.set("stale", "yes").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set_in("users", "42", "Alice")
+            .set_in("users_by_name", "Alice", "42")
+            .delete("stale");
+        db.apply_batch(batch).expect("batch should commit");
+        let committed_len = std::fs::metadata(db_path).unwrap().len();
+
+        // A second batch that loses its commit marker, as if we crashed mid write
+        let mut batch = WriteBatch::new();
+        batch
+            .set_in("users", "43", "Bob")
+            .set_in("users_by_name", "Bob", "43");
+        db.apply_batch(batch).unwrap();
+        drop(db);
+        let file_len = std::fs::metadata(db_path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(db_path).unwrap();
+        file.set_len(file_len - 1).unwrap();
+        drop(file);
+
+        let mut db = EmbeddedDatabase::new(db_path).unwrap();
+        assert_eq!(
+            db.collection("users").unwrap().get("42").unwrap(),
+            Some("Alice".to_string())
+        );
+        assert_eq!(
+            db.collection("users_by_name")
+                .unwrap()
+                .get("Alice")
+                .unwrap(),
+            Some("42".to_string())
+        );
+        assert_eq!(db.get("stale").unwrap(), None);
+        assert_eq!(db.collection("users").unwrap().get("43").unwrap(), None);
+        assert_eq!(
+            db.collection("users_by_name").unwrap().get("Bob").unwrap(),
+            None
+        );
+        // The half written batch is cut off the file
+        assert_eq!(std::fs::metadata(db_path).unwrap().len(), committed_len);
+    }
+    #[test]
+    fn test_set_if_absent() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+
+        assert!(db.set_if_absent("lock", "worker-1").unwrap());
+        assert!(!db.set_if_absent("lock", "worker-2").unwrap());
+        assert_eq!(db.get("lock").unwrap(), Some("worker-1".to_string()));
+
+        db.delete("lock").unwrap();
+        assert!(db.set_if_absent("lock", "worker-2").unwrap());
+        assert_eq!(db.get("lock").unwrap(), Some("worker-2".to_string()));
+    }
+    #[test]
+    fn test_floor_and_ceiling() {
+        for sorted_index in [false, true] {
+            let temp_file = NamedTempFile::new().expect("failed to create temp file");
+            let options = DbOptions {
+                sorted_index,
+                ..Default::default()
+            };
+            let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+            for start in ["10.0.0.0", "10.0.1.0", "10.0.4.0"] {
+                db.set(start, "route").unwrap();
+            }
+            db.collection("other")
+                .unwrap()
+                .set("10.0.3.0", "x")
+                .unwrap();
+            // Its stored keys sort in between those of the default collection
+            db.collection("10.0.2").unwrap().set("x", "x").unwrap();
+
+            assert_eq!(db.floor("10.0.3.7"), Some("10.0.1.0".to_string()));
+            assert_eq!(db.floor("10.0.4.0"), Some("10.0.4.0".to_string()));
+            assert_eq!(db.floor("09"), None);
+            assert_eq!(db.ceiling("10.0.1.1"), Some("10.0.4.0".to_string()));
+            assert_eq!(db.ceiling("10.0.5"), None);
+            db.delete("10.0.1.0").unwrap();
+            assert_eq!(db.floor("10.0.3.7"), Some("10.0.0.0".to_string()));
+
+            let other = db.collection("other").unwrap();
+            assert_eq!(other.floor("10.0.9"), Some("10.0.3.0".to_string()));
+            assert_eq!(other.ceiling("10.0.0.0"), Some("10.0.3.0".to_string()));
+            assert_eq!(other.ceiling("10.0.4"), None);
+        }
+    }
+
+    #[test]
+    fn test_set_with_ttl() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let clock = MockClock::default();
+        let options = DbOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        db.set_with_ttl("session", "abc", Duration::from_secs(60))
+            .unwrap();
+        db.collection("cache")
+            .unwrap()
+            .set_with_ttl("page", "<html>", Duration::from_secs(10))
+            .unwrap();
+        db.set("user", "anna").unwrap();
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(db.get("session").unwrap(), Some("abc".to_string()));
+        assert_eq!(db.collection("cache").unwrap().get("page").unwrap(), None);
+
+        clock.advance(Duration::from_secs(30));
+        db.compact().unwrap();
+        assert_eq!(db.collection_stats("").live_keys, 1);
+        assert_eq!(db.get("session").unwrap(), None);
+        assert_eq!(db.get("user").unwrap(), Some("anna".to_string()));
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let bytes = vec![0u8, 159, 146, 150, 255];
+        db.set_bytes("blob", &bytes).unwrap();
+        db.set("empty", "").unwrap();
+        db.set("gone", "1").unwrap();
+        db.delete("gone").unwrap();
+
+        assert_eq!(db.get_bytes("blob").unwrap(), Some(bytes.clone()));
+        assert!(db.get("blob").is_err());
+        assert_eq!(db.get("empty").unwrap(), Some(String::new()));
+        assert_eq!(db.get_bytes("empty").unwrap(), Some(Vec::new()));
+
+        db.compact().unwrap();
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get_bytes("blob").unwrap(), Some(bytes));
+        assert_eq!(db.get("empty").unwrap(), Some(String::new()));
+        assert_eq!(db.get("gone").unwrap(), None);
+    }
+
+    #[test]
+    fn test_approx_count_prefix() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        for i in 0..10_000 {
+            let tenant = if i % 4 == 0 { "acme" } else { "globex" };
+            db.set(&format!("{tenant}/{i}"), "1").unwrap();
+        }
+        db.collection("other").unwrap().set("acme/x", "1").unwrap();
+
+        // A quarter of the keys, give or take what sampling gets wrong
+        let estimate = db.approx_count_prefix("acme/");
+        assert!((2_000..3_000).contains(&estimate), "{estimate}");
+        assert_eq!(db.approx_count_prefix("initech/"), 0);
+
+        let options = DbOptions {
+            sorted_index: true,
+            ..Default::default()
+        };
+        let db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        assert_eq!(db.approx_count_prefix("acme/"), 2_500);
+    }
+
+    #[test]
+    fn test_range() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        for sorted_index in [false, true] {
+            let options = DbOptions {
+                sorted_index,
+                ..Default::default()
+            };
+            let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+            for key in ["apple", "kiwi", "mango", "zucchini"] {
+                db.set(key, key).unwrap();
+            }
+            db.delete("kiwi").unwrap();
+            db.set("lime", "lime").unwrap();
+            db.collection("b").unwrap().set("b", "other").unwrap();
+
+            let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+                pairs.into_iter().map(|(key, _)| key).collect()
+            };
+            assert_eq!(keys(db.range("a".."m").unwrap()), vec!["apple", "lime"]);
+            assert_eq!(
+                keys(db.range("lime"..="mango").unwrap()),
+                vec!["lime", "mango"]
+            );
+            assert_eq!(keys(db.range("n"..).unwrap()), vec!["zucchini"]);
+            assert!(db.range("m".."a").unwrap().is_empty());
+            assert_eq!(db.collection("b").unwrap().range(..).unwrap().len(), 1);
+
+            db.compact().unwrap();
+            assert_eq!(keys(db.range(.."b").unwrap()), vec!["apple"]);
+        }
+    }
+
+    #[test]
+    fn test_garbage_by_collection() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("a", "2").unwrap();
+        let mut sessions = db.collection("sessions").unwrap();
+        for i in 0..3 {
+            sessions.set("s", &i.to_string()).unwrap();
+        }
+        sessions.delete("s").unwrap();
+        db.collection("users").unwrap().set("u", "1").unwrap();
+
+        let ranked: Vec<String> = db
+            .garbage_by_collection()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(ranked, vec!["sessions".to_string(), String::new()]);
+
+        db.compact().unwrap();
+        assert!(db.garbage_by_collection().is_empty());
+    }
+
+    #[test]
+    fn test_iter() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("b", "1").unwrap();
+        db.set("a", "2").unwrap();
+        db.set("b", "3").unwrap();
+        db.set("c", "4").unwrap();
+        db.delete("c").unwrap();
+        db.collection("other").unwrap().set("d", "5").unwrap();
+
+        let pairs: Vec<(String, String)> = db.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a".to_string(), "2".to_string()),
+                ("b".to_string(), "3".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iter_stops_after_error() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        db.sync().unwrap();
+
+        // A broken length prefix can't be skipped over, it's reported once
+        let (offset, _) = db.live_span("a").unwrap();
+        let mut bytes = std::fs::read(temp_file.path()).unwrap();
+        bytes[offset as usize..offset as usize + 8].fill(0xff);
+        std::fs::write(temp_file.path(), bytes).unwrap();
+        let mut iter = db.iter().unwrap();
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_swap() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("current", "green").unwrap();
+        db.set("previous", "blue").unwrap();
+        db.swap("current", "previous").unwrap();
+        assert_eq!(db.get("current").unwrap(), Some("blue".to_string()));
+        assert_eq!(db.get("previous").unwrap(), Some("green".to_string()));
+
+        // A missing key moves over as a delete
+        db.swap("current", "next").unwrap();
+        assert_eq!(db.get("current").unwrap(), None);
+        assert_eq!(db.get("next").unwrap(), Some("blue".to_string()));
+        drop(db);
+
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.scan_keys(""), vec!["next", "previous"]);
+        assert_eq!(db.get("next").unwrap(), Some("blue".to_string()));
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("balance", "90").set("ledger:1", "-10");
+        db.apply_batch(batch).unwrap();
+
+        let read = db.multi_get(&["balance", "ledger:1", "ledger:2"]).unwrap();
+        assert_eq!(read.seq, db.last_seq());
+        assert_eq!(
+            read.values,
+            vec![Some("90".to_string()), Some("-10".to_string()), None]
+        );
+        assert!(db.multi_get(&["balance", "bad\0key"]).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_index() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+
+        // A second handle stands in for another process writing to the file
+        let mut writer = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        writer.set("c", "3").unwrap();
+        writer.delete("a").unwrap();
+        assert_eq!(db.get("c").unwrap(), None);
+
+        db.rebuild_index().unwrap();
+        assert_eq!(db.scan_keys(""), vec!["b", "c"]);
+        assert_eq!(db.get("c").unwrap(), Some("3".to_string()));
+        assert_eq!(db.last_seq(), writer.last_seq());
+        assert_eq!(db.collection_stats("").live_keys, 2);
+
+        // New writes go after the ones the other handle made
+        db.set("d", "4").unwrap();
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.scan_keys(""), vec!["b", "c", "d"]);
+        assert_eq!(db.get("d").unwrap(), Some("4".to_string()));
+    }
+
+    #[test]
+    fn test_refresh() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut writer = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        writer.set("a", "1").unwrap();
+        let options = DbOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut replica = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        replica.enable_text_index("").unwrap();
+
+        writer.set("b", "blue sky").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("c", "3").delete("a");
+        writer.apply_batch(batch).unwrap();
+        replica.refresh().unwrap();
+        assert_eq!(replica.scan_keys(""), vec!["b", "c"]);
+        assert_eq!(replica.get("b").unwrap(), Some("blue sky".to_string()));
+        assert_eq!(replica.search("sky").unwrap(), vec!["b"]);
+        assert_eq!(replica.last_seq(), writer.last_seq());
+
+        // A compacted file is a new file, the replica starts over on it
+        writer.set("b", "grey sky").unwrap();
+        writer.compact().unwrap();
+        writer.set("d", "4").unwrap();
+        replica.refresh().unwrap();
+        assert_eq!(replica.scan_keys(""), vec!["b", "c", "d"]);
+        assert_eq!(replica.get("b").unwrap(), Some("grey sky".to_string()));
+        assert_eq!(replica.search("grey").unwrap(), vec!["b"]);
+    }
+
+    #[test]
+    fn test_refresh_during_open_batch() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let read_only = || DbOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut writer = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        writer.set("a", "1").unwrap();
+        let mut replica = EmbeddedDatabase::with_options(temp_file.path(), read_only()).unwrap();
+
+        // The writer is half way through a batch: its records are out, the
+        // commit marker isn't yet
+        let seq = writer.last_seq + 1;
+        let mut buffer = Vec::new();
+        let record = writer
+            .build_record("b".to_string(), b"2", None, RecordKind::Batched, seq)
+            .unwrap();
+        encode_frame(&record, &mut buffer).unwrap();
+        writer.write_frames(&buffer).unwrap();
+        let file_len = std::fs::metadata(temp_file.path()).unwrap().len();
+
+        // Readers neither see the batch nor cut it off
+        replica.refresh().unwrap();
+        let mut late = EmbeddedDatabase::with_options(temp_file.path(), read_only()).unwrap();
+        late.rebuild_index().unwrap();
+        assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len(), file_len);
+        assert_eq!(replica.get("b").unwrap(), None);
+        assert_eq!(late.get("b").unwrap(), None);
+
+        let mut buffer = Vec::new();
+        encode_frame(
+            &tombstone(String::new(), RecordKind::BatchCommit, seq),
+            &mut buffer,
+        )
+        .unwrap();
+        writer.write_frames(&buffer).unwrap();
+        writer.last_seq = seq;
+        writer.set("c", "3").unwrap();
+        replica.refresh().unwrap();
+        assert_eq!(replica.scan_keys(""), vec!["a", "b", "c"]);
+        drop(writer);
+        let mut reopened = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(reopened.get("b").unwrap(), Some("2".to_string()));
+
+        // Nothing gets written through a read-only handle
+        let err = replica.set("d", "4").unwrap_err();
+        assert!(err.to_string().contains("read-only"), "{err}");
+        assert!(replica.compact().is_err());
+        assert!(EmbeddedDatabase::with_options("/nonexistent/db", read_only()).is_err());
+    }
+
+    #[test]
+    fn test_scan_keys() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("user:2", "Bob").unwrap();
+        db.set("user:1", "Alice").unwrap();
+        db.set("order:1", "book").unwrap();
+        db.collection("archive")
+            .unwrap()
+            .set("user:3", "Carol")
+            .unwrap();
+        db.delete("user:2").unwrap();
+
+        assert_eq!(db.scan_keys("user:"), vec!["user:1".to_string()]);
+        assert_eq!(db.scan_keys("").len(), 2);
+        assert_eq!(
+            db.collection("archive").unwrap().scan_keys("user:"),
+            vec!["user:3".to_string()]
+        );
+    }
+    #[test]
+    fn test_get_prefix_bytes() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("blob", "HEADER|body body body").unwrap();
+        db.set("short", "ab").unwrap();
+
+        assert_eq!(
+            db.get_prefix_bytes("blob", 6).unwrap(),
+            Some(b"HEADER".to_vec())
+        );
+        assert_eq!(
+            db.get_prefix_bytes("short", 6).unwrap(),
+            Some(b"ab".to_vec())
+        );
+        assert_eq!(db.get_prefix_bytes("missing", 6).unwrap(), None);
+
+        // Transformed values are decoded first
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            default_collection: CollectionOptions {
+                transformers: vec![Arc::new(Lz4Compression)],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        db.set("blob", "HEADER|body body body").unwrap();
+        assert_eq!(
+            db.get_prefix_bytes("blob", 6).unwrap(),
+            Some(b"HEADER".to_vec())
+        );
+    }
+    #[test]
+    fn test_open_progress() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        for i in 0..500 {
+            db.set(&format!("key{i}"), "value").unwrap();
+        }
+        drop(db);
+
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let options = DbOptions {
+            open_progress: Some(crate::OpenProgressCallback::new(move |progress| {
+                sink.lock().unwrap().push(progress)
+            })),
+            ..Default::default()
+        };
+        EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 50 && reports.len() <= 101);
+        assert!(
+            reports
+                .windows(2)
+                .all(|w| w[0].bytes_scanned < w[1].bytes_scanned)
+        );
+        let last = reports.last().unwrap();
+        assert_eq!(last.fraction(), 1.0);
+        assert_eq!(last.records_indexed, 500);
+    }
+    #[test]
+    fn test_open_cancel_and_timeout() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("key", "value").unwrap();
+        drop(db);
+
+        let token = crate::CancellationToken::new();
+        token.cancel();
+        let options = DbOptions {
+            open_cancel: Some(token),
+            ..Default::default()
+        };
+        let err = EmbeddedDatabase::with_options(temp_file.path(), options)
+            .err()
+            .expect("a cancelled open should fail");
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::OpenCancelled {
+                bytes_scanned: 0,
+                ..
+            })
+        ));
+
+        let options = DbOptions {
+            open_timeout: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let err = EmbeddedDatabase::with_options(temp_file.path(), options)
+            .err()
+            .expect("an open without time left should fail");
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::OpenTimedOut { .. })
+        ));
+
+        // Nothing was lost by the aborted opens
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get("key").unwrap(), Some("value".to_string()));
+    }
+
+    #[test]
+    fn test_damaged_frame_before_good_records() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        db.set("c", "3").unwrap();
+        let (offset, _) = db.live_span("b").unwrap();
+        let (last_offset, _) = db.live_span("c").unwrap();
+        drop(db);
+        let good = std::fs::read(temp_file.path()).unwrap();
+
+        // Records after the damage are still there, so the open fails instead
+        // of cutting the file off at the damaged frame
+        let mut damaged = good.clone();
+        damaged[offset as usize..offset as usize + 8].fill(0xff);
+        std::fs::write(temp_file.path(), &damaged).unwrap();
+        let err = EmbeddedDatabase::new(temp_file.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::CorruptFrame { offset: at, .. }) if *at == offset
+        ));
+        assert_eq!(std::fs::read(temp_file.path()).unwrap(), damaged);
+
+        // The same damage with nothing behind it is a torn write
+        let mut torn = good;
+        torn[last_offset as usize..last_offset as usize + 8].fill(0xff);
+        std::fs::write(temp_file.path(), &torn).unwrap();
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get("b").unwrap(), Some("2".to_string()));
+        assert_eq!(db.get("c").unwrap(), None);
+    }
+
+    #[test]
+    fn test_damaged_frame_before_lots_of_bytes() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        let (offset, _) = db.live_span("b").unwrap();
+        drop(db);
+
+        // No record turns up within the limit, so the scan gives up & leaves
+        // the file alone rather than throwing all of it away
+        let mut damaged = std::fs::read(temp_file.path()).unwrap();
+        damaged[offset as usize..offset as usize + 8].fill(0xff);
+        damaged.resize(damaged.len() + RESYNC_LIMIT as usize + RESYNC_CHUNK, 0xab);
+        std::fs::write(temp_file.path(), &damaged).unwrap();
+        let err = EmbeddedDatabase::new(temp_file.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::CorruptFrame { offset: at, .. }) if *at == offset
+        ));
+        assert_eq!(
+            std::fs::metadata(temp_file.path()).unwrap().len(),
+            damaged.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_damaged_files_dont_panic() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = || DbOptions {
+            default_collection: CollectionOptions {
+                transformers: vec![Arc::new(Lz4Compression)],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("a", &"x".repeat(100)).set("b", "2");
+        db.apply_batch(batch).unwrap();
+        db.set_with_ttl("c", "3", Duration::from_secs(60)).unwrap();
+        db.delete("b").unwrap();
+        drop(db);
+        let good = std::fs::read(temp_file.path()).unwrap();
+
+        // Flip bytes all over a good file, every open & read has to return
+        // instead of panicking or allocating whatever a length claims
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |below: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % below
+        };
+        for _ in 0..500 {
+            let mut damaged = good.clone();
+            for _ in 0..1 + next(4) {
+                let at = next(damaged.len());
+                damaged[at] = match next(3) {
+                    0 => 0xff,
+                    1 => 0,
+                    _ => damaged[at] ^ (1 << next(8)),
+                };
+            }
+            std::fs::write(temp_file.path(), &damaged).unwrap();
+            let _ = EmbeddedDatabase::verify_backup(temp_file.path(), None);
+            if let Ok(mut db) = EmbeddedDatabase::with_options(temp_file.path(), options()) {
+                for key in ["a", "b", "c"] {
+                    let _ = db.get(key);
+                }
+                let _ = db.iter().map(|iter| iter.count());
+                let _ = db.compact();
+            }
+        }
+    }
 }
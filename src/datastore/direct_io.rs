@@ -0,0 +1,210 @@
+use super::{
+    RecordKind, Result,
+    database::{encode_frame, tombstone},
+};
+use std::{fs::File, path::Path};
+
+/// Smallest alignment `DbOptions::direct_io` accepts, the logical block size
+/// of most disks. 4096 is the safe choice on 4K sector drives.
+pub(crate) const MIN_DIRECT_ALIGNMENT: u64 = 512;
+
+/// Bytes to add after `position` to land on a multiple of `alignment`,
+/// widened by whole alignment units when a padding record wouldn't fit
+fn padding_needed(position: u64, alignment: u64, smallest_padding: u64) -> u64 {
+    let mut gap = (alignment - position % alignment) % alignment;
+    while gap != 0 && gap < smallest_padding {
+        gap += alignment;
+    }
+    gap
+}
+
+/// Append a `Padding` record taking up exactly `len` bytes on disk
+fn push_padding(len: u64, smallest_padding: u64, buffer: &mut Vec<u8>) -> Result<()> {
+    let mut padding = tombstone(String::new(), RecordKind::Padding, 0);
+    padding.val = vec![0; (len - smallest_padding) as usize];
+    encode_frame(&padding, buffer)?;
+    Ok(())
+}
+
+/// Lay out the frames of one write so they start & end on a multiple of
+/// `alignment` when appended at `end_of_file`.
+/// Returns where the first frame ends up & the bytes to append.
+pub(crate) fn align_frames(
+    frames: &[u8],
+    end_of_file: u64,
+    alignment: u64,
+) -> Result<(u64, Vec<u8>)> {
+    let smallest_padding =
+        8 + bincode::serialized_size(&tombstone(String::new(), RecordKind::Padding, 0))?;
+    let mut aligned = Vec::with_capacity(frames.len() + 2 * alignment as usize);
+
+    // Only files written without alignment (or by an older compaction) need this
+    let before = padding_needed(end_of_file, alignment, smallest_padding);
+    if before > 0 {
+        push_padding(before, smallest_padding, &mut aligned)?;
+    }
+    aligned.extend_from_slice(frames);
+    let after = padding_needed(
+        end_of_file + aligned.len() as u64,
+        alignment,
+        smallest_padding,
+    );
+    if after > 0 {
+        push_padding(after, smallest_padding, &mut aligned)?;
+    }
+    Ok((end_of_file + before, aligned))
+}
+
+/// Padding that brings a file of `len` bytes up to a multiple of `alignment`
+pub(crate) fn tail_padding(len: u64, alignment: u64) -> Result<Vec<u8>> {
+    Ok(align_frames(&[], len, alignment)?.1)
+}
+
+/// A second handle on the data file that writes around the page cache
+/// (O_DIRECT on Linux, F_NOCACHE on macOS). Offsets, lengths & buffers all
+/// have to be multiples of the alignment.
+#[derive(Debug)]
+pub(crate) struct DirectFile {
+    file: File,
+    alignment: u64,
+}
+
+impl DirectFile {
+    pub(crate) fn open(path: &Path, alignment: u64) -> Result<Self> {
+        if alignment < MIN_DIRECT_ALIGNMENT {
+            return Err(format!(
+                "direct IO needs a record alignment of at least {MIN_DIRECT_ALIGNMENT}, e.g. 4096"
+            )
+            .into());
+        }
+        Ok(DirectFile {
+            file: open_uncached(path)?,
+            alignment,
+        })
+    }
+
+    pub(crate) fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// Write `bytes` at `offset`, both aligned
+    pub(crate) fn write_at(&self, offset: u64, bytes: &[u8]) -> Result<()> {
+        // The memory has to be aligned too, which a Vec<u8> doesn't promise
+        let alignment = self.alignment as usize;
+        let mut storage = vec![0u8; bytes.len() + alignment];
+        let shift = storage.as_ptr().align_offset(alignment);
+        let aligned = &mut storage[shift..shift + bytes.len()];
+        aligned.copy_from_slice(bytes);
+        write_all_at(&self.file, aligned, offset)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_uncached(path: &Path) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    Ok(std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)?)
+}
+
+#[cfg(target_os = "macos")]
+fn open_uncached(path: &Path) -> Result<File> {
+    use std::os::fd::AsRawFd;
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(file)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn open_uncached(_path: &Path) -> Result<File> {
+    Err("direct IO isn't supported on this platform".into())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, bytes: &[u8], offset: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    Ok(file.write_all_at(bytes, offset)?)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(_file: &File, _bytes: &[u8], _offset: u64) -> Result<()> {
+    Err("direct IO isn't supported on this platform".into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{DbOptions, EmbeddedDatabase, WriteBatch};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_record_alignment() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            record_alignment: Some(512),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options.clone()).unwrap();
+        db.set("a", "1").unwrap();
+        // 460 bytes of value leave a gap too small for a padding record
+        db.set("b", &"x".repeat(460)).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("c", "3");
+        batch.delete("a");
+        db.apply_batch(batch).unwrap();
+        let len = std::fs::metadata(temp_file.path()).unwrap().len();
+        assert_eq!(len % 512, 0);
+        // The header takes the first block
+        assert_eq!(len, 512 + 512 + 1024 + 512);
+        drop(db);
+
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        assert_eq!(db.get("a").unwrap(), None);
+        assert_eq!(db.get("b").unwrap(), Some("x".repeat(460)));
+        assert_eq!(db.get("c").unwrap(), Some("3".to_string()));
+        db.compact().unwrap();
+        assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len() % 512, 0);
+        db.set("d", "4").unwrap();
+        assert_eq!(std::fs::metadata(temp_file.path()).unwrap().len() % 512, 0);
+        assert_eq!(db.scan_keys(""), vec!["b", "c", "d"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_direct_io() {
+        // tmpfs may not do O_DIRECT, the build dir is on a real filesystem
+        let dir = tempfile::tempdir_in(concat!(env!("CARGO_MANIFEST_DIR"), "/target")).unwrap();
+        let path = dir.path().join("direct.db");
+        let options = DbOptions {
+            record_alignment: Some(4096),
+            direct_io: true,
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(&path, options.clone()).unwrap();
+        for i in 0..10 {
+            db.set(&format!("key{i}"), &format!("val{i}")).unwrap();
+        }
+        db.sync().unwrap();
+        assert_eq!(db.get("key7").unwrap(), Some("val7".to_string()));
+        // One block for the header & one for each write
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 11 * 4096);
+        drop(db);
+
+        let mut db = EmbeddedDatabase::with_options(&path, options).unwrap();
+        assert_eq!(db.get("key3").unwrap(), Some("val3".to_string()));
+        assert!(
+            EmbeddedDatabase::with_options(
+                dir.path().join("small.db"),
+                DbOptions {
+                    record_alignment: Some(64),
+                    direct_io: true,
+                    ..Default::default()
+                }
+            )
+            .is_err()
+        );
+    }
+}
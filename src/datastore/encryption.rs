@@ -0,0 +1,454 @@
+use super::{EmbeddedDatabase, Result, tenant::tenant_collection};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// Recorded in `Record::transforms` for values encrypted at rest, followed by
+/// `:<key id>` of the data key that was used
+pub(crate) const ENCRYPTION_TRANSFORM: &str = "chacha20poly1305";
+
+const NONCE_LEN: usize = 12;
+
+/// 256-bit key the per-collection data keys are encrypted ("wrapped") with.
+/// Keep it outside of the database, e.g. in a secrets manager.
+#[derive(Clone, PartialEq)]
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        MasterKey(bytes)
+    }
+
+    /// A fresh random key
+    pub fn generate() -> Self {
+        MasterKey(ChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+/// The data keys of one collection. New values use `current`, the others are
+/// kept around while a key rotation still has records to re-encrypt.
+#[derive(Debug, Clone, Default)]
+struct CollectionKeys {
+    current: u32,
+    keys: HashMap<u32, Key>,
+}
+
+/// How a collection's keys are stored in the key file, wrapped & hex encoded
+#[derive(Serialize, Deserialize)]
+struct WrappedKeys {
+    current: u32,
+    keys: HashMap<u32, String>,
+}
+
+/// Envelope encryption for values at rest.
+/// Every collection (& so every tenant) gets its own random data key. The data
+/// keys live wrapped by the master key in a small `<db file>.keys` file next to
+/// the data file. Deleting a collection's data keys from there makes all of its
+/// values unreadable, without touching the data file.
+pub(crate) struct KeyStore {
+    path: PathBuf,
+    master: RwLock<MasterKey>,
+    data_keys: RwLock<HashMap<String, CollectionKeys>>,
+}
+
+impl fmt::Debug for KeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyStore")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyStore {
+    pub(crate) fn open(db_path: &Path, master_key: &MasterKey) -> Result<Self> {
+        let path = keys_path(db_path);
+        let master = master_key.cipher();
+
+        let mut data_keys = HashMap::new();
+        if path.exists() {
+            let wrapped: HashMap<String, WrappedKeys> = serde_json::from_slice(&fs::read(&path)?)?;
+            for (collection, wrapped_keys) in wrapped {
+                let mut keys = HashMap::new();
+                for (id, wrapped_key) in wrapped_keys.keys {
+                    let key = open_sealed(&master, &from_hex(&wrapped_key)?)
+                        .map_err(|_| "the master key doesn't match the data keys of this db")?;
+                    if key.len() != 32 {
+                        return Err(
+                            format!("corrupt data key for collection {collection:?}").into()
+                        );
+                    }
+                    keys.insert(id, *Key::from_slice(&key));
+                }
+                let current = wrapped_keys.current;
+                data_keys.insert(collection, CollectionKeys { current, keys });
+            }
+        }
+
+        Ok(KeyStore {
+            path,
+            master: RwLock::new(master_key.clone()),
+            data_keys: RwLock::new(data_keys),
+        })
+    }
+
+    /// Encrypt a value with the collection's current data key, creating the
+    /// key on first use. Returns the sealed value & the transform name to record.
+    pub(crate) fn encrypt(&self, collection: &str, val: &[u8]) -> Result<(Vec<u8>, String)> {
+        let existing = self
+            .read_keys()?
+            .get(collection)
+            .map(|keys| (keys.current, keys.keys[&keys.current]));
+        let (id, key) = match existing {
+            Some(current) => current,
+            None => {
+                let mut data_keys = self.write_keys()?;
+                let keys = data_keys.entry(collection.to_string()).or_default();
+                if keys.keys.is_empty() {
+                    keys.current = 1;
+                    keys.keys
+                        .insert(1, ChaCha20Poly1305::generate_key(&mut OsRng));
+                }
+                let current = (keys.current, keys.keys[&keys.current]);
+                // The key has to be on disk before anything encrypted with it is
+                self.persist(&data_keys)?;
+                current
+            }
+        };
+        let sealed = seal(&ChaCha20Poly1305::new(&key), val)?;
+        Ok((sealed, transform_name(id)))
+    }
+
+    /// Decrypt a value sealed with the collection's data key `transform` names
+    pub(crate) fn decrypt(&self, collection: &str, transform: &str, val: &[u8]) -> Result<Vec<u8>> {
+        let id = key_id(transform).ok_or("not an encryption transform")?;
+        let key = self
+            .read_keys()?
+            .get(collection)
+            .and_then(|keys| keys.keys.get(&id).copied());
+        let Some(key) = key else {
+            return Err(format!(
+                "data key {id} of collection {collection:?} was shredded or rotated out"
+            )
+            .into());
+        };
+        open_sealed(&ChaCha20Poly1305::new(&key), val)
+            .map_err(|_| format!("a value in collection {collection:?} failed to decrypt").into())
+    }
+
+    /// Re-encrypt a value under its collection's current data key, `None` if it already is
+    pub(crate) fn reencrypt(
+        &self,
+        collection: &str,
+        transform: &str,
+        val: &[u8],
+    ) -> Result<Option<(Vec<u8>, String)>> {
+        let current = self.read_keys()?.get(collection).map(|keys| keys.current);
+        if current.is_some() && current == key_id(transform) {
+            return Ok(None);
+        }
+        let plain = self.decrypt(collection, transform, val)?;
+        Ok(Some(self.encrypt(collection, &plain)?))
+    }
+
+    /// Forget a collection's data keys for good. Returns false if there were none.
+    pub(crate) fn shred(&self, collection: &str) -> Result<bool> {
+        let mut data_keys = self.write_keys()?;
+        if data_keys.remove(collection).is_none() {
+            return Ok(false);
+        }
+        self.persist(&data_keys)?;
+        Ok(true)
+    }
+
+    pub(crate) fn is_master(&self, key: &MasterKey) -> Result<bool> {
+        let master = self
+            .master
+            .read()
+            .map_err(|_| "the master key lock was poisoned by a panic")?;
+        Ok(*master == *key)
+    }
+
+    /// Give every collection a new current data key. The old ones stay, so
+    /// everything written before can still be read until it is re-encrypted.
+    pub(crate) fn start_rotation(&self) -> Result<()> {
+        let mut data_keys = self.write_keys()?;
+        for keys in data_keys.values_mut() {
+            let id = keys.keys.keys().max().copied().unwrap_or(0) + 1;
+            keys.keys
+                .insert(id, ChaCha20Poly1305::generate_key(&mut OsRng));
+            keys.current = id;
+        }
+        self.persist(&data_keys)
+    }
+
+    /// Drop the data keys that aren't current anymore & wrap the rest with `new_master`
+    pub(crate) fn finish_rotation(&self, new_master: &MasterKey) -> Result<()> {
+        let mut data_keys = self.write_keys()?;
+        for keys in data_keys.values_mut() {
+            let current = keys.current;
+            keys.keys.retain(|id, _| *id == current);
+        }
+        *self
+            .master
+            .write()
+            .map_err(|_| "the master key lock was poisoned by a panic")? = new_master.clone();
+        self.persist(&data_keys)
+    }
+
+    fn read_keys(&self) -> Result<RwLockReadGuard<'_, HashMap<String, CollectionKeys>>> {
+        Ok(self
+            .data_keys
+            .read()
+            .map_err(|_| "the data key lock was poisoned by a panic")?)
+    }
+
+    fn write_keys(&self) -> Result<RwLockWriteGuard<'_, HashMap<String, CollectionKeys>>> {
+        Ok(self
+            .data_keys
+            .write()
+            .map_err(|_| "the data key lock was poisoned by a panic")?)
+    }
+
+    /// Replace the key file with a new one holding `data_keys`, wrapped.
+    /// Goes through a temp file & a rename so a crash leaves the old or the new file.
+    fn persist(&self, data_keys: &HashMap<String, CollectionKeys>) -> Result<()> {
+        let master = self
+            .master
+            .read()
+            .map_err(|_| "the master key lock was poisoned by a panic")?
+            .cipher();
+        let mut wrapped = HashMap::with_capacity(data_keys.len());
+        for (collection, keys) in data_keys {
+            let mut wrapped_keys = HashMap::with_capacity(keys.keys.len());
+            for (id, key) in &keys.keys {
+                wrapped_keys.insert(*id, to_hex(&seal(&master, key)?));
+            }
+            let wrapped_keys = WrappedKeys {
+                current: keys.current,
+                keys: wrapped_keys,
+            };
+            wrapped.insert(collection.clone(), wrapped_keys);
+        }
+
+        let temp_path = self.path.with_extension("keys.tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&serde_json::to_vec(&wrapped)?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Transform name for values sealed with data key `id`
+fn transform_name(id: u32) -> String {
+    format!("{ENCRYPTION_TRANSFORM}:{id}")
+}
+
+/// The data key id of an encryption transform name, `None` for other transforms
+pub(crate) fn key_id(transform: &str) -> Option<u32> {
+    transform
+        .strip_prefix(ENCRYPTION_TRANSFORM)?
+        .strip_prefix(':')?
+        .parse()
+        .ok()
+}
+
+impl EmbeddedDatabase {
+    /// Crypto-shred a collection: drop its data key, so every value it ever
+    /// had, in the data file, old backups or copies, can't be decrypted anymore.
+    /// Its keys are deleted too. Needs `DbOptions::master_key`.
+    pub fn shred_collection(&mut self, name: &str) -> Result<()> {
+        super::collection::validate_collection_name(name)?;
+        self.shred(name)
+    }
+
+    /// `shred_collection` for a tenant, see `EmbeddedDatabase::tenant`
+    pub fn shred_tenant(&mut self, tenant: &str) -> Result<()> {
+        self.shred(&tenant_collection(tenant))
+    }
+
+    /// Move from the `old` master key to `new`. Every collection gets a new data
+    /// key & a compaction pass re-encrypts every record with it. Until that is
+    /// done the old & new data keys both stay in the key file (wrapped by
+    /// `old`), so a crash halfway leaves a db that opens with `old` & can be
+    /// rotated again. Snapshot iterators taken before the rotation can't
+    /// decrypt anymore once it is done.
+    pub fn rotate_key(&mut self, old: &MasterKey, new: &MasterKey) -> Result<()> {
+        let registry = self.transformers.clone();
+        let Some(keys) = registry.key_store() else {
+            return Err("key rotation needs a master key in DbOptions".into());
+        };
+        if !keys.is_master(old)? {
+            return Err("the old key isn't this db's master key".into());
+        }
+        keys.start_rotation()?;
+        self.compact_reencrypting(true)?;
+        keys.finish_rotation(new)?;
+        self.options.master_key = Some(new.clone());
+        Ok(())
+    }
+
+    fn shred(&mut self, collection: &str) -> Result<()> {
+        let Some(keys) = self.transformers.key_store() else {
+            return Err("crypto-shredding needs a master key in DbOptions".into());
+        };
+        keys.shred(collection)?;
+        for stored_key in self.live_keys(collection) {
+            self.remove(stored_key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the wrapped data keys of a db file are kept
+fn keys_path(db_path: &Path) -> PathBuf {
+    let mut file_name = db_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".keys");
+    db_path.with_file_name(file_name)
+}
+
+/// `[12-byte nonce][ciphertext + tag]`
+fn seal(cipher: &ChaCha20Poly1305, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "encryption failed")?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+fn open_sealed(
+    cipher: &ChaCha20Poly1305,
+    sealed: &[u8],
+) -> std::result::Result<Vec<u8>, chacha20poly1305::aead::Error> {
+    if sealed.len() < NONCE_LEN {
+        return Err(chacha20poly1305::aead::Error);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err("bad hex in the key file".into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_crypto_shredding() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            master_key: Some(MasterKey::generate()),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options.clone()).unwrap();
+        db.tenant("acme").unwrap().set("name", "Jane Doe").unwrap();
+        db.tenant("globex").unwrap().set("name", "Bob").unwrap();
+        drop(db);
+
+        let raw = fs::read(temp_file.path()).unwrap();
+        assert!(!raw.windows(4).any(|window| window == b"Jane"));
+
+        // Reopening with the master key reads everything back
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options.clone()).unwrap();
+        assert_eq!(
+            db.tenant("acme").unwrap().get("name").unwrap(),
+            Some("Jane Doe".to_string())
+        );
+        db.shred_tenant("acme").unwrap();
+        assert_eq!(db.tenant("acme").unwrap().get("name").unwrap(), None);
+        drop(db);
+
+        // A copy of the data file from before the shred is no help anymore
+        let copy = NamedTempFile::new().expect("failed to create temp file");
+        fs::write(copy.path(), &raw).unwrap();
+        fs::copy(keys_path(temp_file.path()), keys_path(copy.path())).unwrap();
+        let mut db = EmbeddedDatabase::with_options(copy.path(), options.clone()).unwrap();
+        assert!(db.tenant("acme").unwrap().get("name").is_err());
+        assert_eq!(
+            db.tenant("globex").unwrap().get("name").unwrap(),
+            Some("Bob".to_string())
+        );
+
+        // Without the right master key nothing opens
+        let wrong = DbOptions {
+            master_key: Some(MasterKey::generate()),
+            ..Default::default()
+        };
+        assert!(EmbeddedDatabase::with_options(temp_file.path(), wrong).is_err());
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let old = MasterKey::generate();
+        let options = DbOptions {
+            master_key: Some(old.clone()),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        db.set("a", "1").unwrap();
+        db.collection("users").unwrap().set("42", "Alice").unwrap();
+
+        let new = MasterKey::generate();
+        assert!(db.rotate_key(&new, &new).is_err(), "wrong old key");
+        db.rotate_key(&old, &new).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("1".to_string()));
+
+        // Every record was re-encrypted with the second data key of its collection
+        db.scan_records(|_, record| {
+            if !record.is_tombstone() {
+                assert_eq!(record.transforms, vec!["chacha20poly1305:2"]);
+            }
+            Ok(())
+        })
+        .unwrap();
+        drop(db);
+
+        let with = |key: &MasterKey| DbOptions {
+            master_key: Some(key.clone()),
+            ..Default::default()
+        };
+        assert!(EmbeddedDatabase::with_options(temp_file.path(), with(&old)).is_err());
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), with(&new)).unwrap();
+        assert_eq!(
+            db.collection("users").unwrap().get("42").unwrap(),
+            Some("Alice".to_string())
+        );
+    }
+}
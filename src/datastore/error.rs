@@ -1 +1,301 @@
+use std::{
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Errors the database raises itself, as opposed to IO or (de)serialization
+/// errors bubbling up from below. Callers can `downcast_ref::<DbError>()` the
+/// boxed error to react to a specific case.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbError {
+    /// Garbage is piling up faster than background compaction can reclaim it.
+    /// `blocked_by` names the change consumer whose unacked changes compaction
+    /// has to keep, when there is one.
+    CompactionBehind {
+        garbage_bytes: u64,
+        limit: u64,
+        blocked_by: Option<String>,
+    },
+    /// Rebuilding the index on open took longer than `DbOptions::open_timeout`
+    OpenTimedOut {
+        bytes_scanned: u64,
+        total_bytes: u64,
+    },
+    /// The open was aborted through `DbOptions::open_cancel`
+    OpenCancelled {
+        bytes_scanned: u64,
+        total_bytes: u64,
+    },
+    /// A tenant write would take the tenant past its `TenantQuota`
+    QuotaExceeded {
+        tenant: String,
+        quota: Quota,
+        used: u64,
+        limit: u64,
+    },
+    /// The data file doesn't match its MAC, see `DbOptions::mac_key`
+    IntegrityCheckFailed { reason: String },
+    /// A callback handed to the db (value transformer, index extractor,
+    /// scheduled task, ...) panicked. Whatever it was called for is
+    /// abandoned, the db stays usable.
+    CallbackPanicked { callback: String, message: String },
+    /// The handle's fencing epoch was superseded by a newer writer, see
+    /// `EmbeddedDatabase::fence`
+    Fenced { epoch: u64, current: u64 },
+    /// The file holds a different version of a collection's schema than
+    /// `DbOptions::schema_versions` expects
+    SchemaMismatch {
+        collection: String,
+        expected: u32,
+        found: u32,
+    },
+    /// The file doesn't start with the header of a data file
+    NotADatabase,
+    /// The file was written by a newer version, or with format flags this
+    /// version doesn't know
+    UnsupportedFormat { version: u32, flags: u32 },
+    /// A record's length prefix runs past the end of the data, the file is
+    /// damaged there
+    CorruptFrame { offset: u64, len: u64 },
+    /// A write on a handle opened with `DbOptions::read_only`
+    ReadOnly,
+}
+
+/// Which limit of a `TenantQuota` was hit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quota {
+    Bytes,
+    Keys,
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::CompactionBehind {
+                garbage_bytes,
+                limit,
+                blocked_by,
+            } => {
+                write!(
+                    f,
+                    "write rejected: {garbage_bytes} bytes of garbage waiting for compaction (limit {limit})"
+                )?;
+                match blocked_by {
+                    Some(consumer) => write!(f, ", held back by consumer {consumer:?}"),
+                    None => Ok(()),
+                }
+            }
+            DbError::OpenTimedOut {
+                bytes_scanned,
+                total_bytes,
+            } => write!(
+                f,
+                "open timed out after scanning {bytes_scanned} of {total_bytes} bytes"
+            ),
+            DbError::OpenCancelled {
+                bytes_scanned,
+                total_bytes,
+            } => write!(
+                f,
+                "open cancelled after scanning {bytes_scanned} of {total_bytes} bytes"
+            ),
+            DbError::QuotaExceeded {
+                tenant,
+                quota,
+                used,
+                limit,
+            } => {
+                let unit = match quota {
+                    Quota::Bytes => "bytes",
+                    Quota::Keys => "keys",
+                };
+                write!(
+                    f,
+                    "tenant {tenant:?} would use {used} {unit}, its quota is {limit}"
+                )
+            }
+            DbError::IntegrityCheckFailed { reason } => {
+                write!(f, "integrity check failed: {reason}")
+            }
+            DbError::CallbackPanicked { callback, message } => {
+                write!(f, "{callback} panicked: {message}")
+            }
+            DbError::Fenced { epoch, current } => write!(
+                f,
+                "write rejected: epoch {epoch} was fenced off by epoch {current}"
+            ),
+            DbError::SchemaMismatch {
+                collection,
+                expected,
+                found,
+            } => write!(
+                f,
+                "collection {collection:?} is at schema version {found}, expected {expected}"
+            ),
+            DbError::NotADatabase => write!(f, "not a database file"),
+            DbError::UnsupportedFormat { version, flags } => write!(
+                f,
+                "unsupported file format version {version} (flags {flags:#x})"
+            ),
+            DbError::CorruptFrame { offset, len } => write!(
+                f,
+                "record at byte {offset} claims {len} bytes, past the end of the data"
+            ),
+            DbError::ReadOnly => write!(f, "the database was opened read-only"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+/// An IO, encoding or other lower level error along with the operation it
+/// broke, e.g. `set(key=user:42) failed: write at offset 1024 failed: No
+/// space left on device`. The original error is its `source()`. `DbError`s
+/// are never wrapped, so they can still be downcast directly.
+#[derive(Debug)]
+pub struct OperationFailed {
+    pub operation: String,
+    source: Box<dyn Error>,
+}
+
+impl fmt::Display for OperationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.operation, self.source)
+    }
+}
+
+impl Error for OperationFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+pub(crate) trait Context<T> {
+    /// Wrap an error in an `OperationFailed` naming `operation`
+    fn context(self, operation: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T, E: Into<Box<dyn Error>>> Context<T> for std::result::Result<T, E> {
+    fn context(self, operation: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|err| {
+            let err = err.into();
+            if err.is::<DbError>() {
+                return err;
+            }
+            OperationFailed {
+                operation: operation(),
+                source: err,
+            }
+            .into()
+        })
+    }
+}
+
+/// Run a user supplied callback, turning a panic into
+/// `DbError::CallbackPanicked` instead of letting it unwind through the db
+/// (& poison the lock of a ThreadSafeDB on the way)
+pub(crate) fn catch_callback<T>(
+    callback: impl FnOnce() -> String,
+    run: impl FnOnce() -> T,
+) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(run)).map_err(|payload| {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload
+                .downcast_ref::<&str>()
+                .map_or("unknown panic", |message| message)
+                .to_string(),
+        };
+        DbError::CallbackPanicked {
+            callback: callback(),
+            message,
+        }
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EmbeddedDatabase;
+    use std::{
+        fs::{self, OpenOptions},
+        io::{Seek, SeekFrom, Write},
+    };
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_error_context() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("user:42", "anna").unwrap();
+        db.collection("users").unwrap().set("42", "anna").unwrap();
+        // Damage the keys' length prefixes under the open db
+        let bytes = fs::read(temp_file.path()).unwrap();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(temp_file.path())
+            .unwrap();
+        for key in [&b"user:42"[..], b"users\x0042"] {
+            let key_at = bytes.windows(key.len()).position(|w| w == key).unwrap();
+            file.seek(SeekFrom::Start(key_at as u64 - 8)).unwrap();
+            file.write_all(&[0xff; 8]).unwrap();
+        }
+
+        let err = db.get("user:42").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("get(key=user:42) failed: read at offset "),
+            "{err}"
+        );
+        let failed = err.downcast_ref::<OperationFailed>().unwrap();
+        assert_eq!(failed.operation, "get(key=user:42)");
+        let read = failed.source().unwrap().downcast_ref::<OperationFailed>();
+        assert!(read.unwrap().operation.starts_with("read at offset "));
+        let decode_err = read.unwrap().source().unwrap();
+        assert!(decode_err.downcast_ref::<bincode::Error>().is_some());
+
+        // Every operation names itself, collections included
+        let err = db.multi_get(&["user:42"]).unwrap_err();
+        assert!(err.to_string().starts_with("multi_get(1 keys) failed: "));
+        let err = db.collection("users").unwrap().take("42").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("take(collection=users, key=42) failed: "),
+            "{err}"
+        );
+
+        // The database's own errors stay as they are
+        let err = Err::<(), _>(DbError::NotADatabase)
+            .context(|| "open".to_string())
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<DbError>(), Some(&DbError::NotADatabase));
+    }
+
+    #[test]
+    fn test_invalid_names_carry_context() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+
+        let err = db.set("a\0b", "1").unwrap_err();
+        let failed = err.downcast_ref::<OperationFailed>().unwrap();
+        assert_eq!(failed.operation, "set(key=a\0b)");
+        assert!(err.to_string().contains("reserved NUL character"), "{err}");
+
+        let err = db.swap("a", "a\0b").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("swap(key_a=a, key_b=a\0b) failed: ")
+        );
+        let err = db.multi_get(&["a", "a\0b"]).unwrap_err();
+        assert!(err.to_string().starts_with("multi_get(2 keys) failed: "));
+        let err = db.collection("__system").err().unwrap();
+        assert!(
+            err.to_string()
+                .starts_with("collection(name=__system) failed: ")
+        );
+    }
+}
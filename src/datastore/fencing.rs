@@ -0,0 +1,99 @@
+use super::{
+    DbError, EmbeddedDatabase, Result,
+    collection::{SYSTEM_COLLECTION, namespaced_key},
+};
+
+/// Latest epoch a writer claimed, under `__system`
+const EPOCH_KEY: &str = "fencing_epoch";
+
+impl EmbeddedDatabase {
+    /// Claim the file for `epoch`, e.g. the term of a newly elected primary.
+    /// From then on every write of this handle first checks whether another
+    /// handle claimed a newer epoch, & fails with `DbError::Fenced` if so.
+    /// A deposed primary that comes back can't write over its successor.
+    /// Claiming an epoch lower than the one on file fails the same way.
+    pub fn fence(&mut self, epoch: u64) -> Result<()> {
+        self.refresh()?;
+        if let Some(current) = self.stored_epoch()?
+            && current > epoch
+        {
+            return Err(DbError::Fenced { epoch, current }.into());
+        }
+        self.epoch = Some(epoch);
+        self.put(epoch_key(), epoch.to_string().as_bytes(), None)?;
+        self.sync()
+    }
+
+    /// The epoch this handle claimed with `fence`
+    pub fn epoch(&self) -> Option<u64> {
+        self.epoch
+    }
+
+    fn stored_epoch(&mut self) -> Result<Option<u64>> {
+        match self.get_stored(&epoch_key())? {
+            Some(epoch) => Ok(Some(epoch.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Before a fenced handle writes: pick up whatever others wrote to the
+    /// file since, & refuse to go on if that claimed a newer epoch
+    pub(crate) fn check_epoch(&mut self) -> Result<()> {
+        let Some(epoch) = self.epoch else {
+            return Ok(());
+        };
+        let changed = self.written_elsewhere()?;
+        if changed {
+            self.refresh()?;
+        }
+        match self.stored_epoch()? {
+            Some(current) if current > epoch => Err(DbError::Fenced { epoch, current }.into()),
+            // Someone wrote without claiming a newer epoch, the write this
+            // handle was about to make was planned against an older index
+            _ if changed => Err("another handle wrote to the file, retry the write".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn epoch_key() -> String {
+    namespaced_key(SYSTEM_COLLECTION, EPOCH_KEY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_fencing() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut old_primary = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        old_primary.fence(1).unwrap();
+        old_primary.set("a", "1").unwrap();
+
+        // Failover, the new primary opens the same file
+        let mut new_primary = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        new_primary.fence(2).unwrap();
+        new_primary.set("a", "2").unwrap();
+
+        let err = old_primary.set("a", "stale").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DbError>(),
+            Some(&DbError::Fenced {
+                epoch: 1,
+                current: 2
+            })
+        );
+        assert!(old_primary.compact().is_err());
+        assert!(old_primary.fence(1).is_err());
+        // It still serves what the new primary wrote
+        assert_eq!(old_primary.get("a").unwrap(), Some("2".to_string()));
+
+        new_primary.set("b", "3").unwrap();
+        drop(new_primary);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("2".to_string()));
+        assert_eq!(db.scan_keys(""), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,278 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{namespaced_key, user_key},
+};
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+/// Characters of the geohash members are stored under, cells of ~4 cm
+const STORED_PRECISION: usize = 12;
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Geohash of a point with `precision` characters (at most 12), points
+/// that share a prefix are in the same cell of that size
+pub fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let (mut bits, mut char_index) = (0, 0);
+    while hash.len() < precision.min(STORED_PRECISION) {
+        // Bits alternate between longitude & latitude, longitude first
+        let (range, val): (&mut (f64, f64), f64) = match even {
+            true => (&mut lon_range, lon),
+            false => (&mut lat_range, lat),
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        char_index <<= 1;
+        if val >= mid {
+            char_index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[char_index] as char);
+            (bits, char_index) = (0, 0);
+        }
+    }
+    hash
+}
+
+/// Height & width in degrees of the geohash cells with `precision` characters
+fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision as i32;
+    let lon_bits = (bits + 1) / 2;
+    (
+        180.0 / 2f64.powi(bits - lon_bits),
+        360.0 / 2f64.powi(lon_bits),
+    )
+}
+
+/// Great circle distance in meters
+fn haversine(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat_a, lat_b) = (a.0.to_radians(), b.0.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.1 - a.1).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * h.sqrt().asin()
+}
+
+fn validate_point(lat: f64, lon: f64) -> Result<()> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("({lat}, {lon}) isn't a valid latitude & longitude").into());
+    }
+    Ok(())
+}
+
+/// A member found by `GeoIndex::near`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoMatch {
+    pub member: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// From the point searched around, in meters
+    pub distance: f64,
+}
+
+/// Named points, stored under keys that start with their geohash so the
+/// members close to a point are found with a few prefix scans. Best with
+/// `DbOptions::sorted_index`, which keeps those scans to the keys in range.
+pub struct GeoIndex<'a> {
+    db: &'a mut EmbeddedDatabase,
+    collection: String,
+}
+
+impl EmbeddedDatabase {
+    /// A handle to the named geo index, created on first use
+    pub fn geo(&mut self, name: &str) -> Result<GeoIndex<'_>> {
+        if name.is_empty() {
+            return Err("geo index name can't be empty".into());
+        }
+        Ok(GeoIndex {
+            db: self,
+            collection: format!("__geo:{name}"),
+        })
+    }
+}
+
+impl GeoIndex<'_> {
+    /// Put `member` at a point, moving it if it was somewhere else
+    pub fn add(&mut self, member: &str, lat: f64, lon: f64) -> Result<()> {
+        validate_point(lat, lon)?;
+        let hash = geohash(lat, lon, STORED_PRECISION);
+        let mut writes = Vec::with_capacity(3);
+        if let Some(old_hash) = self.db.get_stored(&self.member_key(member))?
+            && old_hash != hash
+        {
+            writes.push((self.cell_key(&old_hash, member), None));
+        }
+        writes.push((
+            self.cell_key(&hash, member),
+            Some(format!("{lat},{lon}").into_bytes()),
+        ));
+        writes.push((self.member_key(member), Some(hash.into_bytes())));
+        self.db.commit_writes(writes, None)
+    }
+
+    /// Where `member` is, if it's there
+    pub fn position(&mut self, member: &str) -> Result<Option<(f64, f64)>> {
+        let Some(hash) = self.db.get_stored(&self.member_key(member))? else {
+            return Ok(None);
+        };
+        match self.db.get_stored(&self.cell_key(&hash, member))? {
+            Some(point) => Ok(Some(parse_point(&point)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns false if there was no such member
+    pub fn remove(&mut self, member: &str) -> Result<bool> {
+        let Some(hash) = self.db.get_stored(&self.member_key(member))? else {
+            return Ok(false);
+        };
+        let writes = vec![
+            (self.cell_key(&hash, member), None),
+            (self.member_key(member), None),
+        ];
+        self.db.commit_writes(writes, None)?;
+        Ok(true)
+    }
+
+    /// Members within `radius` meters of a point, closest first
+    pub fn near(&mut self, lat: f64, lon: f64, radius: f64) -> Result<Vec<GeoMatch>> {
+        validate_point(lat, lon)?;
+        let mut matches = Vec::new();
+        for prefix in covering_cells(lat, lon, radius) {
+            let cell_prefix = format!("cell/{prefix}");
+            for stored_key in self
+                .db
+                .stored_keys_with_prefix(&self.collection, &cell_prefix)
+            {
+                let Some((_, member)) = user_key(&stored_key)["cell/".len()..].split_once('/')
+                else {
+                    continue;
+                };
+                let member = member.to_string();
+                let Some(point) = self.db.get_stored(&stored_key)? else {
+                    continue;
+                };
+                let (member_lat, member_lon) = parse_point(&point)?;
+                let distance = haversine((lat, lon), (member_lat, member_lon));
+                if distance <= radius {
+                    matches.push(GeoMatch {
+                        member,
+                        lat: member_lat,
+                        lon: member_lon,
+                        distance,
+                    });
+                }
+            }
+        }
+        matches.sort_by(|a, b| {
+            a.distance
+                .total_cmp(&b.distance)
+                .then(a.member.cmp(&b.member))
+        });
+        Ok(matches)
+    }
+
+    fn cell_key(&self, hash: &str, member: &str) -> String {
+        namespaced_key(&self.collection, &format!("cell/{hash}/{member}"))
+    }
+
+    fn member_key(&self, member: &str) -> String {
+        namespaced_key(&self.collection, &format!("member/{member}"))
+    }
+}
+
+/// Geohash prefixes whose cells together cover the circle. Cells are picked
+/// at least as big as the circle's bounding box, so it spans two of them at
+/// most each way & its corners land in all of them.
+fn covering_cells(lat: f64, lon: f64, radius: f64) -> Vec<String> {
+    let d_lat = radius / METERS_PER_DEGREE;
+    let d_lon = radius / (METERS_PER_DEGREE * lat.to_radians().cos().max(1e-9));
+    let precision = (1..=STORED_PRECISION)
+        .rev()
+        .find(|precision| {
+            let (height, width) = cell_size(*precision);
+            height >= 2.0 * d_lat && width >= 2.0 * d_lon
+        })
+        .unwrap_or(0);
+    // Near the poles or for huge circles, look at everything
+    if precision == 0 || d_lon >= 180.0 {
+        return vec![String::new()];
+    }
+
+    let mut cells: Vec<String> = [(-1.0, -1.0), (-1.0, 1.0), (1.0, -1.0), (1.0, 1.0)]
+        .iter()
+        .map(|(lat_sign, lon_sign)| {
+            let corner_lat = (lat + lat_sign * d_lat).clamp(-90.0, 90.0);
+            // Wrap around the antimeridian
+            let corner_lon = (lon + lon_sign * d_lon + 540.0).rem_euclid(360.0) - 180.0;
+            geohash(corner_lat, corner_lon, precision)
+        })
+        .collect();
+    cells.sort();
+    cells.dedup();
+    cells
+}
+
+fn parse_point(point: &str) -> Result<(f64, f64)> {
+    let (lat, lon) = point
+        .split_once(',')
+        .ok_or_else(|| format!("bad stored point {point:?}"))?;
+    Ok((lat.parse()?, lon.parse()?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_geohash() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(-25.382708, -49.265506, 5), "6gkzw");
+    }
+
+    #[test]
+    fn test_near() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        for sorted_index in [false, true] {
+            let options = DbOptions {
+                sorted_index,
+                ..Default::default()
+            };
+            let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+            let mut cafes = db.geo("cafes").unwrap();
+            // Around Berlin Hauptbahnhof, plus one in Potsdam
+            cafes.add("station", 52.5251, 13.3694).unwrap();
+            cafes.add("reichstag", 52.5186, 13.3762).unwrap();
+            cafes.add("alex", 52.5219, 13.4132).unwrap();
+            cafes.add("potsdam", 52.3906, 13.0645).unwrap();
+            cafes.add("moved", 48.1371, 11.5754).unwrap();
+            cafes.add("moved", 52.5250, 13.3700).unwrap();
+
+            let near: Vec<String> = cafes
+                .near(52.5250, 13.3690, 1_500.0)
+                .unwrap()
+                .into_iter()
+                .map(|found| found.member)
+                .collect();
+            assert_eq!(near, vec!["station", "moved", "reichstag"]);
+            assert_eq!(cafes.near(52.5250, 13.3690, 50_000.0).unwrap().len(), 5);
+            assert_eq!(cafes.position("moved").unwrap(), Some((52.5250, 13.3700)));
+
+            assert!(cafes.remove("station").unwrap());
+            assert!(!cafes.remove("station").unwrap());
+            assert_eq!(
+                cafes.near(52.5250, 13.3690, 100.0).unwrap()[0].member,
+                "moved"
+            );
+            assert!(cafes.add("nowhere", 91.0, 0.0).is_err());
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use super::{
+    DbError, Record, RecordKind, Result,
+    database::{encode_frame, frame_len},
+};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+/// Starts the value of the header frame every data file begins with
+const MAGIC: &[u8; 8] = b"\x89TinyDB\n";
+/// Bumped whenever the file format changes in a way older code can't read
+pub(crate) const FORMAT_VERSION: u32 = 1;
+/// The file is signed (see `DbOptions::mac_key`), so only a handle with the
+/// key may write to it: an append without the key would leave the `.mac`
+/// sidecar behind & half the file signed
+pub(crate) const FLAG_SIGNED: u32 = 1;
+/// Flags this version knows how to read
+const KNOWN_FLAGS: u32 = FLAG_SIGNED;
+
+/// What the header of a data file says about it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct FileHeader {
+    pub version: u32,
+    pub flags: u32,
+}
+
+/// The first frame of a data file: a regular frame of kind `Header` whose
+/// value is the magic bytes, the format version & the flags
+pub(crate) fn header_record(flags: u32) -> Record {
+    let mut val = MAGIC.to_vec();
+    val.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    val.extend_from_slice(&flags.to_le_bytes());
+    Record {
+        key: String::new(),
+        val,
+        tombstone: false,
+        expires_at: None,
+        transforms: Vec::new(),
+        kind: RecordKind::Header,
+        seq: 0,
+    }
+}
+
+/// Make sure `file` starts with a header this version can read & return it.
+/// A file from before headers starts right away with a record, that is
+/// version 0 without flags: it's read as it is & gets a header once it's
+/// compacted. Its records may still be in the baseline layout, see `Record::decode`.
+pub(crate) fn check_header(file: &mut File) -> Result<FileHeader> {
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut len_buffer = [0u8; 8];
+    // Checked against the file's length before anything is allocated, so a
+    // random file can't make us read gigabytes
+    let len = match file.read_exact(&mut len_buffer) {
+        Ok(()) => frame_len(len_buffer, 0, file_len).map_err(|_| DbError::NotADatabase)?,
+        Err(_) => return Err(DbError::NotADatabase.into()),
+    };
+    let mut record_buffer = vec![0u8; len];
+    file.read_exact(&mut record_buffer)?;
+    // Only a real record, of either layout, takes up exactly its frame
+    let Ok(record) = Record::decode(&record_buffer, 0) else {
+        return Err(DbError::NotADatabase.into());
+    };
+    if record.kind != RecordKind::Header {
+        return Ok(FileHeader {
+            version: 0,
+            flags: 0,
+        });
+    }
+    if record.val.len() != MAGIC.len() + 8 || !record.val.starts_with(MAGIC) {
+        return Err(DbError::NotADatabase.into());
+    }
+
+    let field = |at: usize| u32::from_le_bytes(record.val[at..at + 4].try_into().expect("4 bytes"));
+    let (version, flags) = (field(MAGIC.len()), field(MAGIC.len() + 4));
+    if version > FORMAT_VERSION || flags & !KNOWN_FLAGS != 0 {
+        return Err(DbError::UnsupportedFormat { version, flags }.into());
+    }
+    Ok(FileHeader { version, flags })
+}
+
+/// Change the flags in the header of a file that has one. The header frame
+/// is the same size whatever the flags, so it's rewritten in place with a
+/// single write inside the first sector: after a crash the file carries the
+/// old flags or the new ones, never a torn mix.
+pub(crate) fn set_header_flags(file: &mut File, flags: u32) -> Result<()> {
+    if check_header(file)?.version == 0 {
+        return Err("a file without a header can't carry flags, compact it first".into());
+    }
+    let mut buffer = Vec::new();
+    encode_frame(&header_record(flags), &mut buffer)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&buffer)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, EmbeddedDatabase, MacKey};
+    use std::{fs, io::Write};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_rejects_other_files() {
+        let mut temp_file = NamedTempFile::new().expect("failed to create temp file");
+        temp_file.write_all(b"name,age\nanna,31\n").unwrap();
+        let err = EmbeddedDatabase::new(temp_file.path()).err().unwrap();
+        assert_eq!(err.downcast_ref::<DbError>(), Some(&DbError::NotADatabase));
+
+        // A file written by this version opens again, compacted or not
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        db.compact().unwrap();
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("1".to_string()));
+    }
+    #[test]
+    fn test_opens_legacy_files() {
+        // A file written before headers existed, the same records without one
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        drop(db);
+        let header_len = 8 + bincode::serialized_size(&header_record(0)).unwrap() as usize;
+        let bytes = fs::read(temp_file.path()).unwrap();
+        fs::write(temp_file.path(), &bytes[header_len..]).unwrap();
+        let mut file = File::open(temp_file.path()).unwrap();
+        assert_eq!(check_header(&mut file).unwrap().version, 0);
+
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("1".to_string()));
+        db.set("c", "3").unwrap();
+
+        // Compaction writes the header
+        db.compact().unwrap();
+        let mut file = File::open(temp_file.path()).unwrap();
+        assert_eq!(check_header(&mut file).unwrap().version, FORMAT_VERSION);
+        drop(db);
+        let db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.scan_keys("").len(), 3);
+    }
+    #[test]
+    fn test_opens_baseline_files() {
+        // The very first layout: a key & a string value per frame, an empty
+        // value deleting the key
+        let mut bytes = Vec::new();
+        for (key, val) in [("a", "1"), ("b", "2"), ("a", "3"), ("b", "")] {
+            let frame = bincode::serialize(&(key, val)).unwrap();
+            bytes.extend_from_slice(&(frame.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(&frame);
+        }
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        fs::write(temp_file.path(), &bytes).unwrap();
+        let mut file = File::open(temp_file.path()).unwrap();
+        assert_eq!(check_header(&mut file).unwrap().version, 0);
+
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("3".to_string()));
+        assert_eq!(db.get("b").unwrap(), None);
+
+        // New records go in next to the old ones until compaction rewrites them
+        db.set("c", "4").unwrap();
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("3".to_string()));
+        assert_eq!(db.get("c").unwrap(), Some("4".to_string()));
+        db.compact().unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("3".to_string()));
+        drop(db);
+
+        let mut file = File::open(temp_file.path()).unwrap();
+        assert_eq!(check_header(&mut file).unwrap().version, FORMAT_VERSION);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.scan_keys(""), vec!["a".to_string(), "c".to_string()]);
+        assert_eq!(db.get("b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_signed_flag() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = || DbOptions {
+            mac_key: Some(MacKey::new([7; 32])),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options()).unwrap();
+        db.set("a", "1").unwrap();
+        let mut file = File::open(temp_file.path()).unwrap();
+        assert_eq!(check_header(&mut file).unwrap().flags, FLAG_SIGNED);
+
+        // Compaction carries it over to the new file
+        db.compact().unwrap();
+        drop(db);
+        let mut file = File::open(temp_file.path()).unwrap();
+        assert_eq!(check_header(&mut file).unwrap().flags, FLAG_SIGNED);
+        assert!(EmbeddedDatabase::new(temp_file.path()).is_err());
+
+        // Rewritten in place, nothing else in the file moves
+        let len = fs::metadata(temp_file.path()).unwrap().len();
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp_file.path())
+            .unwrap();
+        set_header_flags(&mut file, 0).unwrap();
+        assert_eq!(check_header(&mut file).unwrap().flags, 0);
+        assert_eq!(fs::metadata(temp_file.path()).unwrap().len(), len);
+    }
+}
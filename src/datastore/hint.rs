@@ -0,0 +1,149 @@
+use super::{CollectionStats, Result, database::IndexEntry};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Bytes at the end of the compacted data a hint fingerprints
+const TAIL_BYTES: u64 = 4096;
+
+/// The index as compaction left it, so opening the file only has to scan
+/// what was appended after. It's only ever a shortcut: a hint that is
+/// missing, unreadable or doesn't match the data file is ignored.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Hint {
+    /// Length of the compacted data file, everything past it came later
+    pub(crate) data_len: u64,
+    /// SHA-256 of the last bytes before `data_len`, tells a hint that
+    /// belongs to another file apart
+    tail_digest: Vec<u8>,
+    pub(crate) last_seq: u64,
+    pub(crate) entries: Vec<(String, IndexEntry)>,
+    pub(crate) stats: Vec<(String, CollectionStats)>,
+}
+
+impl Hint {
+    pub(crate) fn new(
+        file: &mut File,
+        data_len: u64,
+        last_seq: u64,
+        entries: Vec<(String, IndexEntry)>,
+        stats: Vec<(String, CollectionStats)>,
+    ) -> Result<Self> {
+        Ok(Hint {
+            data_len,
+            tail_digest: tail_digest(file, data_len)?,
+            last_seq,
+            entries,
+            stats,
+        })
+    }
+
+    /// Write the hint next to the data file, replacing any older one
+    pub(crate) fn save(&self, db_path: &Path) -> Result<()> {
+        let path = hint_path(db_path);
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&bincode::serialize(self)?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    /// The hint of the data file `file`, if it has one that still matches it
+    pub(crate) fn load(db_path: &Path, file: &mut File) -> Result<Option<Self>> {
+        let bytes = match fs::read(hint_path(db_path)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let Ok(hint) = bincode::deserialize::<Hint>(&bytes) else {
+            return Ok(None);
+        };
+        if hint.data_len > file.metadata()?.len()
+            || tail_digest(file, hint.data_len)? != hint.tail_digest
+        {
+            return Ok(None);
+        }
+        Ok(Some(hint))
+    }
+
+    /// Drop the hint before the data file it describes changes under it
+    pub(crate) fn remove(db_path: &Path) -> Result<()> {
+        match fs::remove_file(hint_path(db_path)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn tail_digest(file: &mut File, data_len: u64) -> Result<Vec<u8>> {
+    let start = data_len.saturating_sub(TAIL_BYTES);
+    let mut tail = vec![0u8; (data_len - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut tail)?;
+    Ok(Sha256::digest(&tail).to_vec())
+}
+
+/// `<db file>.hint`
+fn hint_path(db_path: &Path) -> PathBuf {
+    let mut file_name = db_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".hint");
+    db_path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{DbOptions, EmbeddedDatabase, OpenProgress, OpenProgressCallback};
+    use std::sync::{Arc, Mutex};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_open_from_hint() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let scanned = Arc::new(Mutex::new(Vec::new()));
+        let options = || DbOptions {
+            hint_files: true,
+            open_progress: Some(OpenProgressCallback::new({
+                let scanned = scanned.clone();
+                move |progress: OpenProgress| scanned.lock().unwrap().push(progress)
+            })),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options()).unwrap();
+        for i in 0..100 {
+            db.set(&format!("key:{i}"), "old").unwrap();
+            db.set(&format!("key:{i}"), "new").unwrap();
+        }
+        db.delete("key:0").unwrap();
+        db.compact().unwrap();
+        db.set("after", "compaction").unwrap();
+        let (seq, stats) = (db.last_seq(), db.collection_stats(""));
+        drop(db);
+
+        // Only the record written after the compaction is scanned
+        scanned.lock().unwrap().clear();
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options()).unwrap();
+        assert_eq!(scanned.lock().unwrap().last().unwrap().records_indexed, 1);
+        assert_eq!(db.get("key:1").unwrap(), Some("new".to_string()));
+        assert_eq!(db.get("key:0").unwrap(), None);
+        assert_eq!(db.get("after").unwrap(), Some("compaction".to_string()));
+        assert_eq!((db.last_seq(), db.collection_stats("")), (seq, stats));
+        drop(db);
+
+        // Compacting without hint files drops the old hint instead of leaving
+        // one behind that no longer matches
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("key:1", "changed").unwrap();
+        db.compact().unwrap();
+        drop(db);
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options()).unwrap();
+        assert_eq!(db.get("key:1").unwrap(), Some("changed".to_string()));
+        assert_eq!(db.scan_keys("").len(), 100);
+    }
+}
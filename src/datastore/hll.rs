@@ -0,0 +1,141 @@
+use super::{EmbeddedDatabase, Result};
+use sha2::{Digest, Sha256};
+
+/// Bits of the hash that pick the register, 2^12 registers give ~1.6% error
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// Approximate count of distinct items in a fixed 4 KiB, however many
+/// items went in. Items are hashed with SHA-256 so counts saved by one
+/// process read back the same in another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read back what `as_bytes` gave
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != REGISTERS {
+            return Err(format!("a HyperLogLog is {REGISTERS} bytes, got {}", bytes.len()).into());
+        }
+        Ok(HyperLogLog {
+            registers: bytes.to_vec(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Count an item, returns whether that changed anything
+    pub fn add(&mut self, item: &[u8]) -> bool {
+        let digest = Sha256::digest(item);
+        let hash = u64::from_le_bytes(digest[..8].try_into().expect("digest is 32 bytes"));
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // Leading zeros after the register bits, the marker bit caps the run
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank <= self.registers[register] {
+            return false;
+        }
+        self.registers[register] = rank;
+        true
+    }
+
+    /// Fold in the items counted by `other`
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    /// Estimated number of distinct items added
+    pub fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|rank| 2f64.powi(-i32::from(*rank)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+
+        // Small counts leave most registers empty, linear counting is closer then
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        if estimate <= 2.5 * m && empty > 0 {
+            return (m * (m / empty as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Count `item` in the HyperLogLog stored under `key`, creating it on
+    /// first use. Nothing is written when the item doesn't change the count.
+    pub fn hll_add(&mut self, key: &str, item: &str) -> Result<()> {
+        let mut hll = self.hll(key)?;
+        if hll.add(item.as_bytes()) {
+            self.set_bytes(key, hll.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Estimated distinct items added to `key`, 0 when there is no such key
+    pub fn hll_count(&mut self, key: &str) -> Result<u64> {
+        Ok(self.hll(key)?.count())
+    }
+
+    fn hll(&mut self, key: &str) -> Result<HyperLogLog> {
+        match self.get_bytes(key)? {
+            Some(bytes) => HyperLogLog::from_bytes(&bytes),
+            None => Ok(HyperLogLog::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_hll() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        for i in 0..3 {
+            db.hll_add("small", &format!("visitor-{i}")).unwrap();
+        }
+        for i in 0..2_000 {
+            db.hll_add("visitors", &format!("visitor-{i}")).unwrap();
+        }
+        // Items seen before don't change a register, so nothing is written
+        let seq = db.last_seq();
+        for i in 0..1_000 {
+            db.hll_add("visitors", &format!("visitor-{i}")).unwrap();
+        }
+        assert_eq!(db.last_seq(), seq);
+
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert_eq!(db.hll_count("small").unwrap(), 3);
+        let count = db.hll_count("visitors").unwrap();
+        assert!((1_900..2_100).contains(&count), "{count}");
+        assert_eq!(db.hll_count("nobody").unwrap(), 0);
+
+        db.set("text", "not registers").unwrap();
+        assert!(db.hll_add("text", "x").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use super::{
+    EmbeddedDatabase,
+    collection::{collection_of, user_key},
+};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+/// Rows & columns of the sketch behind `hot_keys`. 4 x 2048 counters keep
+/// the overestimate small for anything but huge key spaces, in 32KiB.
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 2048;
+
+/// How many of the most accessed keys are remembered by name
+const TRACKED_KEYS: usize = 128;
+
+/// A key that gets accessed a lot
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotKey {
+    /// "" for the default collection
+    pub collection: String,
+    pub key: String,
+    /// Estimated reads & writes since the db was opened. Never lower than the
+    /// real count, can be a bit higher.
+    pub accesses: u64,
+}
+
+/// Count-min sketch, approximate counts per key in fixed memory.
+/// An estimate is never lower than the real count, collisions can only push it up.
+#[derive(Debug)]
+pub(crate) struct CountMinSketch {
+    counters: Vec<u32>,
+    width: usize,
+}
+
+impl CountMinSketch {
+    pub(crate) fn new(width: usize) -> Self {
+        CountMinSketch {
+            counters: vec![0; SKETCH_DEPTH * width],
+            width,
+        }
+    }
+
+    /// Count one more of `key` & return its new estimate
+    pub(crate) fn increment(&mut self, key: &str) -> u64 {
+        let mut estimate = u32::MAX;
+        for row in 0..SKETCH_DEPTH {
+            let slot = self.slot(row, key);
+            self.counters[slot] = self.counters[slot].saturating_add(1);
+            estimate = estimate.min(self.counters[slot]);
+        }
+        estimate as u64
+    }
+
+    pub(crate) fn estimate(&self, key: &str) -> u64 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counters[self.slot(row, key)])
+            .min()
+            .unwrap_or(0) as u64
+    }
+
+    /// Halve every counter, so old popularity fades away
+    pub(crate) fn halve(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+    }
+
+    fn slot(&self, row: usize, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        row * self.width + (hasher.finish() as usize % self.width)
+    }
+}
+
+/// Approximate per-key access counts in fixed memory.
+/// The sketch counts every key, the candidates map keeps the names of the
+/// keys with the highest counts seen so far.
+pub(crate) struct AccessTracker {
+    sketch: CountMinSketch,
+    candidates: HashMap<String, u64>,
+}
+
+impl AccessTracker {
+    pub(crate) fn new() -> Self {
+        AccessTracker {
+            sketch: CountMinSketch::new(SKETCH_WIDTH),
+            candidates: HashMap::with_capacity(TRACKED_KEYS + 1),
+        }
+    }
+
+    /// Count one access of a stored key
+    pub(crate) fn record(&mut self, key: &str) {
+        let estimate = self.sketch.increment(key);
+
+        if let Some(count) = self.candidates.get_mut(key) {
+            *count = estimate;
+            return;
+        }
+        if self.candidates.len() < TRACKED_KEYS {
+            self.candidates.insert(key.to_string(), estimate);
+            return;
+        }
+        // Push out the coldest candidate if this key has overtaken it
+        if let Some((coldest, count)) = self
+            .candidates
+            .iter()
+            .min_by_key(|(_, count)| **count)
+            .map(|(key, count)| (key.clone(), *count))
+            && estimate > count
+        {
+            self.candidates.remove(&coldest);
+            self.candidates.insert(key.to_string(), estimate);
+        }
+    }
+
+    /// Drop the names of a collection's keys, e.g. once it's purged
+    pub(crate) fn forget_collection(&mut self, collection: &str) {
+        self.candidates
+            .retain(|key, _| collection_of(key) != collection);
+    }
+
+    /// The `n` most accessed keys, most accessed first
+    pub(crate) fn top(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut top: Vec<(&str, u64)> = self
+            .candidates
+            .iter()
+            .map(|(key, count)| (key.as_str(), *count))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+impl EmbeddedDatabase {
+    /// The `n` keys that were read or written the most since the db was opened,
+    /// across all collections. Empty unless `DbOptions::track_hot_keys` is on.
+    /// Only the top 128 keys are remembered, so `n` above that gets 128 at most.
+    pub fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        let Some(tracker) = &self.access_tracker else {
+            return Vec::new();
+        };
+        tracker
+            .top(n)
+            .into_iter()
+            .map(|(stored_key, accesses)| HotKey {
+                collection: collection_of(stored_key).to_string(),
+                key: user_key(stored_key).to_string(),
+                accesses,
+            })
+            .collect()
+    }
+
+    /// Count an access of an already namespaced key, when tracking is on
+    pub(crate) fn record_access(&mut self, key: &str) {
+        if let Some(tracker) = &mut self.access_tracker {
+            tracker.record(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_hot_keys() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            track_hot_keys: true,
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        for i in 0..500 {
+            db.set(&format!("cold{i}"), "x").unwrap();
+        }
+        db.collection("users").unwrap().set("42", "Alice").unwrap();
+        for _ in 0..50 {
+            db.get("cold7").unwrap();
+            db.collection("users").unwrap().get("42").unwrap();
+        }
+        for _ in 0..20 {
+            db.get("cold3").unwrap();
+        }
+
+        let hot = db.hot_keys(3);
+        let names: Vec<(&str, &str)> = hot
+            .iter()
+            .map(|hot| (hot.collection.as_str(), hot.key.as_str()))
+            .collect();
+        assert_eq!(names, vec![("", "cold7"), ("users", "42"), ("", "cold3")]);
+        assert!(hot[0].accesses >= 51);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+
+type BuildFn = Arc<dyn Fn() -> Box<dyn Hasher> + Send + Sync>;
+
+/// Hashes the keys of the in-memory index, see `DbOptions::index_hasher`.
+/// SipHash unless the `fxhash` feature is on.
+#[derive(Clone)]
+pub struct IndexHasher {
+    name: &'static str,
+    build: BuildFn,
+}
+
+impl IndexHasher {
+    /// The standard library's SipHash with random keys, slower but safe
+    /// against keys picked to collide
+    pub fn sip() -> Self {
+        IndexHasher::custom("sip", RandomState::new())
+    }
+
+    /// FxHash, a lot quicker on short keys. Only for keys that don't come
+    /// from someone who'd want to slow the db down with collisions.
+    #[cfg(feature = "fxhash")]
+    pub fn fx() -> Self {
+        IndexHasher::custom("fx", rustc_hash::FxBuildHasher)
+    }
+
+    /// Any other hasher, e.g. `ahash::RandomState`
+    pub fn custom<S>(name: &'static str, build: S) -> Self
+    where
+        S: BuildHasher + Send + Sync + 'static,
+        S::Hasher: 'static,
+    {
+        IndexHasher {
+            name,
+            build: Arc::new(move || Box::new(build.build_hasher())),
+        }
+    }
+}
+
+impl Default for IndexHasher {
+    fn default() -> Self {
+        #[cfg(feature = "fxhash")]
+        return IndexHasher::fx();
+        #[cfg(not(feature = "fxhash"))]
+        IndexHasher::sip()
+    }
+}
+
+impl BuildHasher for IndexHasher {
+    type Hasher = Box<dyn Hasher>;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        (self.build)()
+    }
+}
+
+impl fmt::Debug for IndexHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IndexHasher({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, EmbeddedDatabase};
+    use std::hash::{BuildHasherDefault, DefaultHasher};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_custom_index_hasher() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            index_hasher: Some(IndexHasher::custom(
+                "default",
+                BuildHasherDefault::<DefaultHasher>::default(),
+            )),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options.clone()).unwrap();
+        for i in 0..100 {
+            db.set(&format!("key{i}"), &i.to_string()).unwrap();
+        }
+        db.delete("key5").unwrap();
+        db.compact().unwrap();
+        drop(db);
+
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        assert_eq!(db.get("key42").unwrap(), Some("42".to_string()));
+        assert_eq!(db.get("key5").unwrap(), None);
+        assert_eq!(db.scan_keys("key").len(), 99);
+    }
+}
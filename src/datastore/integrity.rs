@@ -0,0 +1,395 @@
+use super::{
+    DbError, EmbeddedDatabase, Result,
+    header::{FLAG_SIGNED, check_header, set_header_flags},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `[8-byte LE bytes covered][32-byte HMAC-SHA256 of those bytes]`
+const SIDECAR_LEN: usize = 8 + 32;
+
+/// Secret the data file is signed with. Whoever holds it can write a file
+/// that passes the check, so keep it off the devices that only read.
+#[derive(Clone, PartialEq)]
+pub struct MacKey([u8; 32]);
+
+impl MacKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        MacKey(bytes)
+    }
+
+    fn hmac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0).expect("HMAC takes keys of any length")
+    }
+}
+
+impl fmt::Debug for MacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MacKey(..)")
+    }
+}
+
+/// Rolling HMAC over every byte of the data file, kept in `<db file>.mac`.
+/// Every append feeds the new bytes in & rewrites the sidecar, so changing,
+/// dropping or reordering anything in the file breaks the tag.
+pub(crate) struct FileMac {
+    key: MacKey,
+    path: PathBuf,
+    sidecar: File,
+    state: HmacSha256, // Has seen the first `covered` bytes of the data file
+    covered: u64,
+}
+
+impl fmt::Debug for FileMac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileMac")
+            .field("path", &self.path)
+            .field("covered", &self.covered)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileMac {
+    /// Check the data file against its sidecar. Bytes past what the tag
+    /// covers (a write the sidecar never caught up with, or something
+    /// appended by someone else) are cut off, the rest has to match.
+    pub(crate) fn open(db_path: &Path, key: &MacKey, data: &File) -> Result<Self> {
+        let path = mac_path(db_path);
+        let pending = pending_path(db_path);
+        // A compaction that crashed after swapping the data file but before
+        // swapping the sidecar left the tag of the new file in here
+        if pending.exists() {
+            match read_sidecar(&pending)
+                .and_then(|(covered, tag)| verify(key, data, covered, &tag).map(|_| covered))
+            {
+                Ok(_) => fs::rename(&pending, &path)?,
+                Err(_) => fs::remove_file(&pending)?,
+            }
+        }
+
+        let file_len = data.metadata()?.len();
+        let (state, covered) = if path.exists() {
+            let (covered, tag) = read_sidecar(&path)?;
+            if covered > file_len {
+                return Err(tampered(format!(
+                    "the file is {file_len} bytes but {covered} were signed"
+                )));
+            }
+            let state = verify(key, data, covered, &tag)?;
+            if covered < file_len {
+                log::warn!(
+                    path:% = db_path.display(),
+                    bytes_truncated = file_len - covered;
+                    "dropped an unsigned tail"
+                );
+                data.set_len(covered)?;
+            }
+            (state, covered)
+        } else if file_len == 0 {
+            (key.hmac(), 0)
+        } else {
+            return Err(tampered(format!(
+                "{} is missing, sign the file with EmbeddedDatabase::sign_file first",
+                path.display()
+            )));
+        };
+
+        let sidecar = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut mac = FileMac {
+            key: key.clone(),
+            path,
+            sidecar,
+            state,
+            covered,
+        };
+        mac.write_sidecar()?;
+        Ok(mac)
+    }
+
+    /// Sign bytes that were just appended to the data file
+    pub(crate) fn append(&mut self, bytes: &[u8]) -> Result<()> {
+        let before = self.state.clone();
+        self.state.update(bytes);
+        self.covered += bytes.len() as u64;
+        if let Err(err) = self.write_sidecar() {
+            self.state = before;
+            self.covered -= bytes.len() as u64;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Sign the data file from scratch, after it was cut short
+    pub(crate) fn rebuild(&mut self, data: &File) -> Result<()> {
+        let len = data.metadata()?.len();
+        self.state = hash_prefix(&self.key, data, len)?;
+        self.covered = len;
+        self.write_sidecar()
+    }
+
+    /// A fresh tag for compaction to feed the new data file into
+    pub(crate) fn start_over(&self) -> HmacSha256 {
+        self.key.hmac()
+    }
+
+    /// Park the tag of a compacted file next to the sidecar, before the
+    /// compacted file replaces the data file
+    pub(crate) fn stage(&self, db_path: &Path, state: &HmacSha256, covered: u64) -> Result<()> {
+        let mut file = File::create(pending_path(db_path))?;
+        file.write_all(&encode_sidecar(state, covered))?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Switch over to the staged tag once the compacted file is in place
+    pub(crate) fn commit_staged(
+        &mut self,
+        db_path: &Path,
+        state: HmacSha256,
+        covered: u64,
+    ) -> Result<()> {
+        fs::rename(pending_path(db_path), &self.path)?;
+        self.sidecar = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.state = state;
+        self.covered = covered;
+        Ok(())
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.sidecar.sync_data()?;
+        Ok(())
+    }
+
+    fn write_sidecar(&mut self) -> Result<()> {
+        self.sidecar.seek(SeekFrom::Start(0))?;
+        self.sidecar
+            .write_all(&encode_sidecar(&self.state, self.covered))?;
+        Ok(())
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Sign an existing data file with `key`, so it can be opened with
+    /// `DbOptions::mac_key`. Whatever the file holds right now is trusted.
+    /// From then on the header says the file is signed & handles without the
+    /// key can't open it for writing. A file from before headers only gets
+    /// that once it is compacted.
+    pub fn sign_file<P: AsRef<Path>>(path: P, key: &MacKey) -> Result<()> {
+        let path = path.as_ref();
+        let mut data = OpenOptions::new().read(true).write(true).open(path)?;
+        // Flagged first: a crash before the sidecar is written leaves a file
+        // no handle writes to until it is signed again
+        if data.metadata()?.len() > 0 {
+            let header = check_header(&mut data)?;
+            if header.version > 0 && header.flags & FLAG_SIGNED == 0 {
+                set_header_flags(&mut data, header.flags | FLAG_SIGNED)?;
+            }
+        }
+        let len = data.metadata()?.len();
+        let state = hash_prefix(key, &data, len)?;
+        fs::write(mac_path(path), encode_sidecar(&state, len))?;
+        Ok(())
+    }
+}
+
+/// Check that all of a file is signed & matches its sidecar, without
+/// touching either of them
+pub(crate) fn verify_whole_file(db_path: &Path, key: &MacKey, data: &File) -> Result<()> {
+    let (covered, tag) = read_sidecar(&mac_path(db_path))?;
+    let file_len = data.metadata()?.len();
+    if covered != file_len {
+        return Err(tampered(format!(
+            "the file is {file_len} bytes but {covered} were signed"
+        )));
+    }
+    verify(key, data, covered, &tag)?;
+    Ok(())
+}
+
+/// HMAC of the first `len` bytes of the data file
+fn hash_prefix(key: &MacKey, data: &File, len: u64) -> Result<HmacSha256> {
+    let mut state = key.hmac();
+    let mut reader = data.try_clone()?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut reader = reader.take(len);
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut read = 0;
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        state.update(&chunk[..n]);
+        read += n as u64;
+    }
+    if read < len {
+        return Err(tampered(format!(
+            "the file ended after {read} of {len} signed bytes"
+        )));
+    }
+    Ok(state)
+}
+
+/// Check the first `covered` bytes against `tag` & hand back the HMAC state
+/// to keep appending to
+fn verify(key: &MacKey, data: &File, covered: u64, tag: &[u8]) -> Result<HmacSha256> {
+    let state = hash_prefix(key, data, covered)?;
+    state
+        .clone()
+        .verify_slice(tag)
+        .map_err(|_| tampered(format!("the first {covered} bytes don't match their MAC")))?;
+    Ok(state)
+}
+
+fn encode_sidecar(state: &HmacSha256, covered: u64) -> Vec<u8> {
+    let mut sidecar = Vec::with_capacity(SIDECAR_LEN);
+    sidecar.extend_from_slice(&covered.to_le_bytes());
+    sidecar.extend_from_slice(&state.clone().finalize().into_bytes());
+    sidecar
+}
+
+fn read_sidecar(path: &Path) -> Result<(u64, Vec<u8>)> {
+    let sidecar = fs::read(path)?;
+    if sidecar.len() != SIDECAR_LEN {
+        return Err(tampered(format!("{} is not a MAC file", path.display())));
+    }
+    let (covered, tag) = sidecar.split_at(8);
+    let covered = u64::from_le_bytes(covered.try_into()?);
+    Ok((covered, tag.to_vec()))
+}
+
+fn tampered(reason: String) -> Box<dyn std::error::Error> {
+    DbError::IntegrityCheckFailed { reason }.into()
+}
+
+/// `<db file>.mac`
+fn mac_path(db_path: &Path) -> PathBuf {
+    let mut file_name = db_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".mac");
+    db_path.with_file_name(file_name)
+}
+
+/// `<db file>.mac.compact`, the tag of a compacted file until it's in place
+fn pending_path(db_path: &Path) -> PathBuf {
+    let mut file_name = db_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".mac.compact");
+    db_path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, Record, RecordKind, datastore::database::encode_frame};
+    use tempfile::NamedTempFile;
+
+    fn signed(key: &MacKey) -> DbOptions {
+        DbOptions {
+            mac_key: Some(key.clone()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let key = MacKey::new([7; 32]);
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), signed(&key)).unwrap();
+        db.set("price", "100").unwrap();
+        db.set("stale", "x").unwrap();
+        db.delete("stale").unwrap();
+        db.compact().unwrap();
+        db.set("name", "widget").unwrap();
+        drop(db);
+
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), signed(&key)).unwrap();
+        assert_eq!(db.get("price").unwrap(), Some("100".to_string()));
+        drop(db);
+
+        // Flip the price in place, the file still parses fine
+        let mut raw = fs::read(temp_file.path()).unwrap();
+        let at = raw.windows(3).position(|window| window == b"100").unwrap();
+        raw[at] = b'9';
+        fs::write(temp_file.path(), &raw).unwrap();
+
+        let Err(err) = EmbeddedDatabase::with_options(temp_file.path(), signed(&key)) else {
+            panic!("a tampered file opened");
+        };
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::IntegrityCheckFailed { .. })
+        ));
+        // Nor does it pass with another key
+        raw[at] = b'1';
+        fs::write(temp_file.path(), &raw).unwrap();
+        assert!(
+            EmbeddedDatabase::with_options(temp_file.path(), signed(&MacKey::new([8; 32])))
+                .is_err()
+        );
+        assert!(EmbeddedDatabase::with_options(temp_file.path(), signed(&key)).is_ok());
+    }
+
+    #[test]
+    fn test_unsigned_tail_is_dropped() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("a", "1").unwrap();
+        drop(db);
+
+        let key = MacKey::new([7; 32]);
+        assert!(EmbeddedDatabase::with_options(temp_file.path(), signed(&key)).is_err());
+        EmbeddedDatabase::sign_file(temp_file.path(), &key).unwrap();
+
+        // A handle without the key can't write to a signed file anymore
+        let err = EmbeddedDatabase::new(temp_file.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::IntegrityCheckFailed { .. })
+        ));
+        assert!(
+            EmbeddedDatabase::with_options(
+                temp_file.path(),
+                DbOptions {
+                    read_only: true,
+                    ..Default::default()
+                }
+            )
+            .is_ok()
+        );
+
+        // Someone appends a record around the db
+        let record = Record {
+            key: "b".to_string(),
+            val: b"2".to_vec(),
+            tombstone: false,
+            expires_at: None,
+            transforms: Vec::new(),
+            kind: RecordKind::Single,
+            seq: 2,
+        };
+        let mut frame = Vec::new();
+        encode_frame(&record, &mut frame).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(temp_file.path())
+            .unwrap();
+        file.write_all(&frame).unwrap();
+        drop(file);
+
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), signed(&key)).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("1".to_string()));
+        assert_eq!(db.get("b").unwrap(), None);
+    }
+}
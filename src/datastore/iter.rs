@@ -0,0 +1,178 @@
+use super::{
+    EmbeddedDatabase, Record, Result, ThreadSafeDB,
+    collection::{collection_of, user_key},
+    database::{frame_len, read_record_at},
+    transform::TransformerRegistry,
+};
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    sync::Arc,
+    vec,
+};
+
+/// Iterator over a pinned copy of the index.
+/// It reads values through its own handle to the data file, so it sees the
+/// keys & values exactly as they were when it was created: every key that was
+/// live at that moment is returned once, in key order, no matter what is
+/// written, deleted or compacted while it runs.
+pub struct SnapshotIter {
+    file: File,
+    entries: vec::IntoIter<(String, u64)>, // Stored key & offset of its record
+    transformers: Arc<TransformerRegistry>,
+    seq: u64,
+    version: u32, // Format of the file the handle points at
+}
+
+impl SnapshotIter {
+    pub(crate) fn new(
+        file: File,
+        entries: Vec<(String, u64)>,
+        transformers: Arc<TransformerRegistry>,
+        seq: u64,
+        version: u32,
+    ) -> Self {
+        SnapshotIter {
+            file,
+            entries: entries.into_iter(),
+            transformers,
+            seq,
+            version,
+        }
+    }
+
+    /// Sequence number of the last write the snapshot includes
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (stored_key, offset) = self.entries.next()?;
+        let val = read_record_at(&mut self.file, offset, self.version)
+            .and_then(|record| self.transformers.decode(record))
+            .and_then(|val| Ok(String::from_utf8(val)?));
+        Some(val.map(|val| (user_key(&stored_key).to_string(), val)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+/// Iterator over the default collection that reads the data file front to
+/// back, returning each live record as it gets to it. Nothing is collected
+/// up front, not even the keys, which suits dumps of big databases. Keys come
+/// in file order, not key order. It borrows the db, so nothing can be written
+/// while it runs.
+pub struct Iter<'a> {
+    db: &'a EmbeddedDatabase,
+    reader: BufReader<File>,
+    position: u64,
+    /// Set after the first error, a broken frame would otherwise be read
+    /// over & over
+    done: bool,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(db: &'a EmbeddedDatabase) -> Result<Self> {
+        Ok(Iter {
+            db,
+            reader: BufReader::new(File::open(&db.path)?),
+            position: 0,
+            done: false,
+        })
+    }
+
+    fn next_record(&mut self) -> Result<Option<(u64, Record)>> {
+        // Anything past the last append is reserved space or a torn write
+        if self.position >= self.db.end_of_data {
+            return Ok(None);
+        }
+        let mut len_buffer = [0u8; 8];
+        self.reader.read_exact(&mut len_buffer)?;
+        let len = frame_len(len_buffer, self.position, self.db.end_of_data)?;
+        let mut record_buffer = vec![0u8; len];
+        self.reader.read_exact(&mut record_buffer)?;
+        let offset = self.position;
+        self.position += 8 + len as u64;
+        let record = Record::decode(&record_buffer, self.db.format_version)?;
+        Ok(Some((offset, record)))
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let (offset, record) = match self.next_record() {
+                Ok(Some(next)) => next,
+                Ok(None) => return None,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            };
+            // Only the version the index points at is live
+            if !collection_of(&record.key).is_empty()
+                || self.db.live_span(&record.key).map(|(at, _)| at) != Some(offset)
+            {
+                continue;
+            }
+            let key = record.key.clone();
+            let val = self
+                .db
+                .transformers
+                .decode(record)
+                .and_then(|val| Ok(String::from_utf8(val)?));
+            self.done = val.is_err();
+            return Some(val.map(|val| (key, val)));
+        }
+    }
+}
+
+/// Iterator that walks the keys that were live when it was created, but reads
+/// each value at the moment it is returned, taking the lock once per item.
+/// Writers are free to run in between: a key is never returned twice, keys
+/// deleted before the iterator reaches them are skipped, values are always the
+/// latest ones & keys added after the iterator was created are not returned.
+pub struct LiveIter {
+    db: ThreadSafeDB,
+    keys: vec::IntoIter<String>, // Stored keys, sorted
+}
+
+impl LiveIter {
+    pub(crate) fn new(db: ThreadSafeDB, keys: Vec<String>) -> Self {
+        LiveIter {
+            db,
+            keys: keys.into_iter(),
+        }
+    }
+}
+
+impl Iterator for LiveIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for stored_key in self.keys.by_ref() {
+            let val = match self.db.lock() {
+                Ok(mut db) => db.get_stored(&stored_key),
+                Err(err) => Err(err),
+            };
+            match val {
+                Ok(Some(val)) => return Some(Ok((user_key(&stored_key).to_string(), val))),
+                // Deleted since the iterator was created
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,277 @@
+//! Order-preserving key encodings.
+//! Keys are compared byte by byte, so "10" sorts before "9" & range scans over
+//! numbers built with `format!` come back in the wrong order. The encodings
+//! here sort exactly like the values they encode:
+//! * integers & timestamps become fixed width big-endian hex, signed ones
+//!   with the sign bit flipped so negatives come first
+//! * strings get their \x01 escaped & end in \x01\x01, so a string sorts
+//!   before everything it is a prefix of & can't run into the next part
+//! * tuples are their parts one after the other, every part knows where it ends
+//!
+//! Everything encodes to plain (non-NUL) text, so it can be used as a key as is.
+
+use super::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Ends a string part. Sorts below every other character a key can hold.
+const STRING_END: &str = "\u{1}\u{1}";
+/// A literal \x01 inside a string part
+const ESCAPED_ONE: &str = "\u{1}\u{2}";
+
+/// A value that can be written as part of an order-preserving key
+pub trait KeyEncode {
+    /// Append the encoded value to `out`
+    fn encode_into(&self, out: &mut String);
+}
+
+/// A value that can be read back from an order-preserving key
+pub trait KeyPart: KeyEncode + Sized {
+    /// Decode a value from the front of `input` & move `input` past it
+    fn decode_from(input: &mut &str) -> Result<Self>;
+}
+
+/// Encode a value (or a tuple of values) as a key
+pub fn encode<T: KeyEncode + ?Sized>(value: &T) -> String {
+    let mut out = String::new();
+    value.encode_into(&mut out);
+    out
+}
+
+/// Turn a key made by `encode` back into its value
+pub fn decode<T: KeyPart>(key: &str) -> Result<T> {
+    let mut input = key;
+    let value = T::decode_from(&mut input)?;
+    if !input.is_empty() {
+        return Err(format!("{} trailing characters after the key", input.len()).into());
+    }
+    Ok(value)
+}
+
+/// Split the next `n` characters off the front of `input`
+fn take<'a>(input: &mut &'a str, n: usize) -> Result<&'a str> {
+    if input.len() < n || !input.is_char_boundary(n) {
+        return Err("key ends in the middle of a part".into());
+    }
+    let (part, rest) = input.split_at(n);
+    *input = rest;
+    Ok(part)
+}
+
+impl KeyEncode for u64 {
+    fn encode_into(&self, out: &mut String) {
+        out.push_str(&format!("{self:016x}"));
+    }
+}
+
+impl KeyPart for u64 {
+    fn decode_from(input: &mut &str) -> Result<Self> {
+        Ok(u64::from_str_radix(take(input, 16)?, 16)?)
+    }
+}
+
+impl KeyEncode for i64 {
+    fn encode_into(&self, out: &mut String) {
+        // Flipping the sign bit puts i64::MIN at 0 & i64::MAX at u64::MAX
+        ((*self as u64) ^ (1 << 63)).encode_into(out);
+    }
+}
+
+impl KeyPart for i64 {
+    fn decode_from(input: &mut &str) -> Result<Self> {
+        Ok((u64::decode_from(input)? ^ (1 << 63)) as i64)
+    }
+}
+
+/// Millisecond precision, times before 1970 sort before the ones after
+impl KeyEncode for SystemTime {
+    fn encode_into(&self, out: &mut String) {
+        let millis = match self.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_millis() as i64,
+            Err(before) => -(before.duration().as_millis() as i64),
+        };
+        millis.encode_into(out);
+    }
+}
+
+impl KeyPart for SystemTime {
+    fn decode_from(input: &mut &str) -> Result<Self> {
+        let millis = i64::decode_from(input)?;
+        let offset = Duration::from_millis(millis.unsigned_abs());
+        Ok(if millis >= 0 {
+            UNIX_EPOCH + offset
+        } else {
+            UNIX_EPOCH - offset
+        })
+    }
+}
+
+impl KeyEncode for str {
+    fn encode_into(&self, out: &mut String) {
+        out.push_str(&self.replace('\u{1}', ESCAPED_ONE));
+        out.push_str(STRING_END);
+    }
+}
+
+impl KeyEncode for String {
+    fn encode_into(&self, out: &mut String) {
+        self.as_str().encode_into(out);
+    }
+}
+
+impl<T: KeyEncode + ?Sized> KeyEncode for &T {
+    fn encode_into(&self, out: &mut String) {
+        (**self).encode_into(out);
+    }
+}
+
+impl KeyPart for String {
+    fn decode_from(input: &mut &str) -> Result<Self> {
+        let mut decoded = String::new();
+        loop {
+            let Some(at) = input.find('\u{1}') else {
+                return Err("string part of the key isn't terminated".into());
+            };
+            decoded.push_str(take(input, at)?);
+            match take(input, 2)? {
+                STRING_END => return Ok(decoded),
+                ESCAPED_ONE => decoded.push('\u{1}'),
+                _ => return Err("bad escape in a string part of the key".into()),
+            }
+        }
+    }
+}
+
+impl<A: KeyEncode, B: KeyEncode> KeyEncode for (A, B) {
+    fn encode_into(&self, out: &mut String) {
+        self.0.encode_into(out);
+        self.1.encode_into(out);
+    }
+}
+
+impl<A: KeyPart, B: KeyPart> KeyPart for (A, B) {
+    fn decode_from(input: &mut &str) -> Result<Self> {
+        Ok((A::decode_from(input)?, B::decode_from(input)?))
+    }
+}
+
+impl<A: KeyEncode, B: KeyEncode, C: KeyEncode> KeyEncode for (A, B, C) {
+    fn encode_into(&self, out: &mut String) {
+        self.0.encode_into(out);
+        self.1.encode_into(out);
+        self.2.encode_into(out);
+    }
+}
+
+impl<A: KeyPart, B: KeyPart, C: KeyPart> KeyPart for (A, B, C) {
+    fn decode_from(input: &mut &str) -> Result<Self> {
+        Ok((
+            A::decode_from(input)?,
+            B::decode_from(input)?,
+            C::decode_from(input)?,
+        ))
+    }
+}
+
+/// Builds a multi-part key, one canonical encoding per list of parts:
+/// `KeyBuilder::new().push("tenant").push(user_id).build()`.
+/// Parts never bleed into each other, whatever characters they hold, so the
+/// key of the first few parts is a prefix of exactly the keys that start
+/// with those parts & can be handed to `scan_keys` as is.
+#[derive(Debug, Clone, Default)]
+pub struct KeyBuilder {
+    key: String,
+}
+
+impl KeyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<T: KeyEncode>(mut self, part: T) -> Self {
+        part.encode_into(&mut self.key);
+        self
+    }
+
+    pub fn build(self) -> String {
+        self.key
+    }
+}
+
+/// Reads the parts of a key made by `KeyBuilder` back, in the order they were pushed
+#[derive(Debug, Clone)]
+pub struct KeyParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> KeyParser<'a> {
+    pub fn new(key: &'a str) -> Self {
+        KeyParser { rest: key }
+    }
+
+    /// Decode the next part as a `T`
+    pub fn next_part<T: KeyPart>(&mut self) -> Result<T> {
+        T::decode_from(&mut self.rest)
+    }
+
+    /// Whether every part has been read
+    pub fn is_done(&self) -> bool {
+        self.rest.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encodings_sort_like_their_values() {
+        let numbers = [-500i64, -1, 0, 9, 10, i64::MAX];
+        let keys: Vec<String> = numbers.iter().map(encode).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        let decoded: Vec<i64> = keys.iter().map(|key| decode(key).unwrap()).collect();
+        assert_eq!(decoded, numbers);
+
+        let tuples = [
+            ("ab".to_string(), 2u64),
+            ("ab".to_string(), 10),
+            ("ab\u{1}".to_string(), 0),
+            ("abc".to_string(), 0),
+        ];
+        let keys: Vec<String> = tuples.iter().map(encode).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        for (key, tuple) in keys.iter().zip(&tuples) {
+            assert_eq!(&decode::<(String, u64)>(key).unwrap(), tuple);
+        }
+
+        let before = UNIX_EPOCH - Duration::from_secs(60);
+        let after = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        assert!(encode(&before) < encode(&after));
+        assert_eq!(decode::<SystemTime>(&encode(&before)).unwrap(), before);
+        assert!(decode::<u64>("12").is_err());
+    }
+
+    #[test]
+    fn test_key_builder_with_prefix_scans() {
+        let temp_file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        let mut db = crate::EmbeddedDatabase::new(temp_file.path()).unwrap();
+        // With format!("{}:{}") "acme:1" & "acme" + ":1" would be the same key
+        for (tenant, user) in [("acme", 2u64), ("acme", 10), ("acme:1", 1), ("acmex", 1)] {
+            let key = KeyBuilder::new().push(tenant).push(user).build();
+            db.set(&key, "user").unwrap();
+        }
+
+        let prefix = KeyBuilder::new().push("acme").build();
+        let users: Vec<u64> = db
+            .scan_keys(&prefix)
+            .iter()
+            .map(|key| {
+                let mut parser = KeyParser::new(key);
+                assert_eq!(parser.next_part::<String>().unwrap(), "acme");
+                let user = parser.next_part::<u64>().unwrap();
+                assert!(parser.is_done());
+                user
+            })
+            .collect();
+        assert_eq!(users, vec![2, 10]);
+    }
+}
@@ -0,0 +1,115 @@
+use super::{EmbeddedDatabase, Result, database::validate_plain_key};
+use std::time::Duration;
+
+/// Cooperative locking on top of TTLs: a lease is a key whose value is the
+/// owner's name and which expires on its own if the owner stops renewing it.
+impl EmbeddedDatabase {
+    /// Take the lease if nobody holds it (or the previous holder let it expire).
+    /// Returns whether `owner` now holds the lease.
+    pub fn try_lease(&mut self, key: &str, owner: &str, ttl: Duration) -> Result<bool> {
+        validate_plain_key(key)?;
+        self.put_if_absent(key.to_string(), owner.as_bytes(), Some(ttl))
+    }
+
+    /// Push the expiry of a lease `owner` holds `ttl` into the future.
+    /// Returns false if the lease expired or was taken by someone else.
+    pub fn renew(&mut self, key: &str, owner: &str, ttl: Duration) -> Result<bool> {
+        validate_plain_key(key)?;
+        if !self.holds_lease(key, owner)? {
+            return Ok(false);
+        }
+        self.put(key.to_string(), owner.as_bytes(), Some(ttl))?;
+        Ok(true)
+    }
+
+    /// Give up a lease before it expires.
+    /// Returns false if `owner` wasn't holding it (anymore).
+    pub fn release(&mut self, key: &str, owner: &str) -> Result<bool> {
+        validate_plain_key(key)?;
+        if !self.holds_lease(key, owner)? {
+            return Ok(false);
+        }
+        self.remove(key.to_string())?;
+        Ok(true)
+    }
+
+    /// Write a tombstone for every expired key of the default collection
+    /// starting with `prefix`, all in one batch. Tidies up lock & lease
+    /// namespaces for good, where `sweep_expired` only drops keys from the index.
+    /// Returns the number of keys deleted.
+    pub fn cleanup_expired(&mut self, prefix: &str) -> Result<usize> {
+        validate_plain_key(prefix)?;
+        let expired = self.expired_keys(prefix);
+        let count = expired.len();
+        self.commit_writes(expired.into_iter().map(|key| (key, None)).collect(), None)?;
+        Ok(count)
+    }
+
+    fn holds_lease(&mut self, key: &str, owner: &str) -> Result<bool> {
+        Ok(self.get_stored(key)?.is_some_and(|holder| holder == owner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, MockClock};
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_cleanup_expired() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let clock = MockClock::default();
+        let options = DbOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        for i in 0..5 {
+            db.try_lease(
+                &format!("locks/{i}"),
+                "a",
+                Duration::from_secs(10 * (i + 1)),
+            )
+            .unwrap();
+        }
+        db.try_lease("other/lock", "a", Duration::from_secs(1))
+            .unwrap();
+        clock.advance(Duration::from_secs(30));
+
+        let seq = db.last_seq();
+        assert_eq!(db.cleanup_expired("locks/").unwrap(), 3);
+        assert_eq!(db.last_seq(), seq + 1);
+        assert_eq!(db.cleanup_expired("locks/").unwrap(), 0);
+        assert_eq!(db.scan_keys("locks/"), vec!["locks/3", "locks/4"]);
+        // Other prefixes are left alone
+        assert_eq!(db.expired_keys("other/"), vec!["other/lock"]);
+    }
+
+    #[test]
+    fn test_lease_lifecycle() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let ttl = Duration::from_millis(50);
+
+        assert!(db.try_lease("jobs/lock", "a", ttl).unwrap());
+        assert!(!db.try_lease("jobs/lock", "b", ttl).unwrap());
+        assert!(!db.renew("jobs/lock", "b", ttl).unwrap());
+        assert!(!db.release("jobs/lock", "b").unwrap());
+        assert!(db.renew("jobs/lock", "a", ttl).unwrap());
+
+        // Once "a" stops renewing, the lease is up for grabs again
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(!db.renew("jobs/lock", "a", ttl).unwrap());
+        assert!(db.try_lease("jobs/lock", "b", ttl).unwrap());
+        assert!(db.release("jobs/lock", "b").unwrap());
+        assert!(db.try_lease("jobs/lock", "a", ttl).unwrap());
+
+        // An empty owner is a name like any other
+        assert!(db.try_lease("empty/lock", "", ttl).unwrap());
+        assert!(!db.try_lease("empty/lock", "a", ttl).unwrap());
+        assert!(db.renew("empty/lock", "", ttl).unwrap());
+        assert!(db.release("empty/lock", "").unwrap());
+    }
+}
@@ -0,0 +1,175 @@
+use super::{DbError, DbOptions, Result, Schedule, ShardedDb, Task, ThreadSafeDB};
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+};
+
+/// What `DbManager::open_all` needs to open one database
+#[derive(Debug, Clone)]
+pub struct DbSpec {
+    pub name: String,
+    pub path: PathBuf,
+    pub options: DbOptions,
+}
+
+impl DbSpec {
+    pub fn new(name: &str, path: impl Into<PathBuf>) -> Self {
+        DbSpec {
+            name: name.to_string(),
+            path: path.into(),
+            options: DbOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: DbOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// Owns the databases of a service, looked up by name
+#[derive(Default)]
+pub struct DbManager {
+    databases: HashMap<String, ThreadSafeDB>,
+}
+
+impl DbManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open every database, rebuilding at most `max_parallel` indexes at the same time.
+    /// If any of them fails to open the first error is returned & nothing is kept open.
+    pub fn open_all(specs: Vec<DbSpec>, max_parallel: usize) -> Result<Self> {
+        let workers = max_parallel.clamp(1, specs.len().max(1));
+        let queue = Mutex::new(specs);
+        let opened = Mutex::new(Vec::new());
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    loop {
+                        // Take the next database to open, stop when none are left
+                        let Some(spec) = queue.lock().ok().and_then(|mut queue| queue.pop()) else {
+                            break;
+                        };
+                        let result = ThreadSafeDB::with_options(&spec.path, spec.options)
+                            .map_err(|err| sendable(&spec.name, err));
+                        if let Ok(mut opened) = opened.lock() {
+                            opened.push((spec.name, result));
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut manager = DbManager::new();
+        let opened = opened
+            .into_inner()
+            .map_err(|_| "a database open thread panicked")?;
+        for (name, result) in opened {
+            match result {
+                Ok(db) => manager.databases.insert(name, db),
+                Err(err) => return Err(err),
+            };
+        }
+        Ok(manager)
+    }
+
+    /// Open a single database & manage it under `name`
+    pub fn open(
+        &mut self,
+        name: &str,
+        path: impl Into<PathBuf>,
+        options: DbOptions,
+    ) -> Result<ThreadSafeDB> {
+        let db = ThreadSafeDB::with_options(path.into(), options)?;
+        self.databases.insert(name.to_string(), db.clone());
+        Ok(db)
+    }
+
+    /// Open a database split over `shards` files in `dir`, see `ShardedDb`.
+    /// Each shard is managed as `{name}/{shard}`, so `schedule` reaches them too.
+    pub fn open_sharded(
+        &mut self,
+        name: &str,
+        dir: impl AsRef<Path>,
+        shards: usize,
+        options: DbOptions,
+    ) -> Result<ShardedDb> {
+        let db = ShardedDb::open(dir.as_ref(), shards, options)?;
+        for shard in 0..db.shard_count() {
+            if let Some(handle) = db.shard(shard) {
+                self.databases
+                    .insert(format!("{name}/{shard}"), handle.clone());
+            }
+        }
+        Ok(db)
+    }
+
+    pub fn get(&self, name: &str) -> Option<ThreadSafeDB> {
+        self.databases.get(name).cloned()
+    }
+
+    /// Stop managing a database. It is closed once every handle to it is dropped.
+    pub fn remove(&mut self, name: &str) -> Option<ThreadSafeDB> {
+        self.databases.remove(name)
+    }
+
+    /// Names of the managed databases, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.databases.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Schedule `task` on every managed database, see `ThreadSafeDB::schedule`.
+    /// Databases opened later don't get it.
+    pub fn schedule(&self, name: &str, schedule: Schedule, task: Task) -> Result<()> {
+        for db in self.databases.values() {
+            db.schedule(name, schedule.clone(), task.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors have to be Send to leave the open threads.
+/// Our own errors are kept as they are so callers can still match on them.
+fn sendable(name: &str, err: Box<dyn Error>) -> Box<dyn Error + Send + Sync> {
+    match err.downcast::<DbError>() {
+        Ok(err) => err,
+        Err(err) => format!("failed to open {name}: {err}").into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_open_all_in_parallel() {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let specs: Vec<DbSpec> = (0..8)
+            .map(|i| DbSpec::new(&format!("db{i}"), dir.path().join(format!("db{i}.db"))))
+            .collect();
+
+        let manager = DbManager::open_all(specs.clone(), 3).unwrap();
+        assert_eq!(manager.names().len(), 8);
+        manager.get("db3").unwrap().set("key", "value").unwrap();
+        drop(manager);
+
+        let manager = DbManager::open_all(specs, 3).unwrap();
+        assert_eq!(
+            manager.get("db3").unwrap().get("key").unwrap(),
+            Some("value".to_string())
+        );
+
+        // A database that can't be opened fails the whole thing
+        let broken = vec![DbSpec::new("missing", dir.path().join("no/such/dir/x.db"))];
+        assert!(DbManager::open_all(broken, 2).is_err());
+    }
+}
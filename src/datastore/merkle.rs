@@ -0,0 +1,215 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{SYSTEM_COLLECTION, collection_of, user_key},
+};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+
+/// Levels below the root, the tree has 2^DEPTH leaves
+const DEPTH: usize = 10;
+
+type Hash = [u8; 32];
+
+/// How a key differs between two databases
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Difference {
+    /// Live here, missing (deleted or expired) in the other db
+    OnlyInSelf,
+    OnlyInOther,
+    /// Live in both with different values
+    ValueDiffers,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyDifference {
+    /// "" for the default collection
+    pub collection: String,
+    pub key: String,
+    pub difference: Difference,
+}
+
+/// Hash tree over the live keys & values of a database.
+/// Keys go into one of 1024 leaves by the hash of the key, every inner node
+/// hashes its two children. Two copies only have to compare the nodes under
+/// the ones that differ, starting at the root, & then the entries of the
+/// leaves that still differ, instead of every key they hold. `node` &
+/// `leaf_entries` are what a sync protocol would send over the wire.
+/// The internal `__system` collection (consumer offsets & such) is local to
+/// every copy & left out.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>, // levels[0] is the root, the last level are the leaves
+    leaves: Vec<Vec<(String, Hash)>>, // (stored key, value hash), sorted by key
+}
+
+impl MerkleTree {
+    pub fn root(&self) -> Hash {
+        self.levels[0][0]
+    }
+
+    /// Hash of the `index`th node at `depth`, the root is at depth 0 & the
+    /// leaves at depth 10
+    pub fn node(&self, depth: usize, index: usize) -> Option<Hash> {
+        self.levels.get(depth)?.get(index).copied()
+    }
+
+    /// Stored keys & value hashes of a leaf
+    pub fn leaf_entries(&self, leaf: usize) -> &[(String, Hash)] {
+        self.leaves.get(leaf).map_or(&[], Vec::as_slice)
+    }
+
+    /// Keys that differ between the two trees, sorted. Only the subtrees
+    /// whose hashes differ are looked into.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<KeyDifference> {
+        let mut differences = Vec::new();
+        let mut differing = vec![0];
+        for depth in 0..DEPTH {
+            differing = differing
+                .into_iter()
+                .filter(|&index| self.levels[depth][index] != other.levels[depth][index])
+                .flat_map(|index| [index * 2, index * 2 + 1])
+                .collect();
+        }
+        for leaf in differing {
+            if self.levels[DEPTH][leaf] != other.levels[DEPTH][leaf] {
+                diff_entries(&self.leaves[leaf], &other.leaves[leaf], &mut differences);
+            }
+        }
+        differences.sort_by(|a, b| (&a.collection, &a.key).cmp(&(&b.collection, &b.key)));
+        differences
+    }
+}
+
+/// Merge the sorted entries of the same leaf in both trees
+fn diff_entries(ours: &[(String, Hash)], theirs: &[(String, Hash)], out: &mut Vec<KeyDifference>) {
+    let mut push = |key: &str, difference| {
+        out.push(KeyDifference {
+            collection: collection_of(key).to_string(),
+            key: user_key(key).to_string(),
+            difference,
+        })
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < ours.len() || j < theirs.len() {
+        let order = match (ours.get(i), theirs.get(j)) {
+            (Some(a), Some(b)) => a.0.cmp(&b.0),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => {
+                push(&ours[i].0, Difference::OnlyInSelf);
+                i += 1;
+            }
+            Ordering::Greater => {
+                push(&theirs[j].0, Difference::OnlyInOther);
+                j += 1;
+            }
+            Ordering::Equal => {
+                if ours[i].1 != theirs[j].1 {
+                    push(&ours[i].0, Difference::ValueDiffers);
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+}
+
+fn leaf_of(stored_key: &str) -> usize {
+    let hash = Sha256::digest(stored_key.as_bytes());
+    u16::from_be_bytes([hash[0], hash[1]]) as usize % (1 << DEPTH)
+}
+
+impl EmbeddedDatabase {
+    /// Build the hash tree of everything that's live right now. Reads every
+    /// value, values are hashed decoded so copies with different compression
+    /// or encryption settings still compare equal.
+    pub fn merkle_tree(&mut self) -> Result<MerkleTree> {
+        let mut leaves: Vec<Vec<(String, Hash)>> = vec![Vec::new(); 1 << DEPTH];
+        for key in self.all_live_keys() {
+            if collection_of(&key) == SYSTEM_COLLECTION {
+                continue;
+            }
+            let Some(val) = self.get_stored(&key)? else {
+                continue;
+            };
+            let hash: Hash = Sha256::digest(val.as_bytes()).into();
+            leaves[leaf_of(&key)].push((key, hash));
+        }
+
+        let mut level: Vec<Hash> = leaves
+            .iter()
+            .map(|entries| {
+                let mut hasher = Sha256::new();
+                for (key, hash) in entries {
+                    hasher.update((key.len() as u64).to_le_bytes());
+                    hasher.update(key.as_bytes());
+                    hasher.update(hash);
+                }
+                hasher.finalize().into()
+            })
+            .collect();
+        let mut levels = vec![level.clone()];
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| {
+                    let mut hasher = Sha256::new();
+                    hasher.update(pair[0]);
+                    hasher.update(pair[1]);
+                    hasher.finalize().into()
+                })
+                .collect();
+            levels.push(level.clone());
+        }
+        levels.reverse();
+
+        Ok(MerkleTree { levels, leaves })
+    }
+
+    /// Keys whose live values differ between this db & `other`
+    pub fn diff(&mut self, other: &mut EmbeddedDatabase) -> Result<Vec<KeyDifference>> {
+        Ok(self.merkle_tree()?.diff(&other.merkle_tree()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_diff() {
+        let (file_a, file_b) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let mut a = EmbeddedDatabase::new(file_a.path()).unwrap();
+        let mut b = EmbeddedDatabase::new(file_b.path()).unwrap();
+        for i in 0..2000 {
+            let (key, val) = (format!("key{i}"), format!("val{i}"));
+            a.set(&key, &val).unwrap();
+            b.set(&key, &val).unwrap();
+        }
+        assert_eq!(
+            a.merkle_tree().unwrap().root(),
+            b.merkle_tree().unwrap().root()
+        );
+        assert!(a.diff(&mut b).unwrap().is_empty());
+
+        a.set("key7", "changed").unwrap();
+        a.delete("key8").unwrap();
+        b.collection("users").unwrap().set("42", "Alice").unwrap();
+        let differences = a.diff(&mut b).unwrap();
+        let found: Vec<(&str, &str, Difference)> = differences
+            .iter()
+            .map(|d| (d.collection.as_str(), d.key.as_str(), d.difference))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("", "key7", Difference::ValueDiffers),
+                ("", "key8", Difference::OnlyInOther),
+                ("users", "42", Difference::OnlyInOther),
+            ]
+        );
+    }
+}
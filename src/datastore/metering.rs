@@ -0,0 +1,158 @@
+use super::{
+    EmbeddedDatabase,
+    collection::{collection_of, user_key},
+    tenant::tenant_of,
+};
+use std::collections::HashMap;
+
+/// How `EmbeddedDatabase::usage` groups the bytes it counts
+#[derive(Debug, Clone, PartialEq)]
+pub enum Metering {
+    /// One group per collection, "" for the default one. Tenants show up
+    /// under their collection, `__tenant:<name>`.
+    Collection,
+    /// One group per tenant, "" for keys outside of any tenant
+    Tenant,
+    /// Keys grouped by what comes before the first `char`, e.g. "acme" for
+    /// "acme/orders/7" with '/'. Keys without it are a group of their own.
+    KeyPrefix(char),
+}
+
+/// Bytes of keys & values that went in & out for a group since the db was
+/// opened. Counted before value transformers, so they don't depend on
+/// compression or encryption. A delete writes the key, a read of a missing
+/// key reads just the key.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ByteUsage {
+    pub reads: u64,
+    pub bytes_read: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct UsageMeter {
+    metering: Metering,
+    usage: HashMap<String, ByteUsage>,
+}
+
+impl UsageMeter {
+    pub(crate) fn new(metering: Metering) -> Self {
+        UsageMeter {
+            metering,
+            usage: HashMap::new(),
+        }
+    }
+
+    /// The group an already namespaced key is billed to. The collections the
+    /// db keeps for itself aren't billed, tenants' collections are.
+    fn group(&self, stored_key: &str) -> Option<String> {
+        let collection = collection_of(stored_key);
+        let tenant = tenant_of(collection);
+        if collection.starts_with("__") && tenant.is_none() {
+            return None;
+        }
+        Some(match &self.metering {
+            Metering::Collection => collection.to_string(),
+            Metering::Tenant => tenant.unwrap_or_default().to_string(),
+            Metering::KeyPrefix(delimiter) => user_key(stored_key)
+                .split(*delimiter)
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        })
+    }
+
+    fn usage_of(&mut self, stored_key: &str) -> Option<&mut ByteUsage> {
+        let group = self.group(stored_key)?;
+        Some(self.usage.entry(group).or_default())
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Bytes read & written per group since the db was opened, see
+    /// `ByteUsage`. Empty unless `DbOptions::metering` is set.
+    pub fn usage(&self) -> HashMap<String, ByteUsage> {
+        self.usage_meter
+            .as_ref()
+            .map(|meter| meter.usage.clone())
+            .unwrap_or_default()
+    }
+
+    /// Start counting again, e.g. after the usage was billed
+    pub fn reset_usage(&mut self) {
+        if let Some(meter) = &mut self.usage_meter {
+            meter.usage.clear();
+        }
+    }
+
+    /// Count a read of an already namespaced key that returned `val_len` bytes
+    pub(crate) fn meter_read(&mut self, key: &str, val_len: usize) {
+        let bytes = (user_key(key).len() + val_len) as u64;
+        if let Some(usage) = self
+            .usage_meter
+            .as_mut()
+            .and_then(|meter| meter.usage_of(key))
+        {
+            usage.reads += 1;
+            usage.bytes_read += bytes;
+        }
+    }
+
+    /// Count a write of an already namespaced key, `val_len` is 0 for deletes
+    pub(crate) fn meter_write(&mut self, key: &str, val_len: usize) {
+        let bytes = (user_key(key).len() + val_len) as u64;
+        if let Some(usage) = self
+            .usage_meter
+            .as_mut()
+            .and_then(|meter| meter.usage_of(key))
+        {
+            usage.writes += 1;
+            usage.bytes_written += bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, WriteBatch};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_usage_by_tenant() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            metering: Some(Metering::Tenant),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        db.tenant("acme").unwrap().set("key", "12345").unwrap();
+        db.tenant("acme").unwrap().get("key").unwrap();
+        db.tenant("acme").unwrap().delete("key").unwrap();
+        db.tenant("globex").unwrap().get("missing").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1");
+        batch.set("b", "22");
+        db.apply_batch(batch).unwrap();
+        // Bookkeeping of the db itself isn't billed to anyone
+        db.next_id("orders").unwrap();
+
+        let usage = db.usage();
+        assert_eq!(usage.len(), 3);
+        assert_eq!(
+            usage["acme"],
+            ByteUsage {
+                reads: 1,
+                bytes_read: 8,
+                writes: 2,
+                bytes_written: 11,
+            }
+        );
+        assert_eq!(usage["globex"].bytes_read, 7);
+        assert_eq!((usage[""].writes, usage[""].bytes_written), (2, 5));
+
+        db.reset_usage();
+        assert!(db.usage().is_empty());
+    }
+}
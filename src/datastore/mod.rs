@@ -1,7 +1,99 @@
+#[cfg(feature = "arrow")]
+mod arrow_export;
+mod backup;
+mod batch;
+mod bitmap;
+mod cache;
+mod cdc;
+mod clock;
+mod coalesce;
+mod collection;
+mod config_store;
 mod database;
+mod direct_io;
+mod encryption;
 mod error;
+mod fencing;
+mod geo;
+mod header;
+mod hint;
+mod hll;
+mod hot_keys;
+mod index_hasher;
+mod integrity;
+mod iter;
+pub mod keys;
+mod lease;
+mod manager;
+mod merkle;
+mod metering;
+mod multimap;
+mod numeric_index;
+mod options;
+#[cfg(feature = "parquet")]
+mod parquet_export;
+mod prealloc;
+mod prefetch;
+mod pubsub;
+mod query;
+mod queue;
 mod record;
+mod repartition;
+mod scheduler;
+mod schema;
+mod sequence;
+mod shadow;
+mod sharding;
+mod snapshot;
+mod tenant;
+mod text_index;
+mod thread_safe;
+mod timeseries;
+mod transform;
+mod typed_collection;
 
-pub use database::EmbeddedDatabase;
-pub use error::Result;
-pub use record::Record;
+#[cfg(feature = "arrow")]
+pub use arrow_export::{ArrowBatches, arrow_schema};
+pub use backup::BackupReport;
+pub use batch::WriteBatch;
+pub use cache::CacheStats;
+pub use cdc::{Change, ChangeEvent, Consumer, ConsumerOffset};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use collection::Collection;
+pub use config_store::ConfigStore;
+pub use database::{CollectionStats, EmbeddedDatabase, MultiGet, WriteStats};
+pub use encryption::MasterKey;
+pub use error::{DbError, OperationFailed, Quota, Result};
+pub use geo::{GeoIndex, GeoMatch, geohash};
+pub use hll::HyperLogLog;
+pub use hot_keys::HotKey;
+pub use index_hasher::IndexHasher;
+pub use integrity::MacKey;
+pub use iter::{Iter, LiveIter, SnapshotIter};
+pub use manager::{DbManager, DbSpec};
+pub use merkle::{Difference, KeyDifference, MerkleTree};
+pub use metering::{ByteUsage, Metering};
+pub use multimap::Multimap;
+pub use numeric_index::IndexMismatch;
+pub use options::{
+    BackgroundCompaction, Backpressure, BackpressureAction, CancellationToken, CollectionOptions,
+    CompactionPolicy, DbOptions, NumericExtractor, NumericIndex, OpenProgress,
+    OpenProgressCallback, SyncPolicy, TenantQuota,
+};
+#[cfg(feature = "parquet")]
+pub use parquet_export::{ParquetCompression, ParquetOptions};
+pub use prefetch::Prefetch;
+pub use pubsub::{Message, Subscription};
+pub use query::QueryResult;
+pub use queue::{Queue, QueueItem};
+pub use record::{Record, RecordKind};
+pub use repartition::ConflictPolicy;
+pub use scheduler::{CustomTask, MaintenanceWindow, Schedule, Task, TaskStatus};
+pub use shadow::ShadowDb;
+pub use sharding::ShardedDb;
+pub use snapshot::Snapshot;
+pub use tenant::{PurgeReport, TenantDb};
+pub use thread_safe::ThreadSafeDB;
+pub use timeseries::TimeSeries;
+pub use transform::{Lz4Compression, ValueTransformer};
+pub use typed_collection::TypedCollection;
@@ -0,0 +1,128 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{COLLECTION_SEPARATOR, namespaced_key, user_key},
+};
+
+/// Keys that hold a set of values instead of a single one.
+/// Every value is a record of its own, keyed by the key & the value, so adding
+/// one is a single small append no matter how big the set is. Adding a value
+/// twice just overwrites it & compaction drops removed ones like any other
+/// dead record.
+pub struct Multimap<'a> {
+    db: &'a mut EmbeddedDatabase,
+    collection: String,
+}
+
+impl EmbeddedDatabase {
+    /// A handle to the named multimap, created on first use
+    pub fn multimap(&mut self, name: &str) -> Result<Multimap<'_>> {
+        if name.is_empty() {
+            return Err("multimap name can't be empty".into());
+        }
+        Ok(Multimap {
+            db: self,
+            collection: format!("__multimap:{name}"),
+        })
+    }
+}
+
+impl Multimap<'_> {
+    /// Add `val` to the values of `key`.
+    /// Returns false if it was there already.
+    pub fn set(&mut self, key: &str, val: &str) -> Result<bool> {
+        let member_key = self.member_key(key, val)?;
+        if self.db.is_live(&member_key) {
+            return Ok(false);
+        }
+        self.db.put(member_key, b"1", None)?;
+        Ok(true)
+    }
+
+    /// Every value of `key`, sorted
+    pub fn get_all(&self, key: &str) -> Result<Vec<String>> {
+        let prefix = self.member_prefix(key)?;
+        Ok(self
+            .db
+            .live_keys(&self.collection)
+            .iter()
+            .filter_map(|stored_key| stored_key.strip_prefix(&prefix))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Remove a single value, returns false if `key` didn't have it
+    pub fn remove(&mut self, key: &str, val: &str) -> Result<bool> {
+        let member_key = self.member_key(key, val)?;
+        if !self.db.is_live(&member_key) {
+            return Ok(false);
+        }
+        self.db.remove(member_key)?;
+        Ok(true)
+    }
+
+    /// Drop `key` with all of its values in one batch, returns how many there were
+    pub fn delete(&mut self, key: &str) -> Result<usize> {
+        let prefix = self.member_prefix(key)?;
+        let prefix = user_key(&prefix).to_string();
+        self.db.delete_prefix_in(&self.collection, &prefix)
+    }
+
+    /// Keys with at least one value, sorted
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .db
+            .live_keys(&self.collection)
+            .iter()
+            .filter_map(|stored_key| user_key(stored_key).split_once(COLLECTION_SEPARATOR))
+            .map(|(key, _)| key.to_string())
+            .collect();
+        keys.dedup();
+        keys
+    }
+
+    fn member_prefix(&self, key: &str) -> Result<String> {
+        if key.contains(COLLECTION_SEPARATOR) {
+            return Err(format!("invalid multimap key {key:?}").into());
+        }
+        Ok(namespaced_key(
+            &self.collection,
+            &format!("{key}{COLLECTION_SEPARATOR}"),
+        ))
+    }
+
+    fn member_key(&self, key: &str, val: &str) -> Result<String> {
+        Ok(self.member_prefix(key)? + val)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_multimap() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let mut tags = db.multimap("tags").unwrap();
+        assert!(tags.set("rust", "alice").unwrap());
+        assert!(tags.set("rust", "bob").unwrap());
+        assert!(!tags.set("rust", "alice").unwrap());
+        assert!(tags.set("rusty", "carol").unwrap());
+        assert!(tags.remove("rust", "bob").unwrap());
+        assert!(!tags.remove("rust", "bob").unwrap());
+        assert!(tags.set("go", "dave").unwrap());
+
+        assert_eq!(tags.get_all("rust").unwrap(), vec!["alice"]);
+        assert_eq!(tags.keys(), vec!["go", "rust", "rusty"]);
+        assert_eq!(tags.delete("rust").unwrap(), 1);
+
+        db.compact().unwrap();
+        drop(db);
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let tags = db.multimap("tags").unwrap();
+        assert!(tags.get_all("rust").unwrap().is_empty());
+        assert_eq!(tags.get_all("rusty").unwrap(), vec!["carol"]);
+        assert_eq!(tags.keys(), vec!["go", "rusty"]);
+    }
+}
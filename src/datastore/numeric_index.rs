@@ -0,0 +1,289 @@
+use super::{
+    EmbeddedDatabase, NumericIndex, Result,
+    collection::{
+        SYSTEM_COLLECTION, collection_of, namespaced_key, user_key, validate_collection_name,
+    },
+    error::catch_callback,
+    keys,
+};
+use std::{collections::HashMap, ops::RangeBounds};
+
+/// Marks an index as built, under `__system`
+const BUILT_PREFIX: &str = "index/";
+
+/// What `verify_indexes` found wrong with a numeric index
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexMismatch {
+    pub index: String,
+    pub key: String,
+    /// The number the value holds, `None` if it holds none
+    pub expected: Option<i64>,
+    /// The numbers the index has an entry for
+    pub indexed: Vec<i64>,
+}
+
+/// Writes to apply together, `None` deletes
+type Writes = Vec<(String, Option<Vec<u8>>)>;
+
+/// Collection holding the entries of a numeric index. Entries are keyed by
+/// the order-preserving encoding of (number, key), so they sort by number.
+fn index_collection(name: &str) -> String {
+    format!("__index:{name}")
+}
+
+fn entry_key(name: &str, number: i64, key: &str) -> String {
+    namespaced_key(&index_collection(name), &keys::encode(&(number, key)))
+}
+
+fn built_marker(name: &str) -> String {
+    namespaced_key(SYSTEM_COLLECTION, &format!("{BUILT_PREFIX}{name}"))
+}
+
+impl NumericIndex {
+    /// Run the extractor of the index `name`, a panic in it becomes an error
+    pub(crate) fn number(&self, name: &str, val: Option<&str>) -> Result<Option<i64>> {
+        let Some(val) = val else {
+            return Ok(None);
+        };
+        catch_callback(
+            || format!("extractor of numeric index {name:?}"),
+            || (self.extract)(val),
+        )
+    }
+}
+
+impl EmbeddedDatabase {
+    pub(crate) fn has_numeric_index(&self, collection: &str) -> bool {
+        self.options
+            .numeric_indexes
+            .values()
+            .any(|index| index.collection == collection)
+    }
+
+    /// `writes` plus the index entries they add & remove, so all of them can
+    /// go behind the same commit marker
+    pub(crate) fn with_index_updates(&mut self, mut writes: Writes) -> Result<Writes> {
+        if self.options.numeric_indexes.is_empty() {
+            return Ok(writes);
+        }
+        let indexes = self.options.numeric_indexes.clone();
+
+        // What earlier writes of the same batch left behind
+        let mut pending: HashMap<String, Option<String>> = HashMap::new();
+        let mut updates = Vec::new();
+        for (key, val) in &writes {
+            let collection = collection_of(key);
+            if !indexes.values().any(|index| index.collection == collection) {
+                continue;
+            }
+            let old = match pending.get(key) {
+                Some(old) => old.clone(),
+                None => self.get_stored(key)?,
+            };
+            let new = val
+                .as_deref()
+                .and_then(|val| String::from_utf8(val.to_vec()).ok());
+
+            for (name, index) in &indexes {
+                if index.collection != collection {
+                    continue;
+                }
+                let old_number = index.number(name, old.as_deref())?;
+                let new_number = index.number(name, new.as_deref())?;
+                if old_number == new_number {
+                    continue;
+                }
+                if let Some(number) = old_number {
+                    updates.push((entry_key(name, number, user_key(key)), None));
+                }
+                if let Some(number) = new_number {
+                    updates.push((entry_key(name, number, user_key(key)), Some(vec![1])));
+                }
+            }
+            pending.insert(key.clone(), new);
+        }
+        writes.extend(updates);
+        Ok(writes)
+    }
+
+    /// Build the indexes that were registered since the db was last opened
+    pub(crate) fn backfill_numeric_indexes(&mut self) -> Result<()> {
+        let names: Vec<String> = self.options.numeric_indexes.keys().cloned().collect();
+        for name in names {
+            validate_collection_name(&name)?;
+            if !self.is_live(&built_marker(&name)) {
+                self.rebuild_numeric_index(&name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Throw away the entries of an index & index every live value of its
+    /// collection again, e.g. after its extractor changed
+    pub fn rebuild_numeric_index(&mut self, name: &str) -> Result<()> {
+        let Some(index) = self.options.numeric_indexes.get(name).cloned() else {
+            return Err(format!("no numeric index named {name:?}").into());
+        };
+        let mut writes: Writes = self
+            .live_keys(&index_collection(name))
+            .into_iter()
+            .map(|entry| (entry, None))
+            .collect();
+        for key in self.live_keys(&index.collection) {
+            let val = self.get_stored(&key)?;
+            if let Some(number) = index.number(name, val.as_deref())? {
+                writes.push((entry_key(name, number, user_key(&key)), Some(vec![1])));
+            }
+        }
+        writes.push((built_marker(name), Some(vec![1])));
+        self.commit_writes(writes, None)
+    }
+
+    /// Compare every numeric index with the values it covers, e.g. after a
+    /// crash or a bug, & fix any it reports with `rebuild_numeric_index`.
+    /// Entries of values that expired are left out, those are expected.
+    pub fn verify_indexes(&mut self) -> Result<Vec<IndexMismatch>> {
+        let mut indexes: Vec<(String, NumericIndex)> = self
+            .options
+            .numeric_indexes
+            .iter()
+            .map(|(name, index)| (name.clone(), index.clone()))
+            .collect();
+        indexes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut mismatches = Vec::new();
+        for (name, index) in indexes {
+            let mut indexed: HashMap<String, Vec<i64>> = HashMap::new();
+            for entry in self.live_keys(&index_collection(&name)) {
+                let (number, key): (i64, String) = keys::decode(user_key(&entry))?;
+                indexed.entry(key).or_default().push(number);
+            }
+            let mut keys: Vec<String> = self
+                .live_keys(&index.collection)
+                .iter()
+                .map(|key| user_key(key).to_string())
+                .chain(indexed.keys().cloned())
+                .collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let stored_key = namespaced_key(&index.collection, &key);
+                if !self.is_live(&stored_key) {
+                    continue;
+                }
+                let val = self.get_stored(&stored_key)?;
+                let expected = index.number(&name, val.as_deref())?;
+                let indexed = indexed.remove(&key).unwrap_or_default();
+                if indexed != expected.into_iter().collect::<Vec<_>>() {
+                    mismatches.push(IndexMismatch {
+                        index: name.clone(),
+                        key,
+                        expected,
+                        indexed,
+                    });
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Keys whose value the index `name` pulls a number in `range` out of,
+    /// ordered by that number (ties by key). Entries are persisted & updated
+    /// in the same commit as the value, so they never get ahead of or fall
+    /// behind the data.
+    pub fn index_range(&mut self, name: &str, range: impl RangeBounds<i64>) -> Result<Vec<String>> {
+        let Some(index) = self.options.numeric_indexes.get(name).cloned() else {
+            return Err(format!("no numeric index named {name:?}").into());
+        };
+        let mut found = Vec::new();
+        for entry in self.live_keys(&index_collection(name)) {
+            let (number, key): (i64, String) = keys::decode(user_key(&entry))?;
+            if !range.contains(&number) {
+                continue;
+            }
+            // An entry outlives a value that expired, only trust it if the
+            // value still holds the number
+            let val = self.get_stored(&namespaced_key(&index.collection, &key))?;
+            if index.number(name, val.as_deref())? == Some(number) {
+                found.push(key);
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DbOptions, WriteBatch};
+    use tempfile::NamedTempFile;
+
+    fn age(val: &str) -> Option<i64> {
+        val.split(';').nth(1)?.parse().ok()
+    }
+
+    #[test]
+    fn test_index_range() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.collection("users")
+            .unwrap()
+            .set("ann", "Ann;17")
+            .unwrap();
+        drop(db);
+
+        // Registering the index picks up what's already there
+        let options = DbOptions::default().with_numeric_index("age", "users", age);
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options.clone()).unwrap();
+        let mut users = db.collection("users").unwrap();
+        users.set("bob", "Bob;29").unwrap();
+        users.set("cid", "Cid;18").unwrap();
+        users.set("dan", "Dan;unknown").unwrap();
+        assert_eq!(db.index_range("age", 18..30).unwrap(), vec!["cid", "bob"]);
+
+        let mut batch = WriteBatch::new();
+        batch.set_in("users", "ann", "Ann;18");
+        batch.delete_in("users", "bob");
+        db.apply_batch(batch).unwrap();
+        drop(db);
+
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        assert_eq!(db.index_range("age", 18..30).unwrap(), vec!["ann", "cid"]);
+        assert_eq!(db.index_range("age", ..).unwrap(), vec!["ann", "cid"]);
+        assert!(db.index_range("height", ..).is_err());
+    }
+
+    #[test]
+    fn test_verify_indexes() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions::default().with_numeric_index("age", "users", age);
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        let mut users = db.collection("users").unwrap();
+        users.set("ann", "Ann;17").unwrap();
+        users.set("bob", "Bob;29").unwrap();
+        users.set("tmp/1", "Tmp;40").unwrap();
+        users.set("tmp/2", "Tmp;41").unwrap();
+
+        // Renames & prefix deletes move the entries along with the values
+        assert!(users.rename("ann", "anne").unwrap());
+        assert!(!users.rename("zed", "zack").unwrap());
+        assert_eq!(users.delete_prefix("tmp/").unwrap(), 2);
+        assert_eq!(db.index_range("age", ..).unwrap(), vec!["anne", "bob"]);
+        assert!(db.verify_indexes().unwrap().is_empty());
+
+        // Lose an entry behind the index's back
+        let entry = entry_key("age", 29, "bob");
+        db.commit_writes(vec![(entry, None)], None).unwrap();
+        assert_eq!(
+            db.verify_indexes().unwrap(),
+            vec![IndexMismatch {
+                index: "age".to_string(),
+                key: "bob".to_string(),
+                expected: Some(29),
+                indexed: vec![],
+            }]
+        );
+        db.rebuild_numeric_index("age").unwrap();
+        assert!(db.verify_indexes().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use super::{
+    Clock, IndexHasher, MacKey, MaintenanceWindow, MasterKey, Metering, SystemClock,
+    ValueTransformer,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+/// Settings used when opening an EmbeddedDatabase.
+/// Collections that are not listed in `collections` fall back to `default_collection`.
+#[derive(Debug, Clone, Default)]
+pub struct DbOptions {
+    pub default_collection: CollectionOptions,
+    pub collections: HashMap<String, CollectionOptions>,
+    /// Let a ThreadSafeDB compact on a background thread instead of on the
+    /// write that pushed a collection over its compaction policy
+    pub background_compaction: Option<BackgroundCompaction>,
+    /// Called while the index is rebuilt on open, so big databases can report progress
+    pub open_progress: Option<OpenProgressCallback>,
+    /// Give up opening with `DbError::OpenTimedOut` if rebuilding the index takes longer
+    pub open_timeout: Option<Duration>,
+    /// Lets another thread abort the open with `DbError::OpenCancelled`
+    pub open_cancel: Option<CancellationToken>,
+    /// Let concurrent `ThreadSafeDB::get`s of the same key share one read
+    pub coalesce_gets: bool,
+    /// Count key accesses so `EmbeddedDatabase::hot_keys` can report the busiest ones
+    pub track_hot_keys: bool,
+    /// Bytes of decoded keys & values kept in memory for reads.
+    /// 0 turns the cache off, pinned keys are cached either way.
+    pub cache_capacity_bytes: u64,
+    /// When writes are synced to the disk, i.e. how much a power failure can lose
+    pub sync_policy: SyncPolicy,
+    /// Limits of tenants that are not listed in `tenant_quotas`
+    pub default_tenant_quota: TenantQuota,
+    pub tenant_quotas: HashMap<String, TenantQuota>,
+    /// Encrypt every value at rest with a per-collection data key wrapped by
+    /// this key, see `EmbeddedDatabase::shred_collection`
+    pub master_key: Option<MasterKey>,
+    /// Sign the data file with a rolling HMAC kept in `<db file>.mac`. Opening
+    /// fails with `DbError::IntegrityCheckFailed` if the file was changed
+    /// without the key, anything appended without it is dropped. The file's
+    /// header marks it signed, so only read-only handles open it without the key.
+    pub mac_key: Option<MacKey>,
+    /// Numeric secondary indexes by name, see `EmbeddedDatabase::index_range`.
+    /// Open the db with the same indexes every time, writes made without an
+    /// index registered don't update it.
+    pub numeric_indexes: HashMap<String, NumericIndex>,
+    /// Times of day a ThreadSafeDB's scheduler may run heavy tasks like
+    /// compaction in, see `Task::is_heavy`. Empty allows them any time.
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Log a warning when a ThreadSafeDB waits longer than this for its lock
+    pub slow_lock_threshold: Option<Duration>,
+    /// What TTLs & the scheduler read the time from, `None` is the system
+    /// clock. Tests can hand in a `MockClock` & move time along themselves.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Pad every write with filler records so it starts & ends on a multiple
+    /// of this many bytes (a power of two). The padding isn't counted as garbage.
+    pub record_alignment: Option<u64>,
+    /// Append around the page cache (O_DIRECT on Linux, F_NOCACHE on macOS)
+    /// so the db doesn't push a co-resident application's pages out. Needs a
+    /// `record_alignment` of at least the disk's block size, 4096 is safe.
+    /// Reads still go through the page cache.
+    pub direct_io: bool,
+    /// Reserve disk space ahead of the appends in chunks of this many bytes
+    /// (fallocate on Linux, F_PREALLOCATE on macOS). Keeps the file in fewer
+    /// pieces & makes a full disk fail the reservation instead of a half
+    /// written record. The file's length stays where the data ends.
+    pub preallocate_chunk: Option<u64>,
+    /// Count the bytes of keys & values read & written, grouped this way,
+    /// see `EmbeddedDatabase::usage`
+    pub metering: Option<Metering>,
+    /// Hashes the keys of the in-memory index, `None` is `IndexHasher::default()`
+    pub index_hasher: Option<IndexHasher>,
+    /// Also keep the keys of the in-memory index sorted, so
+    /// `EmbeddedDatabase::range` only looks at the keys in range. Costs a
+    /// second copy of every key.
+    pub sorted_index: bool,
+    /// Save the index to `<db file>.hint` on every compaction, so opening the
+    /// file only scans what was written after the last one
+    pub hint_files: bool,
+    /// Schema version the app expects per collection ("" is the default
+    /// one). Opening a file that recorded another version fails with
+    /// `DbError::SchemaMismatch`, see `EmbeddedDatabase::register_schema`.
+    pub schema_versions: HashMap<String, u32>,
+    /// Open the file for reading only, e.g. for a replica following a writer
+    /// in another process with `EmbeddedDatabase::refresh`. The file has to
+    /// exist & is never changed: an uncommitted batch or torn write at its
+    /// end is left alone, writes & compaction fail with `DbError::ReadOnly`.
+    /// Can't be combined with `mac_key` or `direct_io`.
+    pub read_only: bool,
+}
+
+impl DbOptions {
+    /// Register the options for a named collection
+    pub fn with_collection(mut self, name: &str, options: CollectionOptions) -> Self {
+        self.collections.insert(name.to_string(), options);
+        self
+    }
+
+    /// Register the quota of a tenant, see `EmbeddedDatabase::tenant`
+    pub fn with_tenant_quota(mut self, tenant: &str, quota: TenantQuota) -> Self {
+        self.tenant_quotas.insert(tenant.to_string(), quota);
+        self
+    }
+
+    /// Register a numeric index over the values of `collection`. `extract`
+    /// pulls the number out of a value, values it returns `None` for aren't indexed.
+    pub fn with_numeric_index(
+        mut self,
+        name: &str,
+        collection: &str,
+        extract: impl Fn(&str) -> Option<i64> + Send + Sync + 'static,
+    ) -> Self {
+        let index = NumericIndex {
+            collection: collection.to_string(),
+            extract: Arc::new(extract),
+        };
+        self.numeric_indexes.insert(name.to_string(), index);
+        self
+    }
+
+    /// The hasher the in-memory index is built with
+    pub fn index_hasher(&self) -> IndexHasher {
+        self.index_hasher.clone().unwrap_or_default()
+    }
+
+    /// The clock the db runs on
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+
+    /// Look up the quota that applies to a tenant
+    pub fn tenant_quota(&self, tenant: &str) -> TenantQuota {
+        self.tenant_quotas
+            .get(tenant)
+            .copied()
+            .unwrap_or(self.default_tenant_quota)
+    }
+
+    /// Look up the options that apply to a collection
+    pub fn collection(&self, name: &str) -> &CollectionOptions {
+        self.collections
+            .get(name)
+            .unwrap_or(&self.default_collection)
+    }
+}
+
+/// Per-collection behaviour. A "cache" collection and a "config" collection
+/// usually want very different lifecycles, so each one can carry its own.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionOptions {
+    /// TTL applied to every write in the collection
+    pub default_ttl: Option<Duration>,
+    /// Applied in order to every value written to the collection (compression,
+    /// encryption, ...) & in reverse order when it is read back
+    pub transformers: Vec<Arc<dyn ValueTransformer>>,
+    /// When to rewrite the data file to drop dead records of this collection.
+    /// `None` means the collection never triggers a compaction by itself.
+    pub compaction: Option<CompactionPolicy>,
+}
+
+/// A secondary index mapping a number pulled out of every value of a
+/// collection ("" for the default one) back to the keys
+#[derive(Clone)]
+pub struct NumericIndex {
+    pub collection: String,
+    pub extract: NumericExtractor,
+}
+
+/// Pulls the indexed number out of a value
+pub type NumericExtractor = Arc<dyn Fn(&str) -> Option<i64> + Send + Sync>;
+
+impl fmt::Debug for NumericIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NumericIndex")
+            .field("collection", &self.collection)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How much a tenant may store, `None` means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TenantQuota {
+    /// Live bytes on disk, value transformers already applied
+    pub max_bytes: Option<u64>,
+    pub max_keys: Option<u64>,
+}
+
+/// Compaction kicks in once the collection's garbage crosses both limits
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionPolicy {
+    /// Fraction of the collection's bytes on disk that are dead (0.0 - 1.0)
+    pub garbage_ratio: f64,
+    /// Don't bother compacting until at least this many bytes can be reclaimed
+    pub min_garbage_bytes: u64,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            garbage_ratio: 0.5,
+            min_garbage_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// How the background compaction thread of a ThreadSafeDB behaves
+#[derive(Debug, Clone, Copy)]
+pub struct BackgroundCompaction {
+    /// How often the collections' compaction policies are checked
+    pub check_interval: Duration,
+    /// What to do with writes when compaction can't keep up
+    pub backpressure: Option<Backpressure>,
+}
+
+impl Default for BackgroundCompaction {
+    fn default() -> Self {
+        BackgroundCompaction {
+            check_interval: Duration::from_secs(10),
+            backpressure: None,
+        }
+    }
+}
+
+/// When writes are flushed to the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SyncPolicy {
+    /// Leave it to `sync()`, compaction & the OS. Anything not synced yet
+    /// can be lost.
+    #[default]
+    Never,
+    /// Every write (or batch) is synced before it returns
+    EveryWrite,
+    /// Sync writes in groups, at most this long after they were made. That's
+    /// also the most a crash can lose. The write that finds the oldest
+    /// unsynced write overdue syncs, a ThreadSafeDB also syncs from a
+    /// background thread so quiet periods are covered.
+    Interval(Duration),
+}
+
+/// Once the whole file carries `max_garbage_bytes` of garbage, writers are
+/// slowed down or turned away until compaction catches up. Garbage a change
+/// consumer hasn't acked can't be reclaimed, so after a compaction that freed
+/// nothing the next one waits until a consumer acks.
+#[derive(Debug, Clone, Copy)]
+pub struct Backpressure {
+    pub max_garbage_bytes: u64,
+    pub action: BackpressureAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackpressureAction {
+    /// Wake up the compaction thread & hold the write back for this long
+    Delay(Duration),
+    /// Fail the write with `DbError::CompactionBehind`
+    Reject,
+}
+
+/// How far the scan of the data file on open has come
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenProgress {
+    pub bytes_scanned: u64,
+    pub total_bytes: u64,
+    pub records_indexed: u64,
+}
+
+impl OpenProgress {
+    /// 0.0 - 1.0, an empty file counts as done
+    pub fn fraction(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 1.0;
+        }
+        self.bytes_scanned as f64 / self.total_bytes as f64
+    }
+}
+
+/// Receives an `OpenProgress` every time another percent of the file is
+/// scanned, and once more when the scan is done
+#[derive(Clone)]
+pub struct OpenProgressCallback(pub Arc<dyn Fn(OpenProgress) + Send + Sync>);
+
+impl OpenProgressCallback {
+    pub fn new(callback: impl Fn(OpenProgress) + Send + Sync + 'static) -> Self {
+        OpenProgressCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for OpenProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OpenProgressCallback")
+    }
+}
+
+/// Shared flag to abort a long running operation from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,125 @@
+use super::{EmbeddedDatabase, Result};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::{metadata::KeyValue, properties::WriterProperties},
+};
+use std::{
+    fs::{self, File},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How `export_parquet` lays out the file
+#[derive(Debug, Clone, Copy)]
+pub struct ParquetOptions {
+    /// Rows read from the db at a time
+    pub batch_size: usize,
+    /// Rows per row group, bigger groups compress better & need more memory
+    pub row_group_rows: usize,
+    pub compression: ParquetCompression,
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        ParquetOptions {
+            batch_size: 8192,
+            row_group_rows: 128 * 1024,
+            compression: ParquetCompression::Zstd(3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParquetCompression {
+    None,
+    Snappy,
+    /// Level 1 (fast) to 22 (small)
+    Zstd(i32),
+}
+
+impl EmbeddedDatabase {
+    /// Write every live record of the user collections to a Parquet file,
+    /// with the columns of `arrow_schema`. The records come from a snapshot,
+    /// writes that happen while the export runs aren't in the file. The file
+    /// metadata holds the `last_seq` the snapshot was taken at & when it was
+    /// taken. The file is written next to `path` & renamed into place, so
+    /// there's never a half written archive at `path`.
+    /// Returns the number of rows written.
+    pub fn export_parquet<P: AsRef<Path>>(&self, path: P, options: ParquetOptions) -> Result<u64> {
+        let path = path.as_ref();
+        let batches = self.arrow_batches(options.batch_size)?;
+        let exported_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+        let compression = match options.compression {
+            ParquetCompression::None => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd(level) => Compression::ZSTD(ZstdLevel::try_new(level)?),
+        };
+        let properties = WriterProperties::builder()
+            .set_compression(compression)
+            .set_max_row_group_size(options.row_group_rows.max(1))
+            .set_key_value_metadata(Some(vec![
+                KeyValue::new("tiny_db.last_seq".to_string(), self.last_seq().to_string()),
+                KeyValue::new(
+                    "tiny_db.exported_at_ms".to_string(),
+                    exported_at.to_string(),
+                ),
+            ]))
+            .build();
+
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+
+        let file = File::create(&temp_path)?;
+        let mut writer = ArrowWriter::try_new(file, super::arrow_schema(), Some(properties))?;
+        let mut rows = 0;
+        for batch in batches {
+            let batch = batch?;
+            rows += batch.num_rows() as u64;
+            writer.write(&batch)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    fn test_export_parquet() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        for i in 0..1000 {
+            db.set(&format!("key{i}"), &format!("val{i}")).unwrap();
+        }
+        db.delete("key0").unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let archive = dir.path().join("snapshot.parquet");
+        let options = ParquetOptions {
+            row_group_rows: 400,
+            ..Default::default()
+        };
+        assert_eq!(db.export_parquet(&archive, options).unwrap(), 999);
+
+        let reader = SerializedFileReader::new(File::open(&archive).unwrap()).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 999);
+        assert_eq!(metadata.num_row_groups(), 3);
+        let last_seq = metadata
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap()
+            .iter()
+            .find(|kv| kv.key == "tiny_db.last_seq")
+            .and_then(|kv| kv.value.clone());
+        assert_eq!(last_seq, Some(db.last_seq().to_string()));
+    }
+}
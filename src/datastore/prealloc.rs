@@ -0,0 +1,87 @@
+use super::Result;
+use std::fs::File;
+
+/// Reserve disk space for `len` bytes from `offset` without changing the
+/// file's length, so readers still see the end of the data where it is.
+/// Running out of space fails here instead of halfway through an append.
+/// Filesystems & platforms that can't reserve space are left alone.
+#[cfg(target_os = "linux")]
+pub(crate) fn reserve(file: &File, offset: u64, len: u64) -> Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if result == -1 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn reserve(file: &File, _offset: u64, len: u64) -> Result<()> {
+    use std::os::fd::AsRawFd;
+    // F_PREALLOCATE counts from the end of what's allocated already
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATEALL,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: len as libc::off_t,
+        fst_bytesalloc: 0,
+    };
+    // SAFETY: the descriptor belongs to `file` & `store` lives through the call
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } == -1 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ENOTSUP) {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn reserve(_file: &File, _offset: u64, _len: u64) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{DbOptions, EmbeddedDatabase};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_preallocation() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            preallocate_chunk: Some(1024 * 1024),
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options.clone()).unwrap();
+        for i in 0..100 {
+            db.set(&format!("key{i}"), &format!("val{i}")).unwrap();
+        }
+        // The file ends where the data does, the space behind it is reserved
+        let metadata = std::fs::metadata(temp_file.path()).unwrap();
+        assert!(metadata.len() < 1024 * 1024);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(metadata.blocks() * 512 >= 1024 * 1024);
+        }
+        drop(db);
+
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        assert_eq!(db.get("key99").unwrap(), Some("val99".to_string()));
+        db.compact().unwrap();
+        db.set("key100", "val100").unwrap();
+        assert_eq!(db.scan_keys("").len(), 101);
+    }
+}
@@ -0,0 +1,90 @@
+use super::{EmbeddedDatabase, Result};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::RangeBounds,
+    thread::{self, JoinHandle},
+};
+
+/// A prefetch running on a background thread. Dropping it lets the thread
+/// finish on its own, `wait` blocks until it is done.
+pub struct Prefetch {
+    handle: JoinHandle<std::io::Result<u64>>,
+}
+
+impl Prefetch {
+    /// Wait for the prefetch to finish, returns the bytes it read
+    pub fn wait(self) -> Result<u64> {
+        match self.handle.join() {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("the prefetch thread panicked".into()),
+        }
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Start reading the records of `keys` on a background thread, so they are
+    /// in the OS page cache by the time they are asked for. Missing keys are
+    /// skipped. The db can be used as normal while the prefetch runs.
+    pub fn prefetch(&self, keys: &[&str]) -> Result<Prefetch> {
+        let spans = keys.iter().filter_map(|key| self.live_span(key)).collect();
+        self.read_ahead(spans)
+    }
+
+    /// `prefetch` for every key of the default collection within `range`
+    pub fn prefetch_range<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<Prefetch> {
+        let spans = self
+            .live_keys("")
+            .iter()
+            .filter(|key| range.contains(&key.as_str()))
+            .filter_map(|key| self.live_span(key))
+            .collect();
+        self.read_ahead(spans)
+    }
+
+    fn read_ahead(&self, mut spans: Vec<(u64, u64)>) -> Result<Prefetch> {
+        // Its own handle, so the reads don't move the db's cursor around.
+        // If a compaction replaces the file meanwhile we just warm up the old one.
+        let file = File::open(&self.path)?;
+        spans.sort_unstable();
+        let handle = thread::spawn(move || read_spans(file, spans));
+        Ok(Prefetch { handle })
+    }
+}
+
+/// Read the spans front to back so the disk sees one forward sweep
+fn read_spans(mut file: File, spans: Vec<(u64, u64)>) -> std::io::Result<u64> {
+    let mut buffer = Vec::new();
+    let mut read = 0;
+    for (offset, len) in spans {
+        buffer.resize(len as usize, 0);
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        read += len;
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_prefetch() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            db.set(key, "value").unwrap();
+        }
+        let record_len = db.live_span("a").unwrap().1;
+
+        let prefetch = db.prefetch(&["a", "missing"]).unwrap();
+        // The db stays usable while it runs
+        db.set("e", "value").unwrap();
+        assert_eq!(prefetch.wait().unwrap(), record_len);
+
+        let prefetch = db.prefetch_range("b".."d").unwrap();
+        assert_eq!(prefetch.wait().unwrap(), 2 * record_len);
+    }
+}
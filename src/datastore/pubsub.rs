@@ -0,0 +1,194 @@
+use super::{
+    Change, ChangeEvent, CollectionOptions, DbOptions, EmbeddedDatabase, Result,
+    collection::{COLLECTION_SEPARATOR, namespaced_key},
+};
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError},
+    time::{Duration, Instant},
+};
+
+/// A message published on a channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub id: u64,
+    pub payload: String,
+}
+
+/// Collection a channel's messages are stored in
+fn channel_collection(channel: &str) -> String {
+    format!("__channel:{channel}")
+}
+
+/// The separator would end the collection name early
+fn validate_channel(channel: &str) -> Result<()> {
+    if channel.is_empty() || channel.contains(COLLECTION_SEPARATOR) {
+        return Err(format!("invalid channel name {channel:?}").into());
+    }
+    Ok(())
+}
+
+impl DbOptions {
+    /// Keep messages published on `channel` around for `retention`
+    pub fn with_channel_retention(mut self, channel: &str, retention: Duration) -> Self {
+        let options = CollectionOptions {
+            default_ttl: Some(retention),
+            ..Default::default()
+        };
+        self.collections
+            .insert(channel_collection(channel), options);
+        self
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Store a message on the channel & deliver it to its subscribers.
+    /// Messages are regular records, they stay around until the channel's
+    /// retention (see `DbOptions::with_channel_retention`) runs out.
+    pub fn publish(&mut self, channel: &str, payload: &str) -> Result<u64> {
+        validate_channel(channel)?;
+        let collection = channel_collection(channel);
+        let id = self.next_id(&collection)?;
+        let key = namespaced_key(&collection, &format!("{id:020}"));
+        self.put(key, payload.as_bytes(), None)?;
+        Ok(id)
+    }
+
+    /// Receive the messages published on the channel from now on
+    pub fn subscribe(&mut self, channel: &str) -> Subscription {
+        Subscription {
+            collection: channel_collection(channel),
+            changes: self.subscribe_changes(),
+        }
+    }
+
+    /// Messages of the channel that are still retained, oldest first
+    pub fn channel_history(&self, channel: &str) -> Result<Vec<Message>> {
+        validate_channel(channel)?;
+        let collection = channel_collection(channel);
+        self.snapshot_of(&collection)?
+            .map(|item| {
+                let (key, payload) = item?;
+                Ok(Message {
+                    id: key.parse()?,
+                    payload,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Receiving end of a channel, built on the database's change events
+pub struct Subscription {
+    collection: String,
+    changes: Receiver<ChangeEvent>,
+}
+
+impl Subscription {
+    /// Wait for the next message. None once the database is gone.
+    pub fn recv(&self) -> Option<Message> {
+        loop {
+            let event = self.changes.recv().ok()?;
+            if let Some(message) = self.message_from(event) {
+                return Some(message);
+            }
+        }
+    }
+
+    /// The next message if one is already waiting
+    pub fn try_recv(&self) -> Option<Message> {
+        loop {
+            match self.changes.try_recv() {
+                Ok(event) => {
+                    if let Some(message) = self.message_from(event) {
+                        return Some(message);
+                    }
+                }
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the next message
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Message> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.changes.recv_timeout(left) {
+                Ok(event) => {
+                    if let Some(message) = self.message_from(event) {
+                        return Some(message);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    fn message_from(&self, event: ChangeEvent) -> Option<Message> {
+        if event.collection != self.collection {
+            return None;
+        }
+        match event.change {
+            Change::Set(payload) => Some(Message {
+                id: event.key.parse().ok()?,
+                payload,
+            }),
+            Change::Delete | Change::Commit => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_publish_and_subscribe() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions::default().with_channel_retention("news", Duration::from_secs(60));
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+
+        db.publish("news", "before anyone listened").unwrap();
+        let news = db.subscribe("news");
+        let other = db.subscribe("sports");
+        let id = db.publish("news", "hello").unwrap();
+        db.set("unrelated", "write").unwrap();
+
+        assert_eq!(
+            news.try_recv(),
+            Some(Message {
+                id,
+                payload: "hello".to_string()
+            })
+        );
+        assert_eq!(news.try_recv(), None);
+        assert_eq!(other.try_recv(), None);
+
+        // Earlier messages are still there as regular records
+        let history = db.channel_history("news").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].payload, "before anyone listened");
+
+        // An empty payload is published like any other
+        let id = db.publish("news", "").unwrap();
+        assert_eq!(
+            news.try_recv(),
+            Some(Message {
+                id,
+                payload: String::new()
+            })
+        );
+        assert_eq!(db.channel_history("news").unwrap()[2].payload, "");
+    }
+
+    #[test]
+    fn test_channel_names() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert!(db.publish("", "hello").is_err());
+        assert!(db.publish("news\0sports", "hello").is_err());
+        assert!(db.channel_history("news\0sports").is_err());
+        assert!(db.publish("news:sports", "hello").is_ok());
+    }
+}
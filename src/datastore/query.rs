@@ -0,0 +1,520 @@
+//! A small SQL-ish query language over one collection:
+//!
+//! ```text
+//! SELECT key, value FROM users WHERE key LIKE 'user:%' AND value != '' LIMIT 10
+//! ```
+//!
+//! * columns are `key`, `value` or `*` (both)
+//! * `FROM` names a collection, without it the default collection is queried
+//! * conditions are joined with `AND` & compare `key`, `value` or the name of
+//!   a numeric index (`age BETWEEN 18 AND 30`, `age >= 18`) with a literal.
+//!   Operators: `=`, `!=`, `<`, `<=`, `>`, `>=`, `LIKE` (`%` & `_`), `BETWEEN`
+//!
+//! Rows come back ordered by key, or by number when a numeric index picks
+//! them. `key = ..` & `key LIKE 'prefix%'` only look at the matching keys,
+//! a numeric index condition only at the keys in its range, anything else
+//! goes through the whole collection.
+
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{namespaced_key, validate_collection_name},
+};
+use std::ops::Bound;
+
+/// Columns & rows of a query, every row holds one value per column
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Column {
+    Key,
+    Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Key,
+    Value,
+    Index(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Text(String),
+    Number(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Compare(Field, Op, Literal),
+    Like(Field, String),
+    Between(Field, Literal, Literal),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    columns: Vec<Column>,
+    collection: String,
+    conditions: Vec<Condition>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Number(i64),
+    Symbol(&'static str),
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    // '' is a quote inside a string
+                    Some('\'') if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        text.push('\'');
+                    }
+                    Some('\'') => break,
+                    Some(c) => text.push(c),
+                    None => return Err("unterminated string in query".into()),
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut number = String::new();
+            number.push(c);
+            chars.next();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                number.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Number(number.parse()?));
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        } else {
+            chars.next();
+            let symbol = match (c, chars.peek()) {
+                ('<', Some('=')) => "<=",
+                ('>', Some('=')) => ">=",
+                ('!', Some('=')) => "!=",
+                ('<', Some('>')) => "!=",
+                ('<', _) => "<",
+                ('>', _) => ">",
+                ('=', _) => "=",
+                (',', _) => ",",
+                ('*', _) => "*",
+                _ => return Err(format!("unexpected {c:?} in query").into()),
+            };
+            if symbol.len() == 2 {
+                chars.next();
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.peek().cloned().ok_or("query ends too early")?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    /// Consume the keyword if it comes next
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword)) {
+            self.at += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.keyword(keyword) {
+            return Ok(());
+        }
+        Err(format!("expected {keyword} in query").into())
+    }
+
+    fn word(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Word(word) => Ok(word),
+            token => Err(format!("expected a name, found {token:?}").into()),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal> {
+        match self.next()? {
+            Token::Text(text) => Ok(Literal::Text(text)),
+            Token::Number(number) => Ok(Literal::Number(number)),
+            token => Err(format!("expected a value, found {token:?}").into()),
+        }
+    }
+
+    fn parse(mut self) -> Result<Query> {
+        self.expect_keyword("SELECT")?;
+        let mut columns = Vec::new();
+        loop {
+            match self.next()? {
+                Token::Symbol("*") => columns.extend([Column::Key, Column::Value]),
+                Token::Word(word) if word.eq_ignore_ascii_case("key") => columns.push(Column::Key),
+                Token::Word(word) if word.eq_ignore_ascii_case("value") => {
+                    columns.push(Column::Value)
+                }
+                token => return Err(format!("can't select {token:?}").into()),
+            }
+            if self.peek() != Some(&Token::Symbol(",")) {
+                break;
+            }
+            self.at += 1;
+        }
+
+        let mut collection = String::new();
+        if self.keyword("FROM") {
+            collection = self.word()?;
+            validate_collection_name(&collection)?;
+        }
+
+        let mut conditions = Vec::new();
+        if self.keyword("WHERE") {
+            loop {
+                conditions.push(self.condition()?);
+                if !self.keyword("AND") {
+                    break;
+                }
+            }
+        }
+
+        let mut limit = None;
+        if self.keyword("LIMIT") {
+            match self.next()? {
+                Token::Number(n) if n >= 0 => limit = Some(n as usize),
+                token => return Err(format!("bad LIMIT {token:?}").into()),
+            }
+        }
+        if let Some(token) = self.peek() {
+            return Err(format!("unexpected {token:?} at the end of the query").into());
+        }
+
+        Ok(Query {
+            columns,
+            collection,
+            conditions,
+            limit,
+        })
+    }
+
+    fn condition(&mut self) -> Result<Condition> {
+        let name = self.word()?;
+        let field = if name.eq_ignore_ascii_case("key") {
+            Field::Key
+        } else if name.eq_ignore_ascii_case("value") {
+            Field::Value
+        } else {
+            Field::Index(name)
+        };
+
+        if self.keyword("LIKE") {
+            return match self.next()? {
+                Token::Text(pattern) => Ok(Condition::Like(field, pattern)),
+                token => Err(format!("LIKE needs a string, found {token:?}").into()),
+            };
+        }
+        if self.keyword("BETWEEN") {
+            let low = self.literal()?;
+            self.expect_keyword("AND")?;
+            let high = self.literal()?;
+            return Ok(Condition::Between(field, low, high));
+        }
+        let op = match self.next()? {
+            Token::Symbol("=") => Op::Eq,
+            Token::Symbol("!=") => Op::Ne,
+            Token::Symbol("<") => Op::Lt,
+            Token::Symbol("<=") => Op::Le,
+            Token::Symbol(">") => Op::Gt,
+            Token::Symbol(">=") => Op::Ge,
+            token => return Err(format!("expected an operator, found {token:?}").into()),
+        };
+        Ok(Condition::Compare(field, op, self.literal()?))
+    }
+}
+
+/// SQL LIKE: `%` matches any run of characters, `_` exactly one
+fn like(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    // matches[j]: does text[..i] match pattern[..j]
+    let mut matches = vec![false; pattern.len() + 1];
+    matches[0] = true;
+    for j in 1..=pattern.len() {
+        matches[j] = matches[j - 1] && pattern[j - 1] == '%';
+    }
+    for c in text {
+        let mut next = vec![false; pattern.len() + 1];
+        for j in 1..=pattern.len() {
+            next[j] = match pattern[j - 1] {
+                '%' => next[j - 1] || matches[j],
+                '_' => matches[j - 1],
+                p => matches[j - 1] && p == c,
+            };
+        }
+        matches = next;
+    }
+    matches[pattern.len()]
+}
+
+impl Op {
+    fn holds<T: Ord>(self, left: &T, right: &T) -> bool {
+        match self {
+            Op::Eq => left == right,
+            Op::Ne => left != right,
+            Op::Lt => left < right,
+            Op::Le => left <= right,
+            Op::Gt => left > right,
+            Op::Ge => left >= right,
+        }
+    }
+}
+
+/// What a condition is checked against for one row
+enum Subject<'a> {
+    Text(&'a str),
+    Number(Option<i64>),
+}
+
+impl Condition {
+    fn field(&self) -> &Field {
+        match self {
+            Condition::Compare(field, ..)
+            | Condition::Like(field, _)
+            | Condition::Between(field, ..) => field,
+        }
+    }
+
+    fn holds(&self, subject: Subject) -> Result<bool> {
+        Ok(match (self, subject) {
+            (Condition::Like(_, pattern), Subject::Text(text)) => like(text, pattern),
+            (Condition::Compare(_, op, Literal::Text(literal)), Subject::Text(text)) => {
+                op.holds(&text, &literal.as_str())
+            }
+            (
+                Condition::Between(_, Literal::Text(low), Literal::Text(high)),
+                Subject::Text(text),
+            ) => low.as_str() <= text && text <= high.as_str(),
+            (_, Subject::Number(None)) => false,
+            (
+                Condition::Compare(_, op, Literal::Number(literal)),
+                Subject::Number(Some(number)),
+            ) => op.holds(&number, literal),
+            (
+                Condition::Between(_, Literal::Number(low), Literal::Number(high)),
+                Subject::Number(Some(number)),
+            ) => *low <= number && number <= *high,
+            _ => return Err("keys & values compare with strings, indexes with numbers".into()),
+        })
+    }
+
+    /// The range of an index condition, for reading the index
+    fn index_range(&self) -> Option<(Bound<i64>, Bound<i64>)> {
+        use Bound::*;
+        Some(match self {
+            Condition::Compare(Field::Index(_), op, Literal::Number(n)) => match op {
+                Op::Eq => (Included(*n), Included(*n)),
+                Op::Lt => (Unbounded, Excluded(*n)),
+                Op::Le => (Unbounded, Included(*n)),
+                Op::Gt => (Excluded(*n), Unbounded),
+                Op::Ge => (Included(*n), Unbounded),
+                Op::Ne => return None,
+            },
+            Condition::Between(Field::Index(_), Literal::Number(low), Literal::Number(high)) => {
+                (Included(*low), Included(*high))
+            }
+            _ => return None,
+        })
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Run a query, see the module docs for what it understands
+    pub fn query(&mut self, sql: &str) -> Result<QueryResult> {
+        let query = Parser {
+            tokens: tokenize(sql)?,
+            at: 0,
+        }
+        .parse()?;
+        for condition in &query.conditions {
+            if let Field::Index(name) = condition.field()
+                && !self.options.numeric_indexes.contains_key(name)
+            {
+                return Err(format!("unknown column or index {name:?}").into());
+            }
+        }
+
+        let candidates = self.query_candidates(&query)?;
+        let mut rows = Vec::new();
+        for key in candidates {
+            if query.limit.is_some_and(|limit| rows.len() >= limit) {
+                break;
+            }
+            let Some(val) = self.get_stored(&namespaced_key(&query.collection, &key))? else {
+                continue;
+            };
+            if !self.row_matches(&query.conditions, &key, &val)? {
+                continue;
+            }
+            let row = query
+                .columns
+                .iter()
+                .map(|column| match column {
+                    Column::Key => key.clone(),
+                    Column::Value => val.clone(),
+                })
+                .collect();
+            rows.push(row);
+        }
+
+        let columns = query
+            .columns
+            .iter()
+            .map(|column| match column {
+                Column::Key => "key".to_string(),
+                Column::Value => "value".to_string(),
+            })
+            .collect();
+        Ok(QueryResult { columns, rows })
+    }
+
+    /// Keys worth looking at, narrowed down by the cheapest condition there is
+    fn query_candidates(&mut self, query: &Query) -> Result<Vec<String>> {
+        for condition in &query.conditions {
+            if let (Field::Index(name), Some(range)) = (condition.field(), condition.index_range())
+            {
+                return self.index_range(name, range);
+            }
+        }
+        for condition in &query.conditions {
+            match condition {
+                Condition::Compare(Field::Key, Op::Eq, Literal::Text(key)) => {
+                    return Ok(vec![key.clone()]);
+                }
+                Condition::Like(Field::Key, pattern) => {
+                    let prefix: String = pattern
+                        .chars()
+                        .take_while(|c| *c != '%' && *c != '_')
+                        .collect();
+                    if !prefix.is_empty() {
+                        return Ok(self.scan_keys_in(&query.collection, &prefix));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(self.scan_keys_in(&query.collection, ""))
+    }
+
+    fn row_matches(&self, conditions: &[Condition], key: &str, val: &str) -> Result<bool> {
+        for condition in conditions {
+            let subject = match condition.field() {
+                Field::Key => Subject::Text(key),
+                Field::Value => Subject::Text(val),
+                Field::Index(name) => {
+                    Subject::Number(self.options.numeric_indexes[name].number(name, Some(val))?)
+                }
+            };
+            if !condition.holds(subject)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_like() {
+        assert!(like("user:42", "user:%"));
+        assert!(like("user:42", "%:4_"));
+        assert!(!like("user:42", "user:_"));
+        assert!(like("", "%"));
+    }
+
+    #[test]
+    fn test_query() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions::default()
+            .with_numeric_index("age", "people", |val| val.split(';').nth(1)?.parse().ok());
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        for i in 0..20 {
+            db.set(&format!("user:{i:02}"), &format!("name {i}"))
+                .unwrap();
+        }
+        db.set("order:1", "it's shipped").unwrap();
+        let mut people = db.collection("people").unwrap();
+        people.set("ann", "Ann;34").unwrap();
+        people.set("bob", "Bob;17").unwrap();
+        people.set("cid", "Cid;25").unwrap();
+
+        let result = db
+            .query("SELECT key, value WHERE key LIKE 'user:%' LIMIT 3")
+            .unwrap();
+        assert_eq!(result.columns, vec!["key", "value"]);
+        assert_eq!(result.rows.len(), 3);
+        assert_eq!(result.rows[0], vec!["user:00", "name 0"]);
+
+        let result = db.query("select * where value = 'it''s shipped'").unwrap();
+        assert_eq!(result.rows, vec![vec!["order:1", "it's shipped"]]);
+
+        let result = db
+            .query("SELECT key FROM people WHERE age BETWEEN 18 AND 40 AND key != 'cid'")
+            .unwrap();
+        assert_eq!(result.rows, vec![vec!["ann"]]);
+        let result = db.query("SELECT key FROM people WHERE age < 30").unwrap();
+        assert_eq!(result.rows, vec![vec!["bob"], vec!["cid"]]);
+
+        assert!(db.query("SELECT key WHERE height > 3").is_err());
+        assert!(db.query("SELECT key WHERE key > 3").is_err());
+        assert!(db.query("DELETE everything").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{COLLECTION_SEPARATOR, namespaced_key},
+};
+use std::time::Duration;
+
+/// A message taken off a queue, to be acked once it has been processed
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueItem {
+    pub id: u64,
+    pub val: String,
+}
+
+/// A persistent FIFO queue with at-least-once delivery.
+/// Messages live in their own reserved collection, keyed by an increasing id.
+/// Dequeuing hides a message behind a marker key with a TTL, if the message
+/// isn't acked before the marker expires it becomes visible again.
+pub struct Queue<'a> {
+    db: &'a mut EmbeddedDatabase,
+    collection: String,
+}
+
+impl EmbeddedDatabase {
+    /// A handle to the named queue, created on first use
+    pub fn queue(&mut self, name: &str) -> Result<Queue<'_>> {
+        // The separator would end the collection name early
+        if name.is_empty() || name.contains(COLLECTION_SEPARATOR) {
+            return Err(format!("invalid queue name {name:?}").into());
+        }
+        Ok(Queue {
+            db: self,
+            collection: format!("__queue:{name}"),
+        })
+    }
+}
+
+impl Queue<'_> {
+    /// Add a message to the back of the queue & return its id
+    pub fn enqueue(&mut self, val: &str) -> Result<u64> {
+        let id = self.db.next_id(&self.collection)?;
+        self.db.put(self.item_key(id), val.as_bytes(), None)?;
+        Ok(id)
+    }
+
+    /// Take the oldest message nobody is working on.
+    /// It stays hidden for `visibility_timeout`, after which it is handed out
+    /// again unless it was acked.
+    pub fn dequeue(&mut self, visibility_timeout: Duration) -> Result<Option<QueueItem>> {
+        // Ids are zero padded so sorted keys are in FIFO order
+        let item_prefix = self.item_key_prefix();
+        for stored_key in self.db.live_keys(&self.collection) {
+            let Some(id) = stored_key.strip_prefix(&item_prefix) else {
+                continue;
+            };
+            let id: u64 = id.parse()?;
+            let taken_key = self.taken_key(id);
+            if self.db.is_live(&taken_key) {
+                continue;
+            }
+
+            let Some(val) = self.db.get_stored(&stored_key)? else {
+                continue;
+            };
+            self.db.put(taken_key, b"1", Some(visibility_timeout))?;
+            return Ok(Some(QueueItem { id, val }));
+        }
+        Ok(None)
+    }
+
+    /// Remove a processed message for good.
+    /// Returns false if there was no such message (e.g. it was acked already).
+    pub fn ack(&mut self, id: u64) -> Result<bool> {
+        let item_key = self.item_key(id);
+        if !self.db.is_live(&item_key) {
+            return Ok(false);
+        }
+        self.db.remove(item_key)?;
+        let taken_key = self.taken_key(id);
+        if self.db.is_live(&taken_key) {
+            self.db.remove(taken_key)?;
+        }
+        Ok(true)
+    }
+
+    /// Messages that haven't been acked yet, in flight ones included
+    pub fn len(&self) -> usize {
+        let item_prefix = self.item_key_prefix();
+        self.db
+            .live_keys(&self.collection)
+            .iter()
+            .filter(|key| key.starts_with(&item_prefix))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn item_key_prefix(&self) -> String {
+        namespaced_key(&self.collection, "item/")
+    }
+
+    fn item_key(&self, id: u64) -> String {
+        namespaced_key(&self.collection, &format!("item/{id:020}"))
+    }
+
+    fn taken_key(&self, id: u64) -> String {
+        namespaced_key(&self.collection, &format!("taken/{id:020}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_queue_redelivers_unacked_messages() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let timeout = Duration::from_millis(50);
+
+        let mut jobs = db.queue("jobs").unwrap();
+        let first = jobs.enqueue("resize image").unwrap();
+        let second = jobs.enqueue("send email").unwrap();
+
+        let item = jobs.dequeue(timeout).unwrap().unwrap();
+        assert_eq!(item.id, first);
+        assert_eq!(item.val, "resize image");
+        // The first message is in flight, the next consumer gets the second one
+        assert_eq!(jobs.dequeue(timeout).unwrap().unwrap().id, second);
+        assert_eq!(jobs.dequeue(timeout).unwrap(), None);
+
+        assert!(jobs.ack(second).unwrap());
+        assert!(!jobs.ack(second).unwrap());
+
+        // The first one was never acked, so it comes back after the timeout,
+        // also after a restart
+        drop(db);
+        std::thread::sleep(Duration::from_millis(80));
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let mut jobs = db.queue("jobs").unwrap();
+        assert_eq!(jobs.len(), 1);
+        let item = jobs.dequeue(timeout).unwrap().unwrap();
+        assert_eq!(item.id, first);
+        assert!(jobs.ack(first).unwrap());
+        assert!(jobs.is_empty());
+
+        // Empty messages are delivered like any other
+        let id = jobs.enqueue("").unwrap();
+        let item = jobs.dequeue(timeout).unwrap().unwrap();
+        assert_eq!((item.id, item.val.as_str()), (id, ""));
+    }
+
+    #[test]
+    fn test_queue_names() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert!(db.queue("").is_err());
+        assert!(db.queue("jobs\0done").is_err());
+        assert!(db.queue("jobs:done").is_ok());
+    }
+}
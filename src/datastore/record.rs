@@ -1,8 +1,85 @@
+use super::Result;
 use serde::{Deserialize, Serialize};
 
 /// This will be a single K,V record stored in the db file
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Record {
     pub key: String,
-    pub val: String,
+    pub val: Vec<u8>,
+    /// Marks `key` as deleted, the value is empty then. Values may be empty too.
+    pub tombstone: bool,
+    /// Unix time in millis after which the record no longer counts as live
+    pub expires_at: Option<u64>,
+    /// Names of the value transformers that ran over `val`, in the order they ran
+    pub transforms: Vec<String>,
+    pub kind: RecordKind,
+    /// Sequence number of the write (or batch) the record belongs to
+    pub seq: u64,
+}
+
+/// How a record takes part in recovery when the index is rebuilt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordKind {
+    /// A standalone write, applied as soon as it is read
+    Single,
+    /// Part of a write batch, only applied once the batch's commit marker is read
+    Batched,
+    /// Closes a write batch. Carries no key or value of its own
+    BatchCommit,
+    /// Filler that keeps writes aligned, see `DbOptions::record_alignment`
+    Padding,
+    /// Magic bytes, format version & flags, always the first frame of a file
+    Header,
+}
+
+/// A record as the first version of the format wrote it, before headers: just
+/// the key & a UTF-8 value, where an empty value marks the key as deleted
+#[derive(Deserialize)]
+struct BaselineRecord {
+    key: String,
+    val: String,
+}
+
+impl From<BaselineRecord> for Record {
+    fn from(record: BaselineRecord) -> Self {
+        Record {
+            key: record.key,
+            tombstone: record.val.is_empty(),
+            val: record.val.into_bytes(),
+            expires_at: None,
+            transforms: Vec::new(),
+            kind: RecordKind::Single,
+            seq: 0,
+        }
+    }
+}
+
+impl Record {
+    /// Decode the bytes of a frame read from a file of format `version`.
+    /// A file without a header (version 0) may hold records of the baseline
+    /// layout as well as ones appended in this layout since it was opened, so
+    /// there a record only counts if it takes up exactly the frame.
+    pub(crate) fn decode(bytes: &[u8], version: u32) -> Result<Record> {
+        if version > 0 {
+            return Ok(bincode::deserialize(bytes)?);
+        }
+        if let Ok(record) = bincode::deserialize::<Record>(bytes)
+            && bincode::serialized_size(&record)? == bytes.len() as u64
+        {
+            return Ok(record);
+        }
+        let record: BaselineRecord = bincode::deserialize(bytes)?;
+        if 16 + record.key.len() + record.val.len() != bytes.len() {
+            return Err("frame holds no record of either layout".into());
+        }
+        Ok(record.into())
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
 }
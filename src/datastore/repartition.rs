@@ -0,0 +1,197 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{collection_of, user_key},
+    tenant::tenant_of,
+};
+use std::path::Path;
+
+/// What `merge_from` does with a key both files hold a live value for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the value already in this file
+    KeepOurs,
+    /// Take the value from the other file
+    KeepTheirs,
+    /// Keep whichever was written with the higher sequence number. Only
+    /// means something for files that share a history, e.g. two halves of a `split`.
+    KeepNewest,
+    /// Fail the merge before anything is written
+    Fail,
+}
+
+/// A live value on its way from one file to another, decoded
+#[derive(Debug)]
+pub(crate) struct MovedRecord {
+    pub(crate) key: String,
+    pub(crate) val: Vec<u8>,
+    pub(crate) expires_at: Option<u64>,
+    pub(crate) seq: u64,
+}
+
+/// User & tenant collections move, the db's own bookkeeping (queues,
+/// sequences, consumer offsets, index entries) stays behind
+fn is_user_data(stored_key: &str) -> bool {
+    let collection = collection_of(stored_key);
+    !collection.starts_with("__") || tenant_of(collection).is_some()
+}
+
+impl EmbeddedDatabase {
+    /// Copy every live value into two new files, `path_a` getting the keys
+    /// `partition(collection, key)` returns true for. Values keep their TTL
+    /// & sequence number, this file is left as it is. Both files are opened
+    /// with this db's options, so they encrypt & compress the same way.
+    /// Returns how many values went to each file.
+    pub fn split<P: AsRef<Path>>(
+        &mut self,
+        path_a: P,
+        path_b: P,
+        partition: impl Fn(&str, &str) -> bool,
+    ) -> Result<(usize, usize)> {
+        let (mut to_a, mut to_b) = (Vec::new(), Vec::new());
+        for moved in self.user_records()? {
+            if partition(collection_of(&moved.key), user_key(&moved.key)) {
+                to_a.push(moved);
+            } else {
+                to_b.push(moved);
+            }
+        }
+        let counts = (to_a.len(), to_b.len());
+        for (path, moved) in [(path_a.as_ref(), to_a), (path_b.as_ref(), to_b)] {
+            // Sequence numbers only ever go up within a file
+            if path.metadata().is_ok_and(|meta| meta.len() > 0) {
+                return Err(format!("{} isn't empty", path.display()).into());
+            }
+            let mut db = EmbeddedDatabase::with_options(path, self.options.clone())?;
+            db.import_records(moved, None)?;
+            db.sync()?;
+        }
+        Ok(counts)
+    }
+
+    /// Copy every live value of the db at `other_path` into this one, as a
+    /// single batch under a new sequence number. The values keep their TTL,
+    /// keys both sides hold are settled by `policy`. The other file is opened
+    /// with this db's options & left as it is.
+    /// Returns how many values were taken over.
+    pub fn merge_from<P: AsRef<Path>>(
+        &mut self,
+        other_path: P,
+        policy: ConflictPolicy,
+    ) -> Result<usize> {
+        let other_path = other_path.as_ref();
+        if !other_path.exists() {
+            return Err(format!("no db at {}", other_path.display()).into());
+        }
+        let mut other = EmbeddedDatabase::with_options(other_path, self.options.clone())?;
+
+        let mut taken = Vec::new();
+        for moved in other.user_records()? {
+            let take = match self.live_span(&moved.key) {
+                None => true,
+                Some(_) if policy == ConflictPolicy::Fail => {
+                    return Err(format!("both files hold key {:?}", moved.key).into());
+                }
+                Some(_) if policy == ConflictPolicy::KeepOurs => false,
+                Some(_) if policy == ConflictPolicy::KeepTheirs => true,
+                Some((offset, _)) => self.read_record(offset)?.seq < moved.seq,
+            };
+            if take {
+                taken.push(moved);
+            }
+        }
+        let count = taken.len();
+        self.import_records(taken, Some(self.last_seq() + 1))?;
+        Ok(count)
+    }
+
+    /// Live values of user & tenant collections, oldest write first
+    fn user_records(&mut self) -> Result<Vec<MovedRecord>> {
+        let keys: Vec<String> = self
+            .all_live_keys()
+            .into_iter()
+            .filter(|key| is_user_data(key))
+            .collect();
+        let mut moved = Vec::with_capacity(keys.len());
+        for key in keys {
+            let Some((offset, _)) = self.live_span(&key) else {
+                continue;
+            };
+            let record = self.read_record(offset)?;
+            let (expires_at, seq) = (record.expires_at, record.seq);
+            let val = self.transformers.decode(record)?;
+            moved.push(MovedRecord {
+                key,
+                val,
+                expires_at,
+                seq,
+            });
+        }
+        moved.sort_by_key(|moved| moved.seq);
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CollectionOptions, DbOptions, MockClock, WriteBatch};
+    use std::{sync::Arc, time::Duration};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_split_and_merge() {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let clock = MockClock::default();
+        let options = DbOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        }
+        .with_collection(
+            "tmp",
+            CollectionOptions {
+                default_ttl: Some(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        );
+        let mut db =
+            EmbeddedDatabase::with_options(dir.path().join("all.db"), options.clone()).unwrap();
+        for i in 0..10 {
+            db.set(&format!("key{i}"), &i.to_string()).unwrap();
+        }
+        db.collection("eu").unwrap().set("user", "anna").unwrap();
+        db.collection("tmp").unwrap().set("session", "abc").unwrap();
+
+        let (a, b) = (dir.path().join("a.db"), dir.path().join("b.db"));
+        let counts = db
+            .split(&a, &b, |collection, key| {
+                collection == "eu" || key.ends_with(['0', '2', '4', '6', '8'])
+            })
+            .unwrap();
+        assert_eq!(counts, (6, 6));
+        assert!(db.split(&a, &b, |_, _| true).is_err());
+
+        let mut half = EmbeddedDatabase::with_options(&a, options.clone()).unwrap();
+        assert_eq!(half.get("key4").unwrap(), Some("4".to_string()));
+        assert_eq!(half.get("key5").unwrap(), None);
+        assert_eq!(half.last_seq(), 11);
+        let mut other = EmbeddedDatabase::with_options(&b, options).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("key5", "five").set("key4", "four");
+        other.apply_batch(batch).unwrap();
+        drop(other);
+
+        // key4 was written later on the b side
+        assert!(half.merge_from(&b, ConflictPolicy::Fail).is_err());
+        assert_eq!(half.merge_from(&b, ConflictPolicy::KeepNewest).unwrap(), 7);
+        assert_eq!(half.scan_keys("key").len(), 10);
+        assert_eq!(half.get("key4").unwrap(), Some("four".to_string()));
+        let mut eu = half.collection("eu").unwrap();
+        assert_eq!(eu.get("user").unwrap(), Some("anna".to_string()));
+
+        // The TTL came along through both moves
+        let mut tmp = half.collection("tmp").unwrap();
+        assert_eq!(tmp.get("session").unwrap(), Some("abc".to_string()));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(tmp.get("session").unwrap(), None);
+    }
+}
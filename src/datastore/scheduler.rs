@@ -0,0 +1,666 @@
+use super::{Clock, EmbeddedDatabase, Result, error::catch_callback};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::BuildHasher,
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Longest the scheduler thread sleeps before checking whether the db is gone
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// A maintenance job the scheduler can run against the database
+#[derive(Clone)]
+pub enum Task {
+    /// `compact_if_needed`, so the collections' compaction policies decide
+    CompactIfNeeded,
+    /// Compact no matter how much garbage there is
+    Compact,
+    /// Drop expired keys from the index, see `EmbeddedDatabase::sweep_expired`
+    SweepExpired,
+    Sync,
+    /// Anything else, e.g. a backup or a scrub of the data file
+    Custom(CustomTask),
+    /// A custom task that only runs in maintenance windows, like compaction
+    HeavyCustom(CustomTask),
+}
+
+pub type CustomTask = Arc<dyn Fn(&mut EmbeddedDatabase) -> Result<()> + Send + Sync>;
+
+impl Task {
+    pub fn custom(
+        task: impl Fn(&mut EmbeddedDatabase) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Task::Custom(Arc::new(task))
+    }
+
+    /// A custom task that waits for a maintenance window, see `Task::is_heavy`
+    pub fn heavy(
+        task: impl Fn(&mut EmbeddedDatabase) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Task::HeavyCustom(Arc::new(task))
+    }
+
+    /// Heavy tasks are put off until the next of `DbOptions::maintenance_windows`
+    /// when they come due outside of all of them
+    pub fn is_heavy(&self) -> bool {
+        matches!(
+            self,
+            Task::CompactIfNeeded | Task::Compact | Task::HeavyCustom(_)
+        )
+    }
+
+    fn run(&self, name: &str, db: &mut EmbeddedDatabase) -> Result<()> {
+        match self {
+            Task::CompactIfNeeded => db.compact_if_needed().map(|_| ()),
+            Task::Compact => db.compact(),
+            Task::SweepExpired => {
+                db.sweep_expired();
+                Ok(())
+            }
+            Task::Sync => db.sync(),
+            Task::Custom(task) | Task::HeavyCustom(task) => {
+                catch_callback(|| format!("scheduled task {name:?}"), || task(db))?
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::CompactIfNeeded => f.write_str("CompactIfNeeded"),
+            Task::Compact => f.write_str("Compact"),
+            Task::SweepExpired => f.write_str("SweepExpired"),
+            Task::Sync => f.write_str("Sync"),
+            Task::Custom(_) => f.write_str("Custom"),
+            Task::HeavyCustom(_) => f.write_str("HeavyCustom"),
+        }
+    }
+}
+
+/// When a task runs: at a fixed interval or on a cron expression, plus up to
+/// `jitter` of random delay so a fleet of databases doesn't compact in lockstep
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    when: When,
+    jitter: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum When {
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    pub fn every(interval: Duration) -> Self {
+        Schedule {
+            when: When::Every(interval.max(Duration::from_millis(1))),
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Classic 5 field cron expression, "minute hour day-of-month month
+    /// day-of-week", in UTC. Fields take `*`, numbers, ranges (`1-5`), lists
+    /// (`0,30`) & steps (`*/15`). Sunday is 0.
+    pub fn cron(expression: &str) -> Result<Self> {
+        Ok(Schedule {
+            when: When::Cron(Cron::parse(expression)?),
+            jitter: Duration::ZERO,
+        })
+    }
+
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The first time after `after` the task is due, jitter included
+    pub(crate) fn next_after(&self, after: SystemTime) -> SystemTime {
+        let next = match &self.when {
+            When::Every(interval) => after + *interval,
+            When::Cron(cron) => cron.next_after(after),
+        };
+        next + random_below(self.jitter)
+    }
+}
+
+fn random_below(limit: Duration) -> Duration {
+    if limit.is_zero() {
+        return Duration::ZERO;
+    }
+    // Every RandomState is seeded differently, good enough for spreading load
+    let random = RandomState::new().hash_one(SystemTime::now());
+    Duration::from_nanos(random % limit.as_nanos().max(1) as u64)
+}
+
+/// Time of day heavy tasks are allowed to run in, e.g. "02:00-04:00".
+/// Windows that end before they start run past midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaintenanceWindow {
+    start: u32, // Minutes since midnight
+    end: u32,
+    utc_offset: i32, // Minutes the local time is ahead of UTC
+}
+
+impl MaintenanceWindow {
+    /// "HH:MM-HH:MM" in UTC, see `with_utc_offset` for local times
+    pub fn parse(window: &str) -> Result<Self> {
+        let parse_time = |time: &str| -> Result<u32> {
+            let (hours, minutes) = time
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| format!("{time:?} isn't a HH:MM time"))?;
+            let (hours, minutes): (u32, u32) = (hours.parse()?, minutes.parse()?);
+            if hours > 23 || minutes > 59 {
+                return Err(format!("{time:?} isn't a HH:MM time").into());
+            }
+            Ok(hours * 60 + minutes)
+        };
+        let Some((start, end)) = window.split_once('-') else {
+            return Err(
+                format!("maintenance window {window:?} needs to look like 02:00-04:00").into(),
+            );
+        };
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(format!("maintenance window {window:?} is empty").into());
+        }
+        Ok(MaintenanceWindow {
+            start,
+            end,
+            utc_offset: 0,
+        })
+    }
+
+    /// Read the times as local time `minutes` ahead of UTC (negative west of
+    /// it). The offset is fixed, daylight saving time isn't followed.
+    pub fn with_utc_offset(mut self, minutes: i32) -> Self {
+        self.utc_offset = minutes;
+        self
+    }
+
+    /// Minutes since local midnight at `time`
+    fn minute_of_day(&self, time: SystemTime) -> u32 {
+        let minutes = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 60;
+        (minutes as i64 + self.utc_offset as i64).rem_euclid(24 * 60) as u32
+    }
+
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let minute = self.minute_of_day(time);
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// When the window opens next, `time` itself if it's open already
+    fn next_start(&self, time: SystemTime) -> SystemTime {
+        if self.contains(time) {
+            return time;
+        }
+        let seconds_into_minute = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            % 60;
+        let wait = (self.start + 24 * 60 - self.minute_of_day(time)) % (24 * 60);
+        time + Duration::from_secs(wait as u64 * 60 - seconds_into_minute)
+    }
+}
+
+/// Parsed cron expression, one bit per allowed value
+#[derive(Debug, Clone, PartialEq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // With both day fields restricted, cron runs when either one matches
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl Cron {
+    fn parse(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields.as_slice() else {
+            return Err(format!("cron expression {expression:?} needs 5 fields").into());
+        };
+        Ok(Cron {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days_of_month: parse_field(days_of_month, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            // 7 is Sunday too
+            days_of_week: {
+                let days = parse_field(days_of_week, 0, 7)?;
+                (days | (days >> 7)) & 0x7f
+            },
+            any_day_of_month: *days_of_month == "*",
+            any_day_of_week: *days_of_week == "*",
+        })
+    }
+
+    fn day_matches(&self, day: u32, weekday: u32) -> bool {
+        let by_month = self.days_of_month & (1 << day) != 0;
+        let by_week = self.days_of_week & (1 << weekday) != 0;
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => by_week,
+            (false, true) => by_month,
+            (false, false) => by_month || by_week,
+        }
+    }
+
+    /// First whole minute after `after` that matches
+    fn next_after(&self, after: SystemTime) -> SystemTime {
+        let seconds = after
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut minute = seconds / 60 + 1;
+        // Every expression that parsed matches at least once in 4 years (Feb 29)
+        let give_up = minute + 4 * 366 * 24 * 60;
+        while minute < give_up {
+            let days = minute / (24 * 60);
+            let (_, month, day) = civil_from_days(days as i64);
+            let weekday = ((days + 4) % 7) as u32; // 1970-01-01 was a Thursday
+            if self.months & (1 << month) == 0 || !self.day_matches(day, weekday) {
+                minute = (days + 1) * 24 * 60;
+                continue;
+            }
+            let hour = (minute / 60) % 24;
+            if self.hours & (1 << hour) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << (minute % 60)) != 0 {
+                return UNIX_EPOCH + Duration::from_secs(minute * 60);
+            }
+            minute += 1;
+        }
+        // Only reachable with something like "0 0 31 2 *", never run it
+        UNIX_EPOCH + Duration::from_secs(u32::MAX as u64 * 60)
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        let (low, high) = if range == "*" {
+            (min, max)
+        } else if let Some((low, high)) = range.split_once('-') {
+            (low.parse()?, high.parse()?)
+        } else {
+            let value = range.parse()?;
+            (value, if step > 1 { max } else { value })
+        };
+        if low < min || high > max || low > high || step == 0 {
+            return Err(format!("cron field {field:?} is out of range {min}-{max}").into());
+        }
+        for value in (low..=high).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// (year, month, day) of a day counted from 1970-01-01
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Where a scheduled task stands
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskStatus {
+    pub name: String,
+    pub next_run: SystemTime,
+    pub last_run: Option<SystemTime>,
+    /// Error of the last run, `None` if it went fine
+    pub last_error: Option<String>,
+    pub runs: u64,
+}
+
+struct Entry {
+    task: Task,
+    schedule: Schedule,
+    status: TaskStatus,
+}
+
+/// Runs registered tasks on one background thread, one after the other.
+/// The thread starts with the first task & stops once the database is gone.
+pub(crate) struct Scheduler {
+    tasks: Mutex<Vec<Entry>>,
+    changed: Condvar,
+    started: Mutex<bool>,
+    windows: Vec<MaintenanceWindow>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(windows: Vec<MaintenanceWindow>, clock: Arc<dyn Clock>) -> Self {
+        Scheduler {
+            tasks: Mutex::default(),
+            changed: Condvar::new(),
+            started: Mutex::default(),
+            windows,
+            clock,
+        }
+    }
+
+    /// When a heavy task that is due at `time` may run, no windows means any time
+    fn heavy_allowed_at(&self, time: SystemTime) -> SystemTime {
+        self.windows
+            .iter()
+            .map(|window| window.next_start(time))
+            .min()
+            .unwrap_or(time)
+    }
+
+    pub(crate) fn add(
+        self: &Arc<Self>,
+        db: Weak<Mutex<EmbeddedDatabase>>,
+        name: &str,
+        schedule: Schedule,
+        task: Task,
+    ) -> Result<()> {
+        {
+            let mut tasks = self.lock_tasks()?;
+            if tasks.iter().any(|entry| entry.status.name == name) {
+                return Err(format!("a task named {name:?} is scheduled already").into());
+            }
+            let status = TaskStatus {
+                name: name.to_string(),
+                next_run: schedule.next_after(self.clock.now()),
+                last_run: None,
+                last_error: None,
+                runs: 0,
+            };
+            tasks.push(Entry {
+                task,
+                schedule,
+                status,
+            });
+        }
+        self.changed.notify_all();
+
+        let mut started = self
+            .started
+            .lock()
+            .map_err(|_| "the scheduler lock was poisoned by a panic")?;
+        if !*started {
+            let scheduler = self.clone();
+            thread::spawn(move || scheduler.run(db));
+            *started = true;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> Result<bool> {
+        let mut tasks = self.lock_tasks()?;
+        let before = tasks.len();
+        tasks.retain(|entry| entry.status.name != name);
+        Ok(tasks.len() < before)
+    }
+
+    pub(crate) fn statuses(&self) -> Result<Vec<TaskStatus>> {
+        Ok(self
+            .lock_tasks()?
+            .iter()
+            .map(|entry| entry.status.clone())
+            .collect())
+    }
+
+    /// Run the task called `name` on the calling thread right away, inside a
+    /// maintenance window or not. Its next scheduled run stays as it was.
+    pub(crate) fn run_now(&self, db: &Mutex<EmbeddedDatabase>, name: &str) -> Result<()> {
+        let task = self
+            .lock_tasks()?
+            .iter()
+            .find(|entry| entry.status.name == name)
+            .map(|entry| entry.task.clone())
+            .ok_or_else(|| format!("no task named {name:?} is scheduled"))?;
+        self.run_task(db, name, &task)
+    }
+
+    /// Run every task that is due on the clock on the calling thread, see
+    /// `ThreadSafeDB::run_pending`. Returns how many ran.
+    pub(crate) fn run_pending(&self, db: &Mutex<EmbeddedDatabase>) -> Result<usize> {
+        let mut ran = 0;
+        loop {
+            let due = {
+                let mut tasks = self.lock_tasks()?;
+                self.take_due(&mut tasks, self.clock.now())
+            };
+            let Some((name, task)) = due else {
+                return Ok(ran);
+            };
+            // The outcome ends up in the task's status
+            let _ = self.run_task(db, &name, &task);
+            ran += 1;
+        }
+    }
+
+    /// Pick a task that is due at `now` & move its next run along already,
+    /// so it isn't picked twice. Heavy tasks that came due outside the
+    /// maintenance windows are put off until the next one.
+    fn take_due(&self, tasks: &mut [Entry], now: SystemTime) -> Option<(String, Task)> {
+        for entry in tasks.iter_mut() {
+            if entry.status.next_run <= now && entry.task.is_heavy() {
+                entry.status.next_run = self.heavy_allowed_at(now);
+            }
+        }
+        let entry = tasks
+            .iter_mut()
+            .find(|entry| entry.status.next_run <= now)?;
+        entry.status.next_run = entry.schedule.next_after(now);
+        Some((entry.status.name.clone(), entry.task.clone()))
+    }
+
+    /// Run a task under the db lock & record how it went
+    fn run_task(&self, db: &Mutex<EmbeddedDatabase>, name: &str, task: &Task) -> Result<()> {
+        let result = match db.lock() {
+            Ok(mut db) => task.run(name, &mut db),
+            Err(_) => Err("the database lock was poisoned by a panic".into()),
+        };
+        let mut tasks = self.lock_tasks()?;
+        // It may have been unscheduled while it ran
+        if let Some(entry) = tasks.iter_mut().find(|entry| entry.status.name == name) {
+            entry.status.last_run = Some(self.clock.now());
+            entry.status.last_error = result.as_ref().err().map(|err| err.to_string());
+            entry.status.runs += 1;
+        }
+        result
+    }
+
+    fn lock_tasks(&self) -> Result<std::sync::MutexGuard<'_, Vec<Entry>>> {
+        Ok(self
+            .tasks
+            .lock()
+            .map_err(|_| "the scheduler lock was poisoned by a panic")?)
+    }
+
+    fn run(&self, db: Weak<Mutex<EmbeddedDatabase>>) {
+        loop {
+            if db.strong_count() == 0 {
+                return;
+            }
+            let Ok(mut tasks) = self.tasks.lock() else {
+                return;
+            };
+            let now = self.clock.now();
+            let Some((name, task)) = self.take_due(&mut tasks, now) else {
+                // Sleep until the next task is due or the task list changes
+                let sleep = tasks
+                    .iter()
+                    .filter_map(|entry| entry.status.next_run.duration_since(now).ok())
+                    .min()
+                    .unwrap_or(MAX_IDLE)
+                    .min(MAX_IDLE);
+                let _ = self.changed.wait_timeout(tasks, sleep);
+                continue;
+            };
+            drop(tasks);
+
+            // The task list isn't locked while the task runs, so tasks can
+            // be looked at & added meanwhile
+            let Some(db) = db.upgrade() else {
+                return;
+            };
+            // The outcome ends up in the task's status
+            let _ = self.run_task(&db, &name, &task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ThreadSafeDB;
+    use tempfile::NamedTempFile;
+
+    fn at(year_day_hour_minute: (i64, u32, u32, u32, u32)) -> SystemTime {
+        let (year, month, day, hour, minute) = year_day_hour_minute;
+        // Walk to the day, it's a test
+        let mut days = 0;
+        while civil_from_days(days) != (year, month, day) {
+            days += 1;
+        }
+        UNIX_EPOCH
+            + Duration::from_secs(days as u64 * 86_400 + hour as u64 * 3600 + minute as u64 * 60)
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let nightly = Schedule::cron("30 2 * * *").unwrap();
+        assert_eq!(
+            nightly.next_after(at((2026, 10, 17, 14, 0))),
+            at((2026, 10, 18, 2, 30))
+        );
+        // 2026-10-17 is a Saturday, the next weekday quarter hour is Monday's
+        let weekdays = Schedule::cron("*/15 9-17 * * 1-5").unwrap();
+        assert_eq!(
+            weekdays.next_after(at((2026, 10, 17, 10, 0))),
+            at((2026, 10, 19, 9, 0))
+        );
+        let first = Schedule::cron("0 0 1 * *").unwrap();
+        assert_eq!(
+            first.next_after(at((2026, 12, 5, 0, 0))),
+            at((2027, 1, 1, 0, 0))
+        );
+        assert!(Schedule::cron("61 * * * *").is_err());
+        assert!(Schedule::cron("* * *").is_err());
+    }
+
+    #[test]
+    fn test_scheduled_tasks_run() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = ThreadSafeDB::new(temp_file.path()).unwrap();
+        let every =
+            Schedule::every(Duration::from_millis(10)).with_jitter(Duration::from_millis(5));
+        db.schedule(
+            "count",
+            every.clone(),
+            Task::custom(|db| db.next_id("runs").map(|_| ())),
+        )
+        .unwrap();
+        db.schedule(
+            "fail",
+            every.clone(),
+            Task::custom(|_| Err("no disk".into())),
+        )
+        .unwrap();
+        assert!(db.schedule("count", every, Task::Sync).is_err());
+
+        thread::sleep(Duration::from_millis(200));
+        let statuses = db.scheduled_tasks().unwrap();
+        assert!(statuses[0].runs >= 2 && statuses[0].last_error.is_none());
+        assert_eq!(statuses[1].last_error.as_deref(), Some("no disk"));
+
+        assert!(db.unschedule("count").unwrap());
+        let runs = db.next_id("runs").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(db.next_id("runs").unwrap(), runs + 1);
+    }
+
+    #[test]
+    fn test_heavy_tasks_wait_for_window() {
+        let night = MaintenanceWindow::parse("23:30-01:00").unwrap();
+        assert!(night.contains(at((2026, 10, 17, 0, 15))));
+        assert!(!night.contains(at((2026, 10, 17, 1, 0))));
+        assert_eq!(
+            night.next_start(at((2026, 10, 17, 14, 0))),
+            at((2026, 10, 17, 23, 30))
+        );
+        // 02:00-04:00 in UTC+2 is midnight to 02:00 UTC
+        let local = MaintenanceWindow::parse("02:00-04:00")
+            .unwrap()
+            .with_utc_offset(120);
+        assert!(local.contains(at((2026, 10, 17, 1, 59))));
+        assert!(MaintenanceWindow::parse("25:00-04:00").is_err());
+
+        // A window that is a couple of hours away
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 60;
+        let start = (now + 120) % (24 * 60);
+        let window = format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            start / 60,
+            start % 60,
+            (start + 60) % (24 * 60) / 60,
+            start % 60
+        );
+        let options = crate::DbOptions {
+            maintenance_windows: vec![MaintenanceWindow::parse(&window).unwrap()],
+            ..Default::default()
+        };
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+        let every = Schedule::every(Duration::from_millis(10));
+        db.schedule("compact", every.clone(), Task::Compact)
+            .unwrap();
+        db.schedule("sync", every, Task::Sync).unwrap();
+
+        thread::sleep(Duration::from_millis(100));
+        let statuses = db.scheduled_tasks().unwrap();
+        assert_eq!(statuses[0].runs, 0);
+        assert!(statuses[0].next_run > SystemTime::now() + Duration::from_secs(3600));
+        assert!(statuses[1].runs > 0);
+
+        db.run_now("compact").unwrap();
+        assert_eq!(db.scheduled_tasks().unwrap()[0].runs, 1);
+        assert!(db.run_now("scrub").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use super::{
+    DbError, EmbeddedDatabase, Result,
+    collection::{SYSTEM_COLLECTION, namespaced_key, validate_collection_name},
+};
+
+/// Schema versions live under `__system`, one key per collection
+const SCHEMA_PREFIX: &str = "schema/";
+
+fn schema_key(collection: &str) -> String {
+    namespaced_key(SYSTEM_COLLECTION, &format!("{SCHEMA_PREFIX}{collection}"))
+}
+
+impl EmbeddedDatabase {
+    /// Record that the values of `collection` ("" for the default one) are
+    /// at schema `version` now, e.g. once a migration rewrote them. Versions
+    /// only go up, so a newer db can't be marked as an older one by mistake.
+    pub fn register_schema(&mut self, collection: &str, version: u32) -> Result<()> {
+        if !collection.is_empty() {
+            validate_collection_name(collection)?;
+        }
+        if let Some(found) = self.schema_version(collection)?
+            && found > version
+        {
+            return Err(format!(
+                "collection {collection:?} is at schema version {found} already, can't go back to {version}"
+            )
+            .into());
+        }
+        self.put(schema_key(collection), version.to_string().as_bytes(), None)?;
+        self.sync()
+    }
+
+    /// The schema version recorded for `collection`, if any
+    pub fn schema_version(&mut self, collection: &str) -> Result<Option<u32>> {
+        match self.get_stored(&schema_key(collection))? {
+            Some(version) => Ok(Some(version.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// On open: record the expected versions of collections that don't have
+    /// one yet & fail on any that differ
+    pub(crate) fn check_schemas(&mut self) -> Result<()> {
+        let mut expected: Vec<(String, u32)> = self
+            .options
+            .schema_versions
+            .iter()
+            .map(|(collection, version)| (collection.clone(), *version))
+            .collect();
+        expected.sort();
+        for (collection, expected) in expected {
+            match self.schema_version(&collection)? {
+                None => self.register_schema(&collection, expected)?,
+                Some(found) if found == expected => {}
+                Some(found) => {
+                    return Err(DbError::SchemaMismatch {
+                        collection,
+                        expected,
+                        found,
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    fn expecting(version: u32) -> DbOptions {
+        DbOptions {
+            schema_versions: [("users".to_string(), version)].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_schema_versions() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), expecting(1)).unwrap();
+        assert_eq!(db.schema_version("users").unwrap(), Some(1));
+        assert_eq!(db.schema_version("orders").unwrap(), None);
+
+        // The new binary migrates the values & moves the version forward
+        db.register_schema("users", 2).unwrap();
+        assert!(db.register_schema("users", 1).is_err());
+        drop(db);
+
+        // An old binary can't open it anymore
+        let err = EmbeddedDatabase::with_options(temp_file.path(), expecting(1))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<DbError>(),
+            Some(&DbError::SchemaMismatch {
+                collection: "users".to_string(),
+                expected: 1,
+                found: 2
+            })
+        );
+        assert!(EmbeddedDatabase::with_options(temp_file.path(), expecting(2)).is_ok());
+        assert!(EmbeddedDatabase::new(temp_file.path()).is_ok());
+    }
+}
@@ -0,0 +1,150 @@
+use super::{KeyDifference, Result, ThreadSafeDB, WriteBatch};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+};
+
+/// Mirrors every write to a second database while reads stay on the
+/// primary, to move data to a new path or format without downtime. Once
+/// `divergence` comes back empty, cut over by switching to the shadow.
+/// Writes made through the inner handles directly aren't mirrored.
+#[derive(Clone)]
+pub struct ShadowDb {
+    primary: ThreadSafeDB,
+    shadow: ThreadSafeDB,
+    // Keeps the two copies seeing writes in the same order
+    order: Arc<Mutex<()>>,
+    shadow_errors: Arc<AtomicU64>,
+}
+
+impl ShadowDb {
+    pub fn new(primary: ThreadSafeDB, shadow: ThreadSafeDB) -> Self {
+        ShadowDb {
+            primary,
+            shadow,
+            order: Arc::new(Mutex::new(())),
+            shadow_errors: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn primary(&self) -> &ThreadSafeDB {
+        &self.primary
+    }
+
+    pub fn shadow(&self) -> &ThreadSafeDB {
+        &self.shadow
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.primary.get(key)
+    }
+
+    pub fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.primary.scan_keys(prefix)
+    }
+
+    pub fn set(&self, key: &str, val: &str) -> Result<()> {
+        self.mirror(|db| db.set(key, val))
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.mirror(|db| db.delete(key))
+    }
+
+    pub fn swap(&self, key_a: &str, key_b: &str) -> Result<()> {
+        self.mirror(|db| db.swap(key_a, key_b))
+    }
+
+    pub fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.mirror(|db| db.apply_batch(batch.clone()))
+    }
+
+    /// Only mirrored when the primary took the write
+    pub fn set_if_absent(&self, key: &str, val: &str) -> Result<bool> {
+        let _order = self.lock_order()?;
+        let written = self.primary.set_if_absent(key, val)?;
+        if written {
+            self.shadow_write(self.shadow.set(key, val));
+        }
+        Ok(written)
+    }
+
+    /// Only mirrored when the primary had a value to take
+    pub fn take(&self, key: &str) -> Result<Option<String>> {
+        let _order = self.lock_order()?;
+        let taken = self.primary.take(key)?;
+        if taken.is_some() {
+            self.shadow_write(self.shadow.delete(key));
+        }
+        Ok(taken)
+    }
+
+    /// Writes the primary took but the shadow failed, since the wrapper was made
+    pub fn shadow_errors(&self) -> u64 {
+        self.shadow_errors.load(Ordering::Relaxed)
+    }
+
+    /// Keys whose live values differ between the primary & the shadow.
+    /// Writes wait while the two are compared.
+    pub fn divergence(&self) -> Result<Vec<KeyDifference>> {
+        let _order = self.lock_order()?;
+        let mut primary = self.primary.lock()?;
+        let mut shadow = self.shadow.lock()?;
+        primary.diff(&mut shadow)
+    }
+
+    /// Write to the primary, then to the shadow. A failed shadow write
+    /// doesn't fail the caller, it's counted & shows up in `divergence`.
+    fn mirror(&self, write: impl Fn(&ThreadSafeDB) -> Result<()>) -> Result<()> {
+        let _order = self.lock_order()?;
+        write(&self.primary)?;
+        self.shadow_write(write(&self.shadow));
+        Ok(())
+    }
+
+    fn shadow_write(&self, result: Result<()>) {
+        if let Err(err) = result {
+            self.shadow_errors.fetch_add(1, Ordering::Relaxed);
+            log::warn!(error:% = err; "shadow write failed");
+        }
+    }
+
+    fn lock_order(&self) -> Result<std::sync::MutexGuard<'_, ()>> {
+        self.order
+            .lock()
+            .map_err(|_| "a shadowed write panicked".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Difference;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_shadow_writes() {
+        let (old_file, new_file) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let db = ShadowDb::new(
+            ThreadSafeDB::new(old_file.path()).unwrap(),
+            ThreadSafeDB::new(new_file.path()).unwrap(),
+        );
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("c", "3").delete("a");
+        db.apply_batch(batch).unwrap();
+        assert!(!db.set_if_absent("c", "other").unwrap());
+        assert_eq!(db.take("b").unwrap(), Some("2".to_string()));
+        assert!(db.divergence().unwrap().is_empty());
+        assert_eq!(db.shadow().get("c").unwrap(), Some("3".to_string()));
+
+        // A write that skipped the wrapper shows up
+        db.primary().set("d", "4").unwrap();
+        let divergence = db.divergence().unwrap();
+        assert_eq!(divergence.len(), 1);
+        assert_eq!(divergence[0].key, "d");
+        assert_eq!(divergence[0].difference, Difference::OnlyInSelf);
+        assert_eq!(db.shadow_errors(), 0);
+    }
+}
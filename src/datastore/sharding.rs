@@ -0,0 +1,190 @@
+use super::{
+    DbOptions, Result, ThreadSafeDB,
+    collection::{SYSTEM_COLLECTION, namespaced_key},
+};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Points every shard gets on the ring, more spread the keys more evenly
+const POINTS_PER_SHARD: u32 = 64;
+
+/// Remembers which shard of how many a file is, under `__system`
+const LAYOUT_KEY: &str = "shard_layout";
+
+/// Consistent hashing ring, a key belongs to the first shard point at or
+/// after its hash. Adding a shard only takes keys off its neighbours.
+#[derive(Debug, Clone)]
+struct HashRing {
+    points: Vec<(u64, usize)>, // (hash, shard), sorted
+}
+
+impl HashRing {
+    fn new(shards: usize) -> Self {
+        let mut points: Vec<(u64, usize)> = (0..shards)
+            .flat_map(|shard| {
+                (0..POINTS_PER_SHARD).map(move |point| {
+                    (ring_hash(format!("shard{shard}#{point}").as_bytes()), shard)
+                })
+            })
+            .collect();
+        points.sort();
+        HashRing { points }
+    }
+
+    fn shard_of(&self, key: &str) -> usize {
+        let hash = ring_hash(key.as_bytes());
+        let next = self.points.partition_point(|(point, _)| *point < hash);
+        // Past the last point wraps around to the first
+        self.points[next % self.points.len()].1
+    }
+}
+
+/// Has to be the same on every run & platform, so no `RandomState` here
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let hash = Sha256::digest(bytes);
+    u64::from_be_bytes(hash[..8].try_into().expect("a sha256 has 32 bytes"))
+}
+
+/// One logical database spread over several files, see `DbManager::open_sharded`.
+/// Keys of the default collection are placed on a shard by consistent
+/// hashing, every shard compacts on its own.
+#[derive(Clone)]
+pub struct ShardedDb {
+    shards: Vec<ThreadSafeDB>,
+    ring: HashRing,
+}
+
+impl ShardedDb {
+    /// Open (or create) `shards` files named `shard-N.db` in `dir`.
+    /// The shard count is recorded in every file, reopening with a different
+    /// one fails since keys aren't moved between shards.
+    pub(crate) fn open(dir: &Path, shards: usize, options: DbOptions) -> Result<Self> {
+        if shards == 0 {
+            return Err("a sharded db needs at least one shard".into());
+        }
+        std::fs::create_dir_all(dir)?;
+        let layout = (0..shards)
+            .map(|shard| {
+                let db = ThreadSafeDB::with_options(
+                    dir.join(format!("shard-{shard}.db")),
+                    options.clone(),
+                )?;
+                check_layout(&db, shard, shards)?;
+                Ok(db)
+            })
+            .collect::<Result<_>>()?;
+        Ok(ShardedDb {
+            shards: layout,
+            ring: HashRing::new(shards),
+        })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard `key` lives on
+    pub fn shard_for(&self, key: &str) -> usize {
+        self.ring.shard_of(key)
+    }
+
+    /// A single shard, e.g. to compact or inspect it on its own
+    pub fn shard(&self, shard: usize) -> Option<&ThreadSafeDB> {
+        self.shards.get(shard)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.shards[self.shard_for(key)].get(key)
+    }
+
+    pub fn set(&self, key: &str, val: &str) -> Result<()> {
+        self.shards[self.shard_for(key)].set(key, val)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.shards[self.shard_for(key)].delete(key)
+    }
+
+    /// Keys starting with `prefix` across every shard, sorted
+    pub fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.scan_keys(prefix)?);
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Compact one shard, the others keep serving
+    pub fn compact_shard(&self, shard: usize) -> Result<()> {
+        match self.shards.get(shard) {
+            Some(db) => db.compact(),
+            None => Err(format!("no shard {shard}, there are {}", self.shards.len()).into()),
+        }
+    }
+
+    /// Compact the shards one after the other
+    pub fn compact(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.compact()?;
+        }
+        Ok(())
+    }
+}
+
+/// Make sure a shard file is opened as the shard it was created as
+fn check_layout(db: &ThreadSafeDB, shard: usize, shards: usize) -> Result<()> {
+    let key = namespaced_key(SYSTEM_COLLECTION, LAYOUT_KEY);
+    let layout = format!("{shard}/{shards}");
+    let mut db = db.lock()?;
+    match db.get_stored(&key)? {
+        None => db.put(key, layout.as_bytes(), None),
+        Some(found) if found == layout => Ok(()),
+        Some(found) => Err(format!(
+            "shard file {shard} was created as shard {found}, not {layout}, resharding isn't supported"
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{DbManager, DbOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sharded_db() {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let mut manager = DbManager::new();
+        let db = manager
+            .open_sharded("users", dir.path(), 4, DbOptions::default())
+            .unwrap();
+        for i in 0..200 {
+            db.set(&format!("user{i:03}"), &i.to_string()).unwrap();
+        }
+        db.delete("user007").unwrap();
+
+        // Every shard got a share of the keys
+        for shard in 0..4 {
+            assert!(db.shard(shard).unwrap().scan_keys("").unwrap().len() > 20);
+        }
+        let keys = db.scan_keys("user").unwrap();
+        assert_eq!(keys.len(), 199);
+        assert!(keys.is_sorted());
+        db.compact_shard(2).unwrap();
+        assert_eq!(manager.names().len(), 4);
+        drop((db, manager));
+
+        let mut manager = DbManager::new();
+        let db = manager
+            .open_sharded("users", dir.path(), 4, DbOptions::default())
+            .unwrap();
+        assert_eq!(db.get("user123").unwrap(), Some("123".to_string()));
+        assert_eq!(db.get("user007").unwrap(), None);
+        assert!(
+            manager
+                .open_sharded("users", dir.path(), 5, DbOptions::default())
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use super::{EmbeddedDatabase, Result, SnapshotIter, ThreadSafeDB, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, btree_map},
+    ops::Index,
+};
+
+/// Every key & value of a collection read into memory at one point in time,
+/// to be used like a map: `snapshot["key"]`, `for (key, val) in &snapshot`.
+/// Meant for tests & small scripts, everything is held in memory so use
+/// `iter_snapshot` for anything big.
+/// It (de)serializes as `{"seq": .., "entries": {"key": "val", ..}}`, so it
+/// can be compared against a saved copy or loaded from a JSON fixture with
+/// `load_snapshot` (`seq` may be left out there).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    seq: u64,
+    entries: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    pub(crate) fn read(iter: SnapshotIter) -> Result<Self> {
+        let seq = iter.seq();
+        let entries = iter.collect::<Result<_>>()?;
+        Ok(Snapshot { entries, seq })
+    }
+
+    /// Sequence number of the last write the snapshot includes
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Keys & values in key order
+    pub fn iter(&self) -> btree_map::Iter<'_, String, String> {
+        self.entries.iter()
+    }
+}
+
+/// Panics when there is no such key, use `get` when it may be missing
+impl Index<&str> for Snapshot {
+    type Output = str;
+
+    fn index(&self, key: &str) -> &str {
+        match self.get(key) {
+            Some(val) => val,
+            None => panic!("no key {key:?} in snapshot"),
+        }
+    }
+}
+
+impl IntoIterator for Snapshot {
+    type Item = (String, String);
+    type IntoIter = btree_map::IntoIter<String, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Snapshot {
+    type Item = (&'a String, &'a String);
+    type IntoIter = btree_map::Iter<'a, String, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Read the whole default collection into a `Snapshot`
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::read(self.iter_snapshot()?)
+    }
+
+    /// Write every key of `snapshot` to the default collection in one batch.
+    /// Keys that aren't in it are left alone.
+    pub fn load_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, val) in snapshot {
+            batch.set(key, val);
+        }
+        self.apply_batch(batch)
+    }
+}
+
+impl ThreadSafeDB {
+    /// See `EmbeddedDatabase::snapshot`. The lock is only held while the
+    /// index is copied, values are read after.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::read(self.iter_snapshot()?)
+    }
+
+    /// See `EmbeddedDatabase::load_snapshot`
+    pub fn load_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, val) in snapshot {
+            batch.set(key, val);
+        }
+        self.apply_batch(batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_snapshot() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("b", "2").unwrap();
+        db.set("a", "1").unwrap();
+        db.collection("users").unwrap().set("anna", "31").unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+        db.set("a", "changed").unwrap();
+        assert_eq!(&snapshot["a"], "1");
+        assert_eq!(snapshot.get("users"), None);
+        let keys: Vec<&String> = (&snapshot).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["a", "b"]);
+
+        let users = db.collection("users").unwrap().snapshot().unwrap();
+        assert_eq!(
+            users.into_iter().collect::<Vec<_>>(),
+            vec![("anna".to_string(), "31".to_string())]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "no key \"missing\" in snapshot")]
+    fn test_snapshot_index_panics() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let _ = &db.snapshot().unwrap()["missing"];
+    }
+
+    #[test]
+    fn test_snapshot_serde() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let fixture: Snapshot =
+            serde_json::from_str(r#"{"entries": {"b": "2", "a": "1"}}"#).unwrap();
+        db.load_snapshot(&fixture).unwrap();
+        db.collection("users")
+            .unwrap()
+            .load_snapshot(&fixture)
+            .unwrap();
+
+        let json = serde_json::to_string(&db.snapshot().unwrap()).unwrap();
+        assert_eq!(json, r#"{"seq":2,"entries":{"a":"1","b":"2"}}"#);
+        let users = db.collection("users").unwrap().snapshot().unwrap();
+        assert_eq!(
+            users.iter().collect::<Vec<_>>(),
+            fixture.iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use super::{
+    CollectionStats, DbError, EmbeddedDatabase, Quota, Result, TenantQuota,
+    collection::{COLLECTION_SEPARATOR, collection_of, namespaced_key, user_key},
+};
+use serde::Serialize;
+use std::io::Write;
+
+/// Bincode puts a few bytes around every record on top of its key & value
+/// (lengths, TTL, transforms, kind, seq). Used to guess a write's size up front.
+const RECORD_OVERHEAD: u64 = 64;
+
+/// One tenant's slice of the database.
+/// Every tenant lives in its own reserved collection, so tenants can't see or
+/// touch each other's keys, & writes are checked against the tenant's quota
+/// from `DbOptions` before they happen.
+pub struct TenantDb<'a> {
+    db: &'a mut EmbeddedDatabase,
+    tenant: String,
+    collection: String,
+    quota: TenantQuota,
+}
+
+impl EmbeddedDatabase {
+    /// A handle to the named tenant, created on first write
+    pub fn tenant(&mut self, tenant: &str) -> Result<TenantDb<'_>> {
+        if tenant.is_empty() || tenant.contains(COLLECTION_SEPARATOR) {
+            return Err(format!("invalid tenant name {tenant:?}").into());
+        }
+        let quota = self.options().tenant_quota(tenant);
+        Ok(TenantDb {
+            db: self,
+            tenant: tenant.to_string(),
+            collection: tenant_collection(tenant),
+            quota,
+        })
+    }
+
+    /// Keys & bytes a tenant has on disk
+    pub fn tenant_stats(&self, tenant: &str) -> CollectionStats {
+        self.collection_stats(&tenant_collection(tenant))
+    }
+
+    /// Write every live key of a tenant to `writer` as JSON lines,
+    /// `{"key":"...","val":"..."}`, in key order. Returns how many were written.
+    pub fn export_tenant(&mut self, tenant: &str, mut writer: impl Write) -> Result<u64> {
+        let collection = tenant_collection(tenant);
+        let mut exported = 0;
+        for stored_key in self.live_keys(&collection) {
+            // Expired between listing & reading
+            let Some(val) = self.get_stored(&stored_key)? else {
+                continue;
+            };
+            let line = ExportedRecord {
+                key: user_key(&stored_key),
+                val: &val,
+            };
+            serde_json::to_writer(&mut writer, &line)?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// Delete every key of a tenant & compact them off the disk, then scan the
+    /// new data file to check that no record of the tenant is left in it.
+    /// Records a change consumer hasn't acked yet survive compaction, those
+    /// show up in the report until the consumer catches up & it runs again.
+    pub fn purge_tenant(&mut self, tenant: &str) -> Result<PurgeReport> {
+        let collection = tenant_collection(tenant);
+        let bytes_before = self.collection_stats(&collection);
+        let stored_keys = self.live_keys(&collection);
+        for stored_key in &stored_keys {
+            self.remove(stored_key.clone())?;
+        }
+        self.compact()?;
+        if let Some(tracker) = &mut self.access_tracker {
+            tracker.forget_collection(&collection);
+        }
+
+        let mut report = PurgeReport {
+            keys_deleted: stored_keys.len() as u64,
+            bytes_before: bytes_before.live_bytes + bytes_before.garbage_bytes,
+            records_left: 0,
+            bytes_left: 0,
+        };
+        self.scan_records(|_, record| {
+            if collection_of(&record.key) == collection {
+                report.records_left += 1;
+                report.bytes_left += (record.key.len() + record.val.len()) as u64;
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedRecord<'a> {
+    key: &'a str,
+    val: &'a str,
+}
+
+/// What `purge_tenant` did & what it found on disk afterwards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PurgeReport {
+    pub keys_deleted: u64,
+    /// Bytes of the tenant's records (live & garbage) before the purge
+    pub bytes_before: u64,
+    /// Records of the tenant still in the data file after compaction
+    pub records_left: u64,
+    /// Key & value bytes of those records
+    pub bytes_left: u64,
+}
+
+impl PurgeReport {
+    /// Nothing of the tenant is left in the data file
+    pub fn is_complete(&self) -> bool {
+        self.records_left == 0
+    }
+}
+
+/// Reserved collection holding a tenant's keys
+pub(crate) fn tenant_collection(tenant: &str) -> String {
+    format!("__tenant:{tenant}")
+}
+
+/// The tenant a collection belongs to, if it's a tenant's
+pub(crate) fn tenant_of(collection: &str) -> Option<&str> {
+    collection.strip_prefix("__tenant:")
+}
+
+impl TenantDb<'_> {
+    pub fn name(&self) -> &str {
+        &self.tenant
+    }
+
+    /// Write a key, failing with `DbError::QuotaExceeded` if that would take
+    /// the tenant over its quota. Sizes are checked before value transformers
+    /// run, so a compressed value may be turned away a little early.
+    pub fn set(&mut self, key: &str, val: &str) -> Result<()> {
+        let stored_key = namespaced_key(&self.collection, key);
+        self.check_quota(&stored_key, val)?;
+        self.db.put(stored_key, val.as_bytes(), None)
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let stored_key = namespaced_key(&self.collection, key);
+        self.db.get_stored(&stored_key)
+    }
+
+    /// Deletes are always allowed, they are how a tenant gets back under quota
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        let stored_key = namespaced_key(&self.collection, key);
+        self.db.remove(stored_key)
+    }
+
+    /// Keys of the tenant starting with `prefix`, sorted
+    pub fn scan_keys(&self, prefix: &str) -> Vec<String> {
+        self.db.scan_keys_in(&self.collection, prefix)
+    }
+
+    pub fn stats(&self) -> CollectionStats {
+        self.db.collection_stats(&self.collection)
+    }
+
+    fn check_quota(&self, stored_key: &str, val: &str) -> Result<()> {
+        let stats = self.stats();
+        let replaced = self.db.live_span(stored_key);
+
+        if let Some(limit) = self.quota.max_keys {
+            let keys = stats.live_keys + u64::from(replaced.is_none());
+            if keys > limit {
+                return Err(self.exceeded(Quota::Keys, keys, limit));
+            }
+        }
+        if let Some(limit) = self.quota.max_bytes {
+            let new_len = RECORD_OVERHEAD + (stored_key.len() + val.len()) as u64;
+            let old_len = replaced.map_or(0, |(_, len)| len);
+            let bytes = (stats.live_bytes + new_len).saturating_sub(old_len);
+            if bytes > limit {
+                return Err(self.exceeded(Quota::Bytes, bytes, limit));
+            }
+        }
+        Ok(())
+    }
+
+    fn exceeded(&self, quota: Quota, used: u64, limit: u64) -> Box<dyn std::error::Error> {
+        DbError::QuotaExceeded {
+            tenant: self.tenant.clone(),
+            quota,
+            used,
+            limit,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_tenant_quotas() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions::default().with_tenant_quota(
+            "acme",
+            TenantQuota {
+                max_keys: Some(2),
+                max_bytes: None,
+            },
+        );
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+
+        let mut acme = db.tenant("acme").unwrap();
+        acme.set("a", "1").unwrap();
+        acme.set("b", "2").unwrap();
+        // Overwriting doesn't add a key
+        acme.set("b", "3").unwrap();
+        let err = acme.set("c", "4").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DbError>(),
+            Some(&DbError::QuotaExceeded {
+                tenant: "acme".to_string(),
+                quota: Quota::Keys,
+                used: 3,
+                limit: 2,
+            })
+        );
+        acme.delete("a").unwrap();
+        acme.set("c", "4").unwrap();
+        assert_eq!(acme.scan_keys(""), vec!["b", "c"]);
+
+        // Other tenants & the default collection don't see acme's keys
+        let mut globex = db.tenant("globex").unwrap();
+        assert_eq!(globex.get("b").unwrap(), None);
+        globex.set("b", "mine").unwrap();
+        assert_eq!(db.get("b").unwrap(), None);
+        assert_eq!(db.tenant_stats("acme").live_keys, 2);
+        assert_eq!(db.tenant_stats("globex").live_keys, 1);
+    }
+
+    #[test]
+    fn test_export_and_purge_tenant() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let mut acme = db.tenant("acme").unwrap();
+        acme.set("email", "jane@example.com").unwrap();
+        acme.set("name", "Jane \"J\" Doe").unwrap();
+        acme.set("name", "Jane Doe").unwrap();
+        db.tenant("globex").unwrap().set("name", "Bob").unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(db.export_tenant("acme", &mut exported).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(exported).unwrap(),
+            "{\"key\":\"email\",\"val\":\"jane@example.com\"}\n{\"key\":\"name\",\"val\":\"Jane Doe\"}\n"
+        );
+
+        let report = db.purge_tenant("acme").unwrap();
+        assert_eq!(report.keys_deleted, 2);
+        assert!(report.bytes_before > 0);
+        assert!(report.is_complete());
+        let raw = std::fs::read(temp_file.path()).unwrap();
+        assert!(!raw.windows(4).any(|window| window == b"Jane"));
+        assert_eq!(
+            db.tenant("globex").unwrap().get("name").unwrap(),
+            Some("Bob".to_string())
+        );
+    }
+}
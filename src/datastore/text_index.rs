@@ -0,0 +1,230 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{collection_of, user_key},
+};
+use std::collections::HashMap;
+
+/// BM25 tuning: how fast repeated terms stop adding to the score & how much
+/// long values are penalised
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Inverted index over the values of one collection
+#[derive(Debug, Default)]
+struct TextIndex {
+    postings: HashMap<String, HashMap<String, u32>>, // Term -> stored key -> times it appears
+    terms: HashMap<String, Vec<String>>,             // Stored key -> its distinct terms
+    lengths: HashMap<String, u32>,                   // Stored key -> number of terms in its value
+    total_length: u64,
+}
+
+impl TextIndex {
+    fn insert(&mut self, key: &str, val: &str) {
+        self.remove(key);
+        let tokens = tokenize(val);
+        if tokens.is_empty() {
+            return;
+        }
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for token in &tokens {
+            *counts.entry(token.clone()).or_default() += 1;
+        }
+        for (term, count) in &counts {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(key.to_string(), *count);
+        }
+        self.terms
+            .insert(key.to_string(), counts.into_keys().collect());
+        self.lengths.insert(key.to_string(), tokens.len() as u32);
+        self.total_length += tokens.len() as u64;
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(terms) = self.terms.remove(key) else {
+            return;
+        };
+        for term in terms {
+            if let Some(keys) = self.postings.get_mut(&term) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+        if let Some(length) = self.lengths.remove(key) {
+            self.total_length -= length as u64;
+        }
+    }
+
+    /// Stored keys matching any term of the query, best match first (BM25)
+    fn search(&self, query: &str) -> Vec<(String, f64)> {
+        let docs = self.lengths.len() as f64;
+        if docs == 0.0 {
+            return Vec::new();
+        }
+        let average_length = self.total_length as f64 / docs;
+
+        let mut query_terms = tokenize(query);
+        query_terms.sort();
+        query_terms.dedup();
+
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        for term in &query_terms {
+            let Some(keys) = self.postings.get(term) else {
+                continue;
+            };
+            let matching = keys.len() as f64;
+            let idf = (1.0 + (docs - matching + 0.5) / (matching + 0.5)).ln();
+            for (key, count) in keys {
+                let count = *count as f64;
+                let length = self.lengths[key] as f64;
+                let norm = K1 * (1.0 - B + B * length / average_length);
+                *scores.entry(key).or_default() += idf * count * (K1 + 1.0) / (count + norm);
+            }
+        }
+
+        let mut ranked: Vec<(String, f64)> = scores
+            .into_iter()
+            .map(|(key, score)| (key.to_string(), score))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked
+    }
+}
+
+/// Lowercased runs of letters & digits, everything else separates words
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The text indexes of every collection that has one, in memory only
+#[derive(Debug, Default)]
+pub(crate) struct TextIndexes {
+    by_collection: HashMap<String, TextIndex>,
+}
+
+impl TextIndexes {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index a value that was just written, if its collection is indexed
+    pub(crate) fn insert(&mut self, stored_key: &str, val: &[u8]) {
+        if let Some(index) = self.by_collection.get_mut(collection_of(stored_key))
+            && let Ok(val) = std::str::from_utf8(val)
+        {
+            index.insert(stored_key, val);
+        }
+    }
+
+    /// Whether values of `collection` get indexed
+    pub(crate) fn covers(&self, collection: &str) -> bool {
+        self.by_collection.contains_key(collection)
+    }
+
+    /// Drop a key that was overwritten, deleted or expired
+    pub(crate) fn remove(&mut self, stored_key: &str) {
+        if let Some(index) = self.by_collection.get_mut(collection_of(stored_key)) {
+            index.remove(stored_key);
+        }
+    }
+}
+
+impl EmbeddedDatabase {
+    /// Keep an inverted index over the values of `collection` ("" for the
+    /// default one) so `search` doesn't have to read every value. The index
+    /// lives in memory: it's built from the data file here & has to be
+    /// enabled again after the db is reopened.
+    pub fn enable_text_index(&mut self, collection: &str) -> Result<()> {
+        if self.text_index.by_collection.contains_key(collection) {
+            return Ok(());
+        }
+        let mut index = TextIndex::default();
+        for key in self.live_keys(collection) {
+            if let Some(val) = self.get_stored(&key)? {
+                index.insert(&key, &val);
+            }
+        }
+        self.text_index
+            .by_collection
+            .insert(collection.to_string(), index);
+        Ok(())
+    }
+
+    /// Build every enabled text index again from the values on disk
+    pub(crate) fn rebuild_text_indexes(&mut self) -> Result<()> {
+        let collections: Vec<String> = self
+            .text_index
+            .by_collection
+            .drain()
+            .map(|(name, _)| name)
+            .collect();
+        for collection in collections {
+            self.enable_text_index(&collection)?;
+        }
+        Ok(())
+    }
+
+    pub fn disable_text_index(&mut self, collection: &str) {
+        self.text_index.by_collection.remove(collection);
+    }
+
+    /// Keys of the default collection whose values contain any of the words
+    /// in `query`, best match first. Words are compared lowercased.
+    pub fn search(&self, query: &str) -> Result<Vec<String>> {
+        self.search_in("", query)
+    }
+
+    pub(crate) fn search_in(&self, collection: &str, query: &str) -> Result<Vec<String>> {
+        let Some(index) = self.text_index.by_collection.get(collection) else {
+            return Err(format!("collection {collection:?} has no text index").into());
+        };
+        Ok(index
+            .search(query)
+            .into_iter()
+            .filter(|(key, _)| self.live_span(key).is_some())
+            .map(|(key, _)| user_key(&key).to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::WriteBatch;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_search() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        db.set("log1", "Connection timeout while reading").unwrap();
+        db.set("log2", "ERROR: request timeout, error code 504")
+            .unwrap();
+        db.set("log3", "all good").unwrap();
+        assert!(db.search("timeout").is_err(), "not indexed yet");
+
+        db.enable_text_index("").unwrap();
+        db.set("log4", "disk error").unwrap();
+        assert_eq!(
+            db.search("error timeout").unwrap(),
+            vec!["log2", "log4", "log1"]
+        );
+
+        db.set("log2", "fixed").unwrap();
+        db.delete("log4").unwrap();
+        assert_eq!(db.search("error timeout").unwrap(), vec!["log1"]);
+
+        let mut batch = WriteBatch::new();
+        batch.set_in("logs", "a", "Timeout again");
+        db.enable_text_index("logs").unwrap();
+        db.apply_batch(batch).unwrap();
+        let found = db.collection("logs").unwrap().search("TIMEOUT").unwrap();
+        assert_eq!(found, vec!["a"]);
+    }
+}
@@ -0,0 +1,808 @@
+use super::{
+    BackpressureAction, DbError, DbOptions, EmbeddedDatabase, LiveIter, MultiGet, Result,
+    SnapshotIter, SyncPolicy, WriteBatch,
+    coalesce::{InFlightGets, Joined},
+    scheduler::{Schedule, Scheduler, Task, TaskStatus},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    ops::RangeBounds,
+    path::Path,
+    sync::{
+        Arc, Mutex, MutexGuard, Weak,
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A cloneable handle to an EmbeddedDatabase that can be shared between threads.
+/// Every operation takes a lock on the database for as long as it runs.
+#[derive(Clone)]
+pub struct ThreadSafeDB {
+    inner: Arc<Mutex<EmbeddedDatabase>>,
+    // Wakes the background compaction thread early, when there is one
+    compactor: Option<Sender<()>>,
+    // Set when `DbOptions::coalesce_gets` is on
+    in_flight: Option<Arc<InFlightGets>>,
+    // Maintenance tasks, its thread starts with the first one
+    scheduler: Arc<Scheduler>,
+    slow_lock_threshold: Option<Duration>,
+}
+
+impl ThreadSafeDB {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::with_options(path, DbOptions::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(path: P, options: DbOptions) -> Result<Self> {
+        let background = options.background_compaction;
+        let sync_policy = options.sync_policy;
+        let slow_lock_threshold = options.slow_lock_threshold;
+        let scheduler = Arc::new(Scheduler::new(
+            options.maintenance_windows.clone(),
+            options.clock(),
+        ));
+        let in_flight = options
+            .coalesce_gets
+            .then(|| Arc::new(InFlightGets::default()));
+        let db = EmbeddedDatabase::with_options(path, options)?;
+        let inner = Arc::new(Mutex::new(db));
+
+        let compactor = background.map(|background| {
+            let (sender, receiver) = mpsc::channel();
+            let db = Arc::downgrade(&inner);
+            thread::spawn(move || {
+                let mut stalled = None;
+                // Sleep until the next check or until a stalled writer nudges us.
+                // Once every handle is dropped the channel disconnects & we stop.
+                while let Ok(()) | Err(RecvTimeoutError::Timeout) =
+                    receiver.recv_timeout(background.check_interval)
+                {
+                    if !compact_in_background(&db, &mut stalled) {
+                        break;
+                    }
+                }
+            });
+            sender
+        });
+
+        if let SyncPolicy::Interval(interval) = sync_policy {
+            let db = Arc::downgrade(&inner);
+            thread::spawn(move || {
+                // Anything written right after a round is synced by the next one
+                loop {
+                    thread::sleep(interval);
+                    if !sync_in_background(&db) {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Ok(ThreadSafeDB {
+            inner,
+            compactor,
+            in_flight,
+            scheduler,
+            slow_lock_threshold,
+        })
+    }
+
+    /// Lock the database, for anything that isn't covered by the methods below
+    /// (e.g. collections). Other threads wait until the guard is dropped.
+    pub fn lock(&self) -> Result<MutexGuard<'_, EmbeddedDatabase>> {
+        let started = Instant::now();
+        let guard = self
+            .inner
+            .lock()
+            .map_err(|_| "the database lock was poisoned by a panic")?;
+        if let Some(threshold) = self.slow_lock_threshold {
+            let waited = started.elapsed();
+            if waited >= threshold {
+                log::warn!(
+                    path:% = guard.path.display(),
+                    waited_ms = waited.as_millis() as u64,
+                    threshold_ms = threshold.as_millis() as u64;
+                    "slow database lock acquisition"
+                );
+            }
+        }
+        Ok(guard)
+    }
+
+    pub fn set(&self, key: &str, val: &str) -> Result<()> {
+        self.apply_backpressure()?;
+        self.lock()?.set(key, val)
+    }
+
+    /// See `EmbeddedDatabase::set_with_ttl`
+    pub fn set_with_ttl(&self, key: &str, val: &str, ttl: Duration) -> Result<()> {
+        self.apply_backpressure()?;
+        self.lock()?.set_with_ttl(key, val, ttl)
+    }
+
+    /// Check & write under one lock, see `EmbeddedDatabase::set_if_absent`
+    pub fn set_if_absent(&self, key: &str, val: &str) -> Result<bool> {
+        self.apply_backpressure()?;
+        self.lock()?.set_if_absent(key, val)
+    }
+
+    /// See `EmbeddedDatabase::try_lease`
+    pub fn try_lease(&self, key: &str, owner: &str, ttl: Duration) -> Result<bool> {
+        self.apply_backpressure()?;
+        self.lock()?.try_lease(key, owner, ttl)
+    }
+
+    /// See `EmbeddedDatabase::renew`
+    pub fn renew(&self, key: &str, owner: &str, ttl: Duration) -> Result<bool> {
+        self.apply_backpressure()?;
+        self.lock()?.renew(key, owner, ttl)
+    }
+
+    /// See `EmbeddedDatabase::release`
+    pub fn release(&self, key: &str, owner: &str) -> Result<bool> {
+        self.lock()?.release(key, owner)
+    }
+
+    /// See `EmbeddedDatabase::cleanup_expired`
+    pub fn cleanup_expired(&self, prefix: &str) -> Result<usize> {
+        self.apply_backpressure()?;
+        self.lock()?.cleanup_expired(prefix)
+    }
+
+    /// See `EmbeddedDatabase::next_id`
+    pub fn next_id(&self, namespace: &str) -> Result<u64> {
+        self.lock()?.next_id(namespace)
+    }
+
+    /// With `DbOptions::coalesce_gets`, threads asking for a key that is
+    /// being read already wait for that read instead of doing their own
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let Some(in_flight) = &self.in_flight else {
+            return self.lock()?.get(key);
+        };
+        let lead = match in_flight.join(key)? {
+            Joined::Wait(read) => return read.wait(),
+            Joined::Lead(lead) => lead,
+        };
+
+        let result = self.lock().and_then(|mut db| {
+            let result = db.get(key);
+            lead.close();
+            result
+        });
+        lead.finish(&result);
+        result
+    }
+
+    /// Read several keys under one lock, so no write lands between them.
+    /// Never coalesced with other reads, see `EmbeddedDatabase::multi_get`
+    pub fn multi_get(&self, keys: &[&str]) -> Result<MultiGet> {
+        self.lock()?.multi_get(keys)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.apply_backpressure()?;
+        self.lock()?.delete(key)
+    }
+
+    /// Get & delete under one lock, see `EmbeddedDatabase::take`
+    pub fn take(&self, key: &str) -> Result<Option<String>> {
+        self.apply_backpressure()?;
+        self.lock()?.take(key)
+    }
+
+    /// See `EmbeddedDatabase::swap`
+    pub fn swap(&self, key_a: &str, key_b: &str) -> Result<()> {
+        self.apply_backpressure()?;
+        self.lock()?.swap(key_a, key_b)
+    }
+
+    /// See `EmbeddedDatabase::rename`
+    pub fn rename(&self, from: &str, to: &str) -> Result<bool> {
+        self.apply_backpressure()?;
+        self.lock()?.rename(from, to)
+    }
+
+    /// See `EmbeddedDatabase::delete_prefix`
+    pub fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        self.apply_backpressure()?;
+        self.lock()?.delete_prefix(prefix)
+    }
+
+    pub fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.apply_backpressure()?;
+        self.lock()?.apply_batch(batch)
+    }
+
+    /// See `EmbeddedDatabase::scan_keys`
+    pub fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self.lock()?.scan_keys(prefix))
+    }
+
+    /// See `EmbeddedDatabase::rebuild_index`, every other call waits until it's done
+    pub fn rebuild_index(&self) -> Result<()> {
+        self.lock()?.rebuild_index()
+    }
+
+    /// See `EmbeddedDatabase::fence`
+    pub fn fence(&self, epoch: u64) -> Result<()> {
+        self.lock()?.fence(epoch)
+    }
+
+    /// See `EmbeddedDatabase::refresh`
+    pub fn refresh(&self) -> Result<()> {
+        self.lock()?.refresh()
+    }
+
+    /// See `EmbeddedDatabase::set_typed`
+    pub fn set_typed<T: Serialize>(&self, key: &str, val: &T) -> Result<()> {
+        self.apply_backpressure()?;
+        self.lock()?.set_typed(key, val)
+    }
+
+    /// See `EmbeddedDatabase::get_typed`
+    pub fn get_typed<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        self.lock()?.get_typed(key)
+    }
+
+    /// Read, count & write back under one lock, see `EmbeddedDatabase::hll_add`
+    pub fn hll_add(&self, key: &str, item: &str) -> Result<()> {
+        self.apply_backpressure()?;
+        self.lock()?.hll_add(key, item)
+    }
+
+    /// See `EmbeddedDatabase::hll_count`
+    pub fn hll_count(&self, key: &str) -> Result<u64> {
+        self.lock()?.hll_count(key)
+    }
+
+    /// Read, flip & write back under one lock, see `EmbeddedDatabase::setbit`
+    pub fn setbit(&self, key: &str, index: u64, bit: bool) -> Result<bool> {
+        self.apply_backpressure()?;
+        self.lock()?.setbit(key, index, bit)
+    }
+
+    /// See `EmbeddedDatabase::getbit`
+    pub fn getbit(&self, key: &str, index: u64) -> Result<bool> {
+        self.lock()?.getbit(key, index)
+    }
+
+    /// See `EmbeddedDatabase::bitcount`
+    pub fn bitcount(&self, key: &str) -> Result<u64> {
+        self.lock()?.bitcount(key)
+    }
+
+    /// See `EmbeddedDatabase::approx_count_prefix`
+    pub fn approx_count_prefix(&self, prefix: &str) -> Result<usize> {
+        Ok(self.lock()?.approx_count_prefix(prefix))
+    }
+
+    /// See `EmbeddedDatabase::range`
+    pub fn range<'k>(&self, range: impl RangeBounds<&'k str>) -> Result<Vec<(String, String)>> {
+        self.lock()?.range(range)
+    }
+
+    /// See `EmbeddedDatabase::floor`
+    pub fn floor(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.lock()?.floor(key))
+    }
+
+    /// See `EmbeddedDatabase::ceiling`
+    pub fn ceiling(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.lock()?.ceiling(key))
+    }
+
+    pub fn compact(&self) -> Result<()> {
+        self.lock()?.compact()
+    }
+
+    /// Sync everything written so far right away, instead of waiting for
+    /// the sync interval to run out
+    pub fn flush(&self) -> Result<()> {
+        self.lock()?.sync()
+    }
+
+    /// Iterate over a pinned snapshot of the default collection,
+    /// see `EmbeddedDatabase::iter_snapshot`. The lock is only held while
+    /// the snapshot is taken.
+    pub fn iter_snapshot(&self) -> Result<SnapshotIter> {
+        self.lock()?.iter_snapshot()
+    }
+
+    /// Snapshot of what changed after the write numbered `since`,
+    /// see `EmbeddedDatabase::iter_since`
+    pub fn iter_since(&self, since: u64) -> Result<SnapshotIter> {
+        self.lock()?.iter_since(since)
+    }
+
+    /// Iterate over the default collection while reading the latest value of
+    /// every key, see `LiveIter` for what concurrent writers can & can't change.
+    pub fn iter_live(&self) -> Result<LiveIter> {
+        let keys = self.lock()?.live_keys("");
+        Ok(LiveIter::new(self.clone(), keys))
+    }
+
+    /// Run `task` on `schedule` on the scheduler's background thread until
+    /// it's unscheduled or every handle is dropped. Tasks run one at a time,
+    /// each holding the database lock while it runs. Names must be unique.
+    pub fn schedule(&self, name: &str, schedule: Schedule, task: Task) -> Result<()> {
+        self.scheduler
+            .add(Arc::downgrade(&self.inner), name, schedule, task)
+    }
+
+    /// Returns false if there was no task called `name`. A run that already
+    /// started is finished first.
+    pub fn unschedule(&self, name: &str) -> Result<bool> {
+        self.scheduler.remove(name)
+    }
+
+    /// Run the scheduled task `name` right away on this thread, ignoring the
+    /// maintenance windows. Returns the task's own error if it fails.
+    pub fn run_now(&self, name: &str) -> Result<()> {
+        self.scheduler.run_now(&self.inner, name)
+    }
+
+    /// Run every scheduled task that is due on the db's clock (see
+    /// `DbOptions::clock`) on this thread & return how many ran. With a
+    /// `MockClock` this steps the scheduler deterministically. How each run
+    /// went ends up in `scheduled_tasks`.
+    pub fn run_pending(&self) -> Result<usize> {
+        self.scheduler.run_pending(&self.inner)
+    }
+
+    /// When the scheduled tasks run next & how their last run went
+    pub fn scheduled_tasks(&self) -> Result<Vec<TaskStatus>> {
+        self.scheduler.statuses()
+    }
+
+    /// Slow down or reject a write when background compaction has fallen behind
+    fn apply_backpressure(&self) -> Result<()> {
+        let (backpressure, garbage_bytes) = {
+            let db = self.lock()?;
+            let backpressure = db
+                .options()
+                .background_compaction
+                .and_then(|background| background.backpressure);
+            (backpressure, db.garbage_bytes())
+        };
+        let Some(backpressure) = backpressure else {
+            return Ok(());
+        };
+        if garbage_bytes < backpressure.max_garbage_bytes {
+            return Ok(());
+        }
+
+        match backpressure.action {
+            BackpressureAction::Delay(delay) => {
+                // Let the compaction thread know it is needed, then give it
+                // the lock for a while before carrying on with the write
+                if let Some(compactor) = &self.compactor {
+                    let _ = compactor.send(());
+                }
+                thread::sleep(delay);
+                Ok(())
+            }
+            BackpressureAction::Reject => {
+                // Wake the compaction thread so a later write gets through
+                if let Some(compactor) = &self.compactor {
+                    let _ = compactor.send(());
+                }
+                Err(DbError::CompactionBehind {
+                    garbage_bytes,
+                    limit: backpressure.max_garbage_bytes,
+                    blocked_by: self.lock()?.slowest_consumer()?,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+/// One round of the background compaction thread.
+/// Returns false once the database is gone & the thread should stop.
+fn compact_in_background(
+    db: &Weak<Mutex<EmbeddedDatabase>>,
+    stalled: &mut Option<Option<u64>>,
+) -> bool {
+    let Some(db) = db.upgrade() else {
+        return false;
+    };
+    // A poisoned lock or a failed compaction is left for the next write to
+    // report, the compaction is simply tried again next round
+    if let Ok(mut db) = db.lock() {
+        // Past the backpressure limit writers are held back until the garbage
+        // is gone, so compact whether or not a compaction policy asks for it
+        let behind = db
+            .options()
+            .background_compaction
+            .and_then(|background| background.backpressure)
+            .is_some_and(|backpressure| db.garbage_bytes() >= backpressure.max_garbage_bytes);
+        let _ = match behind {
+            true => compact_unless_stalled(&mut db, stalled),
+            false => db.compact_if_needed(),
+        };
+    }
+    true
+}
+
+/// Compact to get back under the backpressure limit. When the last try
+/// reclaimed nothing & no consumer acked since (`stalled` holds the lowest
+/// acked offset of then), the garbage is all changes a consumer still has to
+/// read: rewriting the whole file again wouldn't free a byte, so it's skipped.
+fn compact_unless_stalled(
+    db: &mut EmbeddedDatabase,
+    stalled: &mut Option<Option<u64>>,
+) -> Result<bool> {
+    let retain_after = db.min_consumer_offset()?;
+    if *stalled == Some(retain_after) {
+        return Ok(false);
+    }
+    let garbage_bytes = db.garbage_bytes();
+    db.compact()?;
+    *stalled = (db.garbage_bytes() >= garbage_bytes).then_some(retain_after);
+    Ok(true)
+}
+
+/// One round of the background sync thread.
+/// Returns false once the database is gone & the thread should stop.
+fn sync_in_background(db: &Weak<Mutex<EmbeddedDatabase>>) -> bool {
+    let Some(db) = db.upgrade() else {
+        return false;
+    };
+    // A failed sync is retried next round, the data is still in the OS's hands
+    if let Ok(mut db) = db.lock()
+        && db.has_unsynced_writes()
+    {
+        let _ = db.sync();
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BackgroundCompaction, Backpressure, CollectionOptions, CompactionPolicy};
+    use tempfile::NamedTempFile;
+
+    /// Check interval for tests that don't want the compaction thread to run
+    const NEVER: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn test_iterators_under_concurrent_writes() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = ThreadSafeDB::new(temp_file.path()).unwrap();
+        for i in 0..10 {
+            db.set(&format!("key{i}"), "old").unwrap();
+        }
+
+        let snapshot = db.iter_snapshot().unwrap();
+        let mut live = db.iter_live().unwrap();
+        assert_eq!(live.next().unwrap().unwrap(), ("key0".into(), "old".into()));
+
+        // Writer thread changes things while both iterators are half way through
+        let writer = db.clone();
+        std::thread::spawn(move || {
+            writer.set("key5", "new").unwrap();
+            writer.delete("key7").unwrap();
+            writer.set("key99", "added").unwrap();
+            writer.compact().unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let snapshot: Vec<(String, String)> = snapshot.map(|item| item.unwrap()).collect();
+        assert_eq!(snapshot.len(), 10);
+        assert!(snapshot.iter().all(|(_, val)| val == "old"));
+
+        let live: Vec<(String, String)> = live.map(|item| item.unwrap()).collect();
+        assert_eq!(live.len(), 8, "key0 was already read & key7 is gone");
+        assert!(live.contains(&("key5".into(), "new".into())));
+        assert!(!live.iter().any(|(key, _)| key == "key7" || key == "key99"));
+    }
+
+    #[test]
+    fn test_incremental_iteration() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = ThreadSafeDB::new(temp_file.path()).unwrap();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..200 {
+                    db.set(&format!("key{:03}", i % 50), &i.to_string())
+                        .unwrap();
+                }
+            })
+        };
+
+        // Every pass picks up where the last one stopped, so the latest value
+        // of every key is seen at the end & values never go backwards
+        let mut latest = std::collections::HashMap::new();
+        let mut checkpoint = 0;
+        loop {
+            let finished = writer.is_finished();
+            let mut changes = db.iter_since(checkpoint).unwrap();
+            for change in changes.by_ref() {
+                let (key, val) = change.unwrap();
+                let val: u32 = val.parse().unwrap();
+                let previous = latest.insert(key, val);
+                assert!(previous.is_none_or(|previous| previous < val));
+            }
+            checkpoint = changes.seq();
+            if finished {
+                break;
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(latest.len(), 50);
+        assert!((0..50).all(|i| latest[&format!("key{i:03}")] == 150 + i));
+        assert_eq!(db.iter_since(checkpoint).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_coalesced_gets() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            coalesce_gets: true,
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+        db.set("hot", "1").unwrap();
+
+        // Hold the lock so every reader piles up behind the first one
+        let guard = db.lock().unwrap();
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || db.get("hot").unwrap())
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), Some("1".to_string()));
+        }
+
+        // Nothing is left behind, later reads see later writes
+        db.set("hot", "2").unwrap();
+        assert_eq!(db.get("hot").unwrap(), Some("2".to_string()));
+    }
+
+    #[test]
+    fn test_consistent_multi_get() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = ThreadSafeDB::new(temp_file.path()).unwrap();
+        db.set("alice", "100").unwrap();
+        db.set("bob", "0").unwrap();
+
+        // Move money one unit at a time, the total never changes
+        let mover = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 1..=100 {
+                    let mut batch = WriteBatch::new();
+                    batch
+                        .set("alice", &(100 - i).to_string())
+                        .set("bob", &i.to_string());
+                    db.apply_batch(batch).unwrap();
+                }
+            })
+        };
+        for _ in 0..200 {
+            let read = db.multi_get(&["alice", "bob"]).unwrap();
+            let total: u32 = read
+                .values
+                .iter()
+                .map(|val| val.as_ref().unwrap().parse::<u32>().unwrap())
+                .sum();
+            assert_eq!(total, 100);
+        }
+        mover.join().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_take() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let db = ThreadSafeDB::new(temp_file.path()).unwrap();
+        for i in 0..50 {
+            db.set(&format!("job{i}"), &i.to_string()).unwrap();
+        }
+
+        // Every job is handed out once, however the workers interleave
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    (0..50)
+                        .filter_map(|i| db.take(&format!("job{i}")).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut taken: Vec<String> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        taken.sort_by_key(|job| job.parse::<u32>().unwrap());
+        assert_eq!(taken, (0..50).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert!(db.scan_keys("job").unwrap().is_empty());
+        assert_eq!(db.take("job1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_sync_interval() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+
+        db.set("key", "1").unwrap();
+        db.flush().unwrap();
+        assert!(!db.lock().unwrap().has_unsynced_writes());
+
+        // Left alone, the background thread syncs within the interval
+        db.set("key", "2").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(!db.lock().unwrap().has_unsynced_writes());
+    }
+
+    #[test]
+    fn test_sync_every_write() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            sync_policy: SyncPolicy::EveryWrite,
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+        db.set("key", "1").unwrap();
+        assert!(!db.lock().unwrap().has_unsynced_writes());
+        db.delete("key").unwrap();
+        assert!(!db.lock().unwrap().has_unsynced_writes());
+        drop(db);
+
+        let db = ThreadSafeDB::new(temp_file.path()).unwrap();
+        db.set("key", "2").unwrap();
+        assert!(db.lock().unwrap().has_unsynced_writes());
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            default_collection: CollectionOptions {
+                compaction: Some(CompactionPolicy {
+                    garbage_ratio: 0.5,
+                    min_garbage_bytes: 0,
+                }),
+                ..Default::default()
+            },
+            background_compaction: Some(BackgroundCompaction {
+                check_interval: Duration::from_millis(10),
+                backpressure: None,
+            }),
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+        db.set("key", "1").unwrap();
+        db.set("key", "2").unwrap();
+        // The write itself doesn't compact anymore
+        assert!(db.lock().unwrap().garbage_bytes() > 0);
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(db.lock().unwrap().garbage_bytes(), 0);
+        assert_eq!(db.get("key").unwrap(), Some("2".to_string()));
+    }
+
+    #[test]
+    fn test_backpressure_rejects_writes() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            background_compaction: Some(BackgroundCompaction {
+                check_interval: NEVER,
+                backpressure: Some(Backpressure {
+                    max_garbage_bytes: 100,
+                    action: BackpressureAction::Reject,
+                }),
+            }),
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+
+        let mut rejected = None;
+        for i in 0..100 {
+            if let Err(err) = db.set("key", &i.to_string()) {
+                rejected = Some(err);
+                break;
+            }
+        }
+        let err = rejected.expect("writes should be rejected once garbage piles up");
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::CompactionBehind { limit: 100, .. })
+        ));
+
+        // Compacting by hand lets writers through again
+        db.compact().unwrap();
+        db.set("key", "after").unwrap();
+    }
+
+    #[test]
+    fn test_backpressure_nudges_compaction() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        // No compaction policy, only the backpressure limit triggers compaction
+        let options = DbOptions {
+            background_compaction: Some(BackgroundCompaction {
+                check_interval: NEVER,
+                backpressure: Some(Backpressure {
+                    max_garbage_bytes: 100,
+                    action: BackpressureAction::Reject,
+                }),
+            }),
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+        let mut i = 0;
+        while db.set("key", &i.to_string()).is_ok() {
+            i += 1;
+        }
+
+        // The rejected write woke the compactor, writes go through once it ran
+        let started = Instant::now();
+        while db.set("key", "after").is_err() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(db.get("key").unwrap(), Some("after".to_string()));
+    }
+
+    #[test]
+    fn test_stalled_compaction_names_the_consumer() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            background_compaction: Some(BackgroundCompaction {
+                check_interval: Duration::from_millis(10),
+                backpressure: Some(Backpressure {
+                    max_garbage_bytes: 100,
+                    action: BackpressureAction::Reject,
+                }),
+            }),
+            ..Default::default()
+        };
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+        let consumer = db.lock().unwrap().consumer("audit").unwrap();
+
+        // Everything overwritten is a change the consumer hasn't read yet
+        let mut i = 0;
+        let err = loop {
+            if let Err(err) = db.set("key", &i.to_string()) {
+                break err;
+            }
+            i += 1;
+        };
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::CompactionBehind { blocked_by: Some(name), .. }) if name == "audit"
+        ));
+
+        // Rewriting the file again wouldn't free anything, so it isn't
+        thread::sleep(Duration::from_millis(100));
+        let compactions = db.lock().unwrap().write_stats().compactions;
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(db.lock().unwrap().write_stats().compactions, compactions);
+
+        // Once the consumer catches up the garbage can go
+        {
+            let mut db = db.lock().unwrap();
+            let last_seq = db.last_seq();
+            consumer.ack(&mut db, last_seq).unwrap();
+        }
+        let started = Instant::now();
+        while db.set("key", "after").is_err() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use super::{
+    EmbeddedDatabase, Result,
+    collection::{namespaced_key, user_key},
+};
+use std::{collections::BTreeMap, ops::RangeBounds};
+
+/// Points of one series, keyed by their zero padded timestamp so key order
+/// is time order & a time range is a key range. A second point at the same
+/// timestamp replaces the first. Best with `DbOptions::sorted_index`.
+pub struct TimeSeries<'a> {
+    db: &'a mut EmbeddedDatabase,
+    collection: String,
+}
+
+impl EmbeddedDatabase {
+    /// A handle to the named time series, created on first use
+    pub fn ts(&mut self, key: &str) -> Result<TimeSeries<'_>> {
+        if key.is_empty() {
+            return Err("time series name can't be empty".into());
+        }
+        Ok(TimeSeries {
+            db: self,
+            collection: format!("__ts:{key}"),
+        })
+    }
+}
+
+impl TimeSeries<'_> {
+    pub fn append(&mut self, timestamp: u64, value: f64) -> Result<()> {
+        let key = namespaced_key(&self.collection, &point_key(timestamp));
+        self.db.put(key, value.to_string().as_bytes(), None)
+    }
+
+    /// Points within `range` of timestamps, oldest first
+    pub fn range(&mut self, range: impl RangeBounds<u64>) -> Result<Vec<(u64, f64)>> {
+        let start = range.start_bound().map(|timestamp| point_key(*timestamp));
+        let end = range.end_bound().map(|timestamp| point_key(*timestamp));
+        let bounds = (
+            start.as_ref().map(String::as_str),
+            end.as_ref().map(String::as_str),
+        );
+        let mut points = Vec::new();
+        for (key, value) in self.db.range_in(&self.collection, bounds)? {
+            points.push((key.parse()?, value.parse()?));
+        }
+        Ok(points)
+    }
+
+    /// Replace the points before `before` with one average per `bucket` of
+    /// time, stamped with the bucket's start. All in one batch, so schedule
+    /// it ahead of a compaction (`Task::Custom`) to keep old data small.
+    /// Returns how many points went away.
+    pub fn downsample(&mut self, before: u64, bucket: u64) -> Result<usize> {
+        if bucket == 0 {
+            return Err("downsampling bucket can't be 0".into());
+        }
+        let mut buckets: BTreeMap<u64, Vec<(u64, f64)>> = BTreeMap::new();
+        for (timestamp, value) in self.range(..before)? {
+            buckets
+                .entry(timestamp - timestamp % bucket)
+                .or_default()
+                .push((timestamp, value));
+        }
+
+        let mut writes = Vec::new();
+        let mut removed = 0;
+        for (start, points) in buckets {
+            // Already as small as it gets
+            if points.len() == 1 && points[0].0 == start {
+                continue;
+            }
+            let average = points.iter().map(|(_, value)| value).sum::<f64>() / points.len() as f64;
+            for (timestamp, _) in &points {
+                if *timestamp != start {
+                    let key = namespaced_key(&self.collection, &point_key(*timestamp));
+                    writes.push((key, None));
+                }
+            }
+            let key = namespaced_key(&self.collection, &point_key(start));
+            writes.push((key, Some(average.to_string().into_bytes())));
+            removed += points.len() - 1;
+        }
+        self.db.commit_writes(writes, None)?;
+        Ok(removed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.stored_keys_with_prefix(&self.collection, "").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The latest point, if there is one
+    pub fn last(&mut self) -> Result<Option<(u64, f64)>> {
+        let Some(key) = self.db.stored_keys_with_prefix(&self.collection, "").pop() else {
+            return Ok(None);
+        };
+        let timestamp: u64 = user_key(&key).parse()?;
+        let points = self.range(timestamp..=timestamp)?;
+        Ok(points.into_iter().next())
+    }
+}
+
+fn point_key(timestamp: u64) -> String {
+    format!("{timestamp:020}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DbOptions;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_time_series() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let options = DbOptions {
+            sorted_index: true,
+            ..Default::default()
+        };
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        let mut cpu = db.ts("cpu").unwrap();
+        for (timestamp, value) in [
+            (100, 1.0),
+            (110, 3.0),
+            (1_000, 5.0),
+            (90, 0.5),
+            (1_010, 7.0),
+        ] {
+            cpu.append(timestamp, value).unwrap();
+        }
+        db.ts("mem").unwrap().append(105, 42.0).unwrap();
+
+        let mut cpu = db.ts("cpu").unwrap();
+        assert_eq!(cpu.range(95..1_000).unwrap(), vec![(100, 1.0), (110, 3.0)]);
+        assert_eq!(
+            cpu.range(1_000..).unwrap(),
+            vec![(1_000, 5.0), (1_010, 7.0)]
+        );
+        assert_eq!(cpu.last().unwrap(), Some((1_010, 7.0)));
+
+        // 90 is a bucket of its own, 100 & 110 become their average
+        assert_eq!(cpu.downsample(1_000, 50).unwrap(), 1);
+        assert_eq!(
+            cpu.range(..).unwrap(),
+            vec![(50, 0.5), (100, 2.0), (1_000, 5.0), (1_010, 7.0)]
+        );
+        assert_eq!(cpu.len(), 4);
+    }
+}
@@ -0,0 +1,259 @@
+use super::{
+    DbOptions, Record, Result,
+    collection::collection_of,
+    encryption::{KeyStore, key_id},
+    error::catch_callback,
+};
+use std::{collections::HashMap, fmt, path::Path, sync::Arc};
+
+/// A reversible step applied to values on their way to disk (compression,
+/// encryption, ...). Each collection runs its own ordered list of them on
+/// write & the reverse list on read.
+pub trait ValueTransformer: fmt::Debug + Send + Sync {
+    /// Stable, unique name. It is stored with every record the transformer
+    /// ran on so the record can still be read after the pipeline changes.
+    fn name(&self) -> &str;
+    fn encode(&self, val: &[u8]) -> Result<Vec<u8>>;
+    fn decode(&self, val: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// lz4 block compression with the uncompressed size prepended
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Compression;
+
+impl ValueTransformer for Lz4Compression {
+    fn name(&self) -> &str {
+        "lz4"
+    }
+
+    fn encode(&self, val: &[u8]) -> Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(val))
+    }
+
+    fn decode(&self, val: &[u8]) -> Result<Vec<u8>> {
+        // lz4 can't shrink anything more than ~255 times, a bigger size up
+        // front is damage & would only make us allocate it
+        let size = val
+            .get(..4)
+            .map(|size| u32::from_le_bytes(size.try_into().expect("4 bytes")));
+        if size.is_none_or(|size| size as usize > val.len().saturating_mul(255)) {
+            return Err(format!("lz4 value claims {size:?} bytes from {}", val.len()).into());
+        }
+        Ok(lz4_flex::decompress_size_prepended(val)?)
+    }
+}
+
+/// Run a collection's pipeline over a value.
+/// Returns the stored bytes and the names of the transformers, in the order they ran.
+pub(crate) fn encode_value(
+    pipeline: &[Arc<dyn ValueTransformer>],
+    val: &[u8],
+) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut val = val.to_vec();
+    let mut applied = Vec::with_capacity(pipeline.len());
+    for transformer in pipeline {
+        let transformer_name = || format!("value transformer {:?}", transformer.name());
+        val = catch_callback(transformer_name, || transformer.encode(&val))??;
+        applied.push(transformer.name().to_string());
+    }
+    Ok((val, applied))
+}
+
+/// Every transformer the database knows about, looked up by name when reading,
+/// plus the data keys when values are encrypted at rest
+#[derive(Debug)]
+pub(crate) struct TransformerRegistry {
+    by_name: HashMap<String, Arc<dyn ValueTransformer>>,
+    key_store: Option<KeyStore>,
+}
+
+impl TransformerRegistry {
+    pub(crate) fn new(options: &DbOptions, db_path: &Path) -> Result<Self> {
+        let mut by_name: HashMap<String, Arc<dyn ValueTransformer>> = HashMap::new();
+        by_name.insert(Lz4Compression.name().to_string(), Arc::new(Lz4Compression));
+
+        let collections =
+            std::iter::once(&options.default_collection).chain(options.collections.values());
+        for collection in collections {
+            for transformer in &collection.transformers {
+                by_name.insert(transformer.name().to_string(), transformer.clone());
+            }
+        }
+        let key_store = match &options.master_key {
+            Some(master_key) => Some(KeyStore::open(db_path, master_key)?),
+            None => None,
+        };
+        Ok(TransformerRegistry { by_name, key_store })
+    }
+
+    pub(crate) fn key_store(&self) -> Option<&KeyStore> {
+        self.key_store.as_ref()
+    }
+
+    /// Encrypt a value that already went through its collection's pipeline,
+    /// when there is a master key. Encryption always runs last.
+    pub(crate) fn encrypt(
+        &self,
+        stored_key: &str,
+        val: Vec<u8>,
+        transforms: &mut Vec<String>,
+    ) -> Result<Vec<u8>> {
+        let Some(key_store) = &self.key_store else {
+            return Ok(val);
+        };
+        let (sealed, transform) = key_store.encrypt(collection_of(stored_key), &val)?;
+        transforms.push(transform);
+        Ok(sealed)
+    }
+
+    /// Move an encrypted record over to its collection's current data key
+    pub(crate) fn reencrypt(&self, record: &mut Record) -> Result<()> {
+        let Some(key_store) = &self.key_store else {
+            return Ok(());
+        };
+        let Some(transform) = record.transforms.last_mut() else {
+            return Ok(());
+        };
+        if key_id(transform).is_none() {
+            return Ok(());
+        }
+        let collection = collection_of(&record.key);
+        if let Some((sealed, name)) = key_store.reencrypt(collection, transform, &record.val)? {
+            record.val = sealed;
+            *transform = name;
+        }
+        Ok(())
+    }
+
+    /// The value of a record as it was originally written
+    pub(crate) fn decode(&self, record: Record) -> Result<Vec<u8>> {
+        let mut val = record.val;
+        for name in record.transforms.iter().rev() {
+            if key_id(name).is_some() {
+                let key_store = self
+                    .key_store
+                    .as_ref()
+                    .ok_or("the value is encrypted but no master key was given")?;
+                val = key_store.decrypt(collection_of(&record.key), name, &val)?;
+                continue;
+            }
+            let transformer = self
+                .by_name
+                .get(name)
+                .ok_or_else(|| format!("no value transformer named {name:?} is registered"))?;
+            let transformer_name = || format!("value transformer {name:?}");
+            val = catch_callback(transformer_name, || transformer.decode(&val))??;
+        }
+        Ok(val)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CollectionOptions, DbError, EmbeddedDatabase, ThreadSafeDB};
+    use tempfile::NamedTempFile;
+
+    /// Toy "encryption" so the test can see the pipeline order
+    #[derive(Debug)]
+    struct Xor(u8);
+
+    impl ValueTransformer for Xor {
+        fn name(&self) -> &str {
+            "xor"
+        }
+
+        fn encode(&self, val: &[u8]) -> Result<Vec<u8>> {
+            Ok(val.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decode(&self, val: &[u8]) -> Result<Vec<u8>> {
+            self.encode(val)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Panicky;
+
+    impl ValueTransformer for Panicky {
+        fn name(&self) -> &str {
+            "panicky"
+        }
+
+        fn encode(&self, val: &[u8]) -> Result<Vec<u8>> {
+            if val == b"boom" {
+                panic!("can't encode boom");
+            }
+            Ok(val.to_vec())
+        }
+
+        fn decode(&self, val: &[u8]) -> Result<Vec<u8>> {
+            Ok(val.to_vec())
+        }
+    }
+
+    #[test]
+    fn test_panicking_transformer() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let risky = CollectionOptions {
+            transformers: vec![Arc::new(Panicky)],
+            ..Default::default()
+        };
+        let options = DbOptions::default().with_collection("risky", risky);
+        let db = ThreadSafeDB::with_options(temp_file.path(), options).unwrap();
+
+        let err = db
+            .lock()
+            .unwrap()
+            .collection("risky")
+            .unwrap()
+            .set("key", "boom");
+        let err = err.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DbError>(),
+            Some(&DbError::CallbackPanicked {
+                callback: "value transformer \"panicky\"".to_string(),
+                message: "can't encode boom".to_string(),
+            })
+        );
+
+        // Nothing was written & the lock isn't poisoned
+        let mut guard = db.lock().unwrap();
+        let mut risky = guard.collection("risky").unwrap();
+        assert_eq!(risky.get("key").unwrap(), None);
+        risky.set("key", "fine").unwrap();
+        assert_eq!(risky.get("key").unwrap(), Some("fine".to_string()));
+    }
+
+    #[test]
+    fn test_pipeline_round_trip() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let secrets = CollectionOptions {
+            transformers: vec![Arc::new(Lz4Compression), Arc::new(Xor(0x5a))],
+            ..Default::default()
+        };
+        let options = DbOptions::default().with_collection("secrets", secrets);
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options.clone()).unwrap();
+        let val = "hunter2 ".repeat(100);
+        db.collection("secrets")
+            .unwrap()
+            .set("password", &val)
+            .unwrap();
+        drop(db);
+
+        // Nothing readable made it to the disk
+        let raw = std::fs::read(temp_file.path()).unwrap();
+        assert!(!raw.windows(7).any(|window| window == b"hunter2"));
+        assert!((raw.len() as u64) < val.len() as u64 / 2);
+
+        let mut db = EmbeddedDatabase::with_options(temp_file.path(), options).unwrap();
+        assert_eq!(
+            db.collection("secrets").unwrap().get("password").unwrap(),
+            Some(val)
+        );
+
+        // Without the xor transformer registered the record can't be read back
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        assert!(db.collection("secrets").unwrap().get("password").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use super::{Collection, EmbeddedDatabase, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use std::marker::PhantomData;
+
+/// A collection whose values are all of type `T`, stored as JSON.
+/// Values that don't parse as `T` (written by hand or by an older version
+/// of the type) fail the read instead of coming back half filled.
+pub struct TypedCollection<'a, T> {
+    inner: Collection<'a>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> TypedCollection<'_, T> {
+    pub fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<T>> {
+        match self.inner.get(key)? {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, val: &T) -> Result<()> {
+        self.inner.set(key, &serde_json::to_string(val)?)
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    /// See `EmbeddedDatabase::take`
+    pub fn take(&mut self, key: &str) -> Result<Option<T>> {
+        match self.inner.take(key)? {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// See `EmbeddedDatabase::scan_keys`
+    pub fn scan_keys(&self, prefix: &str) -> Vec<String> {
+        self.inner.scan_keys(prefix)
+    }
+}
+
+impl EmbeddedDatabase {
+    /// A handle for a named collection whose values are `T`s
+    pub fn typed_collection<T: Serialize + DeserializeOwned>(
+        &mut self,
+        name: &str,
+    ) -> Result<TypedCollection<'_, T>> {
+        Ok(TypedCollection {
+            inner: self.collection(name)?,
+            value: PhantomData,
+        })
+    }
+
+    /// Store any serde value under a key of the default collection, as JSON
+    /// like `TypedCollection` does
+    pub fn set_typed<T: Serialize>(&mut self, key: &str, val: &T) -> Result<()> {
+        self.set(key, &serde_json::to_string(val)?)
+    }
+
+    /// Read back a value written with `set_typed`.
+    /// A value that doesn't parse as `T` is an error.
+    pub fn get_typed<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use tempfile::NamedTempFile;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    #[test]
+    fn test_typed_collection() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let anna = User {
+            name: "anna".to_string(),
+            age: 31,
+        };
+        let mut users = db.typed_collection::<User>("users").unwrap();
+        users.set("1", &anna).unwrap();
+        assert_eq!(users.get("1").unwrap(), Some(anna));
+        assert_eq!(users.get("2").unwrap(), None);
+
+        // Something that isn't a User fails loudly
+        db.collection("users")
+            .unwrap()
+            .set("2", "{\"name\": 5}")
+            .unwrap();
+        let mut users = db.typed_collection::<User>("users").unwrap();
+        assert!(users.get("2").is_err());
+        assert_eq!(users.scan_keys(""), vec!["1", "2"]);
+        assert_eq!(users.take("1").unwrap().map(|user| user.age), Some(31));
+        assert!(db.typed_collection::<User>("__system").is_err());
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let temp_file = NamedTempFile::new().expect("failed to create temp file");
+        let mut db = EmbeddedDatabase::new(temp_file.path()).unwrap();
+        let ben = User {
+            name: "ben".to_string(),
+            age: 40,
+        };
+        db.set_typed("ben", &ben).unwrap();
+        db.set_typed("scores", &vec![3, 1, 2]).unwrap();
+
+        assert_eq!(db.get_typed::<User>("ben").unwrap(), Some(ben));
+        assert_eq!(
+            db.get_typed::<Vec<u8>>("scores").unwrap(),
+            Some(vec![3, 1, 2])
+        );
+        assert_eq!(db.get_typed::<User>("nobody").unwrap(), None);
+        assert!(db.get_typed::<User>("scores").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Runnable examples, `tinydb example <name>`. Each one checks what it does &
+//! fails if something is off, so the binary doubles as a smoke test.
+//!
+//! Configured through the environment:
+//! - `TINYDB_DIR`: where the example databases go, a temp dir by default
+//! - `TINYDB_KEYS`: how many keys to write, 1000 by default
+//! - `TINYDB_THREADS`: writer threads of the concurrency example, 4 by default
+//! - `TINYDB_SYNC`: `never`, `every-write` or a sync interval in ms
+//! - `TINYDB_GARBAGE_RATIO`: compaction threshold of the compaction example
+
+use std::{env, fs, path::PathBuf, str::FromStr, thread, time::Duration};
+use tiny_db_exp::{
+    BackgroundCompaction, CollectionOptions, CompactionPolicy, DbOptions, EmbeddedDatabase, Result,
+    SyncPolicy, ThreadSafeDB,
+};
+
+pub const NAMES: [&str; 4] = ["basic", "concurrency", "compaction", "backup"];
+
+/// Run the example called `name`
+pub fn run(name: &str) -> Result<()> {
+    let config = Config::from_env()?;
+    fs::create_dir_all(&config.dir)?;
+    match name {
+        "basic" => basic(&config),
+        "concurrency" => concurrency(&config),
+        "compaction" => compaction(&config),
+        "backup" => backup(&config),
+        _ => Err(format!("no example {name:?}, try one of {}", NAMES.join(", ")).into()),
+    }
+}
+
+struct Config {
+    dir: PathBuf,
+    keys: usize,
+    threads: usize,
+    sync_policy: SyncPolicy,
+    garbage_ratio: f64,
+}
+
+impl Config {
+    fn from_env() -> Result<Self> {
+        let sync_policy = match env::var("TINYDB_SYNC").as_deref() {
+            Err(_) | Ok("never") => SyncPolicy::Never,
+            Ok("every-write") => SyncPolicy::EveryWrite,
+            Ok(millis) => SyncPolicy::Interval(Duration::from_millis(
+                millis
+                    .parse()
+                    .map_err(|_| format!("bad TINYDB_SYNC {millis:?}"))?,
+            )),
+        };
+        let keys = var("TINYDB_KEYS", 1_000)?;
+        if keys == 0 {
+            return Err("TINYDB_KEYS has to be at least 1".into());
+        }
+        Ok(Config {
+            dir: env::var_os("TINYDB_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| env::temp_dir().join("tinydb-examples")),
+            keys,
+            threads: var("TINYDB_THREADS", 4)?,
+            sync_policy,
+            garbage_ratio: var("TINYDB_GARBAGE_RATIO", 0.5)?,
+        })
+    }
+
+    /// A fresh database file for one example
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        let path = self.dir.join(format!("{name}.db"));
+        for stale in [path.clone(), path.with_extension("db.bak")] {
+            if stale.exists() {
+                fs::remove_file(stale)?;
+            }
+        }
+        Ok(path)
+    }
+
+    fn options(&self) -> DbOptions {
+        DbOptions {
+            sync_policy: self.sync_policy,
+            ..Default::default()
+        }
+    }
+}
+
+fn var<T: FromStr>(name: &str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(val) => Ok(val.parse().map_err(|_| format!("bad {name} {val:?}"))?),
+        Err(_) => Ok(default),
+    }
+}
+
+fn check(ok: bool, what: &str) -> Result<()> {
+    match ok {
+        true => Ok(()),
+        false => Err(format!("check failed: {what}").into()),
+    }
+}
+
+/// Writes, reads & deletes, then reopens the file
+fn basic(config: &Config) -> Result<()> {
+    let path = config.path("basic")?;
+    let mut db = EmbeddedDatabase::with_options(&path, config.options())?;
+    for i in 0..config.keys {
+        db.set(&format!("user:{i}"), &format!("name-{i}"))?;
+    }
+    db.delete("user:0")?;
+    drop(db);
+
+    let mut db = EmbeddedDatabase::with_options(&path, config.options())?;
+    check(db.get("user:0")?.is_none(), "deleted key stays deleted")?;
+    let last = config.keys - 1;
+    check(
+        db.get(&format!("user:{last}"))? == Some(format!("name-{last}")),
+        "keys survive a reopen",
+    )?;
+    println!(
+        "basic: {} keys in {}",
+        db.scan_keys("user:").len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Several threads writing to one ThreadSafeDB at once
+fn concurrency(config: &Config) -> Result<()> {
+    let path = config.path("concurrency")?;
+    let db = ThreadSafeDB::with_options(&path, config.options())?;
+    let writers: Vec<_> = (0..config.threads)
+        .map(|writer| {
+            let db = db.clone();
+            let keys = config.keys;
+            // The boxed error isn't Send, so it leaves the thread as a string
+            thread::spawn(move || -> std::result::Result<(), String> {
+                for i in 0..keys {
+                    db.set(&format!("{writer}:{i}"), &i.to_string())
+                        .map_err(|err| err.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().map_err(|_| "writer thread panicked")??;
+    }
+
+    let written = db.scan_keys("")?.len();
+    check(
+        written == config.threads * config.keys,
+        "every write landed",
+    )?;
+    println!(
+        "concurrency: {} threads wrote {written} keys",
+        config.threads
+    );
+    Ok(())
+}
+
+/// Overwrites pile up garbage until background compaction reclaims it
+fn compaction(config: &Config) -> Result<()> {
+    let path = config.path("compaction")?;
+    let options = DbOptions {
+        default_collection: CollectionOptions {
+            compaction: Some(CompactionPolicy {
+                garbage_ratio: config.garbage_ratio,
+                min_garbage_bytes: 0,
+            }),
+            ..Default::default()
+        },
+        background_compaction: Some(BackgroundCompaction {
+            check_interval: Duration::from_millis(50),
+            backpressure: None,
+        }),
+        ..config.options()
+    };
+    let db = ThreadSafeDB::with_options(&path, options)?;
+    for round in 0..4 {
+        for i in 0..config.keys {
+            db.set(&format!("key:{i}"), &round.to_string())?;
+        }
+    }
+    // Give the compaction thread a few rounds to catch up
+    thread::sleep(Duration::from_millis(500));
+
+    let garbage_bytes = db.lock()?.garbage_bytes();
+    check(
+        garbage_bytes == 0,
+        "background compaction reclaimed the garbage",
+    )?;
+    check(
+        db.get("key:0")?.as_deref() == Some("3"),
+        "latest values kept",
+    )?;
+    println!(
+        "compaction: {} keys in {} bytes",
+        config.keys,
+        fs::metadata(&path)?.len()
+    );
+    Ok(())
+}
+
+/// Copies a synced database & verifies the copy
+fn backup(config: &Config) -> Result<()> {
+    let path = config.path("backup")?;
+    let mut db = EmbeddedDatabase::with_options(&path, config.options())?;
+    for i in 0..config.keys {
+        db.set(&format!("key:{i}"), &i.to_string())?;
+    }
+    db.sync()?;
+    let backup_path = path.with_extension("db.bak");
+    fs::copy(&path, &backup_path)?;
+
+    let report = EmbeddedDatabase::verify_backup(&backup_path, None)?;
+    check(report.is_valid(), "backup verifies")?;
+    check(
+        report.live_keys == config.keys as u64,
+        "backup has every key",
+    )?;
+    println!(
+        "backup: {} records, {} live keys in {}",
+        report.records,
+        report.live_keys,
+        backup_path.display()
+    );
+    Ok(())
+}
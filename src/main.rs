@@ -1,3 +1,71 @@
-fn main() {
-    println!("Hello, world!");
+mod examples;
+mod soak;
+
+use std::process::ExitCode;
+use tiny_db_exp::EmbeddedDatabase;
+
+const USAGE: &str = "usage:
+  tinydb query <db file> \"SELECT key, value WHERE key LIKE 'user:%' LIMIT 10\"
+  tinydb example <name>...   (see src/examples.rs for the environment it reads)
+  tinydb soak [--hours 1] [--threads 4] [--crash-every 10m] [--keys 1000] [--seed N] [--path FILE]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, [path, sql])) if command == "query" => query(path, sql),
+        Some((command, names)) if command == "example" => run_examples(names),
+        Some((command, flags)) if command == "soak" => soak(flags),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the named examples, or all of them when none are named
+fn run_examples(names: &[String]) -> ExitCode {
+    let names: Vec<&str> = match names {
+        [] => examples::NAMES.to_vec(),
+        names => names.iter().map(String::as_str).collect(),
+    };
+    let mut failed = false;
+    for name in names {
+        if let Err(err) = examples::run(name) {
+            eprintln!("{name}: error: {err}");
+            failed = true;
+        }
+    }
+    match failed {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+fn soak(flags: &[String]) -> ExitCode {
+    match soak::run(flags) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn query(path: &str, sql: &str) -> ExitCode {
+    let result = EmbeddedDatabase::new(path).and_then(|mut db| db.query(sql));
+    match result {
+        Ok(result) => {
+            // Tab separated with a header line, easy to pipe into other tools
+            println!("{}", result.columns.join("\t"));
+            for row in result.rows {
+                println!("{}", row.join("\t"));
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
 }
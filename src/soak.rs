@@ -0,0 +1,286 @@
+//! `tinydb soak`: random writes from several threads against a model of what
+//! the db should hold, with a simulated crash every so often. A crash stops
+//! the writers, drops the db without a final sync & leaves half a record at
+//! the end of the file, like a process killed in the middle of a write. The
+//! db is then reopened & has to match the model exactly.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::OpenOptions,
+    io::Write,
+    mem,
+    path::PathBuf,
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tiny_db_exp::{DbOptions, Result, SyncPolicy, ThreadSafeDB, WriteBatch};
+
+struct Config {
+    duration: Duration,
+    threads: usize,
+    crash_every: Duration,
+    keys: u64,
+    seed: u64,
+    path: PathBuf,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut config = Config {
+            duration: Duration::from_secs(3600),
+            threads: 4,
+            crash_every: Duration::from_secs(600),
+            keys: 1_000,
+            seed: SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+            path: env::temp_dir().join(format!("tinydb-soak-{}.db", process::id())),
+        };
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let val = args.next().ok_or_else(|| format!("{flag} needs a value"))?;
+            let bad = || format!("bad value for {flag}: {val:?}");
+            match flag.as_str() {
+                "--hours" => {
+                    let hours: f64 = val.parse().map_err(|_| bad())?;
+                    config.duration =
+                        Duration::try_from_secs_f64(hours * 3600.0).map_err(|_| bad())?;
+                }
+                "--threads" => config.threads = val.parse().map_err(|_| bad())?,
+                "--crash-every" => config.crash_every = parse_duration(val).ok_or_else(bad)?,
+                "--keys" => config.keys = val.parse().map_err(|_| bad())?,
+                "--seed" => config.seed = val.parse().map_err(|_| bad())?,
+                "--path" => config.path = PathBuf::from(val),
+                _ => return Err(format!("unknown flag {flag}").into()),
+            }
+        }
+        if config.threads == 0 || config.keys == 0 || config.crash_every.is_zero() {
+            return Err("threads, keys & crash interval have to be more than 0".into());
+        }
+        Ok(config)
+    }
+}
+
+/// `90s`, `10m` or `2h`
+fn parse_duration(val: &str) -> Option<Duration> {
+    let unit = match val.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        _ => return None,
+    };
+    let count: u64 = val[..val.len() - 1].parse().ok()?;
+    Some(Duration::from_secs(count * unit))
+}
+
+/// xorshift64*, good enough to pick keys & reproducible from the seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+}
+
+/// What one writer thread's keys should hold, `None` for deleted
+type Model = BTreeMap<String, Option<String>>;
+
+#[derive(Default)]
+struct Report {
+    writes: u64,
+    compactions: u64,
+    crashes: u64,
+    keys_checked: u64,
+    mismatches: Vec<String>,
+}
+
+/// Run the soak test with the command line `args`, printing a report
+pub fn run(args: &[String]) -> Result<bool> {
+    let config = Config::parse(args)?;
+    // The model starts out empty, & it's no place for a real db anyway
+    if config.path.exists() {
+        return Err(format!(
+            "{} already exists, pick a new --path",
+            config.path.display()
+        )
+        .into());
+    }
+    println!(
+        "soak: {:?} with {} threads, a crash every {:?}, seed {}, file {}",
+        config.duration,
+        config.threads,
+        config.crash_every,
+        config.seed,
+        config.path.display()
+    );
+
+    let options = || DbOptions {
+        // Every acknowledged write has to survive a crash
+        sync_policy: SyncPolicy::EveryWrite,
+        hint_files: true,
+        ..Default::default()
+    };
+    let mut models: Vec<Model> = vec![Model::new(); config.threads];
+    let mut rngs: Vec<Rng> = (0..config.threads as u64)
+        .map(|thread| Rng::new(config.seed ^ (thread + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+        .collect();
+    let mut report = Report::default();
+    let started = Instant::now();
+
+    while started.elapsed() < config.duration && report.mismatches.is_empty() {
+        let db = ThreadSafeDB::with_options(&config.path, options())?;
+        verify(&db, &models, &mut report)?;
+        if !report.mismatches.is_empty() {
+            break;
+        }
+
+        let round = config
+            .crash_every
+            .min(config.duration.saturating_sub(started.elapsed()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let workers: Vec<_> = mem::take(&mut models)
+            .into_iter()
+            .zip(mem::take(&mut rngs))
+            .enumerate()
+            .map(|(thread, (model, rng))| {
+                let (db, stop, keys) = (db.clone(), stop.clone(), config.keys);
+                thread::spawn(move || write_until(&db, thread, keys, model, rng, &stop))
+            })
+            .collect();
+        thread::sleep(round);
+        stop.store(true, Ordering::Relaxed);
+        for worker in workers {
+            let (model, rng, outcome) = worker.join().map_err(|_| "writer thread panicked")?;
+            models.push(model);
+            rngs.push(rng);
+            report.writes += outcome.writes;
+            report.compactions += outcome.compactions;
+            if let Some(err) = outcome.error {
+                report.mismatches.push(format!("write failed: {err}"));
+            }
+        }
+
+        // Crash: no final sync, then a record torn half way through
+        drop(db);
+        let mut file = OpenOptions::new().append(true).open(&config.path)?;
+        file.write_all(&64u64.to_le_bytes())?;
+        file.write_all(&[0xAB; 20])?;
+        report.crashes += 1;
+        println!(
+            "soak: {:?} in, {} writes, {} crashes",
+            started.elapsed(),
+            report.writes,
+            report.crashes
+        );
+    }
+    if report.mismatches.is_empty() {
+        let db = ThreadSafeDB::with_options(&config.path, options())?;
+        verify(&db, &models, &mut report)?;
+    }
+
+    println!(
+        "soak report: {:?}, {} writes, {} compactions, {} crashes, {} keys checked, {} problems",
+        started.elapsed(),
+        report.writes,
+        report.compactions,
+        report.crashes,
+        report.keys_checked,
+        report.mismatches.len()
+    );
+    for mismatch in report.mismatches.iter().take(20) {
+        println!("  {mismatch}");
+    }
+    Ok(report.mismatches.is_empty())
+}
+
+#[derive(Default)]
+struct Outcome {
+    writes: u64,
+    compactions: u64,
+    error: Option<String>,
+}
+
+/// Random sets, deletes & batches on the thread's own keys until `stop`.
+/// The model is only updated once a write returned, so it never expects
+/// more than what was acknowledged.
+fn write_until(
+    db: &ThreadSafeDB,
+    thread: usize,
+    keys: u64,
+    mut model: Model,
+    mut rng: Rng,
+    stop: &AtomicBool,
+) -> (Model, Rng, Outcome) {
+    let mut outcome = Outcome::default();
+    while !stop.load(Ordering::Relaxed) {
+        let key = format!("{thread}:{}", rng.below(keys));
+        let written = match rng.below(100) {
+            0..60 => {
+                let val = rng.below(u64::MAX).to_string();
+                db.set(&key, &val).map(|()| vec![(key, Some(val))])
+            }
+            60..85 => db.delete(&key).map(|()| vec![(key, None)]),
+            85..99 => {
+                let mut batch = WriteBatch::new();
+                let mut staged = Vec::new();
+                for _ in 0..1 + rng.below(8) {
+                    let key = format!("{thread}:{}", rng.below(keys));
+                    let val = rng.below(u64::MAX).to_string();
+                    batch.set(&key, &val);
+                    staged.push((key, Some(val)));
+                }
+                db.apply_batch(batch).map(|()| staged)
+            }
+            _ => {
+                outcome.compactions += 1;
+                db.compact().map(|()| Vec::new())
+            }
+        };
+        match written {
+            Ok(written) => {
+                outcome.writes += 1;
+                model.extend(written);
+            }
+            Err(err) => {
+                outcome.error = Some(err.to_string());
+                break;
+            }
+        }
+    }
+    (model, rng, outcome)
+}
+
+/// Compare every key the models know of, & the number of live keys
+fn verify(db: &ThreadSafeDB, models: &[Model], report: &mut Report) -> Result<()> {
+    let mut live = 0;
+    for model in models {
+        for (key, expected) in model {
+            report.keys_checked += 1;
+            live += usize::from(expected.is_some());
+            let found = db.get(key)?;
+            if found != *expected {
+                report
+                    .mismatches
+                    .push(format!("{key}: expected {expected:?}, found {found:?}"));
+            }
+        }
+    }
+    let keys = db.scan_keys("")?.len();
+    if keys != live {
+        report
+            .mismatches
+            .push(format!("expected {live} live keys, found {keys}"));
+    }
+    Ok(())
+}